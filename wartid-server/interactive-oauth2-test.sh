@@ -18,8 +18,9 @@ fi
 client_id_def=$(psql -qtAX "$DATABASE_URL" -c "SELECT (id) FROM user_apps LIMIT 1")
 read -r -p "Client ID (default: $client_id_def): " client_id
 client_id=${client_id:-$client_id_def}
-client_secret=$(psql -qtAX "$DATABASE_URL" -c "SELECT (oauth_secret) FROM user_apps WHERE id = '$client_id' LIMIT 1")
-python -c "print('Client Secret (found in database): ' + ('*' * ${#client_secret}))"
+# Only a hash of the secret is stored, it has to be copied from the app's page when it's generated
+read -r -s -p "Client Secret: " client_secret
+echo
 client_redirect=$(psql -qtAX "$DATABASE_URL" -c "SELECT (oauth_redirect) FROM user_apps WHERE id = '$client_id' LIMIT 1")
 echo "Client Redirect (found in database): $client_redirect"
 
@@ -0,0 +1,3 @@
+alter table user_apps
+    drop column oauth_auth_method,
+    drop column oauth_jwks;
@@ -0,0 +1,3 @@
+alter table user_apps
+    add column oauth_auth_method varchar default null,
+    add column oauth_jwks varchar default null;
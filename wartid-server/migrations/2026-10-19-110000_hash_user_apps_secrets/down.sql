@@ -0,0 +1,4 @@
+-- Hashed secrets can't be recovered, every app will have to regenerate theirs
+alter table user_apps drop column oauth_secret_previous_hash;
+update user_apps set oauth_secret_hash = null;
+alter table user_apps rename column oauth_secret_hash to oauth_secret;
@@ -0,0 +1,8 @@
+-- Secrets are random and long enough for a single SHA-256 round to be a safe way to store them
+alter table user_apps rename column oauth_secret to oauth_secret_hash;
+update user_apps
+    set oauth_secret_hash = encode(sha256(convert_to(oauth_secret_hash, 'UTF8')), 'hex')
+    where oauth_secret_hash is not null;
+
+-- Kept valid after a rotation until explicitly revoked
+alter table user_apps add column oauth_secret_previous_hash varchar(64) default null;
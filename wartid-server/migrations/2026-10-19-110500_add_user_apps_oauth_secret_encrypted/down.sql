@@ -0,0 +1 @@
+alter table user_apps drop column oauth_secret_encrypted;
@@ -0,0 +1,3 @@
+-- client_secret_jwt uses the secret itself as the HMAC key, so apps pinned to it keep an encrypted
+-- copy of their current secret. Apps that were pinned to it before have to regenerate theirs.
+alter table user_apps add column oauth_secret_encrypted varchar default null;
@@ -0,0 +1,2 @@
+drop index idx_personal_access_tokens_users;
+drop table personal_access_tokens;
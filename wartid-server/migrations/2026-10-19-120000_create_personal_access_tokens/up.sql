@@ -0,0 +1,11 @@
+create table personal_access_tokens (
+    id uuid primary key default uuid_generate_v4 (),
+    users_id uuid not null references users(id) on delete cascade,
+    name varchar not null,
+    token_hash varchar(64) not null unique,
+    scopes varchar not null,
+    expiration timestamp(0) not null,
+    last_used timestamp(0) default null
+);
+
+create index idx_personal_access_tokens_users on personal_access_tokens(users_id);
@@ -0,0 +1,3 @@
+alter table user_apps
+    drop column saml_entity_id,
+    drop column saml_acs_url;
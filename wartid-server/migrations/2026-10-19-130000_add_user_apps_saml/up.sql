@@ -0,0 +1,3 @@
+alter table user_apps
+    add column saml_entity_id varchar default null unique,
+    add column saml_acs_url varchar default null;
@@ -0,0 +1,2 @@
+drop table groups_members;
+drop table groups;
@@ -0,0 +1,12 @@
+create table groups (
+    id uuid primary key default uuid_generate_v4 (),
+    name varchar(64) not null unique,
+    description varchar default null
+);
+
+create table groups_members (
+    groups_id uuid not null references groups(id) on delete cascade,
+    users_id uuid not null references users(id) on delete cascade,
+
+    primary key (groups_id, users_id)
+);
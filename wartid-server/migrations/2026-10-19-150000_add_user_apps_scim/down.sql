@@ -0,0 +1,2 @@
+alter table user_apps
+    drop column scim_token_hash;
@@ -0,0 +1,2 @@
+alter table user_apps
+    add column scim_token_hash varchar(64) default null unique;
@@ -0,0 +1,2 @@
+drop table webhooks_deliveries;
+drop table webhooks;
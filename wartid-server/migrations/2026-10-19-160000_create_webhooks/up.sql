@@ -0,0 +1,25 @@
+create table webhooks (
+    id uuid primary key default uuid_generate_v4 (),
+    user_apps_id uuid not null references user_apps(id) on delete cascade,
+    url varchar not null,
+    secret varchar(64) not null,
+    events varchar not null
+);
+
+create index idx_webhooks_user_apps on webhooks(user_apps_id);
+
+create table webhooks_deliveries (
+    id uuid primary key default uuid_generate_v4 (),
+    webhooks_id uuid not null references webhooks(id) on delete cascade,
+    event varchar not null,
+    payload varchar not null,
+    created timestamp(0) not null,
+    attempts integer not null default 0,
+    next_attempt timestamp(0) default null,
+    delivered timestamp(0) default null,
+    last_status integer default null,
+    last_error varchar default null
+);
+
+create index idx_webhooks_deliveries_webhooks on webhooks_deliveries(webhooks_id);
+create index idx_webhooks_deliveries_next_attempt on webhooks_deliveries(next_attempt);
@@ -0,0 +1,8 @@
+alter table sessions_oauth2
+    drop column claims;
+
+alter table users
+    drop column picture,
+    drop column locale,
+    drop column email_verified,
+    drop column updated;
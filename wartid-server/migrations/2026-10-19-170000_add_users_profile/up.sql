@@ -0,0 +1,8 @@
+alter table users
+    add column picture varchar default null,
+    add column locale varchar(35) default null,
+    add column email_verified boolean not null default false,
+    add column updated timestamp(0) not null default now();
+
+alter table sessions_oauth2
+    add column claims varchar not null default '';
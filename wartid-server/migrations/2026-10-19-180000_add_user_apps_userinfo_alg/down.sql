@@ -0,0 +1,2 @@
+alter table user_apps
+    drop column oauth_userinfo_alg;
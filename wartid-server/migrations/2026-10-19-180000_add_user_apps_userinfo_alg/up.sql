@@ -0,0 +1,2 @@
+alter table user_apps
+    add column oauth_userinfo_alg varchar(16) default null;
@@ -0,0 +1 @@
+drop table user_apps_groups;
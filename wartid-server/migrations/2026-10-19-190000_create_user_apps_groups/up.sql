@@ -0,0 +1,6 @@
+create table user_apps_groups (
+    user_apps_id uuid not null references user_apps(id) on delete cascade,
+    groups_id uuid not null references groups(id) on delete cascade,
+
+    primary key (user_apps_id, groups_id)
+);
@@ -0,0 +1,2 @@
+alter table users
+    add column suspended timestamp(0) default null;
@@ -0,0 +1,17 @@
+alter table users
+    add column discord_id bigint default null unique;
+create unique index idx_users_discord on users(discord_id);
+
+update users
+set discord_id = (
+    select case
+               when subject::numeric > 9223372036854775807
+                   then (subject::numeric - 18446744073709551616)::bigint
+               else subject::bigint
+           end
+    from federated_identities
+    where provider = 'discord'
+      and users_id = users.id
+);
+
+drop table federated_identities;
@@ -0,0 +1,24 @@
+create table federated_identities (
+    provider varchar(32) not null,
+    subject varchar not null,
+    users_id uuid not null references users(id) on delete cascade,
+    last_login timestamp(0) default null,
+
+    primary key (provider, subject),
+    unique (provider, users_id)
+);
+
+-- Discord IDs were bit-cast into a bigint
+insert into federated_identities (provider, subject, users_id)
+select 'discord',
+       case
+           when discord_id < 0 then (discord_id::numeric + 18446744073709551616)::varchar
+           else discord_id::varchar
+       end,
+       id
+from users
+where discord_id is not null;
+
+drop index idx_users_discord;
+alter table users
+    drop column discord_id;
@@ -0,0 +1,6 @@
+create table notification_opt_outs (
+    users_id uuid not null references users(id) on delete cascade,
+    kind varchar(32) not null,
+
+    primary key (users_id, kind)
+);
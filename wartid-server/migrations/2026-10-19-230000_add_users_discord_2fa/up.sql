@@ -0,0 +1,2 @@
+alter table users
+    add column discord_2fa boolean not null default false;
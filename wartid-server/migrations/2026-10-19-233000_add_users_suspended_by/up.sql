@@ -0,0 +1,7 @@
+alter table users
+    add column suspended_by varchar default null;
+
+-- Only the Discord guild check could suspend users until now
+update users
+    set suspended_by = 'discord-guilds'
+    where suspended is not null;
@@ -1,12 +1,158 @@
 use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 
+use crate::model::UserAppId;
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     #[serde(deserialize_with = "deserialize_base_url")]
     pub base_url: String,
 
+    /// PKCS#8 file holding the Ed25519 key used to sign tokens verified by third parties, created
+    /// on startup if missing. A throwaway key is generated at each start if this isn't set.
+    pub signing_key: Option<PathBuf>,
+
+    /// File holding the key encrypting the secrets WartID has to read back, created on startup if
+    /// missing. Apps can't use `client_secret_jwt` if this isn't set.
+    pub secrets_key: Option<PathBuf>,
+
+    /// Key and certificate of the SAML identity provider. SAML is disabled if this isn't set.
+    pub saml: Option<SamlConfig>,
+
+    /// Read-only LDAP interface over the users and their groups. Disabled if this isn't set.
+    pub ldap: Option<LdapConfig>,
+
+    /// Domain of the `login_session` cookie, like `example.com`. Setting it shares the session with
+    /// the subdomains, which is needed for reverse proxies asking `/auth/verify` to see it.
+    pub session_cookie_domain: Option<String>,
+
+    /// Restrictions on who can access the hosts protected with `/auth/verify`. Hosts without a
+    /// matching rule are open to every logged in user, but not to access tokens of WartApps.
+    #[serde(default)]
+    pub forward_auth: Vec<ForwardAuthRule>,
+
+    /// WartApps allowed to register webhooks, which are sent the events about every user
+    #[serde(default)]
+    pub webhook_apps: Vec<UserAppId>,
+
+    /// WartApps allowed to read users and groups through SCIM. SCIM tokens of the apps listed in
+    /// neither this nor [scim_provisioners](Self::scim_provisioners) are refused.
+    #[serde(default)]
+    pub scim_readers: Vec<UserAppId>,
+
+    /// WartApps allowed to read, create, modify and delete users and groups through SCIM
+    #[serde(default)]
+    pub scim_provisioners: Vec<UserAppId>,
+
     pub discord: Option<DiscordConfig>,
+
+    /// Upstream OpenID Connect (or plain OAuth2) providers users can log in with, like GitLab or
+    /// GitHub
+    #[serde(default)]
+    pub upstream_oidc: Vec<UpstreamOidcConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SamlConfig {
+    /// PEM file of the RSA private key signing assertions (PKCS#1 or PKCS#8)
+    pub key: PathBuf,
+
+    /// PEM file of the X.509 certificate matching [key](Self::key), published in the metadata
+    pub certificate: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LdapConfig {
+    /// There's no TLS support, so this should be a loopback address unless a TLS terminating
+    /// proxy is put in front
+    pub address: SocketAddr,
+
+    /// DN under which `ou=users` and `ou=groups` are exposed, like `dc=wartid,dc=local`
+    pub base_dn: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForwardAuthRule {
+    /// Either an exact host name or a wildcard like `*.example.com`, matching its subdomains
+    pub host: String,
+
+    /// Usernames allowed through
+    #[serde(default)]
+    pub users: Vec<String>,
+
+    /// Names of the groups whose members are allowed through
+    #[serde(default)]
+    pub groups: Vec<String>,
+
+    /// WartApps whose access tokens are accepted for this host. Tokens issued to other apps are
+    /// refused, so an app can't reuse the tokens it was given elsewhere. Personal access tokens
+    /// are always accepted.
+    #[serde(default)]
+    pub clients: Vec<UserAppId>,
+}
+
+impl ForwardAuthRule {
+    pub fn matches(&self, host: &str) -> bool {
+        // Ports aren't part of the rules
+        let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+
+        match self.host.strip_prefix("*.") {
+            Some(domain) => is_subdomain(host, domain),
+            None => host.eq_ignore_ascii_case(&self.host),
+        }
+    }
+}
+
+/// Whether `host` is a subdomain of `domain`, at any depth
+fn is_subdomain(host: &str, domain: &str) -> bool {
+    host.len()
+        .checked_sub(domain.len() + 1)
+        .is_some_and(|split| {
+            host.as_bytes()[split] == b'.' && host[split + 1..].eq_ignore_ascii_case(domain)
+        })
+}
+
+impl Config {
+    /// Whether users can be sent to `url` after logging in: WartID itself, or the hosts protected
+    /// with `/auth/verify`, matching a [forward_auth](Self::forward_auth) rule or within the
+    /// [session_cookie_domain](Self::session_cookie_domain)
+    ///
+    /// Browsers treat `\` like `/` and drop tabs and newlines from URLs, so `/\evil.com` or
+    /// `/\t/evil.com` would send users to another host: such characters are refused anywhere.
+    pub fn is_login_redirect_allowed(&self, url: &str) -> bool {
+        if url.contains('\\') || url.contains(|c: char| c.is_ascii_control()) {
+            return false;
+        }
+        if url.starts_with('/') {
+            return !url.starts_with("//");
+        }
+        if url.starts_with(&self.base_url) {
+            return true;
+        }
+
+        let Some(rest) = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))
+        else {
+            return false;
+        };
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        // `https://example.com@evil.com` goes to evil.com
+        if authority.contains('@') {
+            return false;
+        }
+        let host = authority
+            .rsplit_once(':')
+            .map_or(authority, |(host, _)| host);
+
+        self.forward_auth.iter().any(|rule| rule.matches(host))
+            || self.session_cookie_domain.as_deref().is_some_and(|domain| {
+                let domain = domain.trim_start_matches('.');
+                host.eq_ignore_ascii_case(domain) || is_subdomain(host, domain)
+            })
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -14,6 +160,83 @@ pub struct DiscordConfig {
     pub token: String,
 
     pub allowed_guilds: Arc<[u64]>,
+
+    /// Discord roles granting WartID groups. The bot keeps the groups of linked users in sync,
+    /// adding and removing them as the roles change.
+    #[serde(default)]
+    pub role_groups: Vec<DiscordRoleGroup>,
+
+    /// Credentials of the Discord application, enabling the "Se connecter avec Discord" button.
+    /// Its redirect URI must be set to `<base_url>login/discord/callback`.
+    pub oauth: Option<DiscordOAuthConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DiscordRoleGroup {
+    pub guild: u64,
+
+    pub role: u64,
+
+    /// Name of the WartID group, created if it doesn't exist
+    pub group: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiscordOAuthConfig {
+    pub client_id: u64,
+
+    pub client_secret: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UpstreamOidcConfig {
+    /// Identifies the provider in URLs and in linked accounts, like `gitlab`. Changing it unlinks
+    /// every account.
+    pub id: String,
+
+    /// Shown on the login button, like "GitLab"
+    pub name: String,
+
+    pub client_id: String,
+
+    pub client_secret: String,
+
+    /// Issuer whose `.well-known/openid-configuration` gives the endpoints that aren't set
+    pub issuer: Option<String>,
+
+    pub authorization_endpoint: Option<String>,
+
+    pub token_endpoint: Option<String>,
+
+    pub userinfo_endpoint: Option<String>,
+
+    #[serde(default = "default_upstream_scopes")]
+    pub scopes: String,
+
+    /// Userinfo claim identifying the account, like `id` for GitHub
+    #[serde(default = "default_upstream_subject_claim")]
+    pub subject_claim: String,
+
+    /// Userinfo claim used as the name of new users, like `login` for GitHub
+    #[serde(default = "default_upstream_username_claim")]
+    pub username_claim: String,
+
+    /// Whether logging in with an account that isn't linked yet creates a new user. Otherwise,
+    /// users must link it from their profile first.
+    #[serde(default)]
+    pub create_users: bool,
+}
+
+fn default_upstream_scopes() -> String {
+    String::from("openid profile")
+}
+
+fn default_upstream_subject_claim() -> String {
+    String::from("sub")
+}
+
+fn default_upstream_username_claim() -> String {
+    String::from("preferred_username")
 }
 
 fn deserialize_base_url<'de, D: serde::Deserializer<'de>>(
@@ -31,3 +254,49 @@ fn deserialize_base_url<'de, D: serde::Deserializer<'de>>(
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_redirects() {
+        let config: Config = toml::from_str(
+            r#"
+            base_url = "https://wart.id/"
+            session_cookie_domain = "example.com"
+            forward_auth = [{ host = "*.grafana.net" }]
+            "#,
+        )
+        .unwrap();
+
+        for url in [
+            "/@me",
+            "/oauth2/authorize?client_id=1",
+            "https://wart.id/apps",
+            "https://wiki.example.com/page",
+            "https://example.com:8443/",
+            "http://eu.grafana.net/d/1",
+        ] {
+            assert!(config.is_login_redirect_allowed(url), "{url:?}");
+        }
+
+        for url in [
+            "https://evil.com/",
+            "//evil.com",
+            "/\\evil.com",
+            "\\\\evil.com",
+            "/\t/evil.com",
+            "/\n/evil.com",
+            "https://wart.id.evil.com/",
+            "https://wart.id/\\@evil.com",
+            "https://example.com@evil.com/",
+            "https://evilexample.com/",
+            "https://grafana.net/",
+            "javascript://example.com/%0aalert(1)",
+            "evil.com",
+        ] {
+            assert!(!config.is_login_redirect_allowed(url), "{url:?}");
+        }
+    }
+}
@@ -1,24 +1,38 @@
-use crate::config::Config;
+use crate::config::{Config, DiscordRoleGroup};
+use crate::i18n::Locale;
+use crate::model::{
+    FederatedIdentity, Group, NotificationKind, OAuth2Session, Session, SuspensionSource, User,
+    UserApp, WartIDError, WartIDResult,
+};
+use crate::{DbConn, DbPool};
 use chrono::{Duration, Utc};
+use diesel::Connection;
 use jsonwebtoken::errors::{Error as JwtError, ErrorKind as JwtErrorKind};
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, TokenData, Validation};
 use rocket::form::validate::Contains;
+use serenity::builder::CreateApplicationCommand;
 use serenity::client::bridge::gateway::ShardManager;
 use serenity::framework::StandardFramework;
-use serenity::http::{CacheHttp, Typing};
+use serenity::http::{CacheHttp, HttpError, Typing};
+use serenity::model::application::command::Command;
+use serenity::model::application::component::ButtonStyle;
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::application::interaction::message_component::MessageComponentInteraction;
+use serenity::model::application::interaction::{Interaction, InteractionResponseType};
 use serenity::model::channel::Message;
+use serenity::model::gateway::Ready;
+use serenity::model::guild::{Guild, Member};
 use serenity::model::id::{GuildId, UserId};
+use serenity::model::user::User as DiscordUser;
 use serenity::prelude::*;
+use serenity::utils::MessageBuilder;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
-fn random_of<T: Copy>(slice: &[T]) -> T {
-    use rand::seq::SliceRandom;
-
-    *slice.choose(&mut rand::thread_rng()).expect("empty slice")
-}
-
 /// The claims embedded inside the JWT
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct Claims {
@@ -47,33 +61,866 @@ fn encode(
     )
 }
 
-struct Handler {
-    key: EncodingKey,
-    login_url: String,
+/// Discord's error code for users who aren't in the guild
+const UNKNOWN_MEMBER: isize = 10007;
+
+/// Memberships of linked users are checked again this often, in case member events were missed
+/// or aren't received at all (the "Server Members" intent is only requested for the role sync)
+const REVALIDATION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// `None` if the user isn't in the guild. Other errors, like the bot not being in the guild, don't
+/// say anything about the user.
+async fn fetch_member(
+    cache_http: impl CacheHttp,
+    guild: GuildId,
+    discord_user: UserId,
+) -> serenity::Result<Option<Member>> {
+    match guild.member(cache_http, discord_user).await {
+        Ok(member) => Ok(Some(member)),
+        Err(serenity::Error::Http(err)) if is_unknown_member(&err) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn is_unknown_member(err: &HttpError) -> bool {
+    matches!(err, HttpError::UnsuccessfulRequest(response) if response.error.code == UNKNOWN_MEMBER)
+}
+
+/// Suspends the linked users who left every allowed guild, see [User::suspended]
+struct Membership {
     allowed_guilds: Arc<[u64]>,
+
+    /// Users known to be in an allowed guild, forgotten when they leave one and at each
+    /// [revalidation](Self::revalidate_all)
     allowed_users_cache: RwLock<Vec<UserId>>,
+
+    db: DbPool,
 }
 
-impl Handler {
-    async fn is_user_allowed(&self, ctx: impl CacheHttp, user_id: UserId) -> bool {
-        if self.allowed_users_cache.read().await.contains(&user_id) {
+impl Membership {
+    fn is_allowed_guild(&self, guild: GuildId) -> bool {
+        self.allowed_guilds.contains(&guild.0)
+    }
+
+    async fn is_allowed(&self, cache_http: impl CacheHttp, discord_user: UserId) -> bool {
+        if self
+            .allowed_users_cache
+            .read()
+            .await
+            .contains(&discord_user)
+        {
             return true;
         }
 
+        self.check(cache_http, discord_user).await == Some(true)
+    }
+
+    /// `None` if it can't be known for sure
+    async fn check(&self, cache_http: impl CacheHttp, discord_user: UserId) -> Option<bool> {
+        let mut known = true;
+
         for guild in self.allowed_guilds.iter().copied().map(GuildId) {
-            if guild.member(&ctx, user_id).await.is_ok() {
-                let mut users_cache = self.allowed_users_cache.write().await;
-                users_cache.push(user_id);
-                return true;
+            match fetch_member(&cache_http, guild, discord_user).await {
+                Ok(Some(_)) => {
+                    let mut users_cache = self.allowed_users_cache.write().await;
+                    if !users_cache.contains(&discord_user) {
+                        users_cache.push(discord_user);
+                    }
+                    return Some(true);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    log::warn!("cannot check if {discord_user} is in {guild}: {err}");
+                    known = false;
+                }
+            }
+        }
+
+        known.then_some(false)
+    }
+
+    /// Checks the membership again, suspending the linked WartID user or lifting their suspension
+    async fn revalidate(&self, cache_http: impl CacheHttp, discord_user: UserId) {
+        self.allowed_users_cache
+            .write()
+            .await
+            .retain(|cached| *cached != discord_user);
+
+        let Some(is_member) = self.check(cache_http, discord_user).await else {
+            return;
+        };
+
+        let result = async {
+            let db = self.db.get().await.ok_or(WartIDError::DatabaseConnection)?;
+
+            db.run(move |db| update_suspension(db, discord_user.0, is_member))
+                .await
+        };
+
+        match result.await {
+            Ok(true) if is_member => log::info!("lifted the suspension of {discord_user}"),
+            Ok(true) => log::info!("suspended {discord_user}, who left every allowed guild"),
+            Ok(false) => {}
+            Err(err) => log::error!("cannot update the suspension of {discord_user}: {err}"),
+        }
+    }
+
+    async fn revalidate_all(&self, cache_http: impl CacheHttp) {
+        self.allowed_users_cache.write().await.clear();
+
+        let discord_ids = async {
+            let db = self.db.get().await.ok_or(WartIDError::DatabaseConnection)?;
+            db.run(FederatedIdentity::find_all_discord_ids).await
+        };
+        let discord_ids = match discord_ids.await {
+            Ok(discord_ids) => discord_ids,
+            Err(err) => {
+                log::error!("cannot list the users to revalidate: {err}");
+                return;
             }
+        };
+
+        for discord_user in discord_ids {
+            self.revalidate(&cache_http, UserId(discord_user)).await;
+        }
+    }
+}
+
+/// Suspends the user linked to the Discord account if they left every allowed guild, or lifts a
+/// suspension for that reason. Returns `false` if nothing changed.
+fn update_suspension(
+    db: crate::DbConnection,
+    discord_id: u64,
+    is_member: bool,
+) -> WartIDResult<bool> {
+    match User::find_by_discord_id(db, discord_id)? {
+        Some(user) => User::set_suspended(db, user.id, !is_member, SuspensionSource::DiscordGuilds),
+        None => Ok(false),
+    }
+}
+
+/// Keeps the groups of linked users in sync with their roles, see
+/// [DiscordConfig::role_groups](crate::config::DiscordConfig::role_groups)
+struct RoleSync {
+    role_groups: Vec<DiscordRoleGroup>,
+    db: DbPool,
+}
+
+impl RoleSync {
+    fn is_mapped(&self, guild: GuildId) -> bool {
+        self.role_groups
+            .iter()
+            .any(|mapping| mapping.guild == guild.0)
+    }
+
+    /// Looks up the roles of the user in every mapped guild, since a group can be granted by
+    /// roles from several guilds
+    async fn sync(&self, cache_http: impl CacheHttp, discord_user: UserId) {
+        if self.role_groups.is_empty() {
+            return;
+        }
+
+        let guilds = self
+            .role_groups
+            .iter()
+            .map(|mapping| mapping.guild)
+            .collect::<BTreeSet<_>>();
+
+        let mut roles = Vec::new();
+        for guild in guilds {
+            match fetch_member(&cache_http, GuildId(guild), discord_user).await {
+                Ok(Some(member)) => roles.extend(member.roles.iter().map(|role| (guild, role.0))),
+                Ok(None) => {}
+                Err(err) => {
+                    // Groups aren't removed when the roles can't be known for sure
+                    log::warn!("cannot fetch the roles of {discord_user} in {guild}: {err}");
+                    return;
+                }
+            }
+        }
+
+        let managed = self
+            .role_groups
+            .iter()
+            .map(|mapping| mapping.group.clone())
+            .collect::<BTreeSet<_>>();
+        let member_of = self
+            .role_groups
+            .iter()
+            .filter(|mapping| roles.contains(&(mapping.guild, mapping.role)))
+            .map(|mapping| mapping.group.clone())
+            .collect::<BTreeSet<_>>();
+
+        let result = async {
+            let db = self.db.get().await.ok_or(WartIDError::DatabaseConnection)?;
+
+            db.run(move |db| {
+                let Some(user) = User::find_by_discord_id(db, discord_user.0)? else {
+                    return Ok(());
+                };
+
+                let managed = managed.iter().map(String::as_str).collect::<Vec<_>>();
+                let member_of = member_of.iter().map(String::as_str).collect::<Vec<_>>();
+                Group::sync_memberships(db, user.id, &managed, &member_of)
+            })
+            .await
+        };
+
+        if let Err(err) = result.await {
+            log::error!("cannot sync the groups of {discord_user}: {err}");
+        }
+    }
+}
+
+/// How a user logged in, for [Notification::Login]
+#[derive(Debug)]
+pub enum LoginMethod {
+    Password,
+
+    /// Password login approved from Discord, see [User::discord_2fa]
+    ApprovedPassword,
+
+    /// Link sent by the bot
+    BotLink,
+
+    /// Name of the provider, like Discord or one of the upstream OIDC ones
+    Provider(String),
+}
+
+/// Security event to DM to the user, see [NotificationKind]
+#[derive(Debug)]
+pub enum Notification {
+    Login { method: LoginMethod },
+    PasswordChange,
+    AppConsent { app: String },
+    LoginLink,
+}
+
+impl Notification {
+    pub fn kind(&self) -> NotificationKind {
+        match self {
+            Self::Login { .. } => NotificationKind::Login,
+            Self::PasswordChange => NotificationKind::PasswordChange,
+            Self::AppConsent { .. } => NotificationKind::AppConsent,
+            Self::LoginLink => NotificationKind::LoginLink,
+        }
+    }
+
+    fn message(&self, locale: Locale) -> String {
+        let event = match self {
+            Self::Login { method } => {
+                let method = match method {
+                    LoginMethod::Password => locale.t("notifications.method-password"),
+                    LoginMethod::ApprovedPassword => {
+                        locale.t("notifications.method-approved-password")
+                    }
+                    LoginMethod::BotLink => locale.t("notifications.method-bot-link"),
+                    LoginMethod::Provider(name) => name,
+                };
+                locale.format("notifications.login", &[("method", &method)])
+            }
+            Self::PasswordChange => locale.t("notifications.password-change").to_owned(),
+            Self::AppConsent { app } => {
+                let app = MessageBuilder::new().push_bold_safe(app).build();
+                locale.format("notifications.app-consent", &[("app", &app)])
+            }
+            Self::LoginLink => locale.t("notifications.login-link").to_owned(),
+        };
+
+        format!("{event}\n{}", locale.t("notifications.footer"))
+    }
+}
+
+/// DMs the security notifications to the users who didn't opt out
+struct Notifier {
+    db: DbPool,
+}
+
+impl Notifier {
+    async fn notify(
+        &self,
+        cache_http: impl CacheHttp,
+        user: crate::model::UserId,
+        notification: Notification,
+    ) {
+        let kind = notification.kind();
+        let recipient = async {
+            let db = self.db.get().await.ok_or(WartIDError::DatabaseConnection)?;
+
+            db.run(move |db| -> WartIDResult<_> {
+                if !kind.is_enabled(db, user)? {
+                    return Ok(None);
+                }
+                let Some(discord_id) = FederatedIdentity::find_discord_id(db, user)? else {
+                    return Ok(None);
+                };
+
+                let locale = User::find_by_id(db, user)?
+                    .map(|user| Locale::negotiate(user.locale.as_deref(), None))
+                    .unwrap_or_default();
+                Ok(Some((discord_id, locale)))
+            })
+            .await
+        };
+
+        match recipient.await {
+            Ok(Some((discord_id, locale))) => {
+                self.send(cache_http, UserId(discord_id), &notification, locale)
+                    .await
+            }
+            Ok(None) => {}
+            Err(err) => log::error!("cannot find who to notify of {kind:?}: {err}"),
+        }
+    }
+
+    /// Only notifies Discord users who have a WartID account
+    async fn notify_discord_user(
+        &self,
+        cache_http: impl CacheHttp,
+        discord_user: UserId,
+        notification: Notification,
+    ) {
+        let kind = notification.kind();
+        let locale = async {
+            let db = self.db.get().await.ok_or(WartIDError::DatabaseConnection)?;
+
+            db.run(move |db| -> WartIDResult<_> {
+                match User::find_by_discord_id(db, discord_user.0)? {
+                    Some(user) if kind.is_enabled(db, user.id)? => {
+                        Ok(Some(Locale::negotiate(user.locale.as_deref(), None)))
+                    }
+                    _ => Ok(None),
+                }
+            })
+            .await
+        };
+
+        match locale.await {
+            Ok(Some(locale)) => {
+                self.send(cache_http, discord_user, &notification, locale)
+                    .await
+            }
+            Ok(None) => {}
+            Err(err) => log::error!("cannot check if {discord_user} wants {kind:?}: {err}"),
+        }
+    }
+
+    async fn send(
+        &self,
+        cache_http: impl CacheHttp,
+        discord_user: UserId,
+        notification: &Notification,
+        locale: Locale,
+    ) {
+        let result = async {
+            let private = discord_user.create_dm_channel(cache_http.http()).await?;
+            private
+                .send_message(cache_http.http(), |m| {
+                    m.content(notification.message(locale))
+                })
+                .await
+        };
+
+        // Users can refuse DMs from the guild members
+        if let Err(err) = result.await {
+            log::warn!("cannot notify {discord_user} of {notification:?}: {err}");
+        }
+    }
+}
+
+/// How long users have to approve a password login from Discord, see [User::discord_2fa]
+const APPROVAL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2 * 60);
+
+const APPROVE_PREFIX: &str = "approve-login:";
+const DENY_PREFIX: &str = "deny-login:";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Denied,
+
+    /// The DM asking for approval couldn't be sent
+    Undeliverable,
+}
+
+struct PendingLogin {
+    user: crate::model::UserId,
+    discord_user: UserId,
+    locale: Locale,
+    expires: Instant,
+    status: ApprovalStatus,
+}
+
+/// Password logins waiting to be approved from Discord, by random ID. They are only kept in
+/// memory, a restart just makes users log in again.
+#[derive(Default)]
+struct PendingLogins(std::sync::Mutex<HashMap<String, PendingLogin>>);
+
+impl PendingLogins {
+    fn insert(&self, user: crate::model::UserId, discord_user: UserId, locale: Locale) -> String {
+        let id = crate::utils::gen_alphanumeric(32);
+        let mut pending = self.0.lock().unwrap();
+
+        let now = Instant::now();
+        pending.retain(|_, login| login.expires > now);
+        pending.insert(
+            id.clone(),
+            PendingLogin {
+                user,
+                discord_user,
+                locale,
+                expires: now + APPROVAL_TIMEOUT,
+                status: ApprovalStatus::Pending,
+            },
+        );
+
+        id
+    }
+
+    /// Forgets the login once answered, so an approval can only be used once. `None` if it
+    /// expired or never existed.
+    fn check(&self, id: &str) -> Option<(crate::model::UserId, ApprovalStatus)> {
+        let mut pending = self.0.lock().unwrap();
+
+        let login = pending.get(id)?;
+        let (user, status) = (login.user, login.status);
+        let is_expired = login.expires <= Instant::now();
+        if is_expired || status != ApprovalStatus::Pending {
+            pending.remove(id);
+        }
+
+        (!is_expired || status != ApprovalStatus::Pending).then_some((user, status))
+    }
+
+    /// Only the user whose login it is can answer it, and only once
+    fn answer(&self, id: &str, discord_user: UserId, status: ApprovalStatus) -> bool {
+        let mut pending = self.0.lock().unwrap();
+
+        match pending.get_mut(id) {
+            Some(login)
+                if login.discord_user == discord_user
+                    && login.status == ApprovalStatus::Pending
+                    && login.expires > Instant::now() =>
+            {
+                login.status = status;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// DMs the user a message with buttons to approve or deny the login
+    async fn send(&self, cache_http: impl CacheHttp, id: String) {
+        let Some((discord_user, locale)) = self
+            .0
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|login| (login.discord_user, login.locale))
+        else {
+            return;
+        };
+
+        let result = async {
+            let private = discord_user.create_dm_channel(cache_http.http()).await?;
+            private
+                .send_message(cache_http.http(), |m| {
+                    m.content(locale.t("bot.approval-request")).components(|c| {
+                        c.create_action_row(|row| {
+                            row.create_button(|b| {
+                                b.custom_id(format!("{APPROVE_PREFIX}{id}"))
+                                    .label(locale.t("bot.approve"))
+                                    .style(ButtonStyle::Success)
+                            })
+                            .create_button(|b| {
+                                b.custom_id(format!("{DENY_PREFIX}{id}"))
+                                    .label(locale.t("bot.deny"))
+                                    .style(ButtonStyle::Danger)
+                            })
+                        })
+                    })
+                })
+                .await
+        };
+
+        if let Err(err) = result.await {
+            log::warn!("cannot ask {discord_user} to approve a login: {err}");
+            if let Some(login) = self.0.lock().unwrap().get_mut(&id) {
+                login.status = ApprovalStatus::Undeliverable;
+            }
+        }
+    }
+}
+
+/// How long the password reset links sent by the bot can be used
+const PASSWORD_RESET_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+struct PasswordReset {
+    user: crate::model::UserId,
+    expires: Instant,
+}
+
+/// Password reset links sent by the bot, by random ID. Like [PendingLogins], they are only kept in
+/// memory.
+#[derive(Default)]
+struct PasswordResets(std::sync::Mutex<HashMap<String, PasswordReset>>);
+
+impl PasswordResets {
+    /// `None` if the user already has a link that didn't expire, so the bot can't be used to
+    /// flood their DMs
+    fn insert(&self, user: crate::model::UserId) -> Option<String> {
+        let mut resets = self.0.lock().unwrap();
+
+        let now = Instant::now();
+        resets.retain(|_, reset| reset.expires > now);
+        if resets.values().any(|reset| reset.user == user) {
+            return None;
+        }
+
+        let id = crate::utils::gen_alphanumeric(32);
+        resets.insert(
+            id.clone(),
+            PasswordReset {
+                user,
+                expires: now + PASSWORD_RESET_TIMEOUT,
+            },
+        );
+
+        Some(id)
+    }
+
+    fn check(&self, id: &str) -> Option<crate::model::UserId> {
+        let resets = self.0.lock().unwrap();
+        resets
+            .get(id)
+            .filter(|reset| reset.expires > Instant::now())
+            .map(|reset| reset.user)
+    }
+
+    /// Forgets the link, so it can only be used once
+    fn take(&self, id: &str) -> Option<crate::model::UserId> {
+        let mut resets = self.0.lock().unwrap();
+        resets
+            .remove(id)
+            .filter(|reset| reset.expires > Instant::now())
+            .map(|reset| reset.user)
+    }
+
+    /// DMs the link to the user, whatever their notification settings since they asked for it
+    async fn send(
+        &self,
+        cache_http: impl CacheHttp,
+        discord_user: UserId,
+        locale: Locale,
+        url: String,
+    ) {
+        let result = async {
+            let private = discord_user.create_dm_channel(cache_http.http()).await?;
+            private
+                .send_message(cache_http.http(), |m| {
+                    m.content(locale.format("bot.password-reset", &[("url", &url)]))
+                })
+                .await
+        };
+
+        // Not shown on the reset page, so it doesn't tell who is linked to Discord
+        if let Err(err) = result.await {
+            log::warn!("cannot send a password reset link to {discord_user}: {err}");
+        }
+    }
+}
+
+/// Longest message Discord accepts
+const MAX_MESSAGE_LEN: usize = 2000;
+
+struct Handler {
+    key: EncodingKey,
+    base_url: String,
+    membership: Arc<Membership>,
+    db: DbPool,
+    role_sync: Arc<RoleSync>,
+    notifier: Arc<Notifier>,
+    pending_logins: Arc<PendingLogins>,
+}
+
+impl Handler {
+    fn login_url(
+        &self,
+        discord_user: UserId,
+        name: String,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let jwt = encode(&self.key, discord_user, name)?;
+        Ok(format!("{}login-with-discord?token={jwt}", self.base_url))
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(crate::DbConnection) -> WartIDResult<T> + Send + 'static,
+    ) -> WartIDResult<T> {
+        let db = self.db.get().await.ok_or(WartIDError::DatabaseConnection)?;
+        db.run(f).await
+    }
+
+    /// Discord only gives the locale of users in interactions, so for messages it's their WartID
+    /// preference
+    async fn user_locale(&self, discord_user: UserId) -> Locale {
+        self.run(move |db| User::find_by_discord_id(db, discord_user.0))
+            .await
+            .ok()
+            .flatten()
+            .map(|user| Locale::negotiate(user.locale.as_deref(), None))
+            .unwrap_or_default()
+    }
+
+    /// Handles the buttons of the messages asking to approve a password login
+    async fn answer_login(&self, ctx: &Context, component: &MessageComponentInteraction) {
+        let custom_id = &component.data.custom_id;
+        let (id, status) = if let Some(id) = custom_id.strip_prefix(APPROVE_PREFIX) {
+            (id, ApprovalStatus::Approved)
+        } else if let Some(id) = custom_id.strip_prefix(DENY_PREFIX) {
+            (id, ApprovalStatus::Denied)
+        } else {
+            return;
+        };
+
+        let locale = Locale::parse(&component.locale).unwrap_or_default();
+        let reply = if !self.pending_logins.answer(id, component.user.id, status) {
+            locale.t("bot.approval-expired")
+        } else if status == ApprovalStatus::Approved {
+            locale.t("bot.approved")
+        } else {
+            locale.t("bot.denied")
+        };
+
+        // Removes the buttons, so the login can't be answered twice
+        let result = component
+            .create_interaction_response(&ctx.http, |response| {
+                response
+                    .kind(InteractionResponseType::UpdateMessage)
+                    .interaction_response_data(|data| data.content(reply).components(|c| c))
+            })
+            .await;
+
+        if let Err(err) = result {
+            log::warn!(
+                "cannot answer the login approval of {}: {err}",
+                component.user.id
+            );
+        }
+    }
+
+    /// Runs a slash command, returning the ephemeral reply
+    async fn run_command(&self, ctx: &Context, command: &ApplicationCommandInteraction) -> String {
+        let author = &command.user;
+        let locale = Locale::parse(&command.locale).unwrap_or_default();
+
+        if !self.membership.is_allowed(ctx, author.id).await {
+            log::warn!("foreign user attempted to use /{}", command.data.name);
+            return locale.t("bot.unknown-user").to_owned();
+        }
+
+        if command.data.name == "login" {
+            return match self.login_url(author.id, author.name.clone()) {
+                Ok(url) => {
+                    self.notifier
+                        .notify_discord_user(ctx, author.id, Notification::LoginLink)
+                        .await;
+                    locale.format("bot.login-command", &[("url", &url)])
+                }
+                Err(err) => format!("{}\n```\n{err:?}\n```", locale.t("bot.error")),
+            };
+        }
+
+        let base_url = self.base_url.clone();
+        let name = command.data.name.clone();
+        let discord_id = author.id.0;
+        self.run(move |db| run_linked_command(db, &base_url, locale, discord_id, &name))
+            .await
+            .unwrap_or_else(|err| {
+                log::error!("discord command /{} failed: {err}", command.data.name);
+                locale.t("bot.error").to_owned()
+            })
+    }
+}
+
+/// Runs the slash commands that need a linked account, apart from the gateway so they can be
+/// tested
+fn run_linked_command(
+    db: crate::DbConnection,
+    base_url: &str,
+    locale: Locale,
+    discord_id: u64,
+    name: &str,
+) -> WartIDResult<String> {
+    let Some(user) = User::find_by_discord_id(db, discord_id)? else {
+        return Ok(locale.t("bot.no-account").to_owned());
+    };
+    let user_id = user.id;
+
+    match name {
+        "whoami" => {
+            let username = MessageBuilder::new().push_bold_safe(&user.username).build();
+            let account = format!("{base_url}@{user_id}");
+            Ok(locale.format("bot.whoami", &[("user", &username), ("account", &account)]))
         }
+        "apps" => UserApp::find_all(db, user_id).map(|apps| apps_list(base_url, locale, &apps)),
+        "logout-everywhere" => db
+            .transaction(|db| {
+                let sessions = Session::delete_all_by_user(db, user_id)?;
+                let apps = OAuth2Session::delete_all_by_user(db, user_id)?;
+                Ok((sessions, apps))
+            })
+            .map(|(sessions, apps)| {
+                locale.format(
+                    "bot.logged-out",
+                    &[("sessions", &sessions), ("apps", &apps)],
+                )
+            }),
+        _ => Ok(locale.t("bot.unknown-command").to_owned()),
+    }
+}
 
-        false
+fn apps_list(base_url: &str, locale: Locale, apps: &[UserApp]) -> String {
+    if apps.is_empty() {
+        return locale.t("bot.no-apps").to_owned();
     }
+
+    let mut message = MessageBuilder::new();
+    message.push_line(locale.t("bot.apps"));
+    for app in apps {
+        let line = MessageBuilder::new()
+            .push("• ")
+            .push_bold_safe(&app.name)
+            .push_line(format!(" <{base_url}apps/{}>", app.id))
+            .build();
+
+        if message.0.len() + line.len() > MAX_MESSAGE_LEN - 3 {
+            message.push("…");
+            break;
+        }
+        message.push(line);
+    }
+
+    message.build()
+}
+
+/// Discord shows the English description to users with an English client, and the French one to
+/// everyone else
+fn describe_command<'a>(
+    command: &'a mut CreateApplicationCommand,
+    name: &str,
+    description: &'static str,
+) -> &'a mut CreateApplicationCommand {
+    command
+        .name(name)
+        .description(Locale::Fr.t(description))
+        .description_localized("en-US", Locale::En.t(description))
+        .description_localized("en-GB", Locale::En.t(description))
 }
 
 #[async_trait]
 impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, _ready: Ready) {
+        let result = Command::set_global_application_commands(&ctx.http, |commands| {
+            commands
+                .create_application_command(|command| {
+                    describe_command(command, "login", "bot.command-login")
+                })
+                .create_application_command(|command| {
+                    describe_command(command, "whoami", "bot.command-whoami")
+                })
+                .create_application_command(|command| {
+                    describe_command(command, "apps", "bot.command-apps")
+                })
+                .create_application_command(|command| {
+                    describe_command(
+                        command,
+                        "logout-everywhere",
+                        "bot.command-logout-everywhere",
+                    )
+                })
+        })
+        .await;
+
+        if let Err(err) = result {
+            log::error!("cannot register the Discord slash commands: {err}");
+        }
+    }
+
+    async fn guild_create(&self, ctx: Context, guild: Guild, _is_new: bool) {
+        if !self.role_sync.is_mapped(guild.id) {
+            return;
+        }
+
+        for user in guild.members.keys() {
+            self.role_sync.sync(&ctx, *user).await;
+        }
+    }
+
+    async fn guild_member_addition(&self, ctx: Context, new_member: Member) {
+        if self.membership.is_allowed_guild(new_member.guild_id) {
+            self.membership.revalidate(&ctx, new_member.user.id).await;
+        }
+
+        if self.role_sync.is_mapped(new_member.guild_id) {
+            self.role_sync.sync(&ctx, new_member.user.id).await;
+        }
+    }
+
+    async fn guild_member_update(&self, ctx: Context, _old: Option<Member>, new: Member) {
+        if self.role_sync.is_mapped(new.guild_id) {
+            self.role_sync.sync(&ctx, new.user.id).await;
+        }
+    }
+
+    async fn guild_member_removal(
+        &self,
+        ctx: Context,
+        guild_id: GuildId,
+        user: DiscordUser,
+        _member: Option<Member>,
+    ) {
+        if self.membership.is_allowed_guild(guild_id) {
+            self.membership.revalidate(&ctx, user.id).await;
+        }
+
+        if self.role_sync.is_mapped(guild_id) {
+            self.role_sync.sync(&ctx, user.id).await;
+        }
+    }
+
+    async fn guild_ban_addition(&self, ctx: Context, guild_id: GuildId, banned_user: DiscordUser) {
+        if self.membership.is_allowed_guild(guild_id) {
+            self.membership.revalidate(&ctx, banned_user.id).await;
+        }
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let command = match interaction {
+            Interaction::ApplicationCommand(command) => command,
+            Interaction::MessageComponent(component) => {
+                return self.answer_login(&ctx, &component).await;
+            }
+            _ => return,
+        };
+
+        let reply = self.run_command(&ctx, &command).await;
+
+        // Ephemeral, so login links are never posted publicly
+        let result = command
+            .create_interaction_response(&ctx.http, |response| {
+                response
+                    .kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|data| data.content(reply).ephemeral(true))
+            })
+            .await;
+
+        if let Err(err) = result {
+            log::warn!("cannot reply to /{}: {err}", command.data.name);
+        }
+    }
+
     async fn message(&self, ctx: Context, received_message: Message) {
         if received_message.author.bot
             || !(received_message.is_private()
@@ -82,22 +929,24 @@ impl EventHandler for Handler {
             return;
         }
 
-        if !self.is_user_allowed(&ctx, received_message.author.id).await {
+        let locale = self.user_locale(received_message.author.id).await;
+
+        if !self
+            .membership
+            .is_allowed(&ctx, received_message.author.id)
+            .await
+        {
             log::warn!("foreign user attempted to get a token");
-            let _ = received_message.reply(&ctx, "Je te connais pas.").await;
+            let _ = received_message
+                .reply(&ctx, locale.t("bot.unknown-user"))
+                .await;
             return;
         }
 
         if !received_message.is_private() {
-            let _ = received_message.reply(
-                &ctx,
-                random_of(&[
-                    "Vas donc voir tes DM (c'est un URL de connection privé, je ne vais pas te l'envoyer ici)",
-                    "J'aime pas trop le concept d'envoyer un URL de connection en public, regarde tes DM <:CRONCHE:754810929748901998>",
-                    "Je t'ai envoyé un URL de connection PRIVÉ en DM. La prochaine fois demande le moi directement en pv <:trokoul_pulseur:637313805197639690>",
-                    "Je viens de te glisser un petit MP doux 😏 avec ton code grrrhh",
-                ]),
-            ).await;
+            let _ = received_message
+                .reply(&ctx, locale.random("bot.check-dms"))
+                .await;
         }
 
         // Create and send token
@@ -109,42 +958,33 @@ impl EventHandler for Handler {
         {
             let typing = Typing::start(ctx.http.clone(), private.id.0);
 
-            let jwt = encode(
-                &self.key,
-                received_message.author.id,
-                received_message.author.name,
-            );
+            let url = self.login_url(received_message.author.id, received_message.author.name);
 
-            match jwt {
-                Ok(jwt) => {
-                    let url = format!("{}{jwt}", self.login_url);
-
-                    let _ = private.send_message(&ctx, |m| m.content(
-                        format!(
-                            "{}\n{}",
-                            random_of(&[
-                                &format!("Rends toi sur {} pour te connecter à WartID (attention, ça va aller vite)", &url),
-                                &format!("Il faut maintenant suivre ce lien pour t'identifier sur WartID: {}", &url),
-                            ]),
-                            random_of(&[
-                                "Le lien expire dans 10 min",
-                                "Tu as 10 min 🕑",
-                                "Mes pouvoirs ne me permettent pas d'invoquer un lien durant plus de 10 min, dépêche toi !",
-                                "🔥 Go 🚶 go 🏁 go 🏁, tu as 1️0️ min avant 💥 l'autodestruction 💣 de ton lien 🔐",
-                            ]),
+            match url {
+                Ok(url) => {
+                    let _ = private
+                        .send_message(&ctx, |m| {
+                            m.content(format!(
+                                "{}\n{}",
+                                locale.format_random("bot.login-link", &[("url", &url)]),
+                                locale.random("bot.login-link-expiration"),
+                            ))
+                        })
+                        .await;
+                    self.notifier
+                        .notify_discord_user(
+                            &ctx,
+                            received_message.author.id,
+                            Notification::LoginLink,
                         )
-                    )).await;
+                        .await;
                 }
                 Err(err) => {
                     let _ = private
                         .send_message(&ctx, |m| {
                             m.content(format!(
                                 "{}\n```\n{:?}\n```",
-                                random_of(&[
-                                    "Une erreur est survenue 😕",
-                                    "Ça a merdé...",
-                                    "Aïe aïe aïe il s'est passé un truc imprévu 😬",
-                                ]),
+                                locale.random("bot.error"),
                                 err,
                             ))
                         })
@@ -166,11 +1006,85 @@ pub enum UnauthorizedError {
     Invalid(#[from] JwtError),
 }
 
+/// Managed as an `Option<Arc<DiscordAgent>>`, which is `None` when [Config::discord] isn't set
 pub struct DiscordAgent {
     key: DecodingKey,
+    role_sync: mpsc::UnboundedSender<u64>,
+    notifications: mpsc::UnboundedSender<(crate::model::UserId, Notification)>,
+    approvals: mpsc::UnboundedSender<String>,
+    pending_logins: Arc<PendingLogins>,
+    password_reset_links: mpsc::UnboundedSender<(UserId, Locale, String)>,
+    password_resets: Arc<PasswordResets>,
+}
+
+/// Shorthand for the routes, which get the agent as an `Option`
+pub fn notify(
+    discord_agent: &Option<Arc<DiscordAgent>>,
+    user: crate::model::UserId,
+    notification: Notification,
+) {
+    if let Some(discord_agent) = discord_agent {
+        discord_agent.notify(user, notification);
+    }
 }
 
 impl DiscordAgent {
+    /// Asks the bot to sync the groups of a user from their Discord roles, for instance when their
+    /// account was just created
+    pub fn sync_groups(&self, discord_id: u64) {
+        let _ = self.role_sync.send(discord_id);
+    }
+
+    /// Asks the bot to DM a security notification to the user, if they linked their Discord
+    /// account and didn't opt out
+    pub fn notify(&self, user: crate::model::UserId, notification: Notification) {
+        let _ = self.notifications.send((user, notification));
+    }
+
+    /// Asks the user to approve a password login from a Discord DM, returning the ID to
+    /// [check](Self::check_approval) the answer with
+    pub fn request_approval(
+        &self,
+        user: crate::model::UserId,
+        discord_id: u64,
+        locale: Locale,
+    ) -> String {
+        let id = self.pending_logins.insert(user, UserId(discord_id), locale);
+        let _ = self.approvals.send(id.clone());
+        id
+    }
+
+    /// The user the login is for, and whether they answered yet. Answered logins are forgotten,
+    /// and `None` is returned for expired or unknown ones.
+    pub fn check_approval(&self, id: &str) -> Option<(crate::model::UserId, ApprovalStatus)> {
+        self.pending_logins.check(id)
+    }
+
+    /// Asks the bot to DM a password reset link to the user, unless they already have one. Links
+    /// are used with [take_password_reset](Self::take_password_reset).
+    pub fn request_password_reset(
+        &self,
+        user: crate::model::UserId,
+        discord_id: u64,
+        locale: Locale,
+    ) {
+        if let Some(id) = self.password_resets.insert(user) {
+            let _ = self
+                .password_reset_links
+                .send((UserId(discord_id), locale, id));
+        }
+    }
+
+    /// The user a password reset link is for, `None` if it expired or was already used
+    pub fn check_password_reset(&self, id: &str) -> Option<crate::model::UserId> {
+        self.password_resets.check(id)
+    }
+
+    /// Like [check_password_reset](Self::check_password_reset), but the link can't be used again
+    pub fn take_password_reset(&self, id: &str) -> Option<crate::model::UserId> {
+        self.password_resets.take(id)
+    }
+
     pub fn try_authorize(&self, login_token: &str) -> Result<Claims, UnauthorizedError> {
         let validation = &{
             let mut v = Validation::default();
@@ -212,28 +1126,112 @@ impl DiscordAgent {
 
             async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
                 let config = rocket.state::<Config>().unwrap();
-                let discord_config = config.discord.as_ref().unwrap();
+                let Some(discord_config) = config.discord.as_ref() else {
+                    log::info!("no Discord configuration, the bot and Discord logins are disabled");
+                    return Ok(rocket.manage(None::<Arc<DiscordAgent>>));
+                };
 
                 log::debug!("generating the Discord agent's login URL keypair");
 
                 use rand::Rng;
                 let secret: [u8; 32] = rand::rngs::OsRng.gen();
+                let (role_sync_sender, mut role_sync_receiver) = mpsc::unbounded_channel();
+                let (notification_sender, mut notification_receiver) = mpsc::unbounded_channel();
+                let (approval_sender, mut approval_receiver) = mpsc::unbounded_channel();
+                let pending_logins = Arc::new(PendingLogins::default());
+                let (password_reset_sender, mut password_reset_receiver) =
+                    mpsc::unbounded_channel();
+                let password_resets = Arc::new(PasswordResets::default());
                 let agent = DiscordAgent {
                     key: DecodingKey::from_secret(&secret),
+                    role_sync: role_sync_sender,
+                    notifications: notification_sender,
+                    approvals: approval_sender,
+                    pending_logins: Arc::clone(&pending_logins),
+                    password_reset_links: password_reset_sender,
+                    password_resets: Arc::clone(&password_resets),
                 };
 
-                let mut bot =
-                    Client::builder(&discord_config.token, GatewayIntents::DIRECT_MESSAGES)
-                        .event_handler(Handler {
-                            key: EncodingKey::from_secret(&secret),
-                            login_url: format!("{}login-with-discord?token=", config.base_url),
+                let db = DbConn::pool(&rocket)
+                    .expect("the database must be ignited before the discord agent")
+                    .clone();
+                let role_sync = Arc::new(RoleSync {
+                    role_groups: discord_config.role_groups.clone(),
+                    db: db.clone(),
+                });
 
-                            allowed_guilds: discord_config.allowed_guilds.clone(),
-                            allowed_users_cache: RwLock::default(),
-                        })
-                        .framework(StandardFramework::new())
-                        .await
-                        .expect("error creating client");
+                let notifier = Arc::new(Notifier { db: db.clone() });
+
+                let membership = Arc::new(Membership {
+                    allowed_guilds: discord_config.allowed_guilds.clone(),
+                    allowed_users_cache: RwLock::default(),
+                    db: db.clone(),
+                });
+
+                // Member events are privileged, and only needed for the role sync
+                let mut intents = GatewayIntents::DIRECT_MESSAGES | GatewayIntents::GUILD_BANS;
+                if !discord_config.role_groups.is_empty() {
+                    intents |= GatewayIntents::GUILDS | GatewayIntents::GUILD_MEMBERS;
+                }
+
+                let mut bot = Client::builder(&discord_config.token, intents)
+                    .event_handler(Handler {
+                        key: EncodingKey::from_secret(&secret),
+                        base_url: config.base_url.clone(),
+
+                        membership: Arc::clone(&membership),
+                        db,
+                        role_sync: Arc::clone(&role_sync),
+                        notifier: Arc::clone(&notifier),
+                        pending_logins: Arc::clone(&pending_logins),
+                    })
+                    .framework(StandardFramework::new())
+                    .await
+                    .expect("error creating client");
+
+                let cache_http = Arc::clone(&bot.cache_and_http);
+                tokio::spawn(async move {
+                    while let Some(discord_id) = role_sync_receiver.recv().await {
+                        role_sync.sync(&*cache_http, UserId(discord_id)).await;
+                    }
+                });
+
+                let cache_http = Arc::clone(&bot.cache_and_http);
+                tokio::spawn(async move {
+                    while let Some((user, notification)) = notification_receiver.recv().await {
+                        notifier.notify(&*cache_http, user, notification).await;
+                    }
+                });
+
+                let cache_http = Arc::clone(&bot.cache_and_http);
+                tokio::spawn(async move {
+                    while let Some(id) = approval_receiver.recv().await {
+                        pending_logins.send(&*cache_http, id).await;
+                    }
+                });
+
+                let cache_http = Arc::clone(&bot.cache_and_http);
+                let base_url = config.base_url.clone();
+                tokio::spawn(async move {
+                    while let Some((discord_user, locale, id)) =
+                        password_reset_receiver.recv().await
+                    {
+                        let url = format!("{base_url}login/reset/{id}");
+                        password_resets
+                            .send(&*cache_http, discord_user, locale, url)
+                            .await;
+                    }
+                });
+
+                let cache_http = Arc::clone(&bot.cache_and_http);
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(REVALIDATION_INTERVAL);
+                    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                    loop {
+                        interval.tick().await;
+                        membership.revalidate_all(&*cache_http).await;
+                    }
+                });
 
                 let shard_manager = bot.shard_manager.clone();
 
@@ -255,12 +1253,14 @@ impl DiscordAgent {
                     main_loop,
                 });
 
-                Ok(rocket.manage(Arc::new(agent)))
+                Ok(rocket.manage(Some(Arc::new(agent))))
             }
 
             async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
                 self.shutting_down.store(true, Ordering::SeqCst);
-                let inner = self.inner.lock().await.take().unwrap();
+                let Some(inner) = self.inner.lock().await.take() else {
+                    return;
+                };
 
                 let mut sm = inner.shard_manager.lock().await;
                 log::info!("gracefully shutting down discord agent");
@@ -280,3 +1280,146 @@ impl DiscordAgent {
         Fairing::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::model::NewSession;
+
+    #[test]
+    fn pending_logins() {
+        let pending = PendingLogins::default();
+        let user = crate::model::UserId::from_uuid(Uuid::from_u128(1));
+
+        let id = pending.insert(user, UserId(42), Locale::Fr);
+        assert_eq!(pending.check(&id), Some((user, ApprovalStatus::Pending)));
+
+        // Only by the user whose login it is, and only once
+        assert!(!pending.answer(&id, UserId(43), ApprovalStatus::Approved));
+        assert!(pending.answer(&id, UserId(42), ApprovalStatus::Approved));
+        assert!(!pending.answer(&id, UserId(42), ApprovalStatus::Denied));
+
+        // Answers can only be used once too
+        assert_eq!(pending.check(&id), Some((user, ApprovalStatus::Approved)));
+        assert_eq!(pending.check(&id), None);
+
+        let id = pending.insert(user, UserId(42), Locale::Fr);
+        pending.0.lock().unwrap().get_mut(&id).unwrap().expires = Instant::now();
+        assert!(!pending.answer(&id, UserId(42), ApprovalStatus::Approved));
+        assert_eq!(pending.check(&id), None);
+    }
+
+    #[test]
+    fn logout_everywhere() {
+        let mut db = crate::tests::db();
+        let discord_id = rand::random::<u32>().into();
+        let name = format!("test-{}", crate::utils::gen_alphanumeric(8));
+        let user = User::find_or_create_by_discord_id(&mut db, discord_id, name.clone())
+            .unwrap()
+            .unwrap();
+
+        let session = Session::insert(&mut db, NewSession::new(user.id)).unwrap();
+        Session::insert(&mut db, NewSession::new(user.id)).unwrap();
+        let app = UserApp::insert(&mut db, name, true, user.id).unwrap();
+        let scopes = "basic".parse().unwrap();
+        let claims = "".parse().unwrap();
+        OAuth2Session::insert_or_refresh(&mut db, user.id, app, &scopes, &claims).unwrap();
+
+        let mut run = |name| {
+            run_linked_command(&mut db, "http://localhost/", Locale::En, discord_id, name).unwrap()
+        };
+        let logged_out = |sessions: usize, apps: usize| {
+            Locale::En.format(
+                "bot.logged-out",
+                &[("sessions", &sessions), ("apps", &apps)],
+            )
+        };
+        assert_eq!(run("logout-everywhere"), logged_out(2, 1));
+        assert_eq!(run("logout-everywhere"), logged_out(0, 0));
+
+        assert_eq!(
+            Session::find_by_id(&mut db, session.into_inner()).unwrap(),
+            None
+        );
+        assert!(!OAuth2Session::exists(&mut db, user.id, app).unwrap());
+
+        // Discord accounts that aren't linked can't log anyone out
+        let reply = run_linked_command(
+            &mut db,
+            "http://localhost/",
+            Locale::En,
+            discord_id + 1,
+            "logout-everywhere",
+        )
+        .unwrap();
+        assert_eq!(reply, Locale::En.t("bot.no-account"));
+    }
+
+    #[test]
+    fn guild_suspensions() {
+        let mut db = crate::tests::db();
+        let discord_id = rand::random::<u32>().into();
+        let name = format!("test-{}", crate::utils::gen_alphanumeric(8));
+        let login = |db: crate::DbConnection| {
+            User::find_or_create_by_discord_id(db, discord_id, name.clone())
+                .unwrap()
+                .unwrap()
+        };
+        let user = login(&mut db);
+        Session::insert(&mut db, NewSession::new(user.id)).unwrap();
+
+        // Leaving every allowed guild
+        assert!(update_suspension(&mut db, discord_id, false).unwrap());
+        assert!(!update_suspension(&mut db, discord_id, false).unwrap());
+        assert!(User::find_by_id(&mut db, user.id)
+            .unwrap()
+            .unwrap()
+            .is_suspended());
+        assert_eq!(Session::delete_all_by_user(&mut db, user.id).unwrap(), 0);
+
+        // Coming back, noticed by the bot or when logging in
+        assert!(update_suspension(&mut db, discord_id, true).unwrap());
+        assert!(!User::find_by_id(&mut db, user.id)
+            .unwrap()
+            .unwrap()
+            .is_suspended());
+        assert!(update_suspension(&mut db, discord_id, false).unwrap());
+        assert!(!login(&mut db).is_suspended());
+
+        // Suspensions by administrators stay
+        let manual = SuspensionSource::Manual;
+        assert!(User::set_suspended(&mut db, user.id, true, manual).unwrap());
+        assert!(!update_suspension(&mut db, discord_id, false).unwrap());
+        assert!(!update_suspension(&mut db, discord_id, true).unwrap());
+        let user = login(&mut db);
+        assert!(user.is_suspended());
+        assert_eq!(user.suspended_by.as_deref(), Some(manual.as_str()));
+
+        assert!(User::set_suspended(&mut db, user.id, false, manual).unwrap());
+        assert!(!login(&mut db).is_suspended());
+    }
+
+    #[test]
+    fn password_resets() {
+        let resets = PasswordResets::default();
+        let user = crate::model::UserId::from_uuid(Uuid::from_u128(1));
+
+        let id = resets.insert(user).unwrap();
+        assert_eq!(resets.check(&id), Some(user));
+
+        // One link at a time
+        assert_eq!(resets.insert(user), None);
+
+        // Only once
+        assert_eq!(resets.take(&id), Some(user));
+        assert_eq!(resets.check(&id), None);
+        assert_eq!(resets.take(&id), None);
+
+        let id = resets.insert(user).unwrap();
+        resets.0.lock().unwrap().get_mut(&id).unwrap().expires = Instant::now();
+        assert_eq!(resets.check(&id), None);
+        assert!(resets.insert(user).is_some());
+    }
+}
@@ -0,0 +1,262 @@
+//! Translations of the web UI and the bot, from the message catalogs in `locales/`
+//!
+//! Messages are looked up by `section.key`. A message can have several variants, one of them is
+//! [picked randomly](Locale::random) to keep the bot lively. `{name}` placeholders are replaced by
+//! [Locale::format].
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::{Display, Formatter};
+
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use serde::Deserialize;
+
+use crate::templates::{Html, ToHtml};
+use crate::LoginSession;
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Locale {
+    #[default]
+    Fr,
+    En,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Message {
+    One(String),
+    Variants(Vec<String>),
+}
+
+type Catalog = HashMap<String, Vec<String>>;
+
+fn parse_catalog(source: &str) -> Catalog {
+    let sections: HashMap<String, HashMap<String, Message>> =
+        toml::from_str(source).expect("invalid message catalog");
+
+    sections
+        .into_iter()
+        .flat_map(|(section, messages)| {
+            messages.into_iter().map(move |(key, message)| {
+                let variants = match message {
+                    Message::One(message) => vec![message],
+                    Message::Variants(variants) => variants,
+                };
+                (format!("{section}.{key}"), variants)
+            })
+        })
+        .collect()
+}
+
+lazy_static::lazy_static! {
+    static ref FR: Catalog = parse_catalog(include_str!("../locales/fr.toml"));
+    static ref EN: Catalog = parse_catalog(include_str!("../locales/en.toml"));
+}
+
+/// Replaces the `{name}` placeholders of a message
+fn fill(message: &str, args: &[(&str, &dyn Display)]) -> String {
+    args.iter()
+        .fold(message.to_owned(), |message, (name, value)| {
+            message.replace(&format!("{{{name}}}"), &value.to_string())
+        })
+}
+
+impl Locale {
+    pub const ALL: [Self; 2] = [Self::Fr, Self::En];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Fr => "fr",
+            Self::En => "en",
+        }
+    }
+
+    /// From a BCP 47 tag like `en-GB`, of which only the language matters
+    pub fn parse(tag: &str) -> Option<Self> {
+        let language = tag.trim().split(['-', '_']).next()?;
+        Self::ALL
+            .into_iter()
+            .find(|locale| locale.as_str().eq_ignore_ascii_case(language))
+    }
+
+    /// Supported language with the highest weight in an `Accept-Language` header
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+
+        for range in header.split(',') {
+            let mut params = range.split(';');
+            let Some(locale) = params.next().and_then(Self::parse) else {
+                continue;
+            };
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.trim().parse::<f32>().ok());
+
+            // On ties, the first one is preferred
+            match (quality, best) {
+                (Some(quality), Some((_, best_quality))) if quality <= best_quality => {}
+                (Some(quality), _) if quality > 0.0 => best = Some((locale, quality)),
+                _ => {}
+            }
+        }
+
+        best.map(|(locale, _)| locale)
+    }
+
+    /// The user's preference first, then their browser's
+    pub fn negotiate(preference: Option<&str>, accept_language: Option<&str>) -> Self {
+        preference
+            .and_then(Self::parse)
+            .or_else(|| accept_language.and_then(Self::from_accept_language))
+            .unwrap_or_default()
+    }
+
+    fn catalog(self) -> &'static Catalog {
+        match self {
+            Self::Fr => &FR,
+            Self::En => &EN,
+        }
+    }
+
+    /// Falls back to French, the most complete catalog
+    fn variants(self, key: &'static str) -> &'static [String] {
+        match self
+            .catalog()
+            .get(key)
+            .or_else(|| Self::default().catalog().get(key))
+        {
+            Some(variants) => variants,
+            None => {
+                log::error!("missing message {key}");
+                &[]
+            }
+        }
+    }
+
+    /// First variant of a message, or its key if it doesn't exist
+    pub fn t(self, key: &'static str) -> &'static str {
+        self.variants(key).first().map_or(key, String::as_str)
+    }
+
+    /// Random variant of a message
+    pub fn random(self, key: &'static str) -> &'static str {
+        use rand::seq::SliceRandom;
+
+        self.variants(key)
+            .choose(&mut rand::thread_rng())
+            .map_or(key, String::as_str)
+    }
+
+    pub fn format(self, key: &'static str, args: &[(&str, &dyn Display)]) -> String {
+        fill(self.t(key), args)
+    }
+
+    pub fn format_random(self, key: &'static str, args: &[(&str, &dyn Display)]) -> String {
+        fill(self.random(key), args)
+    }
+
+    /// For messages with markup in templates, the arguments are escaped but not the message
+    pub fn html(self, key: &'static str, args: &[(&str, &dyn Display)]) -> Html<String> {
+        let args = args
+            .iter()
+            .map(|(name, value)| {
+                let mut escaped = Vec::new();
+                value.to_html(&mut escaped).unwrap();
+                (*name, String::from_utf8(escaped).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let args = args
+            .iter()
+            .map(|(name, value)| (*name, value as &dyn Display))
+            .collect::<Vec<_>>();
+
+        Html(fill(self.t(key), &args))
+    }
+}
+
+impl Display for Locale {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Language of the [User::locale](crate::model::User::locale) of the logged in user, or else of
+/// the `Accept-Language` header
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Locale {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let session = request.guard::<&LoginSession>().await.succeeded();
+
+        Outcome::Success(Self::negotiate(
+            session.and_then(|session| session.user.locale.as_deref()),
+            request.headers().get_one("Accept-Language"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn accept_language() {
+        assert_eq!(
+            Locale::from_accept_language("en-US,en;q=0.9"),
+            Some(Locale::En)
+        );
+        assert_eq!(
+            Locale::from_accept_language("de-DE, en;q=0.5, fr;q=0.8"),
+            Some(Locale::Fr)
+        );
+        assert_eq!(Locale::from_accept_language("fr, en"), Some(Locale::Fr));
+        assert_eq!(Locale::from_accept_language("en;q=0, de"), None);
+        assert_eq!(Locale::from_accept_language("*"), None);
+
+        assert_eq!(Locale::negotiate(Some("en-GB"), Some("fr")), Locale::En);
+        assert_eq!(Locale::negotiate(Some("de"), Some("en")), Locale::En);
+        assert_eq!(Locale::negotiate(None, None), Locale::Fr);
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(
+            Locale::En.format("login.with-provider", &[("provider", &"Discord")]),
+            "Log in with Discord"
+        );
+        assert_eq!(
+            Locale::Fr
+                .html("saml.redirecting", &[("app", &"<le taro>")])
+                .0,
+            "Redirection vers <b>&lt;le taro&gt;</b> en cours…"
+        );
+    }
+
+    /// Every message must be translated, with the same placeholders
+    #[test]
+    fn catalogs() {
+        fn placeholders(variants: &[String]) -> BTreeSet<&str> {
+            variants
+                .iter()
+                .flat_map(|variant| variant.split('{').skip(1))
+                .filter_map(|part| part.split_once('}'))
+                .map(|(name, _)| name)
+                .collect()
+        }
+
+        let keys = |catalog: &Catalog| catalog.keys().cloned().collect::<BTreeSet<_>>();
+        assert_eq!(keys(&FR), keys(&EN));
+
+        for (key, variants) in FR.iter() {
+            assert_eq!(
+                placeholders(variants),
+                placeholders(&EN[key]),
+                "placeholders of {key}"
+            );
+        }
+    }
+}
@@ -41,6 +41,12 @@ impl<T> PartialEq for Id<T> {
 
 impl<T> Eq for Id<T> {}
 
+impl<T> std::hash::Hash for Id<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
 impl<T> std::fmt::Debug for Id<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let type_name = std::any::type_name::<T>();
@@ -0,0 +1,60 @@
+use rocket_sync_db_pools::ConnectionPool;
+
+use crate::model::{Group, GroupId, User, UserId, WartIDError, WartIDResult};
+use crate::DbConn;
+
+/// Users and groups as seen by the LDAP server at a given time
+pub struct Snapshot {
+    pub users: Vec<User>,
+    pub groups: Vec<Group>,
+    pub memberships: Vec<(GroupId, UserId)>,
+}
+
+/// Where the LDAP server gets its data from, abstracted so the protocol can be tested without a
+/// database
+#[rocket::async_trait]
+pub trait Directory: Send + Sync + 'static {
+    /// Checks a simple bind as `username`
+    async fn verify_password(&self, username: &str, password: &str) -> bool;
+
+    async fn snapshot(&self) -> WartIDResult<Snapshot>;
+}
+
+/// The actual WartID database
+pub struct DbDirectory(pub ConnectionPool<DbConn, diesel::PgConnection>);
+
+#[rocket::async_trait]
+impl Directory for DbDirectory {
+    async fn verify_password(&self, username: &str, password: &str) -> bool {
+        let Some(db) = self.0.get().await else {
+            return false;
+        };
+
+        let (username, password) = (username.to_owned(), password.to_owned());
+        // LDAP has no way to wait for an approval from Discord, so the users requiring one can't
+        // bind at all rather than with their password alone
+        db.run(move |db| match User::find_by_username(db, &username) {
+            Ok(user) => {
+                user.is_some_and(|user| !user.discord_2fa && user.verify_password(&password))
+            }
+            Err(err) => {
+                log::error!("cannot check LDAP bind: {err}");
+                false
+            }
+        })
+        .await
+    }
+
+    async fn snapshot(&self) -> WartIDResult<Snapshot> {
+        let db = self.0.get().await.ok_or(WartIDError::DatabaseConnection)?;
+
+        db.run(|db| {
+            Ok(Snapshot {
+                users: User::find_all(db, true)?,
+                groups: Group::find_all(db)?,
+                memberships: Group::find_all_memberships(db)?,
+            })
+        })
+        .await
+    }
+}
@@ -0,0 +1,129 @@
+use lber::common::TagClass;
+use lber::structure::{StructureTag, PL};
+
+use super::Entry;
+
+/// Search filter (RFC 4511 §4.5.1.7), only keeping what's needed to evaluate it
+#[derive(Debug)]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Equality(String, String),
+    Substrings {
+        attribute: String,
+        initial: Option<String>,
+        any: Vec<String>,
+        last: Option<String>,
+    },
+    Present(String),
+
+    /// Ordering and extensible matches, which never match
+    Unsupported,
+}
+
+impl Filter {
+    pub fn parse(tag: StructureTag) -> Option<Self> {
+        if tag.class != TagClass::Context {
+            return None;
+        }
+
+        Some(match (tag.id, tag.payload) {
+            (0, PL::C(filters)) => Self::And(Self::parse_all(filters)?),
+            (1, PL::C(filters)) => Self::Or(Self::parse_all(filters)?),
+            (2, PL::C(mut filter)) if filter.len() == 1 => {
+                Self::Not(Box::new(Self::parse(filter.pop()?)?))
+            }
+            // Approximate matches are treated as equality
+            (3 | 8, PL::C(assertion)) => {
+                let [attribute, value] = <[_; 2]>::try_from(assertion).ok()?;
+                Self::Equality(string(attribute)?, string(value)?)
+            }
+            (4, PL::C(substrings)) => {
+                let [attribute, substrings] = <[_; 2]>::try_from(substrings).ok()?;
+
+                let (mut initial, mut any, mut last) = (None, Vec::new(), None);
+                for substring in substrings.expect_constructed()? {
+                    match substring.id {
+                        0 => initial = Some(string(substring)?),
+                        1 => any.push(string(substring)?),
+                        2 => last = Some(string(substring)?),
+                        _ => return None,
+                    }
+                }
+
+                Self::Substrings {
+                    attribute: string(attribute)?,
+                    initial,
+                    any,
+                    last,
+                }
+            }
+            (5 | 6 | 9, PL::C(_)) => Self::Unsupported,
+            (7, PL::P(attribute)) => Self::Present(String::from_utf8(attribute).ok()?),
+            _ => return None,
+        })
+    }
+
+    fn parse_all(filters: Vec<StructureTag>) -> Option<Vec<Self>> {
+        filters.into_iter().map(Self::parse).collect()
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Self::And(filters) => filters.iter().all(|filter| filter.matches(entry)),
+            Self::Or(filters) => filters.iter().any(|filter| filter.matches(entry)),
+            Self::Not(filter) => !filter.matches(entry),
+            Self::Equality(attribute, value) => {
+                let value = normalize_value(attribute, value);
+                entry
+                    .values(attribute)
+                    .any(|candidate| normalize_value(attribute, candidate) == value)
+            }
+            Self::Substrings {
+                attribute,
+                initial,
+                any,
+                last,
+            } => entry.values(attribute).any(|candidate| {
+                let candidate = candidate.to_lowercase();
+                let mut rest = candidate.as_str();
+
+                if let Some(initial) = initial {
+                    match rest.strip_prefix(&initial.to_lowercase()) {
+                        Some(stripped) => rest = stripped,
+                        None => return false,
+                    }
+                }
+
+                for any in any {
+                    let any = any.to_lowercase();
+                    match rest.find(&any) {
+                        Some(index) => rest = &rest[index + any.len()..],
+                        None => return false,
+                    }
+                }
+
+                match last {
+                    Some(last) => rest.ends_with(&last.to_lowercase()),
+                    None => true,
+                }
+            }),
+            Self::Present(attribute) => entry.values(attribute).next().is_some(),
+            Self::Unsupported => false,
+        }
+    }
+}
+
+fn string(tag: StructureTag) -> Option<String> {
+    String::from_utf8(tag.expect_primitive()?).ok()
+}
+
+/// Values are compared case-insensitively, and DNs regardless of spacing
+fn normalize_value(attribute: &str, value: &str) -> String {
+    if attribute.eq_ignore_ascii_case("member") || attribute.eq_ignore_ascii_case("memberOf") {
+        super::normalize_dn(value)
+    } else {
+        value.to_lowercase()
+    }
+}
@@ -0,0 +1,920 @@
+//! Read-only LDAP (v3) interface over WartID users and groups
+//!
+//! Meant for self-hosted services that can only authenticate users through an LDAP bind. Only
+//! simple binds and searches are supported, every write operation is refused. The tree looks like
+//! this, `base_dn` coming from the [config](crate::config::LdapConfig):
+//!
+//! ```text
+//! <base_dn>
+//! ├── ou=users
+//! │   └── uid=<username>   inetOrgPerson: uid, cn, sn, displayName, mail, entryUUID, memberOf
+//! └── ou=groups
+//!     └── cn=<name>        groupOfNames: cn, description, member, entryUUID
+//! ```
+//!
+//! Searching requires being bound as a user, only the root DSE can be read anonymously.
+
+use std::sync::Arc;
+
+use bytes::{Buf, BytesMut};
+use lber::common::TagClass;
+use lber::structure::{StructureTag, PL};
+use lber::structures::{ASNTag, Enumerated, Integer, OctetString, Sequence, Set, Tag};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Orbit, Rocket};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::DbConn;
+
+pub use directory::*;
+use filter::Filter;
+
+mod directory;
+mod filter;
+
+/// Requests bigger than this are considered abusive and get the connection closed
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Operation tags (`[APPLICATION n]`) from RFC 4511
+mod op {
+    pub const BIND_REQUEST: u64 = 0;
+    pub const BIND_RESPONSE: u64 = 1;
+    pub const UNBIND_REQUEST: u64 = 2;
+    pub const SEARCH_REQUEST: u64 = 3;
+    pub const SEARCH_RESULT_ENTRY: u64 = 4;
+    pub const SEARCH_RESULT_DONE: u64 = 5;
+    pub const MODIFY_REQUEST: u64 = 6;
+    pub const ADD_REQUEST: u64 = 8;
+    pub const DEL_REQUEST: u64 = 10;
+    pub const MODIFY_DN_REQUEST: u64 = 12;
+    pub const COMPARE_REQUEST: u64 = 14;
+    pub const ABANDON_REQUEST: u64 = 16;
+    pub const EXTENDED_REQUEST: u64 = 23;
+    pub const EXTENDED_RESPONSE: u64 = 24;
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ResultCode {
+    Success = 0,
+    OperationsError = 1,
+    ProtocolError = 2,
+    SizeLimitExceeded = 4,
+    AuthMethodNotSupported = 7,
+    NoSuchObject = 32,
+    InvalidCredentials = 49,
+    InsufficientAccessRights = 50,
+    UnwillingToPerform = 53,
+}
+
+/// A directory entry, built from a [Snapshot] for each search
+pub struct Entry {
+    dn: String,
+    attributes: Vec<(&'static str, Vec<String>)>,
+}
+
+impl Entry {
+    fn values<'a>(&'a self, attribute: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.attributes
+            .iter()
+            .filter(move |(name, _)| name.eq_ignore_ascii_case(attribute))
+            .flat_map(|(_, values)| values)
+    }
+
+    fn into_tag(self, selection: &AttributeSelection, types_only: bool) -> Tag {
+        let attributes = self
+            .attributes
+            .into_iter()
+            .filter(|(name, values)| !values.is_empty() && selection.contains(name))
+            .map(|(name, values)| {
+                Tag::Sequence(Sequence {
+                    inner: vec![
+                        octet_string(name),
+                        Tag::Set(Set {
+                            inner: if types_only {
+                                Vec::new()
+                            } else {
+                                values.into_iter().map(octet_string).collect()
+                            },
+                            ..Default::default()
+                        }),
+                    ],
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        Tag::Sequence(Sequence {
+            id: op::SEARCH_RESULT_ENTRY,
+            class: TagClass::Application,
+            inner: vec![
+                octet_string(self.dn),
+                Tag::Sequence(Sequence {
+                    inner: attributes,
+                    ..Default::default()
+                }),
+            ],
+        })
+    }
+}
+
+/// Attributes requested by a search
+enum AttributeSelection {
+    All,
+    Only(Vec<String>),
+}
+
+impl AttributeSelection {
+    fn new(requested: Vec<String>) -> Self {
+        // "1.1" asks for no attribute at all, and is never a valid attribute name anyway
+        if requested.is_empty() || requested.iter().any(|attribute| attribute == "*") {
+            Self::All
+        } else {
+            Self::Only(requested)
+        }
+    }
+
+    fn contains(&self, attribute: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(requested) => requested
+                .iter()
+                .any(|requested| requested.eq_ignore_ascii_case(attribute)),
+        }
+    }
+}
+
+/// Lowercased RDNs of a DN, with their values escaped the same way whatever the original
+/// escaping and spacing, for comparisons. An invalid DN is kept as a whole, so it matches nothing.
+fn normalize_rdns(dn: &str) -> Vec<String> {
+    match parse_dn(dn) {
+        Some(rdns) => rdns
+            .iter()
+            .map(|(attribute, value)| normalize_rdn(attribute, value))
+            .collect(),
+        None => vec![dn.to_lowercase()],
+    }
+}
+
+fn normalize_rdn(attribute: &str, value: &str) -> String {
+    format!("{attribute}={}", escape_dn_value(value)).to_lowercase()
+}
+
+/// See [normalize_rdns]
+fn normalize_dn(dn: &str) -> String {
+    normalize_rdns(dn).join(",")
+}
+
+struct Tree {
+    base_dn: String,
+    users_dn: String,
+    groups_dn: String,
+}
+
+impl Tree {
+    fn new(base_dn: &str) -> Self {
+        Self {
+            base_dn: base_dn.to_owned(),
+            users_dn: format!("ou=users,{base_dn}"),
+            groups_dn: format!("ou=groups,{base_dn}"),
+        }
+    }
+
+    fn user_dn(&self, username: &str) -> String {
+        format!("uid={},{}", escape_dn_value(username), self.users_dn)
+    }
+
+    fn group_dn(&self, name: &str) -> String {
+        format!("cn={},{}", escape_dn_value(name), self.groups_dn)
+    }
+
+    /// Username of a `uid=<username>,ou=users,<base_dn>` DN
+    fn username_of(&self, dn: &str) -> Option<String> {
+        let rdns = parse_dn(dn)?;
+        let ((attribute, username), parent) = rdns.split_first()?;
+        let parent: Vec<_> = parent
+            .iter()
+            .map(|(attribute, value)| normalize_rdn(attribute, value))
+            .collect();
+
+        (attribute.eq_ignore_ascii_case("uid") && parent == normalize_rdns(&self.users_dn))
+            .then(|| username.clone())
+    }
+
+    fn root_dse(&self) -> Entry {
+        Entry {
+            dn: String::new(),
+            attributes: vec![
+                ("objectClass", vec![String::from("top")]),
+                ("namingContexts", vec![self.base_dn.clone()]),
+                ("supportedLDAPVersion", vec![String::from("3")]),
+                ("vendorName", vec![String::from("WartID")]),
+            ],
+        }
+    }
+
+    fn entries(&self, snapshot: Snapshot) -> Vec<Entry> {
+        let organizational_unit = |dn: &str, name: &str| Entry {
+            dn: dn.to_owned(),
+            attributes: vec![
+                (
+                    "objectClass",
+                    vec![String::from("top"), String::from("organizationalUnit")],
+                ),
+                ("ou", vec![name.to_owned()]),
+            ],
+        };
+
+        let mut entries = vec![
+            Entry {
+                dn: self.base_dn.clone(),
+                attributes: vec![("objectClass", vec![String::from("top")])],
+            },
+            organizational_unit(&self.users_dn, "users"),
+            organizational_unit(&self.groups_dn, "groups"),
+        ];
+
+        for user in &snapshot.users {
+            let member_of = snapshot
+                .memberships
+                .iter()
+                .filter(|(_, member)| *member == user.id)
+                .filter_map(|(group, _)| snapshot.groups.iter().find(|g| g.id == *group))
+                .map(|group| self.group_dn(&group.name))
+                .collect();
+
+            entries.push(Entry {
+                dn: self.user_dn(&user.username),
+                attributes: vec![
+                    (
+                        "objectClass",
+                        ["top", "person", "organizationalPerson", "inetOrgPerson"]
+                            .map(String::from)
+                            .to_vec(),
+                    ),
+                    ("uid", vec![user.username.clone()]),
+                    ("cn", vec![user.username.clone()]),
+                    ("sn", vec![user.username.clone()]),
+                    ("displayName", vec![user.username.clone()]),
+                    ("mail", user.email.iter().cloned().collect()),
+                    ("entryUUID", vec![user.id.to_string()]),
+                    ("memberOf", member_of),
+                ],
+            });
+        }
+
+        for group in &snapshot.groups {
+            let members = snapshot
+                .memberships
+                .iter()
+                .filter(|(g, _)| *g == group.id)
+                .filter_map(|(_, member)| snapshot.users.iter().find(|u| u.id == *member))
+                .map(|user| self.user_dn(&user.username))
+                .collect();
+
+            entries.push(Entry {
+                dn: self.group_dn(&group.name),
+                attributes: vec![
+                    (
+                        "objectClass",
+                        vec![String::from("top"), String::from("groupOfNames")],
+                    ),
+                    ("cn", vec![group.name.clone()]),
+                    ("description", group.description.iter().cloned().collect()),
+                    ("member", members),
+                    ("entryUUID", vec![group.id.to_string()]),
+                ],
+            });
+        }
+
+        entries
+    }
+}
+
+/// Escapes the special characters of an RDN value (RFC 4514)
+fn escape_dn_value(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        if matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=')
+            || (i == 0 && matches!(c, ' ' | '#'))
+            || (i == last && c == ' ')
+        {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Attributes and unescaped values of the RDNs of a DN (RFC 4514), or `None` if it is invalid.
+/// Values may also be quoted, as RFC 1779 allowed. Multi-valued RDNs are kept as a single value.
+fn parse_dn(dn: &str) -> Option<Vec<(String, String)>> {
+    let mut rdns = Vec::new();
+    if dn.trim().is_empty() {
+        return Some(rdns);
+    }
+
+    let mut chars = dn.chars().peekable();
+    loop {
+        let mut attribute = String::new();
+        loop {
+            match chars.next()? {
+                '=' => break,
+                ',' | '+' | '"' | '\\' => return None,
+                c => attribute.push(c),
+            }
+        }
+        let attribute = attribute.trim();
+        if attribute.is_empty() {
+            return None;
+        }
+
+        while chars.next_if_eq(&' ').is_some() {}
+
+        // Bytes, since escaped hex pairs can be parts of UTF-8 sequences
+        let mut value = Vec::new();
+        let quoted = chars.next_if_eq(&'"').is_some();
+        // Length without the trailing unescaped spaces
+        let mut significant = 0;
+        let (mut closed, mut more) = (!quoted, false);
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    let escaped = chars.next()?;
+                    match escaped
+                        .to_digit(16)
+                        .zip(chars.peek().and_then(|c| c.to_digit(16)))
+                    {
+                        Some((high, low)) => {
+                            chars.next();
+                            value.push((high * 16 + low) as u8);
+                        }
+                        None => value.extend(escaped.to_string().bytes()),
+                    }
+                    significant = value.len();
+                    continue;
+                }
+                '"' if quoted => {
+                    closed = true;
+                    while chars.next_if_eq(&' ').is_some() {}
+                    match chars.next() {
+                        Some(',' | ';') => more = true,
+                        Some(_) => return None,
+                        None => {}
+                    }
+                    break;
+                }
+                ',' | ';' if !quoted => {
+                    more = true;
+                    break;
+                }
+                '"' => return None,
+                c => value.extend(c.to_string().bytes()),
+            }
+            if c != ' ' || quoted {
+                significant = value.len();
+            }
+        }
+
+        if !closed {
+            return None;
+        }
+        value.truncate(significant);
+        rdns.push((attribute.to_owned(), String::from_utf8(value).ok()?));
+
+        if !more {
+            return Some(rdns);
+        }
+    }
+}
+
+fn octet_string(value: impl Into<Vec<u8>>) -> Tag {
+    Tag::OctetString(OctetString {
+        inner: value.into(),
+        ..Default::default()
+    })
+}
+
+fn ldap_result(op: u64, code: ResultCode, diagnostic: &str) -> Tag {
+    Tag::Sequence(Sequence {
+        id: op,
+        class: TagClass::Application,
+        inner: vec![
+            Tag::Enumerated(Enumerated {
+                inner: code as i64,
+                ..Default::default()
+            }),
+            octet_string(""),
+            octet_string(diagnostic),
+        ],
+    })
+}
+
+fn parse_integer(tag: StructureTag) -> Option<i64> {
+    let bytes = tag.expect_primitive()?;
+    if bytes.is_empty() || bytes.len() > 8 {
+        return None;
+    }
+
+    // Sign-extended big endian
+    let fill = if bytes[0] & 0x80 != 0 { 0xff } else { 0 };
+    let mut buf = [fill; 8];
+    buf[8 - bytes.len()..].copy_from_slice(&bytes);
+    Some(i64::from_be_bytes(buf))
+}
+
+fn parse_string(tag: StructureTag) -> Option<String> {
+    String::from_utf8(tag.expect_primitive()?).ok()
+}
+
+struct SearchRequest {
+    base: String,
+    scope: i64,
+    size_limit: i64,
+    types_only: bool,
+    filter: Filter,
+    attributes: AttributeSelection,
+}
+
+impl SearchRequest {
+    fn parse(fields: Vec<StructureTag>) -> Option<Self> {
+        let [base, scope, _deref_aliases, size_limit, _time_limit, types_only, filter, attributes] =
+            <[_; 8]>::try_from(fields).ok()?;
+
+        Some(Self {
+            base: parse_string(base)?,
+            scope: parse_integer(scope)?,
+            size_limit: parse_integer(size_limit)?,
+            types_only: types_only
+                .expect_primitive()?
+                .first()
+                .is_some_and(|b| *b != 0),
+            filter: Filter::parse(filter)?,
+            attributes: AttributeSelection::new(
+                attributes
+                    .expect_constructed()?
+                    .into_iter()
+                    .map(parse_string)
+                    .collect::<Option<_>>()?,
+            ),
+        })
+    }
+
+    fn in_scope(&self, dn: &str) -> bool {
+        let (dn, base) = (normalize_rdns(dn), normalize_rdns(&self.base));
+
+        match self.scope {
+            // baseObject
+            0 => dn == base,
+            // singleLevel
+            1 => dn.len() == base.len() + 1 && dn.ends_with(&base),
+            // wholeSubtree
+            _ => dn.ends_with(&base),
+        }
+    }
+}
+
+struct Session<'a> {
+    directory: &'a dyn Directory,
+    tree: &'a Tree,
+
+    /// Username the connection is bound as
+    bound: Option<String>,
+}
+
+impl Session<'_> {
+    /// Handles one request, returning the response messages' operations, or `None` if the
+    /// connection must be closed
+    async fn handle(&mut self, op: StructureTag) -> Option<Vec<Tag>> {
+        if op.class != TagClass::Application {
+            return None;
+        }
+
+        Some(match (op.id, op.payload) {
+            (op::BIND_REQUEST, PL::C(fields)) => vec![self.bind(fields).await?],
+            (op::UNBIND_REQUEST, _) => return None,
+            (op::SEARCH_REQUEST, PL::C(fields)) => self.search(SearchRequest::parse(fields)?).await,
+            (op::ABANDON_REQUEST, _) => Vec::new(),
+            (
+                request @ (op::MODIFY_REQUEST
+                | op::ADD_REQUEST
+                | op::DEL_REQUEST
+                | op::MODIFY_DN_REQUEST
+                | op::COMPARE_REQUEST),
+                _,
+            ) => vec![ldap_result(
+                request + 1,
+                ResultCode::UnwillingToPerform,
+                "this directory is read-only",
+            )],
+            (op::EXTENDED_REQUEST, _) => vec![ldap_result(
+                op::EXTENDED_RESPONSE,
+                ResultCode::ProtocolError,
+                "unsupported extended operation",
+            )],
+            _ => return None,
+        })
+    }
+
+    async fn bind(&mut self, fields: Vec<StructureTag>) -> Option<Tag> {
+        let mut fields = fields.into_iter();
+        let (_version, name, authentication) = (fields.next()?, fields.next()?, fields.next()?);
+        let name = parse_string(name)?;
+
+        self.bound = None;
+
+        // Only simple authentication ([0]) is supported
+        if authentication.class != TagClass::Context || authentication.id != 0 {
+            return Some(ldap_result(
+                op::BIND_RESPONSE,
+                ResultCode::AuthMethodNotSupported,
+                "only simple binds are supported",
+            ));
+        }
+        let password = parse_string(authentication)?;
+
+        let code = match (name.is_empty(), password.is_empty()) {
+            (true, true) => ResultCode::Success,
+            // Unauthenticated binds (RFC 4513 §5.1.2)
+            (_, true) => ResultCode::UnwillingToPerform,
+            (true, false) => ResultCode::InvalidCredentials,
+            (false, false) => match self.tree.username_of(&name) {
+                Some(username) if self.directory.verify_password(&username, &password).await => {
+                    self.bound = Some(username);
+                    ResultCode::Success
+                }
+                _ => ResultCode::InvalidCredentials,
+            },
+        };
+
+        Some(ldap_result(op::BIND_RESPONSE, code, ""))
+    }
+
+    async fn search(&self, request: SearchRequest) -> Vec<Tag> {
+        let done = |code, diagnostic| ldap_result(op::SEARCH_RESULT_DONE, code, diagnostic);
+
+        let entries = if request.base.is_empty() && request.scope == 0 {
+            vec![self.tree.root_dse()]
+        } else if self.bound.is_none() {
+            return vec![done(
+                ResultCode::InsufficientAccessRights,
+                "bind as a user first",
+            )];
+        } else {
+            match self.directory.snapshot().await {
+                Ok(snapshot) => self.tree.entries(snapshot),
+                Err(err) => {
+                    log::error!("cannot load LDAP directory: {err}");
+                    return vec![done(ResultCode::OperationsError, "")];
+                }
+            }
+        };
+
+        let base = normalize_dn(&request.base);
+        if !entries.iter().any(|entry| normalize_dn(&entry.dn) == base) {
+            return vec![done(ResultCode::NoSuchObject, "")];
+        }
+
+        let mut results: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| request.in_scope(&entry.dn) && request.filter.matches(entry))
+            .collect();
+
+        let mut code = ResultCode::Success;
+        if let Ok(size_limit @ 1..) = usize::try_from(request.size_limit) {
+            if results.len() > size_limit {
+                results.truncate(size_limit);
+                code = ResultCode::SizeLimitExceeded;
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|entry| entry.into_tag(&request.attributes, request.types_only))
+            .chain([done(code, "")])
+            .collect()
+    }
+}
+
+/// Parses an `LDAPMessage` into its ID and operation
+fn parse_message(message: StructureTag) -> Option<(i64, StructureTag)> {
+    let mut fields = message
+        .match_class(TagClass::Universal)?
+        .match_id(lber::universal::Types::Sequence as u64)?
+        .expect_constructed()?
+        .into_iter();
+
+    Some((parse_integer(fields.next()?)?, fields.next()?))
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    directory: &dyn Directory,
+    tree: &Tree,
+) -> std::io::Result<()> {
+    let mut session = Session {
+        directory,
+        tree,
+        bound: None,
+    };
+    let mut buf = BytesMut::new();
+
+    loop {
+        let (consumed, message) = match lber::parse::parse_tag(&buf) {
+            Ok((rest, message)) => (buf.len() - rest.len(), message),
+            Err(lber::Err::Incomplete(_)) if buf.len() < MAX_MESSAGE_SIZE => {
+                if stream.read_buf(&mut buf).await? == 0 {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(_) => return Ok(()),
+        };
+        buf.advance(consumed);
+
+        let Some((id, op)) = parse_message(message) else {
+            return Ok(());
+        };
+        let Some(responses) = session.handle(op).await else {
+            return Ok(());
+        };
+
+        let mut out = BytesMut::new();
+        for response in responses {
+            let message = Tag::Sequence(Sequence {
+                inner: vec![
+                    Tag::Integer(Integer {
+                        inner: id,
+                        ..Default::default()
+                    }),
+                    response,
+                ],
+                ..Default::default()
+            });
+            lber::write::encode_into(&mut out, message.into_structure())?;
+        }
+        stream.write_all(&out).await?;
+    }
+}
+
+/// Accepts connections forever
+pub async fn serve(listener: TcpListener, directory: Arc<dyn Directory>, base_dn: &str) {
+    let tree = Arc::new(Tree::new(base_dn));
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::warn!("cannot accept LDAP connection: {err}");
+                continue;
+            }
+        };
+
+        let (directory, tree) = (Arc::clone(&directory), Arc::clone(&tree));
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, &*directory, &tree).await {
+                log::debug!("LDAP connection closed: {err}");
+            }
+        });
+    }
+}
+
+/// Starts the LDAP server on liftoff if it's enabled in the [Config]
+pub fn fairing() -> impl Fairing {
+    #[derive(Default)]
+    struct LdapFairing {
+        server: Mutex<Option<JoinHandle<()>>>,
+    }
+
+    #[rocket::async_trait]
+    impl Fairing for LdapFairing {
+        fn info(&self) -> Info {
+            Info {
+                name: "LDAP server",
+                kind: Kind::Liftoff | Kind::Shutdown,
+            }
+        }
+
+        async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+            let config = rocket.state::<Config>().unwrap();
+            let Some(ldap_config) = &config.ldap else {
+                return;
+            };
+
+            let listener = match TcpListener::bind(ldap_config.address).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    log::error!("cannot listen for LDAP on {}: {err}", ldap_config.address);
+                    return;
+                }
+            };
+            log::info!("LDAP server listening on {}", ldap_config.address);
+
+            let directory = Arc::new(DbDirectory(DbConn::pool(rocket).unwrap().clone()));
+            let base_dn = ldap_config.base_dn.clone();
+
+            *self.server.lock().await = Some(tokio::spawn(async move {
+                serve(listener, directory, &base_dn).await
+            }));
+        }
+
+        async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+            if let Some(server) = self.server.lock().await.take() {
+                server.abort();
+            }
+        }
+    }
+
+    LdapFairing::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use ldap3::{LdapConnAsync, Scope, SearchEntry};
+    use uuid::Uuid;
+
+    use crate::model::{Group, GroupId, User, UserId, WartIDResult};
+
+    use super::*;
+
+    const BASE_DN: &str = "dc=wartid,dc=test";
+
+    struct TestDirectory;
+
+    impl TestDirectory {
+        fn users() -> Vec<User> {
+            vec![
+                User {
+                    id: UserId::from_uuid(Uuid::from_u128(1)),
+                    username: String::from("Patrice"),
+                    password: Some(bcrypt::hash("hunter2", 4).unwrap()),
+                    email: Some(String::from("patrice@wart.id")),
+                    picture: None,
+                    locale: None,
+                    email_verified: true,
+                    updated: chrono::NaiveDateTime::default(),
+                    suspended: None,
+                    discord_2fa: false,
+                    suspended_by: None,
+                },
+                User {
+                    id: UserId::from_uuid(Uuid::from_u128(2)),
+                    username: String::from("Discordien"),
+                    password: None,
+                    email: None,
+                    picture: None,
+                    locale: None,
+                    email_verified: false,
+                    updated: chrono::NaiveDateTime::default(),
+                    suspended: None,
+                    discord_2fa: false,
+                    suspended_by: None,
+                },
+            ]
+        }
+    }
+
+    #[rocket::async_trait]
+    impl Directory for TestDirectory {
+        async fn verify_password(&self, username: &str, password: &str) -> bool {
+            Self::users()
+                .iter()
+                .any(|user| user.username == username && user.verify_password(password))
+        }
+
+        async fn snapshot(&self) -> WartIDResult<Snapshot> {
+            let group = GroupId::from_uuid(Uuid::from_u128(3));
+
+            Ok(Snapshot {
+                users: Self::users(),
+                groups: vec![Group {
+                    id: group,
+                    name: String::from("wiki"),
+                    description: None,
+                }],
+                memberships: vec![(group, UserId::from_uuid(Uuid::from_u128(1)))],
+            })
+        }
+    }
+
+    #[test]
+    fn escaped_dns() {
+        let tree = Tree::new(BASE_DN);
+        assert_eq!(
+            tree.user_dn("Doe, John"),
+            format!("uid=Doe\\, John,ou=users,{BASE_DN}")
+        );
+
+        for dn in [
+            format!("uid=Doe\\, John,ou=users,{BASE_DN}"),
+            format!("uid=Doe\\2C John,ou=users,{BASE_DN}"),
+            format!("UID = \"Doe, John\" , OU=Users, {BASE_DN}"),
+        ] {
+            assert_eq!(tree.username_of(&dn).as_deref(), Some("Doe, John"), "{dn}");
+        }
+        assert_eq!(
+            tree.username_of(&format!("uid=Doe,John,ou=users,{BASE_DN}")),
+            None
+        );
+
+        assert_eq!(
+            normalize_rdns("cn=a\\,b ,  OU=Groups"),
+            ["cn=a\\,b", "ou=groups"]
+        );
+        assert_eq!(
+            parse_dn("cn=\\C3\\A9\\ "),
+            Some(vec![(String::from("cn"), String::from("é "))])
+        );
+        assert_eq!(parse_dn("cn=\"a,b"), None);
+        assert_eq!(parse_dn("=a"), None);
+    }
+
+    #[rocket::async_test]
+    async fn bind_and_search() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, Arc::new(TestDirectory), BASE_DN));
+
+        let (conn, mut ldap) = LdapConnAsync::new(&url).await.unwrap();
+        ldap3::drive!(conn);
+
+        let patrice = format!("uid=Patrice,ou=users,{BASE_DN}");
+
+        // Anonymous searches are refused
+        let result = ldap
+            .search(BASE_DN, Scope::Subtree, "(objectClass=*)", vec!["*"])
+            .await
+            .unwrap();
+        assert_eq!(result.1.rc, ResultCode::InsufficientAccessRights as u32);
+
+        let result = ldap.simple_bind(&patrice, "hunter3").await.unwrap();
+        assert_eq!(result.rc, ResultCode::InvalidCredentials as u32);
+        let result = ldap
+            .simple_bind(&format!("uid=Discordien,ou=users,{BASE_DN}"), "")
+            .await
+            .unwrap();
+        assert_eq!(result.rc, ResultCode::UnwillingToPerform as u32);
+
+        ldap.simple_bind(&patrice, "hunter2")
+            .await
+            .unwrap()
+            .success()
+            .unwrap();
+
+        let (entries, _) = ldap
+            .search(
+                &format!("ou=users,{BASE_DN}"),
+                Scope::OneLevel,
+                "(&(objectClass=inetOrgPerson)(uid=pat*))",
+                vec!["uid", "mail", "memberOf"],
+            )
+            .await
+            .unwrap()
+            .success()
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+
+        let entry = SearchEntry::construct(entries.into_iter().next().unwrap());
+        assert_eq!(entry.dn, patrice);
+        assert_eq!(entry.attrs["mail"], ["patrice@wart.id"]);
+        assert_eq!(
+            entry.attrs["memberOf"],
+            [format!("cn=wiki,ou=groups,{BASE_DN}")]
+        );
+        assert!(!entry.attrs.contains_key("entryUUID"));
+
+        let (entries, _) = ldap
+            .search(
+                BASE_DN,
+                Scope::Subtree,
+                &format!("(member=uid=patrice, ou=users, {BASE_DN})"),
+                vec!["cn"],
+            )
+            .await
+            .unwrap()
+            .success()
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            SearchEntry::construct(entries[0].clone()).attrs["cn"],
+            ["wiki"]
+        );
+
+        let result = ldap
+            .modify(
+                &patrice,
+                vec![ldap3::Mod::Delete("mail", Default::default())],
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.rc, ResultCode::UnwillingToPerform as u32);
+
+        ldap.unbind().await.unwrap();
+    }
+}
@@ -22,9 +22,14 @@ use rocket::{Request, State};
 use uuid::Uuid;
 
 use crate::config::Config;
-use crate::discord::DiscordAgent;
+use crate::discord::{ApprovalStatus, DiscordAgent, LoginMethod, Notification};
+use crate::i18n::Locale;
 use crate::model::{WartIDError, WartIDResult};
 use crate::ructe::Ructe;
+use crate::utils::saml::SamlIdp;
+use crate::utils::secrets::SecretsKey;
+use crate::utils::signing::SigningKey;
+use crate::utils::upstream::UpstreamProviders;
 
 #[macro_use]
 mod id;
@@ -33,10 +38,14 @@ mod id;
 mod ructe;
 mod config;
 mod discord;
+mod i18n;
+mod ldap;
 mod model;
 mod routes;
 mod schema;
+mod scim;
 mod utils;
+mod webhooks;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
@@ -56,6 +65,37 @@ macro_rules! db_await {
 /// `::`, `[]`, etc. in their syntax), so I'm type-def-ing aliases here.
 pub mod ructe_types {
     pub type Flashes<'a> = &'a [(std::borrow::Cow<'static, str>, bool)];
+    pub type ResponseParams<'a> = Option<&'a [(&'static str, &'a str)]>;
+    pub type Webhooks<'a> =
+        Option<&'a [(crate::model::Webhook, Vec<crate::model::WebhookDelivery>)]>;
+    pub type AppAccess<'a> = Option<&'a [(crate::model::Group, bool)]>;
+    pub type LoginButtons<'a> = &'a [(String, String)];
+    pub type LinkedIdentities<'a> = &'a [(crate::model::FederatedIdentity, String)];
+
+    /// Where the consent page sends the user back to the app
+    pub struct AuthorizeForm<'a> {
+        /// Shown to the user, without the query
+        pub redirect_short: &'a str,
+        pub redirect_uri: &'a str,
+
+        /// `get` or `post`, depending on the response mode
+        pub method: &'static str,
+
+        /// `None` if the request can't be authorized
+        pub response: ResponseParams<'a>,
+    }
+
+    /// Settings only shown to the user themselves on their profile page
+    pub struct UserSettings<'a> {
+        pub tokens: &'a [crate::model::PersonalAccessToken],
+
+        /// Shown once, right after it was created
+        pub new_token: Option<&'a str>,
+        pub identities: LinkedIdentities<'a>,
+        pub links: LoginButtons<'a>,
+        pub notifications: &'a [crate::model::NotificationKind],
+        pub can_discord_2fa: bool,
+    }
 }
 
 impl<'r> rocket::response::Responder<'r, 'static> for WartIDError {
@@ -69,6 +109,9 @@ pub type DbConnection<'a> = &'a mut diesel::PgConnection;
 #[rocket_sync_db_pools::database("wartid")]
 pub struct DbConn(diesel::PgConnection);
 
+/// Pool for the background tasks, which can't use [DbConn] as a request guard
+pub type DbPool = rocket_sync_db_pools::ConnectionPool<DbConn, diesel::PgConnection>;
+
 pub struct LoginSession {
     user: model::User,
 }
@@ -149,6 +192,11 @@ impl<'r> FromRequest<'r> for &'r LoginSession {
                         .into_outcome(Status::InternalServerError))
                 };
 
+                // Their sessions are revoked when suspended, this only closes a race
+                if user.is_suspended() {
+                    return Err(LoginSessionError::InvalidSession).into_outcome(Status::Forbidden);
+                }
+
                 Outcome::Success(LoginSession { user })
             })
             .await
@@ -173,9 +221,15 @@ impl<'r> FromRequest<'r> for model::PageContext {
             .await
             .map_failure(|(s, ())| (s, WartIDError::DatabaseConnection)));
 
+        let locale = request
+            .guard::<Locale>()
+            .await
+            .succeeded()
+            .unwrap_or_default();
+
         let user_id = session.user.id;
         let ctx = try_outcome!(
-            db_await!(Self::new(db, user_id)).into_outcome(Status::InternalServerError)
+            db_await!(Self::new(db, user_id, locale)).into_outcome(Status::InternalServerError)
         );
 
         Outcome::Success(ctx)
@@ -206,56 +260,105 @@ fn home(ctx: model::PageContext) -> WartIDResult<Ructe> {
 
 #[get("/login?<redirect_to>")]
 pub fn login(
+    config: &State<Config>,
+    providers: &State<UpstreamProviders>,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
     session: Option<&LoginSession>,
-    #[allow(unused_variables)] redirect_to: Option<&str>, // Not used but required for rocket to be happy
+    locale: Locale,
+    redirect_to: Option<&str>,
 ) -> Result<Ructe, Redirect> {
     if session.is_some() {
         return Err(Redirect::to("/@me"));
     }
 
-    Ok(render!(login::login_html()))
+    let discord_login = routes::discord::login_url(config, redirect_to);
+    let provider_logins = routes::upstream::login_buttons(providers, redirect_to);
+
+    Ok(render!(login::login_html(
+        locale,
+        discord_login.as_deref(),
+        &provider_logins[..],
+        discord_agent.is_some()
+    )))
+}
+
+/// `login_session` cookie, shared with subdomains if [Config::session_cookie_domain] is set
+fn login_session_cookie(config: &Config, value: String) -> Cookie<'static> {
+    let mut cookie = Cookie::new("login_session", value);
+    if let Some(domain) = &config.session_cookie_domain {
+        cookie.set_domain(domain.clone());
+    }
+
+    cookie
+}
+
+/// Where to send the user once logged in: `redirect_to` if it is
+/// [allowed](Config::is_login_redirect_allowed), the home page if not
+fn after_login(config: &Config, redirect_to: Option<String>) -> Redirect {
+    match redirect_to {
+        Some(url) if config.is_login_redirect_allowed(&url) => Redirect::to(url),
+        Some(_) => Redirect::to("/"),
+        None => Redirect::to("/@me"),
+    }
 }
 
 #[get("/login-with-discord?<token>")]
 pub async fn login_with_discord(
+    config: &State<Config>,
     db: DbConn,
-    discord_agent: &State<Arc<DiscordAgent>>,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
     cookies: &CookieJar<'_>,
+    locale: Locale,
     token: String,
 ) -> Result<Redirect, Result<(Status, Cow<'static, str>), WartIDError>> {
-    let discord_agent = Some(Arc::clone(&discord_agent));
-    let user = match db_await!(model::User::attempt_login(db, discord_agent, "", &token)) {
+    let Some(discord_agent) = discord_agent.inner() else {
+        return Err(Ok((
+            Status::NotFound,
+            Cow::Borrowed(locale.t("login-error.discord-disabled")),
+        )));
+    };
+
+    let agent = Some(Arc::clone(discord_agent));
+    let user = match db_await!(model::User::attempt_login(db, agent, "", &token)) {
         Ok(Some(user)) => user,
         // 😓
         Ok(None) => {
             return Err(Ok((
                 Status::Unauthorized,
-                Cow::Borrowed("Impossible de créer un compte utilisateur."),
+                Cow::Borrowed(locale.t("login-error.account-creation-failed")),
             )));
         }
         Err(WartIDError::InvalidCredentials(msg)) => {
             return Err(Ok((
                 Status::Unauthorized,
-                Cow::Owned(format!(
-                    "Jeton invalide, a-t-il expiré ? Message d'erreur: {msg}"
-                )),
+                Cow::Owned(locale.format("login-error.invalid-token", &[("message", &msg)])),
             )));
         }
         Err(other) => return Err(Err(other)),
     };
 
     let user_id = user.id;
+    match db_await!(model::FederatedIdentity::find_discord_id(db, user_id)) {
+        Ok(Some(discord_id)) => discord_agent.sync_groups(discord_id),
+        Ok(None) => {}
+        Err(err) => return Err(Err(err)),
+    }
+
     let session_id = match db_await!(model::Session::insert(db, model::NewSession::new(user_id))) {
         Ok(x) => x,
         Err(err) => return Err(Err(err)),
     };
-
-    cookies.add(
-        Cookie::build("login_session", session_id.to_string())
-            .max_age(SESSION_COOKIE_EXPIRATION)
-            .finish(),
+    discord_agent.notify(
+        user_id,
+        Notification::Login {
+            method: LoginMethod::BotLink,
+        },
     );
 
+    let mut cookie = login_session_cookie(config, session_id.to_string());
+    cookie.set_max_age(SESSION_COOKIE_EXPIRATION);
+    cookies.add(cookie);
+
     Ok(Redirect::to("/@me"))
 }
 
@@ -275,16 +378,19 @@ struct LoginCredentials {
 /// received.
 #[post("/login?<redirect_to>", data = "<form>")]
 async fn login_post(
+    config: &State<Config>,
     db: DbConn,
-    discord_agent: &State<Arc<DiscordAgent>>,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
     cookies: &CookieJar<'_>,
+    locale: Locale,
     form: Form<LoginCredentials>,
     redirect_to: Option<String>, // TODO Refactor these 2 lines to a tagged union ?
 ) -> Result<Redirect, WartIDError> {
-    let discord_agent = Some(Arc::clone(discord_agent));
+    let is_password = !form.username.is_empty();
+    let agent = discord_agent.inner().clone();
     let res = db_await!(model::User::attempt_login(
         db,
-        discord_agent,
+        agent,
         &form.username,
         &form.password
     ))?;
@@ -294,64 +400,198 @@ async fn login_post(
     };
 
     let user_id = user.id;
+
+    // Logins with a link from the bot already prove the user has the Discord account
+    if let (true, true, Some(agent)) = (is_password, user.discord_2fa, discord_agent.inner()) {
+        if let Some(discord_id) = db_await!(model::FederatedIdentity::find_discord_id(db, user_id))?
+        {
+            // Nothing better than the browser's language if they have no preference
+            let locale = user
+                .locale
+                .as_deref()
+                .and_then(Locale::parse)
+                .unwrap_or(locale);
+            let id = agent.request_approval(user_id, discord_id, locale);
+            cookies.add(Cookie::new(PENDING_LOGIN_COOKIE, id));
+
+            return Ok(Redirect::to(uri!(login_pending(redirect_to.as_deref()))));
+        }
+    }
+
     let session_id = db_await!(model::Session::insert(db, model::NewSession::new(user_id)))?;
+    let method = if is_password {
+        LoginMethod::Password
+    } else {
+        LoginMethod::BotLink
+    };
+    discord::notify(discord_agent, user_id, Notification::Login { method });
+
+    let mut cookie = login_session_cookie(config, session_id.to_string());
+    cookie.set_max_age(time::Duration::days(14));
+    cookies.add(cookie);
 
-    cookies.add(
-        Cookie::build("login_session", session_id.to_string())
-            .max_age(time::Duration::days(14))
-            .finish(),
+    Ok(after_login(config, redirect_to))
+}
+
+/// Binds a password login waiting for approval from Discord to the browser it was made from
+const PENDING_LOGIN_COOKIE: &str = "pending_login";
+
+/// Waits for the user to approve their password login from Discord, see
+/// [User::discord_2fa](model::User::discord_2fa). The page refreshes itself until they answer.
+#[get("/login/pending?<redirect_to>")]
+async fn login_pending(
+    config: &State<Config>,
+    db: DbConn,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
+    cookies: &CookieJar<'_>,
+    locale: Locale,
+    redirect_to: Option<String>,
+) -> Result<Result<Ructe, Redirect>, Result<(Status, Cow<'static, str>), WartIDError>> {
+    let expired = || {
+        Err(Ok((
+            Status::Forbidden,
+            Cow::Borrowed(locale.t("pending.expired")),
+        )))
+    };
+
+    let (Some(agent), Some(id)) = (
+        discord_agent.inner(),
+        cookies
+            .get(PENDING_LOGIN_COOKIE)
+            .map(|cookie| cookie.value().to_owned()),
+    ) else {
+        return expired();
+    };
+
+    let user_id = match agent.check_approval(&id) {
+        Some((_, ApprovalStatus::Pending)) => {
+            let refresh = uri!(login_pending(redirect_to.as_deref())).to_string();
+            return Ok(Ok(render!(login::pending_html(locale, &refresh))));
+        }
+        Some((user_id, ApprovalStatus::Approved)) => user_id,
+        Some((_, ApprovalStatus::Denied)) => {
+            cookies.remove(Cookie::named(PENDING_LOGIN_COOKIE));
+            return Err(Ok((
+                Status::Forbidden,
+                Cow::Borrowed(locale.t("pending.denied")),
+            )));
+        }
+        Some((_, ApprovalStatus::Undeliverable)) => {
+            cookies.remove(Cookie::named(PENDING_LOGIN_COOKIE));
+            return Err(Ok((
+                Status::BadGateway,
+                Cow::Borrowed(locale.t("pending.undeliverable")),
+            )));
+        }
+        None => {
+            cookies.remove(Cookie::named(PENDING_LOGIN_COOKIE));
+            return expired();
+        }
+    };
+    cookies.remove(Cookie::named(PENDING_LOGIN_COOKIE));
+
+    let session_id =
+        db_await!(model::Session::insert(db, model::NewSession::new(user_id))).map_err(Err)?;
+    agent.notify(
+        user_id,
+        Notification::Login {
+            method: LoginMethod::ApprovedPassword,
+        },
     );
 
-    Ok(Redirect::to(
-        redirect_to
-            .unwrap_or_else(|| String::from("/@me"))
-            .as_str()
-            .to_string(),
-    ))
+    let mut cookie = login_session_cookie(config, session_id.to_string());
+    cookie.set_max_age(time::Duration::days(14));
+    cookies.add(cookie);
+
+    Ok(Err(after_login(config, redirect_to)))
 }
 
 // TODO CSRF
 #[post("/logout")]
-fn logout(cookies: &CookieJar) -> Redirect {
-    cookies.remove(Cookie::named("login_session"));
+fn logout(config: &State<Config>, cookies: &CookieJar) -> Redirect {
+    cookies.remove(login_session_cookie(config, String::new()));
     // TODO faire aussi sauter la session dans la BDD
 
     Redirect::to("/login")
 }
 
-#[rocket::launch]
-async fn launch() -> _ {
-    let _ = dotenv::dotenv();
-
-    // Serenity is a bit talkative, and I don't care that much about tracing
-    let _ = tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::new());
+/// Every route of the server, mounted at the root
+fn routes() -> Vec<rocket::Route> {
+    routes![
+        static_file,
+        root,
+        home,
+        routes::apps::list,
+        routes::apps::new,
+        routes::apps::view,
+        routes::apps::view_update,
+        routes::forward_auth::verify,
+        routes::oauth2::authorize,
+        routes::oauth2::jwks,
+        routes::oauth2::token,
+        routes::oauth2::userinfo,
+        routes::openapi::document,
+        routes::saml::metadata,
+        routes::saml::sso_post,
+        routes::saml::sso_redirect,
+        routes::scim::service_provider_config,
+        routes::scim::users_list,
+        routes::scim::users_get,
+        routes::scim::users_create,
+        routes::scim::users_replace,
+        routes::scim::users_patch,
+        routes::scim::users_delete,
+        routes::scim::groups_list,
+        routes::scim::groups_get,
+        routes::scim::groups_create,
+        routes::scim::groups_replace,
+        routes::scim::groups_patch,
+        routes::scim::groups_delete,
+        routes::api::apps_list,
+        routes::api::apps_create,
+        routes::api::apps_get,
+        routes::api::apps_update,
+        routes::api::apps_oauth_secret,
+        routes::api::apps_oauth_revoke_previous,
+        routes::api::apps_oauth_disable,
+        routes::api::users_me,
+        routes::api::users_get,
+        routes::api::users_update,
+        routes::users::view,
+        routes::users::view_me,
+        routes::users::view_update,
+        login,
+        login_post,
+        login_pending,
+        login_with_discord,
+        routes::recovery::request,
+        routes::recovery::request_post,
+        routes::recovery::reset,
+        routes::recovery::reset_post,
+        routes::discord::login,
+        routes::discord::link,
+        routes::discord::callback,
+        routes::upstream::login,
+        routes::upstream::link,
+        routes::upstream::callback,
+        logout,
+    ]
+}
 
-    rocket::build()
+/// The whole server, configured by `figment`
+fn server(figment: impl rocket::figment::Provider) -> rocket::Rocket<rocket::Build> {
+    rocket::custom(figment)
         .attach(AdHoc::config::<Config>())
-        .attach(DiscordAgent::fairing())
+        .attach(SigningKey::fairing())
+        .attach(SecretsKey::fairing())
+        .attach(SamlIdp::fairing())
+        .attach(UpstreamProviders::fairing())
+        // The Discord agent needs the database pool
         .attach(DbConn::fairing())
-        .mount(
-            "/",
-            routes![
-                static_file,
-                root,
-                home,
-                routes::apps::list,
-                routes::apps::new,
-                routes::apps::view,
-                routes::apps::view_update,
-                routes::oauth2::authorize,
-                routes::oauth2::token,
-                routes::oauth2::userinfo,
-                routes::users::view,
-                routes::users::view_me,
-                routes::users::view_update,
-                login,
-                login_post,
-                login_with_discord,
-                logout,
-            ],
-        )
+        .attach(DiscordAgent::fairing())
+        .attach(ldap::fairing())
+        .attach(webhooks::fairing())
+        .mount("/", routes())
         .attach(AdHoc::on_liftoff("migration runner", |rocket| {
             Box::pin(async move {
                 let conn = DbConn::get_one(rocket)
@@ -370,4 +610,274 @@ async fn launch() -> _ {
         }))
 }
 
+#[rocket::launch]
+async fn launch() -> _ {
+    let _ = dotenv::dotenv();
+
+    // Serenity is a bit talkative, and I don't care that much about tracing
+    let _ = tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::new());
+
+    server(rocket::Config::figment())
+}
+
 include!(concat!(env!("OUT_DIR"), "/templates.rs"));
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use rocket::fairing::AdHoc;
+    use rocket::figment::Figment;
+    use rocket::form::Form;
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::{json, Json, Value};
+    use rocket::State;
+
+    use crate::model::{User, UserApp, UserAppId, UserId, WartIDResult};
+
+    /// Only the database is taken from `Rocket.toml` and `ROCKET_*`, so Discord is never set up
+    fn figment() -> Figment {
+        let database_url: String = rocket::Config::figment()
+            .extract_inner("databases.wartid.url")
+            .expect("the tests need the database configured for the server");
+
+        Figment::from(rocket::Config::debug_default())
+            .merge(("base_url", "http://localhost:8000/"))
+            .merge(("databases.wartid.url", database_url))
+    }
+
+    /// Server using the test database, see [figment]
+    pub(crate) async fn client() -> Client {
+        Client::tracked(super::server(figment())).await.unwrap()
+    }
+
+    /// Connection to the test database in a transaction that is never committed, for the tests of
+    /// the model
+    pub(crate) fn db() -> diesel::PgConnection {
+        use diesel::Connection;
+        use diesel_migrations::MigrationHarness;
+
+        static MIGRATED: std::sync::Mutex<bool> = std::sync::Mutex::new(false);
+
+        let database_url: String = figment().extract_inner("databases.wartid.url").unwrap();
+        let mut db = diesel::PgConnection::establish(&database_url).unwrap();
+
+        let mut migrated = MIGRATED.lock().unwrap();
+        if !*migrated {
+            db.run_pending_migrations(super::MIGRATIONS).unwrap();
+            *migrated = true;
+        }
+        drop(migrated);
+
+        db.begin_test_transaction().unwrap();
+        db
+    }
+
+    /// User with a random name, to be deleted by [cleanup]
+    pub(crate) async fn create_user(client: &Client, password: Option<&'static str>) -> User {
+        let username = format!("test-{}", crate::utils::gen_alphanumeric(8));
+        let db = super::DbConn::get_one(client.rocket()).await.unwrap();
+
+        db_await!(User::insert_local(db, username, None, password)).unwrap()
+    }
+
+    /// App managed by `manager` with OAuth2 enabled, along with its secret
+    pub(crate) async fn create_app(client: &Client, manager: UserId) -> (UserAppId, String) {
+        let name = format!("test-{}", crate::utils::gen_alphanumeric(8));
+        let db = super::DbConn::get_one(client.rocket()).await.unwrap();
+
+        let app_id = db_await!(UserApp::insert(db, name, true, manager)).unwrap();
+        let (_, secret) = db_await!(UserApp::set_oauth(db, app_id, true)).unwrap();
+
+        (app_id, secret.unwrap())
+    }
+
+    /// Deletes the users and the apps they manage
+    pub(crate) async fn cleanup(client: &Client, users: Vec<UserId>) {
+        let db = super::DbConn::get_one(client.rocket()).await.unwrap();
+
+        db.run(move |db| {
+            use crate::schema::{user_apps, user_apps_managers};
+            use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+            let apps = user_apps_managers::table
+                .filter(user_apps_managers::users_id.eq_any(&users))
+                .select(user_apps_managers::user_apps_id);
+            diesel::delete(user_apps::table.filter(user_apps::id.eq_any(apps))).execute(db)?;
+
+            for user in users {
+                User::delete(db, user)?;
+            }
+
+            WartIDResult::Ok(())
+        })
+        .await
+        .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn boots_without_discord() {
+        let client = Client::tracked(super::server(figment()))
+            .await
+            .expect("cannot boot without Discord");
+
+        let response = client.get("/login").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().await.unwrap();
+        assert!(!body.contains("/login/discord"));
+
+        for uri in [
+            "/login-with-discord?token=x",
+            "/login/discord",
+            "/login/discord/callback?state=x",
+        ] {
+            let response = client.get(uri).dispatch().await;
+            assert_eq!(response.status(), Status::NotFound, "{uri}");
+        }
+
+        let response = client
+            .post("/login")
+            .header(rocket::http::ContentType::Form)
+            .body("username=&password=x")
+            .dispatch()
+            .await;
+        assert_ne!(response.status(), Status::InternalServerError);
+    }
+
+    /// Upstream OpenID Connect provider only knowing one account, which it always logs in
+    struct MockIdp {
+        port: OnceLock<u16>,
+        subject: u64,
+        username: String,
+    }
+
+    #[get("/.well-known/openid-configuration")]
+    fn mock_discovery(idp: &State<MockIdp>) -> Json<Value> {
+        let issuer = format!("http://127.0.0.1:{}", idp.port.get().unwrap());
+        Json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "userinfo_endpoint": format!("{issuer}/userinfo"),
+        }))
+    }
+
+    #[derive(FromForm)]
+    struct MockTokenRequest<'r> {
+        code: &'r str,
+        client_secret: &'r str,
+    }
+
+    #[post("/token", data = "<request>")]
+    fn mock_token(request: Form<MockTokenRequest<'_>>) -> Result<Json<Value>, Status> {
+        if request.code != "mock-code" || request.client_secret != "mock-secret" {
+            return Err(Status::BadRequest);
+        }
+
+        Ok(Json(
+            json!({ "access_token": "mock-token", "token_type": "Bearer" }),
+        ))
+    }
+
+    #[get("/userinfo")]
+    fn mock_userinfo(idp: &State<MockIdp>) -> Json<Value> {
+        // Numeric, like GitHub's
+        Json(json!({ "sub": idp.subject, "preferred_username": idp.username }))
+    }
+
+    /// Launches the mock IdP on a random port, returning its issuer URL
+    async fn launch_mock_idp(subject: u64, username: String) -> String {
+        let (tx, rx) = rocket::tokio::sync::oneshot::channel();
+        let figment = Figment::from(rocket::Config::debug_default())
+            .merge(("address", "127.0.0.1"))
+            .merge(("port", 0));
+
+        let mock = rocket::custom(figment)
+            .manage(MockIdp {
+                port: OnceLock::new(),
+                subject,
+                username,
+            })
+            .mount("/", routes![mock_discovery, mock_token, mock_userinfo])
+            .attach(AdHoc::on_liftoff("mock IdP port", |rocket| {
+                Box::pin(async move {
+                    let port = rocket.config().port;
+                    rocket.state::<MockIdp>().unwrap().port.set(port).unwrap();
+                    tx.send(port).unwrap();
+                })
+            }));
+        rocket::tokio::spawn(mock.launch());
+
+        format!("http://127.0.0.1:{}", rx.await.unwrap())
+    }
+
+    #[rocket::async_test]
+    async fn logs_in_with_upstream_oidc() {
+        let subject = rand::random::<u32>() as u64;
+        let username = format!("mock-{}", crate::utils::gen_alphanumeric(8));
+        let issuer = launch_mock_idp(subject, username.clone()).await;
+
+        let figment = figment().merge((
+            "upstream_oidc",
+            json!([{
+                "id": "mock",
+                "name": "Mock",
+                "client_id": "wartid",
+                "client_secret": "mock-secret",
+                "issuer": issuer,
+                "create_users": true,
+            }]),
+        ));
+        let client = Client::tracked(super::server(figment)).await.unwrap();
+
+        let response = client.get("/login").dispatch().await;
+        assert!(response
+            .into_string()
+            .await
+            .unwrap()
+            .contains("/login/oidc/mock"));
+
+        // Twice, the second time finds the user created the first time
+        for _ in 0..2 {
+            let response = client.get("/login/oidc/mock").dispatch().await;
+            assert_eq!(response.status(), Status::SeeOther);
+            let location = response.headers().get_one("Location").unwrap();
+            assert!(location.starts_with(&format!("{issuer}/authorize?")));
+            let state = location
+                .split(['?', '&'])
+                .find_map(|param| param.strip_prefix("state="))
+                .unwrap();
+
+            let response = client
+                .get(format!(
+                    "/login/oidc/mock/callback?code=mock-code&state={state}"
+                ))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::SeeOther);
+            assert_eq!(response.headers().get_one("Location"), Some("/@me"));
+
+            let response = client.get("/@me").dispatch().await;
+            assert_eq!(response.status(), Status::SeeOther);
+            let profile = response.headers().get_one("Location").unwrap().to_owned();
+            let response = client.get(profile).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            assert!(response.into_string().await.unwrap().contains(&username));
+        }
+
+        let db = super::DbConn::get_one(client.rocket()).await.unwrap();
+        let deleted = db
+            .run(move |db| {
+                use crate::schema::users;
+                use diesel::{ExpressionMethods, RunQueryDsl};
+
+                diesel::delete(users::table)
+                    .filter(users::username.eq(username))
+                    .execute(db)
+            })
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+    }
+}
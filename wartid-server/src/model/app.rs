@@ -1,38 +1,162 @@
+use std::str::FromStr;
+
 use diesel::dsl::exists;
 use diesel::{
-    BoolExpressionMethods, Connection, ExpressionMethods, QueryDsl, Queryable, RunQueryDsl,
+    BoolExpressionMethods, Connection, ExpressionMethods, JoinOnDsl, QueryDsl, Queryable,
+    RunQueryDsl,
 };
 
 use crate::id::Id;
-use crate::schema::{user_apps, user_apps_managers};
+use crate::schema::{groups, groups_members, user_apps, user_apps_groups, user_apps_managers};
 
 use super::*;
 
 pub type UserAppId = Id<UserApp>;
 
-type OAuthSecret = String;
+pub type OAuthSecret = String;
+
+/// Ways a client can authenticate itself to the token endpoint
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClientAuthMethod {
+    /// `client_id` / `client_secret` in an `Authorization: Basic` header
+    ClientSecretBasic,
+
+    /// `client_id` / `client_secret` in the request body
+    ClientSecretPost,
+
+    /// `client_assertion` JWT signed (HMAC) with the client secret, of which an encrypted copy is
+    /// kept for apps pinned to this method
+    ClientSecretJwt,
+
+    /// `client_assertion` JWT signed with a private key whose public part is in the app's JWKS
+    PrivateKeyJwt,
+}
+
+impl ClientAuthMethod {
+    pub const ALL: [Self; 4] = [
+        Self::ClientSecretBasic,
+        Self::ClientSecretPost,
+        Self::ClientSecretJwt,
+        Self::PrivateKeyJwt,
+    ];
+}
+
+impl FromStr for ClientAuthMethod {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client_secret_basic" => Ok(Self::ClientSecretBasic),
+            "client_secret_post" => Ok(Self::ClientSecretPost),
+            "client_secret_jwt" => Ok(Self::ClientSecretJwt),
+            "private_key_jwt" => Ok(Self::PrivateKeyJwt),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for ClientAuthMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::ClientSecretBasic => "client_secret_basic",
+            Self::ClientSecretPost => "client_secret_post",
+            Self::ClientSecretJwt => "client_secret_jwt",
+            Self::PrivateKeyJwt => "private_key_jwt",
+        })
+    }
+}
 
 #[derive(Queryable)]
 pub struct UserApp {
     pub id: UserAppId,
     pub name: String,
-    oauth_secret: Option<OAuthSecret>,
+    oauth_secret_hash: Option<String>,
     pub oauth_redirect: String,
     pub description: Option<String>,
     pub hidden: bool,
+    oauth_auth_method: Option<String>,
+    pub oauth_jwks: Option<String>,
+    oauth_secret_previous_hash: Option<String>,
+    pub saml_entity_id: Option<String>,
+    pub saml_acs_url: Option<String>,
+    scim_token_hash: Option<String>,
+    oauth_userinfo_alg: Option<String>,
+    oauth_secret_encrypted: Option<String>,
 }
 
 impl UserApp {
-    pub fn oauth2(&self) -> Option<(&str, &str)> {
-        self.oauth_secret
-            .as_ref()
-            .map(|secret| (secret.as_ref(), self.oauth_redirect.as_str()))
+    pub fn is_oauth2_enabled(&self) -> bool {
+        self.oauth_secret_hash.is_some()
+    }
+
+    /// Whether a secret from before the last regeneration is still accepted
+    pub fn has_previous_oauth2_secret(&self) -> bool {
+        self.oauth_secret_previous_hash.is_some()
+    }
+
+    pub fn verify_oauth2_secret(&self, secret: &str) -> bool {
+        // Both are always checked to avoid leaking which one matched through timing
+        let current = self
+            .oauth_secret_hash
+            .as_deref()
+            .is_some_and(|hash| crate::utils::verify_secret(secret, hash));
+        let previous = self
+            .oauth_secret_previous_hash
+            .as_deref()
+            .is_some_and(|hash| crate::utils::verify_secret(secret, hash));
+
+        current | previous
+    }
+
+    /// Current secret, encrypted with the [SecretsKey](crate::utils::secrets::SecretsKey) for
+    /// `client_secret_jwt`
+    pub fn oauth2_secret_encrypted(&self) -> Option<&str> {
+        self.oauth_secret_encrypted.as_deref()
+    }
+
+    /// Pinned to `client_secret_jwt`, but without an encrypted copy of the secret to check it with
+    pub fn needs_oauth2_secret_regeneration(&self) -> bool {
+        self.oauth2_auth_method() == Some(ClientAuthMethod::ClientSecretJwt)
+            && self.oauth_secret_encrypted.is_none()
     }
 
     pub fn is_oauth2_redirect_allowed(&self, uri: &str) -> bool {
         (self.oauth_redirect.len() > "https://a.bc".len())
             && (uri.starts_with(&self.oauth_redirect))
     }
+
+    /// The only method this app is allowed to authenticate with, if it was pinned
+    pub fn oauth2_auth_method(&self) -> Option<ClientAuthMethod> {
+        self.oauth_auth_method
+            .as_deref()
+            .and_then(|method| method.parse().ok())
+    }
+
+    pub fn is_oauth2_auth_method_allowed(&self, method: ClientAuthMethod) -> bool {
+        match self.oauth2_auth_method() {
+            Some(pinned) => pinned == method,
+            None => match method {
+                ClientAuthMethod::ClientSecretJwt => self.oauth_secret_encrypted.is_some(),
+                ClientAuthMethod::PrivateKeyJwt => self.oauth_jwks.is_some(),
+                _ => true,
+            },
+        }
+    }
+
+    /// Algorithm `/oauth2/userinfo` responses are signed with (`userinfo_signed_response_alg`),
+    /// plain JSON being returned if `None`
+    pub fn oauth2_userinfo_alg(&self) -> Option<&str> {
+        self.oauth_userinfo_alg.as_deref()
+    }
+
+    /// Whether this app is configured as a SAML service provider
+    pub fn is_saml_enabled(&self) -> bool {
+        self.saml_entity_id.is_some() && self.saml_acs_url.is_some()
+    }
+
+    pub fn is_scim_enabled(&self) -> bool {
+        self.scim_token_hash.is_some()
+    }
 }
 
 impl UserApp {
@@ -91,16 +215,80 @@ impl UserApp {
         }
     }
 
-    pub fn set_oauth(db: crate::DbConnection, app: UserAppId, enable: bool) -> WartIDResult<Self> {
+    pub fn is_manager(db: crate::DbConnection, app: UserAppId, user: UserId) -> WartIDResult<bool> {
+        use crate::schema::user_apps_managers::dsl::*;
+
+        diesel::select(exists(
+            user_apps_managers.filter(user_apps_id.eq(app).and(users_id.eq(user))),
+        ))
+        .get_result(db)
+        .map_err(Into::into)
+    }
+
+    /// Enables OAuth2 by generating a new secret, or disables it. The new secret is returned, it's
+    /// the only time it is available in plain text.
+    ///
+    /// When regenerating, the current secret stays valid alongside the new one so clients can be
+    /// updated without downtime, until [revoke_previous_oauth_secret](Self::revoke_previous_oauth_secret)
+    /// is called. Only the new one can be used with `client_secret_jwt`, once encrypted with
+    /// [set_oauth_secret_encrypted](Self::set_oauth_secret_encrypted).
+    pub fn set_oauth(
+        db: crate::DbConnection,
+        app: UserAppId,
+        enable: bool,
+    ) -> WartIDResult<(Self, Option<OAuthSecret>)> {
+        use crate::schema::user_apps::dsl::*;
+
+        if !enable {
+            let app = diesel::update(user_apps)
+                .filter(id.eq(app))
+                .set((
+                    oauth_secret_hash.eq(None::<String>),
+                    oauth_secret_previous_hash.eq(None::<String>),
+                    oauth_secret_encrypted.eq(None::<String>),
+                ))
+                .get_result(db)?;
+
+            return Ok((app, None));
+        }
+
+        let secret = crate::utils::gen_alphanumeric(64);
+
+        let app = diesel::update(user_apps)
+            .filter(id.eq(app))
+            .set((
+                oauth_secret_previous_hash.eq(oauth_secret_hash),
+                oauth_secret_hash.eq(crate::utils::hash_secret(&secret)),
+                oauth_secret_encrypted.eq(None::<String>),
+            ))
+            .get_result(db)?;
+
+        Ok((app, Some(secret)))
+    }
+
+    pub fn set_oauth_secret_encrypted(
+        db: crate::DbConnection,
+        app: UserAppId,
+        encrypted: Option<String>,
+    ) -> WartIDResult<Self> {
+        use crate::schema::user_apps::dsl::*;
+
+        diesel::update(user_apps)
+            .filter(id.eq(app))
+            .set(oauth_secret_encrypted.eq(encrypted))
+            .get_result(db)
+            .map_err(Into::into)
+    }
+
+    pub fn revoke_previous_oauth_secret(
+        db: crate::DbConnection,
+        app: UserAppId,
+    ) -> WartIDResult<Self> {
         use crate::schema::user_apps::dsl::*;
 
         diesel::update(user_apps)
             .filter(id.eq(app))
-            .set(oauth_secret.eq(if enable {
-                Some(crate::utils::gen_alphanumeric(64))
-            } else {
-                None
-            }))
+            .set(oauth_secret_previous_hash.eq(None::<String>))
             .get_result(db)
             .map_err(Into::into)
     }
@@ -119,6 +307,202 @@ impl UserApp {
             .map_err(Into::into)
     }
 
+    /// Whether at least one person manages both apps
+    pub fn share_manager(
+        db: crate::DbConnection,
+        a: UserAppId,
+        b: UserAppId,
+    ) -> WartIDResult<bool> {
+        use crate::schema::user_apps_managers::dsl::*;
+
+        let managers_of_b: Vec<UserId> = user_apps_managers
+            .filter(user_apps_id.eq(b))
+            .select(users_id)
+            .load(db)?;
+
+        diesel::select(exists(
+            user_apps_managers.filter(user_apps_id.eq(a).and(users_id.eq_any(managers_of_b))),
+        ))
+        .get_result(db)
+        .map_err(Into::into)
+    }
+
+    /// Groups whose members can use the app, anyone being allowed if there are none
+    pub fn find_allowed_groups(
+        db: crate::DbConnection,
+        app: UserAppId,
+    ) -> WartIDResult<Vec<Group>> {
+        groups::table
+            .inner_join(user_apps_groups::table)
+            .filter(user_apps_groups::user_apps_id.eq(app))
+            .select((groups::id, groups::name, groups::description))
+            .order(groups::name)
+            .load(db)
+            .map_err(Into::into)
+    }
+
+    pub fn set_allowed_groups(
+        db: crate::DbConnection,
+        app: UserAppId,
+        allowed: &[GroupId],
+    ) -> WartIDResult<()> {
+        db.transaction(|db| {
+            diesel::delete(user_apps_groups::table)
+                .filter(user_apps_groups::user_apps_id.eq(app))
+                .execute(db)?;
+
+            diesel::insert_into(user_apps_groups::table)
+                .values(
+                    allowed
+                        .iter()
+                        .map(|group| {
+                            (
+                                user_apps_groups::user_apps_id.eq(app),
+                                user_apps_groups::groups_id.eq(group),
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .execute(db)?;
+
+            Ok(())
+        })
+    }
+
+    /// Whether the user is in one of the [allowed groups](Self::find_allowed_groups) of the app
+    pub fn is_user_allowed(
+        db: crate::DbConnection,
+        app: UserAppId,
+        user: UserId,
+    ) -> WartIDResult<bool> {
+        let restricted =
+            exists(user_apps_groups::table.filter(user_apps_groups::user_apps_id.eq(app)));
+        let member = exists(
+            user_apps_groups::table
+                .inner_join(
+                    groups_members::table
+                        .on(groups_members::groups_id.eq(user_apps_groups::groups_id)),
+                )
+                .filter(user_apps_groups::user_apps_id.eq(app))
+                .filter(groups_members::users_id.eq(user)),
+        );
+
+        diesel::select(diesel::dsl::not(restricted).or(member))
+            .get_result(db)
+            .map_err(Into::into)
+    }
+
+    /// The encrypted copy of the secret is forgotten if the app isn't pinned to
+    /// `client_secret_jwt` anymore. Pinning it doesn't make one, the secret has to be regenerated.
+    pub fn set_oauth_client_auth(
+        db: crate::DbConnection,
+        app: UserAppId,
+        method: Option<ClientAuthMethod>,
+        jwks: Option<String>,
+    ) -> WartIDResult<Self> {
+        use crate::schema::user_apps::dsl::*;
+
+        let query = diesel::update(user_apps).filter(id.eq(app));
+        let changes = (
+            oauth_auth_method.eq(method.map(|method| method.to_string())),
+            oauth_jwks.eq(jwks),
+        );
+
+        if method == Some(ClientAuthMethod::ClientSecretJwt) {
+            query.set(changes).get_result(db)
+        } else {
+            query
+                .set((changes, oauth_secret_encrypted.eq(None::<String>)))
+                .get_result(db)
+        }
+        .map_err(Into::into)
+    }
+
+    pub fn set_oauth_userinfo_alg(
+        db: crate::DbConnection,
+        app: UserAppId,
+        alg: Option<&str>,
+    ) -> WartIDResult<Self> {
+        use crate::schema::user_apps::dsl::*;
+
+        diesel::update(user_apps)
+            .filter(id.eq(app))
+            .set(oauth_userinfo_alg.eq(alg))
+            .get_result(db)
+            .map_err(Into::into)
+    }
+
+    pub fn find_by_saml_entity_id(
+        db: crate::DbConnection,
+        entity_id: &str,
+    ) -> WartIDResult<Option<Self>> {
+        use crate::schema::user_apps::dsl::*;
+
+        match user_apps.filter(saml_entity_id.eq(entity_id)).first(db) {
+            Ok(app) => Ok(Some(app)),
+            Err(diesel::NotFound) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Configures the app as a SAML service provider, or disables SAML if `sp` is `None`
+    pub fn set_saml(
+        db: crate::DbConnection,
+        app: UserAppId,
+        sp: Option<(String, String)>,
+    ) -> WartIDResult<Self> {
+        use crate::schema::user_apps::dsl::*;
+
+        let (entity_id, acs_url) = sp.unzip();
+
+        diesel::update(user_apps)
+            .filter(id.eq(app))
+            .set((saml_entity_id.eq(entity_id), saml_acs_url.eq(acs_url)))
+            .get_result(db)
+            .map_err(Into::into)
+    }
+
+    /// Makes SCIM tokens recognizable, both by us and by secret scanners
+    pub const SCIM_TOKEN_PREFIX: &'static str = "wartid_scim_";
+
+    /// Generates a new SCIM token, replacing the previous one, or disables SCIM. Like OAuth2
+    /// secrets, only its hash is stored so this is the only time it is available in plain text.
+    pub fn set_scim(
+        db: crate::DbConnection,
+        app: UserAppId,
+        enable: bool,
+    ) -> WartIDResult<(Self, Option<String>)> {
+        use crate::schema::user_apps::dsl::*;
+
+        let token = enable.then(|| {
+            format!(
+                "{}{}",
+                Self::SCIM_TOKEN_PREFIX,
+                crate::utils::gen_alphanumeric(40)
+            )
+        });
+
+        let app = diesel::update(user_apps)
+            .filter(id.eq(app))
+            .set(scim_token_hash.eq(token.as_deref().map(crate::utils::hash_secret)))
+            .get_result(db)?;
+
+        Ok((app, token))
+    }
+
+    pub fn find_by_scim_token(db: crate::DbConnection, token: &str) -> WartIDResult<Option<Self>> {
+        use crate::schema::user_apps::dsl::*;
+
+        match user_apps
+            .filter(scim_token_hash.eq(crate::utils::hash_secret(token)))
+            .first(db)
+        {
+            Ok(app) => Ok(Some(app)),
+            Err(diesel::NotFound) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub fn set_name_description(
         db: crate::DbConnection,
         app: UserAppId,
@@ -0,0 +1,191 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use super::{OAuth2Scope, OAuth2Scopes};
+
+/// Claims about the user returned by `/oauth2/userinfo`, besides `sub` and `name` which always are
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Claim {
+    PreferredUsername,
+    Picture,
+    Locale,
+    UpdatedAt,
+    Email,
+    EmailVerified,
+    Groups,
+    DiscordId,
+}
+
+impl Claim {
+    pub const ALL: [Self; 8] = [
+        Self::PreferredUsername,
+        Self::Picture,
+        Self::Locale,
+        Self::UpdatedAt,
+        Self::Email,
+        Self::EmailVerified,
+        Self::Groups,
+        Self::DiscordId,
+    ];
+
+    /// Scope granting this claim
+    pub fn scope(self) -> OAuth2Scope {
+        match self {
+            Self::PreferredUsername | Self::Picture | Self::Locale | Self::UpdatedAt => {
+                OAuth2Scope::Profile
+            }
+            Self::Email | Self::EmailVerified => OAuth2Scope::Email,
+            Self::Groups => OAuth2Scope::Groups,
+            Self::DiscordId => OAuth2Scope::Discord,
+        }
+    }
+}
+
+impl FromStr for Claim {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preferred_username" => Ok(Self::PreferredUsername),
+            "picture" => Ok(Self::Picture),
+            "locale" => Ok(Self::Locale),
+            "updated_at" => Ok(Self::UpdatedAt),
+            "email" => Ok(Self::Email),
+            "email_verified" => Ok(Self::EmailVerified),
+            "groups" => Ok(Self::Groups),
+            "discord_id" => Ok(Self::DiscordId),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Claim {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::PreferredUsername => "preferred_username",
+            Self::Picture => "picture",
+            Self::Locale => "locale",
+            Self::UpdatedAt => "updated_at",
+            Self::Email => "email",
+            Self::EmailVerified => "email_verified",
+            Self::Groups => "groups",
+            Self::DiscordId => "discord_id",
+        })
+    }
+}
+
+/// Claims requested individually with the OIDC `claims` parameter, on top of the ones granted by
+/// the scopes
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Claims(BTreeSet<Claim>);
+
+impl Claims {
+    /// Parses the `claims` authorization parameter (OIDC Core §5.5). Since WartID doesn't issue ID
+    /// tokens, only the `userinfo` member is used. Unknown claims are ignored as the specification
+    /// requires, and so are the `essential`, `value` and `values` requests.
+    pub fn parse_request(request: &str) -> Result<Self, ()> {
+        let request: serde_json::Value = serde_json::from_str(request).map_err(|_| ())?;
+
+        match request.get("userinfo") {
+            Some(serde_json::Value::Object(userinfo)) => Ok(Claims(
+                userinfo
+                    .keys()
+                    .filter_map(|claim| claim.parse().ok())
+                    .collect(),
+            )),
+            Some(_) => Err(()),
+            None if request.is_object() => Ok(Claims::default()),
+            None => Err(()),
+        }
+    }
+
+    /// Claims granted by `scopes`, and the ones requested individually
+    pub fn granted(&self, scopes: &OAuth2Scopes) -> Self {
+        Claims(
+            Claim::ALL
+                .into_iter()
+                .filter(|claim| scopes.contains(claim.scope()) || self.0.contains(claim))
+                .collect(),
+        )
+    }
+
+    pub fn contains(&self, claim: Claim) -> bool {
+        self.0.contains(&claim)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Claim> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl FromStr for Claims {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Claims(
+            s.split_ascii_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+impl std::fmt::Display for Claims {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use std::fmt::Write;
+
+        for (idx, claim) in self.0.iter().enumerate() {
+            if idx != 0 {
+                f.write_char(' ')?;
+            }
+            std::fmt::Display::fmt(claim, f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Claims {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let str = <&str as serde::Deserialize>::deserialize(deserializer)?;
+        str.parse()
+            .map_err(|_| D::Error::custom("cannot parse claims"))
+    }
+}
+
+impl serde::Serialize for Claims {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_and_scopes() {
+        let requested = Claims::parse_request(
+            r#"{"userinfo": {"groups": {"essential": true}, "nickname": null}, "id_token": {}}"#,
+        )
+        .unwrap();
+        assert_eq!(requested.to_string(), "groups");
+
+        let granted = requested.granted(&"basic email".parse().unwrap());
+        assert_eq!(granted.to_string(), "email email_verified groups");
+
+        assert_eq!(Claims::parse_request("{}"), Ok(Claims::default()));
+        assert!(Claims::parse_request(r#"{"userinfo": ["email"]}"#).is_err());
+    }
+}
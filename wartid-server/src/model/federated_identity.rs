@@ -0,0 +1,234 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::exists;
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+use crate::schema::users;
+
+use super::*;
+
+/// Account of a user at an external identity provider (Discord or an upstream OpenID Connect
+/// provider), which they can log in with
+#[derive(Clone, Debug, Queryable)]
+pub struct FederatedIdentity {
+    /// `discord`, or the ID of the provider in [Config::upstream_oidc](crate::config::Config::upstream_oidc)
+    pub provider: String,
+
+    /// Identifier of the account at the provider, like the Discord user ID
+    pub subject: String,
+    pub users_id: UserId,
+    pub last_login: Option<NaiveDateTime>,
+}
+
+impl FederatedIdentity {
+    pub const DISCORD: &'static str = "discord";
+
+    pub fn find_user(
+        db: crate::DbConnection,
+        l_provider: &str,
+        l_subject: &str,
+    ) -> WartIDResult<Option<User>> {
+        use crate::schema::federated_identities::dsl::*;
+
+        federated_identities
+            .inner_join(users::table)
+            .filter(provider.eq(l_provider))
+            .filter(subject.eq(l_subject))
+            .select(users::all_columns)
+            .first(db)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    pub fn find_all_by_user(db: crate::DbConnection, user: UserId) -> WartIDResult<Vec<Self>> {
+        use crate::schema::federated_identities::dsl::*;
+
+        federated_identities
+            .filter(users_id.eq(user))
+            .order_by(provider)
+            .load(db)
+            .map_err(Into::into)
+    }
+
+    pub fn find_subject(
+        db: crate::DbConnection,
+        user: UserId,
+        l_provider: &str,
+    ) -> WartIDResult<Option<String>> {
+        use crate::schema::federated_identities::dsl::*;
+
+        federated_identities
+            .filter(users_id.eq(user))
+            .filter(provider.eq(l_provider))
+            .select(subject)
+            .first(db)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Discord user ID of the user, if they linked their Discord account
+    pub fn find_discord_id(db: crate::DbConnection, user: UserId) -> WartIDResult<Option<u64>> {
+        Ok(Self::find_subject(db, user, Self::DISCORD)?.and_then(|id| id.parse().ok()))
+    }
+
+    /// Discord user IDs of every user who linked their Discord account
+    pub fn find_all_discord_ids(db: crate::DbConnection) -> WartIDResult<Vec<u64>> {
+        use crate::schema::federated_identities::dsl::*;
+
+        let subjects: Vec<String> = federated_identities
+            .filter(provider.eq(Self::DISCORD))
+            .select(subject)
+            .load(db)?;
+
+        Ok(subjects.iter().filter_map(|id| id.parse().ok()).collect())
+    }
+
+    /// Finds the user linked to the account, creating them with `username` if there's none.
+    /// Returns `None` if the account isn't linked yet and either `username` is `None` or already
+    /// taken, since it may belong to someone else: they must log in and link the account from
+    /// their profile instead.
+    pub fn login(
+        db: crate::DbConnection,
+        l_provider: &str,
+        l_subject: &str,
+        l_username: Option<String>,
+    ) -> WartIDResult<Option<User>> {
+        use crate::schema::federated_identities::dsl::*;
+
+        db.transaction(|db| {
+            let user = match Self::find_user(db, l_provider, l_subject)? {
+                Some(user) => user,
+                None => {
+                    let Some(l_username) = l_username else {
+                        return Ok(None);
+                    };
+                    if User::find_by_username(db, &l_username)?.is_some() {
+                        return Ok(None);
+                    }
+
+                    let user = User::insert(
+                        db,
+                        NewUser {
+                            username: l_username,
+                            password: None,
+                            email: None,
+                        },
+                    )?;
+
+                    diesel::insert_into(federated_identities)
+                        .values((
+                            provider.eq(l_provider),
+                            subject.eq(l_subject),
+                            users_id.eq(user.id),
+                        ))
+                        .execute(db)?;
+
+                    user
+                }
+            };
+
+            diesel::update(federated_identities)
+                .filter(provider.eq(l_provider))
+                .filter(subject.eq(l_subject))
+                .set(last_login.eq(Utc::now().naive_utc()))
+                .execute(db)?;
+
+            Ok(Some(user))
+        })
+    }
+
+    /// Links an account to a user who has none at this provider. Returns `false` if the user
+    /// already has one, or if the account is linked to someone else.
+    pub fn link(
+        db: crate::DbConnection,
+        user: UserId,
+        l_provider: &str,
+        l_subject: &str,
+    ) -> WartIDResult<bool> {
+        use crate::schema::federated_identities::dsl::*;
+
+        let linked = diesel::insert_into(federated_identities)
+            .values((
+                provider.eq(l_provider),
+                subject.eq(l_subject),
+                users_id.eq(user),
+                last_login.eq(Utc::now().naive_utc()),
+            ))
+            .on_conflict_do_nothing()
+            .execute(db)?;
+
+        Ok(linked > 0)
+    }
+
+    /// Only allowed if the user has a password, so they can still log in even if their other
+    /// providers go away. Returns `false` if it isn't the case. Unlinking Discord turns off the
+    /// approval of password logins from Discord, see [User::discord_2fa].
+    pub fn unlink(db: crate::DbConnection, user: UserId, l_provider: &str) -> WartIDResult<bool> {
+        use crate::schema::federated_identities::dsl::*;
+
+        db.transaction(|db| {
+            let has_password: bool = diesel::select(exists(
+                users::table
+                    .filter(users::id.eq(user))
+                    .filter(users::password.is_not_null()),
+            ))
+            .get_result(db)?;
+
+            if !has_password {
+                return Ok(false);
+            }
+
+            let unlinked = diesel::delete(federated_identities)
+                .filter(users_id.eq(user))
+                .filter(provider.eq(l_provider))
+                .execute(db)?;
+
+            if unlinked > 0 && l_provider == Self::DISCORD {
+                User::set_discord_2fa(db, user, false)?;
+            }
+
+            Ok(unlinked > 0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlink() {
+        let mut db = crate::tests::db();
+        let subject = rand::random::<u32>().to_string();
+        let username = format!("test-{}", crate::utils::gen_alphanumeric(8));
+        let user = User::insert_local(&mut db, username, None, None).unwrap();
+        assert!(FederatedIdentity::link(&mut db, user.id, "gitlab", &subject).unwrap());
+        assert!(
+            FederatedIdentity::link(&mut db, user.id, FederatedIdentity::DISCORD, &subject)
+                .unwrap()
+        );
+        User::set_discord_2fa(&mut db, user.id, true).unwrap();
+
+        // Refused without a password, even with another linked account
+        assert!(!FederatedIdentity::unlink(&mut db, user.id, FederatedIdentity::DISCORD).unwrap());
+        assert_eq!(
+            FederatedIdentity::find_discord_id(&mut db, user.id).unwrap(),
+            subject.parse().ok()
+        );
+
+        User::update_password(&mut db, user.id, "hunter2").unwrap();
+        assert!(FederatedIdentity::unlink(&mut db, user.id, FederatedIdentity::DISCORD).unwrap());
+        assert_eq!(
+            FederatedIdentity::find_discord_id(&mut db, user.id).unwrap(),
+            None
+        );
+        assert!(
+            !User::find_by_id(&mut db, user.id)
+                .unwrap()
+                .unwrap()
+                .discord_2fa
+        );
+
+        assert!(!FederatedIdentity::unlink(&mut db, user.id, FederatedIdentity::DISCORD).unwrap());
+        assert!(FederatedIdentity::unlink(&mut db, user.id, "gitlab").unwrap());
+    }
+}
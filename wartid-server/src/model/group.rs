@@ -0,0 +1,205 @@
+use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+use crate::id::Id;
+use crate::schema::{groups, groups_members};
+
+use super::*;
+
+pub type GroupId = Id<Group>;
+
+#[derive(Clone, Debug, Queryable)]
+pub struct Group {
+    pub id: GroupId,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+impl Group {
+    pub fn find_all(db: crate::DbConnection) -> WartIDResult<Vec<Self>> {
+        use crate::schema::groups::dsl::*;
+
+        groups.order(name).load(db).map_err(Into::into)
+    }
+
+    pub fn find_by_user(db: crate::DbConnection, user: UserId) -> WartIDResult<Vec<Self>> {
+        use crate::schema::groups::dsl::*;
+        use crate::schema::groups_members::dsl::*;
+
+        groups
+            .inner_join(groups_members)
+            .filter(users_id.eq(user))
+            .select((id, name, description))
+            .order(name)
+            .load(db)
+            .map_err(Into::into)
+    }
+
+    /// Every (group, member) pair
+    pub fn find_all_memberships(db: crate::DbConnection) -> WartIDResult<Vec<(GroupId, UserId)>> {
+        use crate::schema::groups_members::dsl::*;
+
+        groups_members
+            .select((groups_id, users_id))
+            .load(db)
+            .map_err(Into::into)
+    }
+
+    pub fn insert(db: crate::DbConnection, l_name: &str, members: &[UserId]) -> WartIDResult<Self> {
+        db.transaction(|db| {
+            let group: Self = diesel::insert_into(groups::table)
+                .values(groups::name.eq(l_name))
+                .get_result(db)?;
+
+            Self::add_members(db, group.id, members)?;
+
+            Ok(group)
+        })
+    }
+
+    /// Renames the group and replaces all of its members
+    pub fn replace(
+        db: crate::DbConnection,
+        group: GroupId,
+        l_name: &str,
+        members: &[UserId],
+    ) -> WartIDResult<Self> {
+        db.transaction(|db| {
+            let group: Self = diesel::update(groups::table)
+                .filter(groups::id.eq(group))
+                .set(groups::name.eq(l_name))
+                .get_result(db)?;
+
+            diesel::delete(groups_members::table)
+                .filter(groups_members::groups_id.eq(group.id))
+                .execute(db)?;
+            Self::add_members(db, group.id, members)?;
+
+            Ok(group)
+        })
+    }
+
+    /// Members already in the group are ignored
+    pub fn add_members(
+        db: crate::DbConnection,
+        group: GroupId,
+        members: &[UserId],
+    ) -> WartIDResult<()> {
+        use crate::schema::groups_members::dsl::*;
+
+        diesel::insert_into(groups_members)
+            .values(
+                members
+                    .iter()
+                    .map(|member| (groups_id.eq(group), users_id.eq(member)))
+                    .collect::<Vec<_>>(),
+            )
+            .on_conflict_do_nothing()
+            .execute(db)?;
+
+        Ok(())
+    }
+
+    /// Sets which of the `managed` groups the user is in, creating the missing groups. Groups
+    /// outside of `managed` are left untouched.
+    pub fn sync_memberships(
+        db: crate::DbConnection,
+        user: UserId,
+        managed: &[&str],
+        member_of: &[&str],
+    ) -> WartIDResult<()> {
+        db.transaction(|db| {
+            diesel::insert_into(groups::table)
+                .values(
+                    managed
+                        .iter()
+                        .map(|group| groups::name.eq(group))
+                        .collect::<Vec<_>>(),
+                )
+                .on_conflict_do_nothing()
+                .execute(db)?;
+
+            let managed_ids = groups::table
+                .filter(groups::name.eq_any(managed))
+                .select(groups::id);
+            diesel::delete(groups_members::table)
+                .filter(groups_members::users_id.eq(user))
+                .filter(groups_members::groups_id.eq_any(managed_ids))
+                .execute(db)?;
+
+            let member_of: Vec<GroupId> = groups::table
+                .filter(groups::name.eq_any(member_of))
+                .filter(groups::name.eq_any(managed))
+                .select(groups::id)
+                .load(db)?;
+            diesel::insert_into(groups_members::table)
+                .values(
+                    member_of
+                        .into_iter()
+                        .map(|group| {
+                            (
+                                groups_members::groups_id.eq(group),
+                                groups_members::users_id.eq(user),
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .on_conflict_do_nothing()
+                .execute(db)?;
+
+            Ok(())
+        })
+    }
+
+    /// Returns `false` if the group didn't exist
+    pub fn delete(db: crate::DbConnection, group: GroupId) -> WartIDResult<bool> {
+        use crate::schema::groups::dsl::*;
+
+        let deleted = diesel::delete(groups).filter(id.eq(group)).execute(db)?;
+
+        Ok(deleted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_memberships() {
+        let mut db = crate::tests::db();
+        let suffix = crate::utils::gen_alphanumeric(8);
+        let username = format!("test-{suffix}");
+        let user = User::insert_local(&mut db, username, None, None)
+            .unwrap()
+            .id;
+
+        let [staff, dev, manual] =
+            ["staff", "dev", "manual"].map(|name| format!("test-{name}-{suffix}"));
+        let manual_group = Group::insert(&mut db, &manual, &[user]).unwrap();
+        let managed = [staff.as_str(), dev.as_str()];
+
+        let names = |db: crate::DbConnection| -> Vec<String> {
+            Group::find_by_user(db, user)
+                .unwrap()
+                .into_iter()
+                .map(|group| group.name)
+                .collect()
+        };
+
+        // Missing managed groups are created
+        Group::sync_memberships(&mut db, user, &managed, &[&staff]).unwrap();
+        assert_eq!(names(&mut db), [&manual, &staff].map(String::as_str));
+
+        Group::sync_memberships(&mut db, user, &managed, &[&dev, &manual]).unwrap();
+        assert_eq!(names(&mut db), [&dev, &manual].map(String::as_str));
+
+        // Unmanaged memberships are kept, even when the user has no managed role left
+        Group::sync_memberships(&mut db, user, &managed, &[]).unwrap();
+        assert_eq!(names(&mut db), [manual.as_str()]);
+
+        // Nor are any added
+        Group::replace(&mut db, manual_group.id, &manual, &[]).unwrap();
+        Group::sync_memberships(&mut db, user, &managed, &[&manual]).unwrap();
+        assert!(names(&mut db).is_empty());
+    }
+}
@@ -3,20 +3,32 @@ use std::fmt::Debug;
 use diesel::result::Error;
 
 pub use app::*;
+pub use claims::*;
+pub use federated_identity::*;
+pub use group::*;
+pub use notification::*;
 pub use oauth2session::*;
 pub use page_context::*;
+pub use personal_access_token::*;
 pub use scopes::*;
 pub use session::*;
 pub use user::*;
+pub use webhook::*;
 
 pub use crate::db_await;
 
 mod app;
+mod claims;
+mod federated_identity;
+mod group;
+mod notification;
 mod oauth2session;
 mod page_context;
+mod personal_access_token;
 mod scopes;
 mod session;
 mod user;
+mod webhook;
 
 pub type WartIDResult<T> = Result<T, WartIDError>;
 
@@ -25,6 +37,9 @@ pub enum WartIDError {
     #[error("oauth2 error: {0}")]
     OAuth2Error(&'static str),
 
+    #[error("saml error: {0}")]
+    SamlError(&'static str),
+
     #[error("database connection error")]
     DatabaseConnection,
 
@@ -0,0 +1,113 @@
+use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+use super::*;
+
+/// Security events the bot DMs to users who linked their Discord account, unless they opted out
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NotificationKind {
+    /// A new login session was opened
+    Login,
+    PasswordChange,
+
+    /// An app was authorized for the first time
+    AppConsent,
+
+    /// A login link was requested from the bot
+    LoginLink,
+}
+
+impl NotificationKind {
+    pub const ALL: [Self; 4] = [
+        Self::Login,
+        Self::PasswordChange,
+        Self::AppConsent,
+        Self::LoginLink,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Login => "login",
+            Self::PasswordChange => "password-change",
+            Self::AppConsent => "app-consent",
+            Self::LoginLink => "login-link",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|known| known.as_str() == kind)
+    }
+
+    /// Message shown next to the checkbox on the profile page
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Login => "notifications.kind-login",
+            Self::PasswordChange => "notifications.kind-password-change",
+            Self::AppConsent => "notifications.kind-app-consent",
+            Self::LoginLink => "notifications.kind-login-link",
+        }
+    }
+
+    pub fn find_enabled(db: crate::DbConnection, user: UserId) -> WartIDResult<Vec<Self>> {
+        use crate::schema::notification_opt_outs::dsl::*;
+
+        let opted_out: Vec<String> = notification_opt_outs
+            .filter(users_id.eq(user))
+            .select(kind)
+            .load(db)?;
+
+        Ok(Self::ALL
+            .into_iter()
+            .filter(|known| !opted_out.iter().any(|out| out == known.as_str()))
+            .collect())
+    }
+
+    pub fn is_enabled(self, db: crate::DbConnection, user: UserId) -> WartIDResult<bool> {
+        use crate::schema::notification_opt_outs::dsl::*;
+
+        let opted_out: bool = diesel::select(diesel::dsl::exists(
+            notification_opt_outs
+                .filter(users_id.eq(user))
+                .filter(kind.eq(self.as_str())),
+        ))
+        .get_result(db)?;
+
+        Ok(!opted_out)
+    }
+
+    /// Opts the user out of every notification but the `enabled` ones
+    pub fn set_enabled(
+        db: crate::DbConnection,
+        user: UserId,
+        enabled: &[Self],
+    ) -> WartIDResult<()> {
+        use crate::schema::notification_opt_outs::dsl::*;
+
+        let opted_out = Self::ALL
+            .into_iter()
+            .filter(|known| !enabled.contains(known))
+            .map(|known| (users_id.eq(user), kind.eq(known.as_str())))
+            .collect::<Vec<_>>();
+
+        db.transaction(|db| {
+            diesel::delete(notification_opt_outs.filter(users_id.eq(user))).execute(db)?;
+            diesel::insert_into(notification_opt_outs)
+                .values(&opted_out)
+                .execute(db)?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        for kind in NotificationKind::ALL {
+            assert_eq!(NotificationKind::parse(kind.as_str()), Some(kind));
+        }
+        assert_eq!(NotificationKind::parse("spam"), None);
+    }
+}
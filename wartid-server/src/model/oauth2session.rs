@@ -1,7 +1,7 @@
 use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
 
-use crate::model::OAuth2Scopes;
+use crate::model::{Claims, OAuth2Scopes};
 use crate::schema::sessions_oauth2;
 
 use super::WartIDResult;
@@ -15,6 +15,9 @@ pub struct OAuth2Session {
     pub user_apps_id: UserAppId,
     pub initial_scopes: String,
     pub expiration: NaiveDateTime,
+
+    /// Claims requested with the OIDC `claims` parameter, on top of the scopes
+    pub claims: String,
 }
 
 #[derive(Insertable)]
@@ -33,6 +36,7 @@ impl OAuth2Session {
         user: UserId,
         app: UserAppId,
         scopes: &OAuth2Scopes,
+        l_claims: &Claims,
     ) -> WartIDResult<String> {
         use crate::schema::sessions_oauth2::dsl::*;
 
@@ -44,6 +48,7 @@ impl OAuth2Session {
             user_apps_id: app,
             initial_scopes: format!("{scopes}"),
             expiration: Utc::now().naive_utc() + Duration::days(6 * 30),
+            claims: l_claims.to_string(),
         };
 
         let session: OAuth2Session = diesel::insert_into(sessions_oauth2)
@@ -56,6 +61,17 @@ impl OAuth2Session {
         Ok(session.token)
     }
 
+    /// Whether the user already authorized the app, even if its refresh token expired since
+    pub fn exists(db: crate::DbConnection, user: UserId, app: UserAppId) -> WartIDResult<bool> {
+        use crate::schema::sessions_oauth2::dsl::*;
+
+        diesel::select(diesel::dsl::exists(
+            sessions_oauth2.filter(users_id.eq(user).and(user_apps_id.eq(app))),
+        ))
+        .get_result(db)
+        .map_err(Into::into)
+    }
+
     pub fn find_by_token(db: crate::DbConnection, l_token: &str) -> WartIDResult<Option<Self>> {
         use crate::schema::sessions_oauth2::dsl::*;
 
@@ -65,4 +81,14 @@ impl OAuth2Session {
             .optional()
             .map_err(Into::into)
     }
+
+    /// Revokes the refresh tokens of every app, returning how many there were. Access tokens
+    /// already issued stay valid until they expire.
+    pub fn delete_all_by_user(db: crate::DbConnection, user: UserId) -> WartIDResult<usize> {
+        use crate::schema::sessions_oauth2::dsl::*;
+
+        diesel::delete(sessions_oauth2.filter(users_id.eq(user)))
+            .execute(db)
+            .map_err(Into::into)
+    }
 }
@@ -1,8 +1,11 @@
 use std::borrow::Cow;
 
 use super::*;
+use crate::i18n::Locale;
 
 pub struct PageContext {
+    pub locale: Locale,
+
     pub users: Vec<(UserId, String)>,
     pub apps: Vec<(UserAppId, String)>,
 
@@ -11,8 +14,10 @@ pub struct PageContext {
 }
 
 impl PageContext {
-    pub fn new(db: crate::DbConnection, user_id: UserId) -> WartIDResult<Self> {
+    pub fn new(db: crate::DbConnection, user_id: UserId, locale: Locale) -> WartIDResult<Self> {
         Ok(Self {
+            locale,
+
             users: User::find_all(db, false)?
                 .into_iter()
                 .map(|u| (u.id, u.username))
@@ -27,7 +32,9 @@ impl PageContext {
         })
     }
 
-    pub fn add_flash_message(&mut self, message: Cow<'static, str>, is_error: bool) {
+    /// `message` is the key of the message in the catalogs, see [Locale::t]
+    pub fn add_flash_message(&mut self, message: &'static str, is_error: bool) {
+        let message = Cow::Borrowed(self.locale.t(message));
         self.flash_messages.push((message, is_error));
         self.flash_bad_request = self.flash_bad_request || is_error;
     }
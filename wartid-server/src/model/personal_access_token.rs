@@ -0,0 +1,114 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
+    SelectableHelper,
+};
+
+use crate::id::Id;
+use crate::schema::personal_access_tokens;
+
+use super::*;
+
+pub type PersonalAccessTokenId = Id<PersonalAccessToken>;
+
+/// A long-lived token minted by a user for their scripts and bots, accepted wherever an OAuth2
+/// access token is
+///
+/// The token's hash is never loaded back from the database, tokens are only looked up by hash.
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = personal_access_tokens)]
+pub struct PersonalAccessToken {
+    pub id: PersonalAccessTokenId,
+    pub users_id: UserId,
+    pub name: String,
+    pub scopes: String,
+    pub expiration: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+}
+
+impl PersonalAccessToken {
+    /// Makes these tokens recognizable, both by us and by secret scanners
+    pub const PREFIX: &'static str = "wartid_pat_";
+
+    /// Creates a token, returned in plain text alongside its database entry. Only its hash is
+    /// stored, so this is the only time it can be shown.
+    pub fn insert(
+        db: crate::DbConnection,
+        user: UserId,
+        l_name: &str,
+        l_scopes: &OAuth2Scopes,
+        validity: Duration,
+    ) -> WartIDResult<(Self, String)> {
+        use crate::schema::personal_access_tokens::dsl::*;
+
+        let token = format!("{}{}", Self::PREFIX, crate::utils::gen_alphanumeric(40));
+
+        let pat = diesel::insert_into(personal_access_tokens)
+            .values(NewPersonalAccessToken {
+                users_id: user,
+                name: l_name,
+                token_hash: &crate::utils::hash_secret(&token),
+                scopes: &l_scopes.to_string(),
+                expiration: Utc::now().naive_utc() + validity,
+            })
+            .returning(Self::as_select())
+            .get_result(db)?;
+
+        Ok((pat, token))
+    }
+
+    pub fn find_all_by_user(db: crate::DbConnection, user: UserId) -> WartIDResult<Vec<Self>> {
+        use crate::schema::personal_access_tokens::dsl::*;
+
+        personal_access_tokens
+            .filter(users_id.eq(user))
+            .order_by(expiration.desc())
+            .select(Self::as_select())
+            .load(db)
+            .map_err(Into::into)
+    }
+
+    /// Finds the valid token matching `token` and records its use
+    pub fn authenticate(db: crate::DbConnection, token: &str) -> WartIDResult<Option<Self>> {
+        use crate::schema::personal_access_tokens::dsl::*;
+
+        let now = Utc::now().naive_utc();
+
+        diesel::update(personal_access_tokens)
+            .filter(
+                token_hash
+                    .eq(crate::utils::hash_secret(token))
+                    .and(expiration.ge(now)),
+            )
+            .set(last_used.eq(now))
+            .returning(Self::as_select())
+            .get_result(db)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Deletes a token, returns `false` if it didn't exist or belonged to someone else
+    pub fn revoke(
+        db: crate::DbConnection,
+        user: UserId,
+        token: PersonalAccessTokenId,
+    ) -> WartIDResult<bool> {
+        use crate::schema::personal_access_tokens::dsl::*;
+
+        let deleted = diesel::delete(personal_access_tokens)
+            .filter(id.eq(token).and(users_id.eq(user)))
+            .execute(db)?;
+
+        Ok(deleted > 0)
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = personal_access_tokens)]
+struct NewPersonalAccessToken<'a> {
+    users_id: UserId,
+    name: &'a str,
+    token_hash: &'a str,
+    scopes: &'a str,
+    expiration: NaiveDateTime,
+}
@@ -9,9 +9,42 @@ pub enum OAuth2Scope {
     /// Requires the user to have an email address linked to their account
     Email,
 
+    /// Username, picture, locale and last update of the profile
+    Profile,
+
+    /// Names of the groups the user is a member of
+    Groups,
+
+    /// ID of the Discord account linked to the user, if any
+    Discord,
+
     /// Requires nothing, but allows the login form to authenticate us as fake accounts anyone for
     /// testing purposes
     Dev,
+
+    /// Management API: listing, creating and updating the WartApps of the user
+    AdminApps,
+
+    /// Management API: reading profiles and updating the user's own
+    AdminUsers,
+}
+
+impl OAuth2Scope {
+    pub const ALL: [Self; 8] = [
+        Self::Basic,
+        Self::Email,
+        Self::Profile,
+        Self::Groups,
+        Self::Discord,
+        Self::Dev,
+        Self::AdminApps,
+        Self::AdminUsers,
+    ];
+
+    /// Admin scopes can only be granted to personal access tokens, never to WartApps
+    pub fn is_admin(self) -> bool {
+        matches!(self, Self::AdminApps | Self::AdminUsers)
+    }
 }
 
 impl FromStr for OAuth2Scope {
@@ -21,7 +54,12 @@ impl FromStr for OAuth2Scope {
         match s {
             "basic" => Ok(Self::Basic),
             "email" => Ok(Self::Email),
+            "profile" => Ok(Self::Profile),
+            "groups" => Ok(Self::Groups),
+            "discord" => Ok(Self::Discord),
             "dev" => Ok(Self::Dev),
+            "admin:apps" => Ok(Self::AdminApps),
+            "admin:users" => Ok(Self::AdminUsers),
             _ => Err(()),
         }
     }
@@ -32,7 +70,12 @@ impl std::fmt::Display for OAuth2Scope {
         f.write_str(match self {
             Self::Basic => "basic",
             Self::Email => "email",
+            Self::Profile => "profile",
+            Self::Groups => "groups",
+            Self::Discord => "discord",
             Self::Dev => "dev",
+            Self::AdminApps => "admin:apps",
+            Self::AdminUsers => "admin:users",
         })
     }
 }
@@ -44,6 +87,14 @@ impl OAuth2Scopes {
     pub fn contains(&self, scope: OAuth2Scope) -> bool {
         self.0.contains(&scope)
     }
+
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.0.is_subset(&other.0)
+    }
+
+    pub fn has_admin(&self) -> bool {
+        self.0.iter().any(|scope| scope.is_admin())
+    }
 }
 
 impl FromStr for OAuth2Scopes {
@@ -123,4 +174,13 @@ mod tests {
         assert!(scopes.0.contains(&OAuth2Scope::Basic));
         assert!(scopes.0.contains(&OAuth2Scope::Email));
     }
+
+    #[test]
+    fn admin() {
+        let scopes: OAuth2Scopes = "basic admin:apps".parse().unwrap();
+
+        assert!(scopes.contains(OAuth2Scope::AdminApps));
+        assert!(scopes.has_admin());
+        assert!(!"basic email".parse::<OAuth2Scopes>().unwrap().has_admin());
+    }
 }
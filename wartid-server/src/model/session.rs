@@ -36,6 +36,15 @@ impl Session {
             .next()
             .map(|session| session.users_id))
     }
+
+    /// Logs the user out of every browser, returning how many sessions there were
+    pub fn delete_all_by_user(db: crate::DbConnection, user: UserId) -> WartIDResult<usize> {
+        use crate::schema::sessions::dsl::*;
+
+        diesel::delete(sessions.filter(users_id.eq(user)))
+            .execute(db)
+            .map_err(Into::into)
+    }
 }
 
 #[derive(Insertable)]
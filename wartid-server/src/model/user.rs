@@ -1,29 +1,75 @@
-use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use serde_json::json;
 use std::sync::Arc;
 
 use crate::id::Id;
-use crate::schema::users;
+use crate::schema::{federated_identities, users};
 
 use super::*;
 
 pub type UserId = Id<User>;
 
+/// What suspended a user, since only it may lift the suspension
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SuspensionSource {
+    /// The user left every allowed Discord guild, lifted when they come back
+    DiscordGuilds,
+
+    /// An administrator, never lifted automatically
+    Manual,
+}
+
+impl SuspensionSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::DiscordGuilds => "discord-guilds",
+            Self::Manual => "manual",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Queryable)]
 pub struct User {
     pub id: UserId,
     pub username: String,
     pub password: Option<String>,
     pub email: Option<String>,
-    pub discord_id: Option<i64>,
+
+    /// URL of the profile picture
+    pub picture: Option<String>,
+
+    /// BCP 47 language tag, like `fr-FR`
+    pub locale: Option<String>,
+
+    /// Always `false` for now, since e-mail addresses aren't verified yet
+    pub email_verified: bool,
+
+    /// Last change of the username, e-mail address or profile
+    pub updated: NaiveDateTime,
+
+    /// When the user was suspended, see [User::suspended_by]. Suspended users can't log in, and
+    /// their sessions were revoked.
+    pub suspended: Option<NaiveDateTime>,
+
+    /// Whether password logins must be approved from a Discord DM, if the Discord account is
+    /// still linked
+    pub discord_2fa: bool,
+
+    /// [SuspensionSource] of the suspension, if any
+    pub suspended_by: Option<String>,
 }
 
 impl User {
-    fn insert(db: crate::DbConnection, new: NewUser) -> WartIDResult<User> {
+    pub(super) fn insert(db: crate::DbConnection, new: NewUser) -> WartIDResult<User> {
         use crate::schema::users::dsl::*;
 
-        let user: User = diesel::insert_into(users).values(new).get_result(db)?;
+        db.transaction(|db| {
+            let user: User = diesel::insert_into(users).values(new).get_result(db)?;
+            WebhookDelivery::enqueue(db, WebhookEvent::UserCreated, &user, None)?;
 
-        Ok(user)
+            Ok(user)
+        })
     }
 
     pub fn find_all(db: crate::DbConnection, include_guests: bool) -> WartIDResult<Vec<User>> {
@@ -32,7 +78,14 @@ impl User {
         if include_guests {
             users.load::<Self>(db)
         } else {
-            users.filter(discord_id.is_not_null()).load::<Self>(db)
+            // Guests are the users who didn't link a Discord account
+            users
+                .filter(diesel::dsl::exists(
+                    federated_identities::table
+                        .filter(federated_identities::users_id.eq(id))
+                        .filter(federated_identities::provider.eq(FederatedIdentity::DISCORD)),
+                ))
+                .load::<Self>(db)
         }
         .map_err(Into::into)
     }
@@ -48,29 +101,102 @@ impl User {
             .next())
     }
 
-    fn find_or_create_by_discord_id(
+    pub fn find_by_username(
         db: crate::DbConnection,
-        l_discord_id: u64,
-        l_discord_name: String,
-    ) -> WartIDResult<User> {
+        l_username: &str,
+    ) -> WartIDResult<Option<User>> {
         use crate::schema::users::dsl::*;
 
-        // PostgreSQL doesn't have a u64 type, so we bit-cast into i64
-        let l_discord_id = i64::from_le_bytes(l_discord_id.to_le_bytes());
+        users
+            .filter(username.eq(l_username))
+            .first::<Self>(db)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// For the passwords set from the web UI
+    pub const MIN_PASSWORD_LEN: usize = 8;
+
+    /// Profile pictures are linked to, not hosted, so they must be web URLs
+    pub fn is_valid_picture(url: &str) -> bool {
+        url.starts_with("https://") || url.starts_with("http://")
+    }
 
-        // TODO handle not found
-        if let Ok(user) = users.filter(discord_id.eq(l_discord_id)).first::<User>(db) {
-            return Ok(user);
+    /// Loose check of a BCP 47 language tag, e.g. `fr` or `fr-FR`
+    pub fn is_valid_locale(tag: &str) -> bool {
+        (2..=35).contains(&tag.len())
+            && tag.split('-').all(|part| {
+                (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+            })
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.is_some()
+    }
+
+    /// Users without a password (only logging in with Discord or an upstream provider) and
+    /// suspended users never match
+    pub fn verify_password(&self, l_password: &str) -> bool {
+        if self.is_suspended() {
+            return false;
         }
 
-        let new_user = NewUser {
-            username: l_discord_name,
-            password: None,
-            email: None,
-            discord_id: Some(l_discord_id),
+        self.password.as_deref().is_some_and(|db_password| {
+            bcrypt::verify(l_password, db_password).expect("bcrypt cannot verify")
+        })
+    }
+
+    pub fn find_by_discord_id(
+        db: crate::DbConnection,
+        l_discord_id: u64,
+    ) -> WartIDResult<Option<User>> {
+        FederatedIdentity::find_user(db, FederatedIdentity::DISCORD, &l_discord_id.to_string())
+    }
+
+    /// Only used once the user was checked to be in one of the allowed guilds. Returns `None` if
+    /// the account must be created but the name is taken, see [FederatedIdentity::login].
+    pub fn find_or_create_by_discord_id(
+        db: crate::DbConnection,
+        l_discord_id: u64,
+        l_discord_name: String,
+    ) -> WartIDResult<Option<User>> {
+        let subject = l_discord_id.to_string();
+        let Some(mut user) = FederatedIdentity::login(
+            db,
+            FederatedIdentity::DISCORD,
+            &subject,
+            Some(l_discord_name),
+        )?
+        else {
+            return Ok(None);
         };
 
-        User::insert(db, new_user)
+        // So they came back to an allowed guild, which doesn't lift other suspensions
+        if user.is_suspended()
+            && User::set_suspended(db, user.id, false, SuspensionSource::DiscordGuilds)?
+        {
+            user.suspended = None;
+            user.suspended_by = None;
+        }
+
+        Ok(Some(user))
+    }
+
+    /// Creates a user that isn't linked to Discord, like the ones provisioned through SCIM
+    pub fn insert_local(
+        db: crate::DbConnection,
+        l_username: String,
+        l_email: Option<String>,
+        l_password: Option<&str>,
+    ) -> WartIDResult<User> {
+        User::insert(
+            db,
+            NewUser {
+                username: l_username,
+                password: l_password.map(hash_password).transpose()?,
+                email: l_email,
+            },
+        )
     }
 
     pub fn attempt_login(
@@ -79,28 +205,19 @@ impl User {
         l_username: &str,
         l_password: &str,
     ) -> WartIDResult<Option<User>> {
-        use crate::schema::users::dsl::*;
-
         // Attempt JWT / discord login
         if let Some(discord_agent) = discord_agent.filter(|_| l_username.is_empty()) {
             let claims = discord_agent
                 .try_authorize(l_password)
                 .map_err(|err| WartIDError::InvalidCredentials(err.to_string()))?;
 
-            return User::find_or_create_by_discord_id(db, claims.sub, claims.name).map(Some);
+            return User::find_or_create_by_discord_id(db, claims.sub, claims.name);
         }
 
-        match users
-            .filter(username.eq(l_username))
-            .first::<User>(db)
-            .optional()
-            .map_err(Into::into)
-        {
+        match User::find_by_username(db, l_username) {
             Ok(Some(user)) => {
-                if let Some(db_password) = &user.password {
-                    if bcrypt::verify(l_password, db_password).expect("bcrypt cannot verify") {
-                        return Ok(Some(user));
-                    }
+                if user.verify_password(l_password) {
+                    return Ok(Some(user));
                 }
 
                 Err(WartIDError::InvalidCredentials(String::from(
@@ -118,23 +235,179 @@ impl User {
     ) -> WartIDResult<User> {
         use crate::schema::users::dsl::*;
 
+        db.transaction(|db| {
+            let previous: User = users.filter(id.eq(user_id)).first(db)?;
+            let user = diesel::update(users)
+                .filter(id.eq(user_id))
+                .set((
+                    username.eq(new_username),
+                    updated.eq(Utc::now().naive_utc()),
+                ))
+                .get_result(db)?;
+
+            User::enqueue_changes(db, &previous, &user)?;
+            Ok(user)
+        })
+    }
+
+    pub fn update_email(
+        db: crate::DbConnection,
+        user_id: UserId,
+        new_email: &str,
+    ) -> WartIDResult<User> {
+        use crate::schema::users::dsl::*;
+
+        db.transaction(|db| {
+            let previous: User = users.filter(id.eq(user_id)).first(db)?;
+            let user = diesel::update(users)
+                .filter(id.eq(user_id))
+                .set((
+                    email.eq(new_email),
+                    email_verified.eq(false),
+                    updated.eq(Utc::now().naive_utc()),
+                ))
+                .get_result(db)?;
+
+            User::enqueue_changes(db, &previous, &user)?;
+            Ok(user)
+        })
+    }
+
+    /// Sets both the username and e-mail address, removing the latter if `None`
+    pub fn replace(
+        db: crate::DbConnection,
+        user_id: UserId,
+        new_username: &str,
+        new_email: Option<&str>,
+    ) -> WartIDResult<User> {
+        use crate::schema::users::dsl::*;
+
+        db.transaction(|db| {
+            let previous: User = users.filter(id.eq(user_id)).first(db)?;
+            let user = diesel::update(users)
+                .filter(id.eq(user_id))
+                .set((
+                    username.eq(new_username),
+                    email.eq(new_email),
+                    email_verified
+                        .eq(previous.email_verified && previous.email.as_deref() == new_email),
+                    updated.eq(Utc::now().naive_utc()),
+                ))
+                .get_result(db)?;
+
+            User::enqueue_changes(db, &previous, &user)?;
+            Ok(user)
+        })
+    }
+
+    /// Sets the profile picture URL and preferred locale, removing them if `None`
+    pub fn update_profile(
+        db: crate::DbConnection,
+        user_id: UserId,
+        new_picture: Option<&str>,
+        new_locale: Option<&str>,
+    ) -> WartIDResult<User> {
+        use crate::schema::users::dsl::*;
+
         diesel::update(users)
             .filter(id.eq(user_id))
-            .set(username.eq(new_username))
+            .set((
+                picture.eq(new_picture),
+                locale.eq(new_locale),
+                updated.eq(Utc::now().naive_utc()),
+            ))
             .get_result(db)
             .map_err(Into::into)
     }
 
-    pub fn update_email(
+    /// Queues the webhook events for what changed between `previous` and `user`
+    fn enqueue_changes(db: crate::DbConnection, previous: &User, user: &User) -> WartIDResult<()> {
+        if previous.username != user.username {
+            let previous = json!({ "username": previous.username });
+            WebhookDelivery::enqueue(db, WebhookEvent::UserRenamed, user, Some(previous))?;
+        }
+
+        if previous.email != user.email {
+            let previous = json!({ "email": previous.email });
+            WebhookDelivery::enqueue(db, WebhookEvent::UserEmailChanged, user, Some(previous))?;
+        }
+
+        Ok(())
+    }
+
+    /// Suspends the user, revoking their sessions and the access they gave to apps, or lifts their
+    /// suspension if it came from the same `source`. Returns `false` if nothing changed.
+    pub fn set_suspended(
         db: crate::DbConnection,
         user_id: UserId,
-        new_email: &str,
+        l_suspended: bool,
+        source: SuspensionSource,
+    ) -> WartIDResult<bool> {
+        use crate::schema::users::dsl::*;
+
+        db.transaction(|db| {
+            if !l_suspended {
+                let lifted = diesel::update(users)
+                    .filter(id.eq(user_id))
+                    .filter(suspended_by.eq(source.as_str()))
+                    .set((
+                        suspended.eq(None::<NaiveDateTime>),
+                        suspended_by.eq(None::<String>),
+                    ))
+                    .execute(db)?;
+
+                return Ok(lifted > 0);
+            }
+
+            let suspended_now = diesel::update(users)
+                .filter(id.eq(user_id))
+                .filter(suspended.is_null())
+                .set((
+                    suspended.eq(Utc::now().naive_utc()),
+                    suspended_by.eq(source.as_str()),
+                ))
+                .execute(db)?;
+            if suspended_now == 0 {
+                return Ok(false);
+            }
+
+            Session::delete_all_by_user(db, user_id)?;
+            OAuth2Session::delete_all_by_user(db, user_id)?;
+
+            Ok(true)
+        })
+    }
+
+    /// Returns `false` if the user didn't exist
+    pub fn delete(db: crate::DbConnection, user_id: UserId) -> WartIDResult<bool> {
+        use crate::schema::users::dsl::*;
+
+        db.transaction(|db| {
+            let deleted: Option<User> = diesel::delete(users)
+                .filter(id.eq(user_id))
+                .get_result(db)
+                .optional()?;
+
+            match deleted {
+                Some(user) => {
+                    WebhookDelivery::enqueue(db, WebhookEvent::UserDeleted, &user, None)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+    }
+
+    pub fn set_discord_2fa(
+        db: crate::DbConnection,
+        user_id: UserId,
+        enabled: bool,
     ) -> WartIDResult<User> {
         use crate::schema::users::dsl::*;
 
         diesel::update(users)
             .filter(id.eq(user_id))
-            .set(email.eq(new_email))
+            .set(discord_2fa.eq(enabled))
             .get_result(db)
             .map_err(Into::into)
     }
@@ -149,19 +422,7 @@ impl User {
         let new_password = if new_password.is_empty() {
             None
         } else {
-            Some(
-                {
-                    let start = std::time::Instant::now();
-
-                    let l_password = bcrypt::hash(new_password, bcrypt::DEFAULT_COST);
-
-                    let elapsed = start.elapsed();
-                    log::debug!(target: file!(), "generated password in {:?}", elapsed);
-
-                    l_password
-                }
-                .map_err(|e| WartIDError::Any(Box::new(e)))?,
-            )
+            Some(hash_password(new_password)?)
         };
 
         diesel::update(users)
@@ -172,11 +433,21 @@ impl User {
     }
 }
 
+fn hash_password(l_password: &str) -> WartIDResult<String> {
+    let start = std::time::Instant::now();
+
+    let hash = bcrypt::hash(l_password, bcrypt::DEFAULT_COST);
+
+    let elapsed = start.elapsed();
+    log::debug!(target: file!(), "generated password in {:?}", elapsed);
+
+    hash.map_err(|e| WartIDError::Any(Box::new(e)))
+}
+
 #[derive(Insertable)]
 #[diesel(table_name = users)]
-struct NewUser {
+pub(super) struct NewUser {
     pub username: String,
     pub password: Option<String>,
     pub email: Option<String>,
-    pub discord_id: Option<i64>,
 }
@@ -0,0 +1,346 @@
+use std::str::FromStr;
+
+use chrono::{Duration, NaiveDateTime, SecondsFormat, Utc};
+use diesel::{BoolExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper};
+use serde_json::json;
+
+use crate::id::Id;
+use crate::schema::{webhooks, webhooks_deliveries};
+
+use super::*;
+
+pub type WebhookId = Id<Webhook>;
+pub type WebhookDeliveryId = Id<WebhookDelivery>;
+
+/// Account lifecycle events WartApps can subscribe to
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WebhookEvent {
+    UserCreated,
+    UserRenamed,
+    UserEmailChanged,
+    UserDeleted,
+}
+
+impl WebhookEvent {
+    pub const ALL: [Self; 4] = [
+        Self::UserCreated,
+        Self::UserRenamed,
+        Self::UserEmailChanged,
+        Self::UserDeleted,
+    ];
+}
+
+impl FromStr for WebhookEvent {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user.created" => Ok(Self::UserCreated),
+            "user.renamed" => Ok(Self::UserRenamed),
+            "user.email_changed" => Ok(Self::UserEmailChanged),
+            "user.deleted" => Ok(Self::UserDeleted),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::UserCreated => "user.created",
+            Self::UserRenamed => "user.renamed",
+            Self::UserEmailChanged => "user.email_changed",
+            Self::UserDeleted => "user.deleted",
+        })
+    }
+}
+
+/// URL a WartApp wants account lifecycle events to be POSTed to
+///
+/// Unlike the other secrets, the webhook secret is stored in plain text since it's needed to sign
+/// deliveries.
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = webhooks)]
+pub struct Webhook {
+    pub id: WebhookId,
+    pub user_apps_id: UserAppId,
+    pub url: String,
+    pub secret: String,
+    events: String,
+}
+
+impl Webhook {
+    pub fn events(&self) -> impl Iterator<Item = WebhookEvent> + '_ {
+        self.events
+            .split(' ')
+            .filter_map(|event| event.parse().ok())
+    }
+
+    pub fn is_subscribed(&self, event: WebhookEvent) -> bool {
+        self.events().any(|subscribed| subscribed == event)
+    }
+
+    pub fn insert(
+        db: crate::DbConnection,
+        app: UserAppId,
+        l_url: &str,
+        l_events: &[WebhookEvent],
+    ) -> WartIDResult<Self> {
+        use crate::schema::webhooks::dsl::*;
+
+        let l_events = l_events
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        diesel::insert_into(webhooks)
+            .values((
+                user_apps_id.eq(app),
+                url.eq(l_url),
+                secret.eq(crate::utils::gen_alphanumeric(64)),
+                events.eq(l_events),
+            ))
+            .returning(Self::as_select())
+            .get_result(db)
+            .map_err(Into::into)
+    }
+
+    pub fn find_all_by_app(db: crate::DbConnection, app: UserAppId) -> WartIDResult<Vec<Self>> {
+        use crate::schema::webhooks::dsl::*;
+
+        webhooks
+            .filter(user_apps_id.eq(app))
+            .order_by(url)
+            .select(Self::as_select())
+            .load(db)
+            .map_err(Into::into)
+    }
+
+    /// Deletes a webhook and its deliveries, returns `false` if it didn't exist or belonged to
+    /// another app
+    pub fn delete(
+        db: crate::DbConnection,
+        app: UserAppId,
+        webhook: WebhookId,
+    ) -> WartIDResult<bool> {
+        use crate::schema::webhooks::dsl::*;
+
+        let deleted = diesel::delete(webhooks)
+            .filter(id.eq(webhook).and(user_apps_id.eq(app)))
+            .execute(db)?;
+
+        Ok(deleted > 0)
+    }
+}
+
+/// Result of a single attempt at delivering a webhook
+#[derive(Debug)]
+pub struct DeliveryAttempt {
+    /// HTTP status code, if a response was received
+    pub status: Option<u16>,
+
+    /// Why the attempt failed, `None` if it succeeded
+    pub error: Option<String>,
+}
+
+/// An event to POST to a webhook, retried with exponential backoff until it is acknowledged with a
+/// 2xx status code
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = webhooks_deliveries)]
+pub struct WebhookDelivery {
+    pub id: WebhookDeliveryId,
+    pub webhooks_id: WebhookId,
+    pub event: String,
+    pub payload: String,
+    pub created: NaiveDateTime,
+    pub attempts: i32,
+
+    /// `None` once delivered, or after too many failed attempts
+    pub next_attempt: Option<NaiveDateTime>,
+    pub delivered: Option<NaiveDateTime>,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+}
+
+impl WebhookDelivery {
+    /// Deliveries are given up after this many attempts, about 4 hours after the event
+    pub const MAX_ATTEMPTS: i32 = 10;
+
+    /// Delay before retrying a delivery that failed `attempts` times
+    fn backoff(attempts: i32) -> Duration {
+        Duration::seconds(30 << (attempts - 1).clamp(0, 16))
+    }
+
+    /// Queues an event about `user` for every webhook subscribed to it, `previous` holding the
+    /// attributes that changed. Meant to be called in the transaction changing the user, so that
+    /// events are only sent for committed changes.
+    pub fn enqueue(
+        db: crate::DbConnection,
+        l_event: WebhookEvent,
+        user: &User,
+        previous: Option<serde_json::Value>,
+    ) -> WartIDResult<()> {
+        use crate::schema::webhooks_deliveries::dsl::*;
+
+        let subscribed = webhooks::table
+            .select(Webhook::as_select())
+            .load(db)?
+            .into_iter()
+            .filter(|webhook| webhook.is_subscribed(l_event))
+            .collect::<Vec<_>>();
+
+        if subscribed.is_empty() {
+            return Ok(());
+        }
+
+        let now = Utc::now();
+
+        let mut l_payload = json!({
+            "event": l_event.to_string(),
+            "created": now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "user": {
+                "id": user.id,
+                "username": user.username,
+                "email": user.email,
+            },
+        });
+        if let Some(previous) = previous {
+            l_payload["previous"] = previous;
+        }
+        let l_payload = l_payload.to_string();
+
+        let now = now.naive_utc();
+        diesel::insert_into(webhooks_deliveries)
+            .values(
+                subscribed
+                    .iter()
+                    .map(|webhook| {
+                        (
+                            webhooks_id.eq(webhook.id),
+                            event.eq(l_event.to_string()),
+                            payload.eq(&l_payload),
+                            created.eq(now),
+                            next_attempt.eq(now),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .execute(db)?;
+
+        Ok(())
+    }
+
+    /// Deliveries whose next attempt is due, with their webhook
+    pub fn find_due(db: crate::DbConnection, limit: i64) -> WartIDResult<Vec<(Self, Webhook)>> {
+        use crate::schema::webhooks_deliveries::dsl::*;
+
+        webhooks_deliveries
+            .inner_join(webhooks::table)
+            .filter(next_attempt.le(Utc::now().naive_utc()))
+            .order_by(next_attempt)
+            .limit(limit)
+            .select((Self::as_select(), Webhook::as_select()))
+            .load(db)
+            .map_err(Into::into)
+    }
+
+    pub fn find_recent_by_webhook(
+        db: crate::DbConnection,
+        webhook: WebhookId,
+        limit: i64,
+    ) -> WartIDResult<Vec<Self>> {
+        use crate::schema::webhooks_deliveries::dsl::*;
+
+        webhooks_deliveries
+            .filter(webhooks_id.eq(webhook))
+            .order_by(created.desc())
+            .limit(limit)
+            .select(Self::as_select())
+            .load(db)
+            .map_err(Into::into)
+    }
+
+    /// Schedules the next attempt, if the delivery failed and can still be retried
+    pub fn record_attempt(
+        db: crate::DbConnection,
+        delivery: &Self,
+        attempt: DeliveryAttempt,
+    ) -> WartIDResult<()> {
+        use crate::schema::webhooks_deliveries::dsl::*;
+
+        let now = Utc::now().naive_utc();
+        let l_attempts = delivery.attempts + 1;
+
+        let (l_next_attempt, l_delivered) = match attempt.error {
+            None => (None, Some(now)),
+            Some(_) if l_attempts >= Self::MAX_ATTEMPTS => (None, None),
+            Some(_) => (Some(now + Self::backoff(l_attempts)), None),
+        };
+
+        diesel::update(webhooks_deliveries)
+            .filter(id.eq(delivery.id))
+            .set((
+                attempts.eq(l_attempts),
+                next_attempt.eq(l_next_attempt),
+                delivered.eq(l_delivered),
+                last_status.eq(attempt.status.map(i32::from)),
+                last_error.eq(attempt.error),
+            ))
+            .execute(db)?;
+
+        Ok(())
+    }
+
+    /// Stops retrying a delivery that mustn't be attempted
+    pub fn give_up(db: crate::DbConnection, delivery: &Self, error: String) -> WartIDResult<()> {
+        use crate::schema::webhooks_deliveries::dsl::*;
+
+        diesel::update(webhooks_deliveries)
+            .filter(id.eq(delivery.id))
+            .set((
+                next_attempt.eq(None::<NaiveDateTime>),
+                last_status.eq(None::<i32>),
+                last_error.eq(error),
+            ))
+            .execute(db)?;
+
+        Ok(())
+    }
+
+    /// Sends a delivery again as soon as possible, even if it succeeded. Returns `false` if it
+    /// didn't exist or belonged to another app.
+    pub fn replay(
+        db: crate::DbConnection,
+        app: UserAppId,
+        delivery: WebhookDeliveryId,
+    ) -> WartIDResult<bool> {
+        use crate::schema::webhooks_deliveries::dsl::*;
+
+        let app_webhooks = webhooks::table
+            .filter(webhooks::user_apps_id.eq(app))
+            .select(webhooks::id);
+
+        let updated = diesel::update(webhooks_deliveries)
+            .filter(id.eq(delivery).and(webhooks_id.eq_any(app_webhooks)))
+            .set((
+                attempts.eq(0),
+                next_attempt.eq(Utc::now().naive_utc()),
+                delivered.eq(None::<NaiveDateTime>),
+            ))
+            .execute(db)?;
+
+        Ok(updated > 0)
+    }
+
+    /// Deletes the finished deliveries created before `before`
+    pub fn prune(db: crate::DbConnection, before: NaiveDateTime) -> WartIDResult<usize> {
+        use crate::schema::webhooks_deliveries::dsl::*;
+
+        diesel::delete(webhooks_deliveries)
+            .filter(created.lt(before).and(next_attempt.is_null()))
+            .execute(db)
+            .map_err(Into::into)
+    }
+}
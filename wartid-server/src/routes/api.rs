@@ -0,0 +1,835 @@
+//! ### JSON management API
+//!
+//! `/api/v1` mirrors what the HTML panel allows on apps and user profiles. Requests are either
+//! authenticated with the session cookie, which grants everything the panel does, or with a bearer
+//! token holding the `admin:apps` or `admin:users` scope. Only the managers of an app can update
+//! it.
+
+use diesel::Connection;
+use jsonwebtoken::jwk::JwkSet;
+use rocket::http::Status;
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::Responder;
+use rocket::serde::json::{self, Json};
+use rocket::{Request, State};
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
+
+use crate::routes::oauth2::BearerSession;
+use crate::utils::secrets::SecretsKey;
+use crate::utils::signing::SigningKey;
+
+use super::prelude::*;
+
+pub type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Error sent as [ApiErrorBody]
+#[derive(Debug)]
+pub struct ApiError {
+    status: Status,
+    message: Cow<'static, str>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiErrorBody {
+    #[schema(value_type = String)]
+    error: Cow<'static, str>,
+}
+
+impl ApiError {
+    pub fn new(status: Status, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(Status::BadRequest, message)
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(Status::NotFound, "not found")
+    }
+}
+
+impl From<WartIDError> for ApiError {
+    fn from(err: WartIDError) -> Self {
+        use diesel::result::{DatabaseErrorKind, Error};
+
+        match err {
+            WartIDError::Database(Error::NotFound) => Self::not_found(),
+            WartIDError::Database(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                Self::new(Status::Conflict, "name already taken")
+            }
+            err => {
+                log::error!("API request failed: {err}");
+                Self::new(Status::InternalServerError, "internal error")
+            }
+        }
+    }
+}
+
+impl From<json::Error<'_>> for ApiError {
+    fn from(err: json::Error<'_>) -> Self {
+        Self::bad_request(format!("invalid JSON body: {err}"))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut response = (
+            self.status,
+            Json(ApiErrorBody {
+                error: self.message,
+            }),
+        )
+            .respond_to(request)?;
+        if self.status == Status::Unauthorized {
+            response.set_header(rocket::http::Header::new("WWW-Authenticate", "Bearer"));
+        }
+        Ok(response)
+    }
+}
+
+/// User calling the API, with the scopes of their token
+pub struct ApiAuth {
+    user: User,
+
+    /// `None` when authenticated with the session cookie
+    scopes: Option<OAuth2Scopes>,
+}
+
+impl ApiAuth {
+    /// The authenticated user, if their token was granted `scope`
+    fn require(&self, scope: OAuth2Scope) -> Result<&User, ApiError> {
+        match &self.scopes {
+            Some(scopes) if !scopes.contains(scope) => Err(ApiError::new(
+                Status::Forbidden,
+                format!("this token lacks the {scope} scope"),
+            )),
+            _ => Ok(&self.user),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiAuth {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if request.headers().contains("Authorization") {
+            let bearer: BearerSession =
+                try_outcome!(request.guard().await.map_failure(|(_, err)| (
+                    Status::Unauthorized,
+                    ApiError::new(Status::Unauthorized, err)
+                )));
+
+            return Outcome::Success(ApiAuth {
+                user: bearer.user,
+                scopes: Some(bearer.scopes),
+            });
+        }
+
+        let session: &LoginSession = try_outcome!(request.guard().await.map_failure(|_| (
+            Status::Unauthorized,
+            ApiError::new(
+                Status::Unauthorized,
+                "missing session cookie or bearer token"
+            )
+        )));
+
+        Outcome::Success(ApiAuth {
+            user: session.user.clone(),
+            scopes: None,
+        })
+    }
+}
+
+/// Tells an absent field (`None`) from an explicit `null` (`Some(None)`)
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AppOAuth {
+    enabled: bool,
+    redirect_uri: String,
+    auth_method: Option<String>,
+    jwks: Option<String>,
+    userinfo_signed_response_alg: Option<String>,
+    has_previous_secret: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AppResponse {
+    #[schema(value_type = String, format = Uuid)]
+    id: UserAppId,
+    name: String,
+    description: Option<String>,
+    hidden: bool,
+    oauth: AppOAuth,
+    saml_entity_id: Option<String>,
+    saml_acs_url: Option<String>,
+    scim_enabled: bool,
+
+    /// Names of the groups whose members can use the app, anyone can if it's empty
+    allowed_groups: Vec<String>,
+}
+
+impl AppResponse {
+    fn new(app: UserApp, allowed_groups: Vec<Group>) -> Self {
+        AppResponse {
+            oauth: AppOAuth {
+                enabled: app.is_oauth2_enabled(),
+                auth_method: app.oauth2_auth_method().map(|method| method.to_string()),
+                has_previous_secret: app.has_previous_oauth2_secret(),
+                redirect_uri: app.oauth_redirect.clone(),
+                jwks: app.oauth_jwks.clone(),
+                userinfo_signed_response_alg: app.oauth2_userinfo_alg().map(str::to_string),
+            },
+            scim_enabled: app.is_scim_enabled(),
+            id: app.id,
+            name: app.name,
+            description: app.description,
+            hidden: app.hidden,
+            saml_entity_id: app.saml_entity_id,
+            saml_acs_url: app.saml_acs_url,
+            allowed_groups: allowed_groups.into_iter().map(|group| group.name).collect(),
+        }
+    }
+}
+
+/// Loads the [allowed groups](UserApp::find_allowed_groups) of the app to build its response
+fn app_response(db: crate::DbConnection, app: UserApp) -> WartIDResult<AppResponse> {
+    let allowed_groups = UserApp::find_allowed_groups(db, app.id)?;
+    Ok(AppResponse::new(app, allowed_groups))
+}
+
+/// Finds an app `user` can see, hidden apps being only visible to their managers
+async fn find_app(db: &DbConn, user: UserId, app_id: UserAppId) -> Result<UserApp, ApiError> {
+    let app = db_await!(UserApp::find_by_id(db, app_id))?.ok_or_else(ApiError::not_found)?;
+
+    if app.hidden && !db_await!(UserApp::is_manager(db, app_id, user))? {
+        return Err(ApiError::not_found());
+    }
+
+    Ok(app)
+}
+
+/// Finds an app `user` is allowed to update
+async fn find_managed_app(
+    db: &DbConn,
+    user: UserId,
+    app_id: UserAppId,
+) -> Result<UserApp, ApiError> {
+    let app = find_app(db, user, app_id).await?;
+
+    if !db_await!(UserApp::is_manager(db, app_id, user))? {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            "only the managers of this app can update it",
+        ));
+    }
+
+    Ok(app)
+}
+
+/// Apps visible to the user, hidden ones included if they manage them
+#[utoipa::path(
+    get,
+    path = "/api/v1/apps",
+    tag = "apps",
+    responses((status = 200, body = [AppResponse])),
+    security(("session" = []), ("bearer" = ["admin:apps"]))
+)]
+#[get("/api/v1/apps")]
+pub async fn apps_list(auth: Result<ApiAuth, ApiError>, db: DbConn) -> ApiResult<Vec<AppResponse>> {
+    let user_id = auth?.require(OAuth2Scope::AdminApps)?.id;
+
+    let apps = db_await!(UserApp::find_all(db, user_id))?;
+
+    let apps = db
+        .run(move |db| {
+            apps.into_iter()
+                .map(|app| app_response(db, app))
+                .collect::<WartIDResult<_>>()
+        })
+        .await?;
+
+    Ok(Json(apps))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AppCreate {
+    name: String,
+
+    #[serde(default)]
+    hidden: bool,
+}
+
+/// Creates an app managed by the user
+#[utoipa::path(
+    post,
+    path = "/api/v1/apps",
+    tag = "apps",
+    request_body = AppCreate,
+    responses(
+        (status = 201, body = AppResponse),
+        (status = 400, body = ApiErrorBody),
+    ),
+    security(("session" = []), ("bearer" = ["admin:apps"]))
+)]
+#[post("/api/v1/apps", data = "<data>")]
+pub async fn apps_create(
+    auth: Result<ApiAuth, ApiError>,
+    db: DbConn,
+    data: Result<Json<AppCreate>, json::Error<'_>>,
+) -> Result<(Status, Json<AppResponse>), ApiError> {
+    let user_id = auth?.require(OAuth2Scope::AdminApps)?.id;
+    let AppCreate { name, hidden } = data?.into_inner();
+
+    if name.len() < 3 {
+        return Err(ApiError::bad_request(
+            "the name must be at least 3 characters long",
+        ));
+    }
+
+    let app_id = db_await!(UserApp::insert(db, name, hidden, user_id))?;
+    let app = find_app(&db, user_id, app_id).await?;
+
+    Ok((Status::Created, Json(db_await!(app_response(db, app))?)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/apps/{app_id}",
+    tag = "apps",
+    params(("app_id" = Uuid, Path, description = "ID of the app")),
+    responses(
+        (status = 200, body = AppResponse),
+        (status = 404, body = ApiErrorBody),
+    ),
+    security(("session" = []), ("bearer" = ["admin:apps"]))
+)]
+#[get("/api/v1/apps/<app_id>")]
+pub async fn apps_get(
+    auth: Result<ApiAuth, ApiError>,
+    db: DbConn,
+    app_id: UserAppId,
+) -> ApiResult<AppResponse> {
+    let user_id = auth?.require(OAuth2Scope::AdminApps)?.id;
+
+    let app = find_app(&db, user_id, app_id).await?;
+
+    Ok(Json(db_await!(app_response(db, app))?))
+}
+
+/// Fields of an app to update, the absent ones are left untouched
+#[derive(Deserialize, ToSchema)]
+pub struct AppUpdate {
+    name: Option<String>,
+    description: Option<String>,
+    oauth_redirect_uri: Option<String>,
+
+    /// Only method the app can authenticate with, `null` allowing all of them
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable, example = "client_secret_basic")]
+    oauth_auth_method: Option<Option<String>>,
+
+    /// Public keys for `private_key_jwt`, `null` removing them
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable)]
+    oauth_jwks: Option<Option<String>>,
+
+    /// Algorithm to sign `/oauth2/userinfo` responses with, `null` returning plain JSON
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable, example = "EdDSA")]
+    oauth_userinfo_signed_response_alg: Option<Option<String>>,
+
+    /// Names of the groups whose members can use the app, an empty list allowing anyone
+    allowed_groups: Option<Vec<String>>,
+}
+
+/// Updates an app, only allowed to its managers
+#[utoipa::path(
+    patch,
+    path = "/api/v1/apps/{app_id}",
+    tag = "apps",
+    params(("app_id" = Uuid, Path, description = "ID of the app")),
+    request_body = AppUpdate,
+    responses(
+        (status = 200, body = AppResponse),
+        (status = 400, body = ApiErrorBody),
+        (status = 403, body = ApiErrorBody),
+    ),
+    security(("session" = []), ("bearer" = ["admin:apps"]))
+)]
+#[patch("/api/v1/apps/<app_id>", data = "<data>")]
+pub async fn apps_update(
+    auth: Result<ApiAuth, ApiError>,
+    db: DbConn,
+    secrets_key: &State<Option<SecretsKey>>,
+    app_id: UserAppId,
+    data: Result<Json<AppUpdate>, json::Error<'_>>,
+) -> ApiResult<AppResponse> {
+    let user_id = auth?.require(OAuth2Scope::AdminApps)?.id;
+    let update = data?.into_inner();
+    let app = find_managed_app(&db, user_id, app_id).await?;
+
+    // Same checks as the panel, done before any change so a request is applied entirely or not
+    // at all
+    if update.name.as_ref().is_some_and(|name| name.len() < 3) {
+        return Err(ApiError::bad_request(
+            "the name must be at least 3 characters long",
+        ));
+    }
+
+    let client_auth_changed = update.oauth_auth_method.is_some() || update.oauth_jwks.is_some();
+
+    let method =
+        match &update.oauth_auth_method {
+            Some(Some(method)) => Some(method.parse::<ClientAuthMethod>().map_err(|()| {
+                ApiError::bad_request("unknown OAuth2 client authentication method")
+            })?),
+            Some(None) => None,
+            None => app.oauth2_auth_method(),
+        };
+    let jwks = match update.oauth_jwks {
+        Some(jwks) => jwks.filter(|jwks| !jwks.trim().is_empty()),
+        None => app.oauth_jwks.clone(),
+    };
+    if let Some(jwks) = &jwks {
+        if serde_json::from_str::<JwkSet>(jwks).is_err() {
+            return Err(ApiError::bad_request(
+                "the JWKS isn't a valid JSON Web Key Set",
+            ));
+        }
+    }
+    if method == Some(ClientAuthMethod::PrivateKeyJwt) && jwks.is_none() {
+        return Err(ApiError::bad_request("private_key_jwt requires a JWKS"));
+    }
+    if method == Some(ClientAuthMethod::ClientSecretJwt) && secrets_key.is_none() {
+        return Err(ApiError::bad_request(
+            "client_secret_jwt isn't available on this server",
+        ));
+    }
+    if let Some(Some(alg)) = &update.oauth_userinfo_signed_response_alg {
+        if alg != SigningKey::ALGORITHM {
+            return Err(ApiError::bad_request(
+                "unsupported userinfo signing algorithm",
+            ));
+        }
+    }
+
+    let allowed_groups = match update.allowed_groups {
+        Some(names) => {
+            let groups = db.run(Group::find_all).await?;
+            let allowed = names
+                .iter()
+                .map(|name| {
+                    groups
+                        .iter()
+                        .find(|group| &group.name == name)
+                        .map(|group| group.id)
+                        .ok_or_else(|| ApiError::bad_request(format!("unknown group {name}")))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(allowed)
+        }
+        None => None,
+    };
+
+    let app = db
+        .run(move |db| {
+            db.transaction(|db| {
+                let mut app = app;
+
+                if update.name.is_some() || update.description.is_some() {
+                    let name = update.name.unwrap_or(app.name);
+                    let description = update.description.or(app.description).unwrap_or_default();
+                    app = UserApp::set_name_description(db, app_id, &name, &description)?;
+                }
+                if let Some(uri) = update.oauth_redirect_uri {
+                    app = UserApp::set_oauth_redirect_uri(db, app_id, uri)?;
+                }
+                if client_auth_changed {
+                    app = UserApp::set_oauth_client_auth(db, app_id, method, jwks)?;
+                }
+                if let Some(alg) = update.oauth_userinfo_signed_response_alg {
+                    app = UserApp::set_oauth_userinfo_alg(db, app_id, alg.as_deref())?;
+                }
+                if let Some(allowed_groups) = &allowed_groups {
+                    UserApp::set_allowed_groups(db, app_id, allowed_groups)?;
+                }
+
+                Ok::<_, WartIDError>(app)
+            })
+        })
+        .await?;
+
+    Ok(Json(db_await!(app_response(db, app))?))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct OAuthSecretResponse {
+    /// Only ever shown here, it can't be retrieved later
+    secret: String,
+}
+
+/// Enables OAuth2 or regenerates the secret, the current one staying valid until it's revoked
+#[utoipa::path(
+    post,
+    path = "/api/v1/apps/{app_id}/oauth/secret",
+    tag = "apps",
+    params(("app_id" = Uuid, Path, description = "ID of the app")),
+    responses(
+        (status = 200, body = OAuthSecretResponse),
+        (status = 403, body = ApiErrorBody),
+    ),
+    security(("session" = []), ("bearer" = ["admin:apps"]))
+)]
+#[post("/api/v1/apps/<app_id>/oauth/secret")]
+pub async fn apps_oauth_secret(
+    auth: Result<ApiAuth, ApiError>,
+    db: DbConn,
+    secrets_key: &State<Option<SecretsKey>>,
+    app_id: UserAppId,
+) -> ApiResult<OAuthSecretResponse> {
+    let user_id = auth?.require(OAuth2Scope::AdminApps)?.id;
+    find_managed_app(&db, user_id, app_id).await?;
+
+    let (_, secret) =
+        super::apps::regenerate_oauth_secret(&db, secrets_key.as_ref(), app_id).await?;
+    let secret = secret.ok_or_else(|| ApiError::new(Status::InternalServerError, "no secret"))?;
+
+    Ok(Json(OAuthSecretResponse { secret }))
+}
+
+/// Stops accepting the secret from before the last regeneration
+#[utoipa::path(
+    delete,
+    path = "/api/v1/apps/{app_id}/oauth/secret/previous",
+    tag = "apps",
+    params(("app_id" = Uuid, Path, description = "ID of the app")),
+    responses(
+        (status = 200, body = AppResponse),
+        (status = 403, body = ApiErrorBody),
+    ),
+    security(("session" = []), ("bearer" = ["admin:apps"]))
+)]
+#[delete("/api/v1/apps/<app_id>/oauth/secret/previous")]
+pub async fn apps_oauth_revoke_previous(
+    auth: Result<ApiAuth, ApiError>,
+    db: DbConn,
+    app_id: UserAppId,
+) -> ApiResult<AppResponse> {
+    let user_id = auth?.require(OAuth2Scope::AdminApps)?.id;
+    find_managed_app(&db, user_id, app_id).await?;
+
+    let app = db_await!(UserApp::revoke_previous_oauth_secret(db, app_id))?;
+
+    Ok(Json(db_await!(app_response(db, app))?))
+}
+
+/// Disables OAuth2, revoking every secret
+#[utoipa::path(
+    delete,
+    path = "/api/v1/apps/{app_id}/oauth",
+    tag = "apps",
+    params(("app_id" = Uuid, Path, description = "ID of the app")),
+    responses(
+        (status = 200, body = AppResponse),
+        (status = 403, body = ApiErrorBody),
+    ),
+    security(("session" = []), ("bearer" = ["admin:apps"]))
+)]
+#[delete("/api/v1/apps/<app_id>/oauth")]
+pub async fn apps_oauth_disable(
+    auth: Result<ApiAuth, ApiError>,
+    db: DbConn,
+    app_id: UserAppId,
+) -> ApiResult<AppResponse> {
+    let user_id = auth?.require(OAuth2Scope::AdminApps)?.id;
+    find_managed_app(&db, user_id, app_id).await?;
+
+    let (app, _) = db_await!(UserApp::set_oauth(db, app_id, false))?;
+
+    Ok(Json(db_await!(app_response(db, app))?))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UserResponse {
+    #[schema(value_type = String, format = Uuid)]
+    id: UserId,
+    username: String,
+
+    picture: Option<String>,
+
+    /// BCP 47 language tag
+    locale: Option<String>,
+
+    /// Only shown to the user themselves
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+}
+
+impl UserResponse {
+    fn new(user: User, is_me: bool) -> Self {
+        UserResponse {
+            id: user.id,
+            username: user.username,
+            picture: user.picture,
+            locale: user.locale,
+            email: user.email.filter(|_| is_me),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/me",
+    tag = "users",
+    responses((status = 200, body = UserResponse)),
+    security(("session" = []), ("bearer" = ["admin:users"]))
+)]
+#[get("/api/v1/users/me")]
+pub fn users_me(auth: Result<ApiAuth, ApiError>) -> ApiResult<UserResponse> {
+    let user = auth?.require(OAuth2Scope::AdminUsers)?.clone();
+
+    Ok(Json(UserResponse::new(user, true)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/{user_id}",
+    tag = "users",
+    params(("user_id" = Uuid, Path, description = "ID of the user")),
+    responses(
+        (status = 200, body = UserResponse),
+        (status = 404, body = ApiErrorBody),
+    ),
+    security(("session" = []), ("bearer" = ["admin:users"]))
+)]
+#[get("/api/v1/users/<user_id>")]
+pub async fn users_get(
+    auth: Result<ApiAuth, ApiError>,
+    db: DbConn,
+    user_id: UserId,
+) -> ApiResult<UserResponse> {
+    let me = auth?.require(OAuth2Scope::AdminUsers)?.id;
+
+    let user = db_await!(User::find_by_id(db, user_id))?.ok_or_else(ApiError::not_found)?;
+
+    Ok(Json(UserResponse::new(user, me == user_id)))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UserUpdate {
+    username: Option<String>,
+    email: Option<String>,
+
+    /// URL of the profile picture, `null` removing it
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable)]
+    picture: Option<Option<String>>,
+
+    /// BCP 47 language tag, `null` removing it
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable, example = "fr-FR")]
+    locale: Option<Option<String>>,
+}
+
+/// Users can only update their own profile, like in the panel
+#[utoipa::path(
+    patch,
+    path = "/api/v1/users/{user_id}",
+    tag = "users",
+    params(("user_id" = Uuid, Path, description = "ID of the user")),
+    request_body = UserUpdate,
+    responses(
+        (status = 200, body = UserResponse),
+        (status = 400, body = ApiErrorBody),
+        (status = 403, body = ApiErrorBody),
+    ),
+    security(("session" = []), ("bearer" = ["admin:users"]))
+)]
+#[patch("/api/v1/users/<user_id>", data = "<data>")]
+pub async fn users_update(
+    auth: Result<ApiAuth, ApiError>,
+    db: DbConn,
+    user_id: UserId,
+    data: Result<Json<UserUpdate>, json::Error<'_>>,
+) -> ApiResult<UserResponse> {
+    let auth = auth?;
+    let user = auth.require(OAuth2Scope::AdminUsers)?;
+    let update = data?.into_inner();
+
+    if user.id != user_id {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            "users can only update their own profile",
+        ));
+    }
+
+    if update.username.as_ref().is_some_and(|name| name.len() < 3) {
+        return Err(ApiError::bad_request(
+            "the username must be at least 3 characters long",
+        ));
+    }
+    if update
+        .email
+        .as_ref()
+        .is_some_and(|email| !email.contains('@'))
+    {
+        return Err(ApiError::bad_request("invalid e-mail address"));
+    }
+    if let Some(Some(picture)) = &update.picture {
+        if !User::is_valid_picture(picture) {
+            return Err(ApiError::bad_request("the picture must be an HTTP(S) URL"));
+        }
+    }
+    if let Some(Some(locale)) = &update.locale {
+        if !User::is_valid_locale(locale) {
+            return Err(ApiError::bad_request("invalid locale"));
+        }
+    }
+
+    let user = user.clone();
+    let user = db
+        .run(move |db| {
+            let username = update.username.unwrap_or(user.username);
+            let email = update.email.or(user.email);
+            let picture = update.picture.unwrap_or(user.picture);
+            let locale = update.locale.unwrap_or(user.locale);
+            db.transaction(|db| {
+                User::replace(db, user_id, &username, email.as_deref())?;
+                User::update_profile(db, user_id, picture.as_deref(), locale.as_deref())
+            })
+        })
+        .await?;
+
+    Ok(Json(UserResponse::new(user, true)))
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{ContentType, Cookie, Header};
+    use rocket::local::asynchronous::{Client, LocalRequest};
+
+    use super::*;
+    use crate::tests::{cleanup, client, create_app, create_user};
+
+    /// Authenticates as `user` with a new session cookie
+    async fn with_session<'c>(request: LocalRequest<'c>, user: UserId) -> LocalRequest<'c> {
+        let db = DbConn::get_one(request.rocket()).await.unwrap();
+        let session = db_await!(Session::insert(db, NewSession::new(user))).unwrap();
+
+        request.cookie(Cookie::new("login_session", session.to_string()))
+    }
+
+    /// Personal access token of `user`, with `scopes`
+    async fn pat(client: &Client, user: UserId, scopes: &str) -> Header<'static> {
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let scopes = scopes.parse().unwrap();
+        let (_, token) = db
+            .run(move |db| {
+                PersonalAccessToken::insert(db, user, "test", &scopes, chrono::Duration::days(1))
+            })
+            .await
+            .unwrap();
+
+        Header::new("Authorization", format!("Bearer {token}"))
+    }
+
+    #[rocket::async_test]
+    async fn managed_apps() {
+        let client = client().await;
+        let alice = create_user(&client, None).await;
+        let bob = create_user(&client, None).await;
+
+        let (hidden, _) = create_app(&client, alice.id).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let alice_id = alice.id;
+        let visible = db_await!(UserApp::insert(
+            db,
+            String::from("test-visible"),
+            false,
+            alice_id
+        ))
+        .unwrap();
+
+        let request = client.get(format!("/api/v1/apps/{hidden}"));
+        let response = with_session(request, alice.id).await.dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // Others can't see hidden apps, nor update the visible ones
+        let request = client.get(format!("/api/v1/apps/{hidden}"));
+        let response = with_session(request, bob.id).await.dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        for (app, status) in [(hidden, Status::NotFound), (visible, Status::Forbidden)] {
+            let request = client
+                .patch(format!("/api/v1/apps/{app}"))
+                .header(ContentType::JSON)
+                .body(r#"{"name": "stolen"}"#);
+            let response = with_session(request, bob.id).await.dispatch().await;
+            assert_eq!(response.status(), status, "{app}");
+
+            let request = client.post(format!("/api/v1/apps/{app}/oauth/secret"));
+            let response = with_session(request, bob.id).await.dispatch().await;
+            assert_eq!(response.status(), status, "{app}");
+        }
+
+        // Nor anyone else's profile
+        let request = client
+            .patch(format!("/api/v1/users/{}", alice.id))
+            .header(ContentType::JSON)
+            .body(r#"{"email": "bob@wart.id"}"#);
+        let response = with_session(request, bob.id).await.dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        cleanup(&client, vec![alice.id, bob.id]).await;
+    }
+
+    #[rocket::async_test]
+    async fn admin_scopes() {
+        let client = client().await;
+        let user = create_user(&client, None).await;
+
+        let basic = pat(&client, user.id, "basic profile").await;
+        for uri in ["/api/v1/apps", "/api/v1/users/me"] {
+            let response = client.get(uri).header(basic.clone()).dispatch().await;
+            assert_eq!(response.status(), Status::Forbidden, "{uri}");
+        }
+
+        let apps = pat(&client, user.id, "admin:apps").await;
+        let response = client
+            .get("/api/v1/apps")
+            .header(apps.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/api/v1/users/me").header(apps).dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        // Anything the panel allows with the session cookie
+        for uri in ["/api/v1/apps", "/api/v1/users/me"] {
+            let response = with_session(client.get(uri), user.id)
+                .await
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok, "{uri}");
+        }
+
+        let response = client.get("/api/v1/users/me").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        cleanup(&client, vec![user.id]).await;
+    }
+}
@@ -1,7 +1,12 @@
+use jsonwebtoken::jwk::JwkSet;
 use rocket::form::error::ErrorKind;
 use rocket::form::{DataField, FromForm, Options, ValueField};
+use rocket::State;
 
 use super::prelude::*;
+use crate::config::Config;
+use crate::utils::secrets::SecretsKey;
+use crate::utils::signing::SigningKey;
 
 #[get("/apps")]
 pub async fn list(ctx: PageContext, session: &LoginSession, db: DbConn) -> WartIDResult<Ructe> {
@@ -31,25 +36,158 @@ pub async fn new(
     Ok(Redirect::to(format!("/apps/{id}")))
 }
 
-fn view_render(ctx: PageContext, app: UserApp) -> WartIDResult<Option<Ructe>> {
-    Ok(Some(render!(panel::app_view_html(&ctx, &app))))
+type AppWebhooks = Vec<(Webhook, Vec<WebhookDelivery>)>;
+
+type AppAccess = Vec<(Group, bool)>;
+
+/// Webhooks of the app with their recent deliveries, only shown to its managers
+fn find_webhooks(
+    db: crate::DbConnection,
+    app: UserAppId,
+    user: UserId,
+) -> WartIDResult<Option<AppWebhooks>> {
+    if !UserApp::is_manager(db, app, user)? {
+        return Ok(None);
+    }
+
+    Webhook::find_all_by_app(db, app)?
+        .into_iter()
+        .map(|webhook| {
+            let deliveries = WebhookDelivery::find_recent_by_webhook(db, webhook.id, 10)?;
+            Ok((webhook, deliveries))
+        })
+        .collect::<WartIDResult<_>>()
+        .map(Some)
+}
+
+/// All the groups, and whether they are allowed to use the app, only shown to its managers
+fn find_access(
+    db: crate::DbConnection,
+    app: UserAppId,
+    user: UserId,
+) -> WartIDResult<Option<AppAccess>> {
+    if !UserApp::is_manager(db, app, user)? {
+        return Ok(None);
+    }
+
+    let allowed = UserApp::find_allowed_groups(db, app)?;
+    let access = Group::find_all(db)?
+        .into_iter()
+        .map(|group| {
+            let is_allowed = allowed.iter().any(|allowed| allowed.id == group.id);
+            (group, is_allowed)
+        })
+        .collect();
+
+    Ok(Some(access))
+}
+
+async fn view_render(
+    ctx: PageContext,
+    db: &DbConn,
+    user_id: UserId,
+    app: UserApp,
+    new_secret: Option<&str>,
+    new_scim_token: Option<&str>,
+) -> WartIDResult<Option<Ructe>> {
+    let app_id = app.id;
+    let webhooks = db_await!(find_webhooks(db, app_id, user_id))?;
+    let access = db_await!(find_access(db, app_id, user_id))?;
+
+    Ok(Some(render!(panel::app_view_html(
+        &ctx,
+        &app,
+        new_secret,
+        new_scim_token,
+        webhooks.as_deref(),
+        access.as_deref()
+    ))))
+}
+
+/// Enables OAuth2 or regenerates the secret. Apps pinned to `client_secret_jwt` keep an encrypted
+/// copy of the new one, which they need as the HMAC key.
+pub(super) async fn regenerate_oauth_secret(
+    db: &DbConn,
+    secrets_key: Option<&SecretsKey>,
+    app_id: UserAppId,
+) -> WartIDResult<(UserApp, Option<OAuthSecret>)> {
+    let (app, secret) = db_await!(UserApp::set_oauth(db, app_id, true))?;
+
+    match (&secret, secrets_key) {
+        (Some(plain), Some(key))
+            if app.oauth2_auth_method() == Some(ClientAuthMethod::ClientSecretJwt) =>
+        {
+            let encrypted = Some(key.seal(plain, &app_id.to_string()));
+            let app = db_await!(UserApp::set_oauth_secret_encrypted(db, app_id, encrypted))?;
+            Ok((app, secret))
+        }
+        _ => Ok((app, secret)),
+    }
+}
+
+/// Reloads an app after a change that doesn't return it
+async fn find_app(db: &DbConn, app_id: UserAppId) -> WartIDResult<UserApp> {
+    db_await!(UserApp::find_by_id(db, app_id))?.ok_or_else(|| diesel::NotFound.into())
+}
+
+/// Shows the app again with an error message
+async fn view_render_error(
+    mut ctx: PageContext,
+    db: &DbConn,
+    user_id: UserId,
+    app_id: UserAppId,
+    message: &'static str,
+) -> WartIDResult<Option<Ructe>> {
+    ctx.add_flash_message(message, true);
+
+    match db_await!(UserApp::find_by_id(db, app_id))? {
+        Some(app) => view_render(ctx, db, user_id, app, None, None).await,
+        None => Ok(None),
+    }
 }
 
 #[get("/apps/<app_id>")]
-pub async fn view(ctx: PageContext, db: DbConn, app_id: UserAppId) -> WartIDResult<Option<Ructe>> {
+pub async fn view(
+    ctx: PageContext,
+    session: &LoginSession,
+    db: DbConn,
+    app_id: UserAppId,
+) -> WartIDResult<Option<Ructe>> {
     let Some(app) = db_await!(UserApp::find_by_id(db, app_id))? else {
         return Ok(None);
     };
 
-    view_render(ctx, app)
+    view_render(ctx, &db, session.user.id, app, None, None).await
 }
 
 #[derive(Debug)]
 pub enum FormUpdateIntent {
-    UpdateGeneral { name: String, description: String },
+    UpdateGeneral {
+        name: String,
+        description: String,
+    },
     OAuthSetRedirectUri(String),
+    OAuthSetClientAuth {
+        method: Option<ClientAuthMethod>,
+        jwks: String,
+    },
+    OAuthSetUserinfoAlg(String),
     OAuthEnable,
+    OAuthRevokePreviousSecret,
     OAuthDisable,
+    SamlSet {
+        entity_id: String,
+        acs_url: String,
+    },
+    ScimEnable,
+    ScimDisable,
+    WebhookAdd {
+        url: String,
+        events: Vec<WebhookEvent>,
+    },
+    WebhookDelete(WebhookId),
+    WebhookReplay(WebhookDeliveryId),
+    AccessSetGroups(Vec<GroupId>),
 }
 
 #[derive(Debug, FromForm)]
@@ -58,16 +196,56 @@ pub struct FormUpdateIntentRaw {
     description: Option<String>,
     #[field(name = "oauth-redirect")]
     oauth_redirect_uri: Option<String>,
+    #[field(name = "oauth-auth-method")]
+    oauth_auth_method: Option<String>,
+    #[field(name = "oauth-jwks")]
+    oauth_jwks: Option<String>,
+    #[field(name = "oauth-userinfo-alg")]
+    oauth_userinfo_alg: Option<String>,
+    #[field(name = "saml-entity-id")]
+    saml_entity_id: Option<String>,
+    #[field(name = "saml-acs-url")]
+    saml_acs_url: Option<String>,
+    #[field(name = "webhook-url")]
+    webhook_url: Option<String>,
+    #[field(name = "webhook-events")]
+    webhook_events: Vec<String>,
+    #[field(name = "webhook-id")]
+    webhook_id: Option<WebhookId>,
+    #[field(name = "delivery-id")]
+    delivery_id: Option<WebhookDeliveryId>,
+    #[field(name = "access-groups")]
+    access_groups: Vec<GroupId>,
 
     // Buttons (mutually exclusive)
-    #[field(name = "update-general", default = false)]
+    #[field(name = "update-general")]
     update_general: bool,
-    #[field(name = "oauth-enable", default = false)]
+    #[field(name = "oauth-enable")]
     oauth_enable: bool,
-    #[field(name = "oauth-disable", default = false)]
+    #[field(name = "oauth-revoke-previous")]
+    oauth_revoke_previous: bool,
+    #[field(name = "oauth-disable")]
     oauth_disable: bool,
-    #[field(name = "oauth-update-redirect", default = false)]
+    #[field(name = "oauth-update-redirect")]
     oauth_update_redirect: bool,
+    #[field(name = "oauth-update-client-auth")]
+    oauth_update_client_auth: bool,
+    #[field(name = "oauth-update-userinfo")]
+    oauth_update_userinfo: bool,
+    #[field(name = "saml-update")]
+    saml_update: bool,
+    #[field(name = "scim-enable")]
+    scim_enable: bool,
+    #[field(name = "scim-disable")]
+    scim_disable: bool,
+    #[field(name = "webhook-add")]
+    webhook_add: bool,
+    #[field(name = "webhook-delete")]
+    webhook_delete: bool,
+    #[field(name = "webhook-replay")]
+    webhook_replay: bool,
+    #[field(name = "access-update")]
+    access_update: bool,
 }
 
 #[rocket::async_trait]
@@ -96,38 +274,423 @@ impl<'r> FromForm<'r> for FormUpdateIntent {
                 name: Some(name),
                 description: Some(description),
                 oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
                 update_general: true,
                 oauth_enable: false,
+                oauth_revoke_previous: false,
                 oauth_disable: false,
                 oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
             } => FormUpdateIntent::UpdateGeneral { name, description },
             FormUpdateIntentRaw {
                 name: None,
                 description: None,
                 oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
                 update_general: false,
                 oauth_enable: true,
+                oauth_revoke_previous: false,
                 oauth_disable: false,
                 oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
             } => FormUpdateIntent::OAuthEnable,
             FormUpdateIntentRaw {
                 name: None,
                 description: None,
                 oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: true,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
+            } => FormUpdateIntent::OAuthRevokePreviousSecret,
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
                 update_general: false,
                 oauth_enable: false,
+                oauth_revoke_previous: false,
                 oauth_disable: true,
                 oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
             } => FormUpdateIntent::OAuthDisable,
             FormUpdateIntentRaw {
                 name: None,
                 description: None,
                 oauth_redirect_uri: Some(uri),
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
                 update_general: false,
                 oauth_enable: false,
+                oauth_revoke_previous: false,
                 oauth_disable: false,
                 oauth_update_redirect: true,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
             } => FormUpdateIntent::OAuthSetRedirectUri(uri),
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: Some(method),
+                oauth_jwks: Some(jwks),
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: false,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: true,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
+            } => FormUpdateIntent::OAuthSetClientAuth {
+                method: match method.as_str() {
+                    "" => None,
+                    method => Some(method.parse().map_err(|()| {
+                        rocket::form::Error::validation("unknown client authentication method")
+                    })?),
+                },
+                jwks,
+            },
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: Some(alg),
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: false,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: true,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
+            } => FormUpdateIntent::OAuthSetUserinfoAlg(alg),
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: Some(entity_id),
+                saml_acs_url: Some(acs_url),
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: false,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: true,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
+            } => FormUpdateIntent::SamlSet { entity_id, acs_url },
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: false,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: true,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
+            } => FormUpdateIntent::ScimEnable,
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: false,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: true,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
+            } => FormUpdateIntent::ScimDisable,
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: Some(url),
+                webhook_events: events,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups: _,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: false,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: true,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: false,
+            } => FormUpdateIntent::WebhookAdd {
+                url,
+                events: events
+                    .iter()
+                    .map(|event| event.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|()| rocket::form::Error::validation("unknown webhook event"))?,
+            },
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: Some(id),
+                delivery_id: None,
+                access_groups: _,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: false,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: true,
+                webhook_replay: false,
+                access_update: false,
+            } => FormUpdateIntent::WebhookDelete(id),
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: Some(id),
+                access_groups: _,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: false,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: true,
+                access_update: false,
+            } => FormUpdateIntent::WebhookReplay(id),
+            FormUpdateIntentRaw {
+                name: None,
+                description: None,
+                oauth_redirect_uri: None,
+                oauth_auth_method: None,
+                oauth_jwks: None,
+                oauth_userinfo_alg: None,
+                saml_entity_id: None,
+                saml_acs_url: None,
+                webhook_url: None,
+                webhook_events: _,
+                webhook_id: None,
+                delivery_id: None,
+                access_groups,
+                update_general: false,
+                oauth_enable: false,
+                oauth_revoke_previous: false,
+                oauth_disable: false,
+                oauth_update_redirect: false,
+                oauth_update_client_auth: false,
+                oauth_update_userinfo: false,
+                saml_update: false,
+                scim_enable: false,
+                scim_disable: false,
+                webhook_add: false,
+                webhook_delete: false,
+                webhook_replay: false,
+                access_update: true,
+            } => FormUpdateIntent::AccessSetGroups(access_groups),
             _ => Err(ErrorKind::Duplicate)?,
         })
     }
@@ -136,25 +699,21 @@ impl<'r> FromForm<'r> for FormUpdateIntent {
 #[post("/apps/<app_id>", data = "<data>")]
 pub async fn view_update(
     mut ctx: PageContext,
+    session: &LoginSession,
     db: DbConn,
+    config: &State<Config>,
+    secrets_key: &State<Option<SecretsKey>>,
     app_id: UserAppId,
     data: Form<FormUpdateIntent>,
 ) -> WartIDResult<Option<Ructe>> {
+    let user_id = session.user.id;
+    let mut new_secret = None;
+    let mut new_scim_token = None;
+
     let (app, success_message) = match data.into_inner() {
         FormUpdateIntent::UpdateGeneral { name, description } => {
             if name.len() < 3 {
-                ctx.add_flash_message(
-                    Cow::Borrowed("Le nom de la WartApp doit faire minimum 3 caractères de long."),
-                    true,
-                );
-                return view_render(
-                    ctx,
-                    match db_await!(UserApp::find_by_id(db, app_id)) {
-                        Ok(Some(app)) => app,
-                        Ok(None) => return Ok(None),
-                        Err(err) => return Err(err),
-                    },
-                );
+                return view_render_error(ctx, &db, user_id, app_id, "apps.name-too-short").await;
             }
 
             (
@@ -164,24 +723,166 @@ pub async fn view_update(
                     &name,
                     &description
                 ))?,
-                "Nom et/ou description de l'app mis·es à jour avec succès.",
+                "apps.general-updated",
             )
         }
-        FormUpdateIntent::OAuthEnable => (
-            db_await!(UserApp::set_oauth(db, app_id, true))?,
-            "Secret OAuth2 généré.",
+        FormUpdateIntent::OAuthEnable => {
+            let (app, secret) = regenerate_oauth_secret(&db, secrets_key.as_ref(), app_id).await?;
+            new_secret = secret;
+
+            (app, "apps.secret-generated")
+        }
+        FormUpdateIntent::OAuthRevokePreviousSecret => (
+            db_await!(UserApp::revoke_previous_oauth_secret(db, app_id))?,
+            "apps.previous-secret-revoked",
         ),
         FormUpdateIntent::OAuthDisable => (
-            db_await!(UserApp::set_oauth(db, app_id, false))?,
-            "OAuth2 désactivé.",
+            db_await!(UserApp::set_oauth(db, app_id, false))?.0,
+            "apps.oauth-disabled",
         ),
         FormUpdateIntent::OAuthSetRedirectUri(uri) => (
             db_await!(UserApp::set_oauth_redirect_uri(db, app_id, uri))?,
-            "URI de redirection OAuth2 autorisé mis à jour.",
+            "apps.redirect-uri-updated",
         ),
+        FormUpdateIntent::OAuthSetClientAuth { .. }
+            if !db_await!(UserApp::is_manager(db, app_id, user_id))? =>
+        {
+            return view_render_error(ctx, &db, user_id, app_id, "apps.client-auth-forbidden")
+                .await;
+        }
+        FormUpdateIntent::OAuthSetClientAuth { method, jwks } => {
+            let jwks = Some(jwks).filter(|jwks| !jwks.trim().is_empty());
+
+            let error = match (method, &jwks) {
+                (_, Some(jwks)) if serde_json::from_str::<JwkSet>(jwks).is_err() => {
+                    Some("apps.invalid-jwks")
+                }
+                (Some(ClientAuthMethod::PrivateKeyJwt), None) => Some("apps.jwks-required"),
+                (Some(ClientAuthMethod::ClientSecretJwt), _) if secrets_key.is_none() => {
+                    Some("apps.client-secret-jwt-unavailable")
+                }
+                _ => None,
+            };
+
+            if let Some(error) = error {
+                return view_render_error(ctx, &db, user_id, app_id, error).await;
+            }
+
+            (
+                db_await!(UserApp::set_oauth_client_auth(db, app_id, method, jwks))?,
+                "apps.client-auth-updated",
+            )
+        }
+        FormUpdateIntent::OAuthSetUserinfoAlg(alg) => {
+            let alg = match alg.as_str() {
+                "" => None,
+                SigningKey::ALGORITHM => Some(SigningKey::ALGORITHM),
+                _ => {
+                    return view_render_error(ctx, &db, user_id, app_id, "apps.unsupported-alg")
+                        .await
+                }
+            };
+
+            (
+                db_await!(UserApp::set_oauth_userinfo_alg(db, app_id, alg))?,
+                "apps.userinfo-updated",
+            )
+        }
+        FormUpdateIntent::SamlSet { .. }
+            if !db_await!(UserApp::is_manager(db, app_id, user_id))? =>
+        {
+            return view_render_error(ctx, &db, user_id, app_id, "apps.saml-forbidden").await;
+        }
+        FormUpdateIntent::SamlSet { entity_id, acs_url } => {
+            let (entity_id, acs_url) = (entity_id.trim(), acs_url.trim());
+
+            if entity_id.is_empty() {
+                (
+                    db_await!(UserApp::set_saml(db, app_id, None))?,
+                    "apps.saml-disabled",
+                )
+            } else if !acs_url.starts_with("https://") && !acs_url.starts_with("http://") {
+                return view_render_error(ctx, &db, user_id, app_id, "apps.invalid-acs-url").await;
+            } else {
+                let sp = Some((entity_id.to_owned(), acs_url.to_owned()));
+                (
+                    db_await!(UserApp::set_saml(db, app_id, sp))?,
+                    "apps.saml-updated",
+                )
+            }
+        }
+        FormUpdateIntent::ScimEnable | FormUpdateIntent::ScimDisable
+            if !db_await!(UserApp::is_manager(db, app_id, user_id))? =>
+        {
+            return view_render_error(ctx, &db, user_id, app_id, "apps.scim-forbidden").await;
+        }
+        FormUpdateIntent::ScimEnable => {
+            let (app, token) = db_await!(UserApp::set_scim(db, app_id, true))?;
+            new_scim_token = token;
+
+            (app, "apps.scim-token-generated")
+        }
+        FormUpdateIntent::ScimDisable => (
+            db_await!(UserApp::set_scim(db, app_id, false))?.0,
+            "apps.scim-disabled",
+        ),
+        FormUpdateIntent::WebhookAdd { .. }
+        | FormUpdateIntent::WebhookDelete(_)
+        | FormUpdateIntent::WebhookReplay(_)
+            if !db_await!(UserApp::is_manager(db, app_id, user_id))? =>
+        {
+            return view_render_error(ctx, &db, user_id, app_id, "apps.webhooks-forbidden").await;
+        }
+        FormUpdateIntent::AccessSetGroups(_)
+            if !db_await!(UserApp::is_manager(db, app_id, user_id))? =>
+        {
+            return view_render_error(ctx, &db, user_id, app_id, "apps.access-forbidden").await;
+        }
+        FormUpdateIntent::WebhookAdd { url, events } => {
+            let url = url.trim().to_owned();
+
+            let error = if !config.webhook_apps.contains(&app_id) {
+                Some("apps.webhooks-not-allowed")
+            } else if !url.starts_with("https://") && !url.starts_with("http://") {
+                Some("apps.invalid-webhook-url")
+            } else if !crate::webhooks::is_public_url(&url).await {
+                Some("apps.private-webhook-url")
+            } else if events.is_empty() {
+                Some("apps.no-webhook-events")
+            } else {
+                None
+            };
+
+            if let Some(error) = error {
+                return view_render_error(ctx, &db, user_id, app_id, error).await;
+            }
+
+            db_await!(Webhook::insert(db, app_id, &url, &events))?;
+            (find_app(&db, app_id).await?, "apps.webhook-added")
+        }
+        FormUpdateIntent::AccessSetGroups(groups) => {
+            db_await!(UserApp::set_allowed_groups(db, app_id, &groups))?;
+            (find_app(&db, app_id).await?, "apps.access-updated")
+        }
+        FormUpdateIntent::WebhookDelete(webhook_id) => {
+            db_await!(Webhook::delete(db, app_id, webhook_id))?;
+            (find_app(&db, app_id).await?, "apps.webhook-deleted")
+        }
+        FormUpdateIntent::WebhookReplay(delivery_id) => {
+            db_await!(WebhookDelivery::replay(db, app_id, delivery_id))?;
+            (find_app(&db, app_id).await?, "apps.webhook-replayed")
+        }
     };
 
-    ctx.add_flash_message(Cow::Borrowed(success_message), false);
+    ctx.add_flash_message(success_message, false);
 
-    view_render(ctx, app)
+    view_render(
+        ctx,
+        &db,
+        user_id,
+        app,
+        new_secret.as_deref(),
+        new_scim_token.as_deref(),
+    )
+    .await
 }
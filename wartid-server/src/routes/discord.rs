@@ -0,0 +1,310 @@
+//! ### Discord OAuth2 login
+//!
+//! Alternative to the login links sent by the bot in DMs: users are sent to Discord's consent page
+//! with the `identify` and `guilds` scopes, and come back to [callback] which checks they are in
+//! one of the [allowed guilds](crate::config::DiscordConfig::allowed_guilds).
+//!
+//! The same flow started from [link] attaches the Discord account to the logged in user instead,
+//! so users with a password account don't end up with a second one.
+
+use std::sync::Arc;
+
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::State;
+use serde::Deserialize;
+
+use super::prelude::*;
+use crate::config::{Config, DiscordOAuthConfig};
+use crate::discord::{DiscordAgent, LoginMethod, Notification};
+use crate::i18n::Locale;
+use crate::utils::jwt::JWT;
+
+const AUTHORIZE_URL: &str = "https://discord.com/oauth2/authorize";
+const API_URL: &str = "https://discord.com/api/v10";
+
+/// Binds the `state` to the browser that started the login, so it can't be used to log someone
+/// else into the attacker's account
+const NONCE_COOKIE: &str = "discord_login_nonce";
+
+lazy_static::lazy_static! {
+    static ref JWT_STATE: JWT<LoginState, LoginState> = JWT::new("wartid-discord-login", chrono::Duration::minutes(10));
+
+    static ref HTTP: reqwest::Client = reqwest::Client::builder()
+        .user_agent(concat!("WartID/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("cannot build the Discord HTTP client");
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct LoginState {
+    nonce: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    redirect_to: Option<String>,
+
+    /// User to link the Discord account to, instead of logging in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link: Option<UserId>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct DiscordUser {
+    id: String,
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct PartialGuild {
+    id: String,
+}
+
+type LoginError = Result<(Status, Cow<'static, str>), WartIDError>;
+
+fn oauth_config(config: &Config) -> Option<&DiscordOAuthConfig> {
+    config.discord.as_ref()?.oauth.as_ref()
+}
+
+fn redirect_uri(config: &Config) -> String {
+    format!("{}login/discord/callback", config.base_url)
+}
+
+/// URL of the login button, if Discord OAuth2 is configured
+pub fn login_url(config: &Config, then: Option<&str>) -> Option<String> {
+    oauth_config(config).map(|_| uri!(login(redirect_to = then)).to_string())
+}
+
+/// URL of the button linking a Discord account to the user, if Discord OAuth2 is configured
+pub fn link_url(config: &Config) -> Option<String> {
+    oauth_config(config).map(|_| uri!(link).to_string())
+}
+
+#[get("/login/discord?<redirect_to>")]
+pub fn login(
+    config: &State<Config>,
+    cookies: &CookieJar<'_>,
+    redirect_to: Option<String>,
+) -> Option<Redirect> {
+    authorize_redirect(config, cookies, redirect_to, None)
+}
+
+#[get("/login/discord/link")]
+pub fn link(
+    config: &State<Config>,
+    session: &LoginSession,
+    cookies: &CookieJar<'_>,
+) -> Option<Redirect> {
+    authorize_redirect(config, cookies, None, Some(session.user.id))
+}
+
+fn authorize_redirect(
+    config: &Config,
+    cookies: &CookieJar<'_>,
+    redirect_to: Option<String>,
+    link: Option<UserId>,
+) -> Option<Redirect> {
+    let oauth = oauth_config(config)?;
+
+    let nonce = crate::utils::gen_alphanumeric(32);
+    let state = JWT_STATE.encode(LoginState {
+        nonce: nonce.clone(),
+        redirect_to,
+        link,
+    });
+
+    let mut cookie = Cookie::new(NONCE_COOKIE, nonce);
+    // Discord redirects back with a cross-site navigation
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_max_age(time::Duration::minutes(10));
+    cookies.add(cookie);
+
+    let query = [
+        ("response_type", "code"),
+        ("client_id", &oauth.client_id.to_string()),
+        ("scope", "identify guilds"),
+        ("redirect_uri", &redirect_uri(config)),
+        ("state", &state),
+        ("prompt", "none"),
+    ];
+    let query = crate::utils::query_string(&query);
+
+    Some(Redirect::to(format!("{AUTHORIZE_URL}?{query}")))
+}
+
+/// Exchanges the code for a Discord access token, used to read the user and their guilds
+async fn fetch_user(
+    config: &Config,
+    oauth: &DiscordOAuthConfig,
+    code: &str,
+) -> reqwest::Result<(DiscordUser, Vec<PartialGuild>)> {
+    let token: TokenResponse = HTTP
+        .post(format!("{API_URL}/oauth2/token"))
+        .basic_auth(oauth.client_id, Some(&oauth.client_secret))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &redirect_uri(config)),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let user = HTTP
+        .get(format!("{API_URL}/users/@me"))
+        .bearer_auth(&token.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let guilds = HTTP
+        .get(format!("{API_URL}/users/@me/guilds"))
+        .bearer_auth(&token.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok((user, guilds))
+}
+
+#[derive(FromForm)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: String,
+    error: Option<String>,
+}
+
+#[get("/login/discord/callback?<query..>")]
+pub async fn callback(
+    config: &State<Config>,
+    db: DbConn,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
+    session: Option<&LoginSession>,
+    cookies: &CookieJar<'_>,
+    locale: Locale,
+    query: CallbackQuery,
+) -> Result<Redirect, LoginError> {
+    let CallbackQuery { code, state, error } = query;
+    let forbidden = |msg: String| Err(Ok((Status::Forbidden, Cow::Owned(msg))));
+
+    let Some(oauth) = oauth_config(config) else {
+        return Err(Ok((
+            Status::NotFound,
+            Cow::Borrowed(locale.t("login-error.discord-disabled")),
+        )));
+    };
+
+    let nonce = cookies
+        .get(NONCE_COOKIE)
+        .map(|cookie| cookie.value().to_owned());
+    cookies.remove(Cookie::named(NONCE_COOKIE));
+
+    let state = match JWT_STATE.decode(&state) {
+        Ok(state) if Some(&state.nonce) == nonce.as_ref() => state,
+        _ => return forbidden(locale.t("login-error.state-expired").to_owned()),
+    };
+
+    let code = match (code, error) {
+        (Some(code), None) => code,
+        (_, Some(error)) if error == "access_denied" => {
+            return forbidden(locale.format("login-error.cancelled", &[("provider", &"Discord")]))
+        }
+        _ => {
+            return Err(Ok((
+                Status::BadGateway,
+                Cow::Owned(locale.format("login-error.refused", &[("provider", &"Discord")])),
+            )))
+        }
+    };
+
+    let (discord_user, guilds) = match fetch_user(config, oauth, &code).await {
+        Ok(user) => user,
+        Err(err) => {
+            log::error!("cannot fetch the Discord user: {err}");
+            return Err(Ok((
+                Status::BadGateway,
+                Cow::Owned(locale.format("login-error.unreachable", &[("provider", &"Discord")])),
+            )));
+        }
+    };
+
+    let allowed_guilds = &config.discord.as_ref().unwrap().allowed_guilds;
+    let is_allowed = guilds
+        .iter()
+        .filter_map(|guild| guild.id.parse::<u64>().ok())
+        .any(|guild| allowed_guilds.contains(&guild));
+    if !is_allowed {
+        log::warn!("foreign user attempted to log in with Discord");
+        return forbidden(locale.t("login-error.no-allowed-guild").to_owned());
+    }
+
+    let Ok(discord_id) = discord_user.id.parse::<u64>() else {
+        return Err(Ok((
+            Status::BadGateway,
+            Cow::Owned(locale.format("login-error.invalid-response", &[("provider", &"Discord")])),
+        )));
+    };
+
+    if let Some(user_id) = state.link {
+        // Only the user who started linking can finish it
+        if session.map(|session| session.user.id) != Some(user_id) {
+            return forbidden(locale.t("login-error.link-other-account").to_owned());
+        }
+
+        let subject = discord_id.to_string();
+        if !db_await!(FederatedIdentity::link(
+            db,
+            user_id,
+            FederatedIdentity::DISCORD,
+            &subject
+        ))
+        .map_err(Err)?
+        {
+            return forbidden(
+                locale.format("login-error.already-linked", &[("provider", &"Discord")]),
+            );
+        }
+        if let Some(discord_agent) = discord_agent.inner() {
+            discord_agent.sync_groups(discord_id);
+        }
+
+        return Ok(Redirect::to("/@me"));
+    }
+
+    let name = discord_user.username;
+    let user = db_await!(User::find_or_create_by_discord_id(db, discord_id, name)).map_err(Err)?;
+    let Some(user) = user else {
+        return Err(Ok((
+            Status::Conflict,
+            Cow::Borrowed(locale.t("login-error.username-taken")),
+        )));
+    };
+    if let Some(discord_agent) = discord_agent.inner() {
+        discord_agent.sync_groups(discord_id);
+    }
+
+    let user_id = user.id;
+    let session_id = db_await!(Session::insert(db, NewSession::new(user_id))).map_err(Err)?;
+    crate::discord::notify(
+        discord_agent,
+        user_id,
+        Notification::Login {
+            method: LoginMethod::Provider(String::from("Discord")),
+        },
+    );
+
+    let mut cookie = crate::login_session_cookie(config, session_id.to_string());
+    cookie.set_max_age(crate::SESSION_COOKIE_EXPIRATION);
+    cookies.add(cookie);
+
+    Ok(crate::after_login(config, state.redirect_to))
+}
@@ -0,0 +1,246 @@
+//! ### Forward authentication
+//!
+//! Lets reverse proxies (Traefik's `forwardAuth`, nginx's `auth_request`, Caddy's
+//! `forward_auth`...) gate any service behind a WartID login. The proxy asks [verify] about each
+//! request it receives, passing the original request in `X-Forwarded-*` headers, and lets it
+//! through if the answer is a 200.
+
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::Responder;
+use rocket::{Request, Response, State};
+
+use crate::config::{Config, ForwardAuthRule};
+use crate::routes::oauth2::BearerSession;
+
+use super::prelude::*;
+
+/// The request the reverse proxy is asking about
+pub struct ForwardedRequest {
+    host: Option<String>,
+    url: Option<String>,
+
+    /// Whether the client is a browser, which can be sent to the login page
+    accepts_html: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ForwardedRequest {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+
+        // nginx usually only gives the full URL
+        let original_url = headers.get_one("X-Original-URL");
+
+        let host = headers.get_one("X-Forwarded-Host").or_else(|| {
+            original_url
+                .and_then(|url| url.split_once("://"))
+                .map(|(_, rest)| rest.split_once('/').map_or(rest, |(host, _)| host))
+        });
+
+        let url = original_url.map(str::to_owned).or_else(|| {
+            let proto = headers.get_one("X-Forwarded-Proto").unwrap_or("https");
+            let uri = headers.get_one("X-Forwarded-Uri").unwrap_or("/");
+            host.map(|host| format!("{proto}://{host}{uri}"))
+        });
+
+        let accepts_html = request
+            .accept()
+            .is_some_and(|accept| accept.media_types().any(|media| media.is_html()));
+
+        Outcome::Success(Self {
+            host: host.map(str::to_owned),
+            url,
+            accepts_html,
+        })
+    }
+}
+
+pub enum Verdict {
+    Allowed {
+        user: User,
+        email: Option<String>,
+    },
+
+    /// Browsers are sent to the login page, then back to where they wanted to go
+    LoginRequired(String),
+
+    Unauthorized,
+    Forbidden,
+}
+
+impl<'r> Responder<'r, 'static> for Verdict {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        match self {
+            Verdict::Allowed { user, email } => {
+                let mut response = Response::build();
+                response
+                    .status(Status::Ok)
+                    .header(Header::new("X-WartID-User", user.username))
+                    .header(Header::new("X-WartID-User-Id", user.id.to_string()));
+                if let Some(email) = email {
+                    response.header(Header::new("X-WartID-Email", email));
+                }
+                response.ok()
+            }
+            Verdict::LoginRequired(login_url) => Redirect::to(login_url).respond_to(request),
+            Verdict::Unauthorized => Response::build()
+                .status(Status::Unauthorized)
+                .header(Header::new("WWW-Authenticate", "Bearer"))
+                .ok(),
+            Verdict::Forbidden => Err(Status::Forbidden),
+        }
+    }
+}
+
+/// Why a user can't go through to a host, if they can't, given the rule matching it, the app
+/// their access token was issued to (`None` for the session cookie and personal access tokens)
+/// and the names of their groups
+fn refusal(
+    rule: Option<&ForwardAuthRule>,
+    client: Option<UserAppId>,
+    username: &str,
+    groups: &[String],
+) -> Option<Verdict> {
+    if let Some(client) = client {
+        if !rule.is_some_and(|rule| rule.clients.contains(&client)) {
+            return Some(Verdict::Unauthorized);
+        }
+    }
+
+    match rule {
+        Some(rule)
+            if !rule.users.iter().any(|user| user == username)
+                && !groups.iter().any(|group| rule.groups.contains(group)) =>
+        {
+            Some(Verdict::Forbidden)
+        }
+        _ => None,
+    }
+}
+
+/// Accepts both the `login_session` cookie and bearer tokens. With a bearer token, the e-mail
+/// address is only given out if the token has the `email` scope. Access tokens of WartApps are
+/// only accepted for hosts whose rule lists the app in its `clients`.
+#[get("/auth/verify")]
+pub async fn verify(
+    config: &State<Config>,
+    db: DbConn,
+    session: Option<&LoginSession>,
+    bearer: Option<BearerSession>,
+    forwarded: ForwardedRequest,
+) -> WartIDResult<Verdict> {
+    let (user, email, client) = match (session, bearer) {
+        (Some(session), _) => (session.user.clone(), session.user.email.clone(), None),
+        (None, Some(bearer)) => {
+            let email = bearer
+                .user
+                .email
+                .clone()
+                .filter(|_| bearer.scopes.contains(OAuth2Scope::Email));
+            (bearer.user, email, bearer.client)
+        }
+        (None, None) => {
+            // Hosts the proxy protects without a rule or the session cookie reaching them can't
+            // be sent back to
+            let url = forwarded
+                .url
+                .filter(|url| config.is_login_redirect_allowed(url));
+
+            return Ok(if forwarded.accepts_html {
+                Verdict::LoginRequired(format!(
+                    "{}{}",
+                    config.base_url.trim_end_matches('/'),
+                    uri!(crate::login(url))
+                ))
+            } else {
+                Verdict::Unauthorized
+            });
+        }
+    };
+
+    let rule = forwarded
+        .host
+        .as_deref()
+        .and_then(|host| config.forward_auth.iter().find(|rule| rule.matches(host)));
+
+    let groups = if rule.is_some_and(|rule| !rule.groups.is_empty()) {
+        let user_id = user.id;
+        db_await!(Group::find_by_user(db, user_id))?
+            .into_iter()
+            .map(|group| group.name)
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    Ok(refusal(rule, client, &user.username, &groups).unwrap_or(Verdict::Allowed { user, email }))
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn rule(host: &str) -> ForwardAuthRule {
+        ForwardAuthRule {
+            host: String::from(host),
+            users: vec![String::from("alice")],
+            groups: vec![String::from("ops")],
+            clients: vec![UserAppId::from_uuid(Uuid::from_u128(1))],
+        }
+    }
+
+    #[test]
+    fn rule_matches() {
+        let exact = rule("grafana.example.com");
+        assert!(exact.matches("grafana.example.com"));
+        assert!(exact.matches("Grafana.Example.com"));
+        assert!(exact.matches("grafana.example.com:8443"));
+        assert!(!exact.matches("example.com"));
+        assert!(!exact.matches("evil-grafana.example.com"));
+        assert!(!exact.matches("grafana.example.com.evil.org"));
+
+        let wildcard = rule("*.example.com");
+        assert!(wildcard.matches("grafana.example.com"));
+        assert!(wildcard.matches("a.b.example.com:80"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("evilexample.com"));
+        assert!(!wildcard.matches("example.com.evil.org"));
+    }
+
+    #[test]
+    fn verdicts() {
+        let rule = rule("grafana.example.com");
+        let listed = Some(UserAppId::from_uuid(Uuid::from_u128(1)));
+        let other = Some(UserAppId::from_uuid(Uuid::from_u128(2)));
+        let ops = [String::from("ops")];
+
+        // Hosts without rules are open to every user, but not to tokens of apps
+        assert!(refusal(None, None, "bob", &[]).is_none());
+        assert!(matches!(
+            refusal(None, listed, "bob", &[]),
+            Some(Verdict::Unauthorized)
+        ));
+
+        assert!(refusal(Some(&rule), None, "alice", &[]).is_none());
+        assert!(refusal(Some(&rule), None, "bob", &ops).is_none());
+        assert!(matches!(
+            refusal(Some(&rule), None, "bob", &[String::from("wiki")]),
+            Some(Verdict::Forbidden)
+        ));
+
+        assert!(refusal(Some(&rule), listed, "alice", &[]).is_none());
+        assert!(matches!(
+            refusal(Some(&rule), listed, "bob", &[]),
+            Some(Verdict::Forbidden)
+        ));
+        assert!(matches!(
+            refusal(Some(&rule), other, "alice", &ops),
+            Some(Verdict::Unauthorized)
+        ));
+    }
+}
@@ -1,5 +1,13 @@
+pub mod api;
 pub mod apps;
+pub mod discord;
+pub mod forward_auth;
 pub mod oauth2;
+pub mod openapi;
+pub mod recovery;
+pub mod saml;
+pub mod scim;
+pub mod upstream;
 pub mod users;
 
 /// Prelude for child modules
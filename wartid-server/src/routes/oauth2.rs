@@ -1,17 +1,27 @@
 use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::config::Config;
+use crate::discord::{DiscordAgent, Notification};
+use crate::i18n::Locale;
+use crate::ructe_types::AuthorizeForm;
 use rocket::form::error::ErrorKind;
 use rocket::form::{FromFormField, ValueField};
 use rocket::http::uri::Origin;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::outcome::{try_outcome, IntoOutcome};
 use rocket::request::FromRequest;
 use rocket::request::Outcome;
 use rocket::serde::json::Json;
-use rocket::{Request, State};
+use rocket::{Either, Request, State};
+
+use utoipa::{IntoParams, ToSchema};
 
 use crate::utils::jwt::JWT;
+use crate::utils::secrets::SecretsKey;
+use crate::utils::signing::SigningKey;
 
 use super::prelude::*;
 
@@ -34,6 +44,9 @@ struct AuthorizeState<Str, O2S> {
     #[serde(rename = "scopes")]
     initial_scopes: O2S,
 
+    #[serde(default, skip_serializing_if = "Claims::is_empty")]
+    claims: Claims,
+
     redirect_uri: Str,
 }
 
@@ -46,6 +59,22 @@ struct AccessState {
     user: UserId,
 
     scopes: OAuth2Scopes,
+
+    #[serde(default, skip_serializing_if = "Claims::is_empty")]
+    claims: Claims,
+
+    /// The app that obtained this token on the user's behalf through a token exchange, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    act: Option<Actor>,
+}
+
+/// `act` claim from RFC 8693. Exchanging an already exchanged token nests the previous actor.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Actor {
+    sub: UserAppId,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    act: Option<Box<Actor>>,
 }
 
 lazy_static::lazy_static! {
@@ -53,22 +82,92 @@ lazy_static::lazy_static! {
 
     static ref JWT_AUTHORIZE: JWT<AuthorizeState<&'static str, &'static OAuth2Scopes>, AuthorizeState<String, OAuth2Scopes>> = JWT::new("wartid-authorize", chrono::Duration::minutes(10));
     static ref JWT_ACCESS: JWT<AccessState, AccessState> = JWT::new("wartid-access-token", *ACCESS_TOKEN_EXPIRATION);
+
+    static ref USED_ASSERTIONS: UsedAssertions = UsedAssertions::default();
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, rocket::FromFormField)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, rocket::FromFormField, ToSchema)]
 pub enum AuthorizeResponseType {
     #[field(value = "code")]
+    #[schema(rename = "code")]
     Code,
 }
 
-#[derive(FromForm, Debug)]
+/// How the authorization response is sent back to the client. The `*.jwt` variants implement JARM
+/// (JWT Secured Authorization Response Mode), where the response parameters are wrapped in a JWT
+/// signed with WartID's [SigningKey].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, rocket::FromFormField, ToSchema)]
+pub enum AuthorizeResponseMode {
+    #[default]
+    #[field(value = "query")]
+    #[schema(rename = "query")]
+    Query,
+
+    #[field(value = "form_post")]
+    #[schema(rename = "form_post")]
+    FormPost,
+
+    #[field(value = "query.jwt")]
+    #[field(value = "jwt")]
+    #[schema(rename = "query.jwt")]
+    QueryJwt,
+
+    #[field(value = "form_post.jwt")]
+    #[schema(rename = "form_post.jwt")]
+    FormPostJwt,
+}
+
+impl AuthorizeResponseMode {
+    fn is_jwt(self) -> bool {
+        matches!(self, Self::QueryJwt | Self::FormPostJwt)
+    }
+
+    fn http_method(self) -> &'static str {
+        match self {
+            Self::Query | Self::QueryJwt => "get",
+            Self::FormPost | Self::FormPostJwt => "post",
+        }
+    }
+}
+
+/// Claims of a JARM authorization response
+#[derive(serde::Serialize)]
+struct AuthorizeResponse<'a> {
+    iss: &'a str,
+    aud: UserAppId,
+    exp: i64,
+    code: &'a str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<&'a str>,
+}
+
+#[derive(FromForm, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct AuthorizeQuery<'a> {
+    #[param(value_type = String, format = Uuid)]
     client_id: UserAppId,
+
+    /// Must start with the redirect URI configured for the app
     redirect_uri: String,
+
+    /// Space-separated scopes
+    #[param(value_type = Option<String>, example = "basic email")]
     scope: Option<OAuth2Scopes>,
+
+    #[param(inline)]
     response_type: AuthorizeResponseType,
+
+    #[param(inline)]
+    response_mode: Option<AuthorizeResponseMode>,
+
     state: Option<String>,
     nonce: Option<&'a str>,
+
+    /// OIDC `claims` request, a JSON object whose `userinfo` member lists claims wanted on top of
+    /// the scopes
+    #[param(example = r#"{"userinfo": {"groups": null}}"#)]
+    claims: Option<&'a str>,
 }
 
 macro_rules! implies {
@@ -77,11 +176,26 @@ macro_rules! implies {
     };
 }
 
+/// Asks the logged in user to authorize a WartApp, then sends them back to it with an
+/// authorization code
+#[utoipa::path(
+    get,
+    path = "/oauth2/authorize",
+    tag = "oauth2",
+    params(AuthorizeQuery),
+    responses(
+        (status = 200, description = "Consent page, sending the response to the client once accepted", content_type = "text/html"),
+        (status = 303, description = "Redirection to the login page"),
+    ),
+    security(("session" = []))
+)]
 #[get("/oauth2/authorize?<authorize..>")]
 pub async fn authorize(
     config: &State<Config>,
+    signing_key: &State<SigningKey>,
     current_uri: &Origin<'_>,
     session: Option<&LoginSession>,
+    locale: Locale,
     db: DbConn,
     authorize: Result<AuthorizeQuery<'_>, rocket::form::error::Errors<'_>>,
 ) -> WartIDResult<Result<Ructe, Redirect>> {
@@ -100,19 +214,38 @@ pub async fn authorize(
 
                 let authorize_client_id = authorize.client_id;
                 let app = db_await!(UserApp::find_by_id(db, authorize_client_id))?
-                    .filter(|app| app.oauth2().is_some())
+                    .filter(UserApp::is_oauth2_enabled)
                     .ok_or(WartIDError::OAuth2Error("client not found"))?;
 
                 if !app.is_oauth2_redirect_allowed(redirect_uri) {
                     return Err(WartIDError::OAuth2Error("redirect uri is not configured"));
                 }
 
+                let (app_id, user_id) = (app.id, session.user.id);
+                if !db_await!(UserApp::is_user_allowed(db, app_id, user_id))? {
+                    return Err(WartIDError::OAuth2Error(
+                        "you aren't in any of the groups allowed to use this app",
+                    ));
+                }
+
                 let redirect_uri_short = redirect_uri
                     .split_once("//")
                     .and_then(|(_, right)| right.split_once('/'))
                     .map_or(redirect_uri.as_str(), |(left, _)| left);
 
                 let scopes = authorize.scope.unwrap_or_default();
+                if scopes.has_admin() {
+                    return Err(WartIDError::OAuth2Error(
+                        "admin scopes can only be granted to personal access tokens",
+                    ));
+                }
+
+                let claims = match authorize.claims {
+                    Some(claims) => Claims::parse_request(claims)
+                        .map_err(|()| WartIDError::OAuth2Error("invalid claims parameter"))?,
+                    None => Claims::default(),
+                };
+                let granted_claims = claims.granted(&scopes);
 
                 let code = if implies!(scopes.contains(OAuth2Scope::Email) => session.user.email.is_some())
                 {
@@ -120,6 +253,7 @@ pub async fn authorize(
                         client: app.id,
                         user: session.user.id,
                         initial_scopes: unsafe { std::mem::transmute(&scopes) },
+                        claims: claims.clone(),
                         // :see_no_evil: (FIXME obv)
                         redirect_uri: unsafe { std::mem::transmute(redirect_uri.as_str()) },
                     }))
@@ -127,16 +261,43 @@ pub async fn authorize(
                     None
                 };
 
+                let response_mode = authorize.response_mode.unwrap_or_default();
+                let state = authorize.state.as_deref();
+
+                let response_jwt;
+                let mut response = Vec::new();
+                if let Some(code) = &code {
+                    if response_mode.is_jwt() {
+                        response_jwt = signing_key.sign(&AuthorizeResponse {
+                            iss: &config.base_url,
+                            aud: app.id,
+                            exp: (chrono::Utc::now() + chrono::Duration::minutes(10)).timestamp(),
+                            code,
+                            state,
+                        });
+                        response.push(("response", response_jwt.as_str()));
+                    } else {
+                        response.push(("code", code.as_str()));
+                        if let Some(state) = state {
+                            response.push(("state", state));
+                        }
+                    }
+                }
+
                 // TODO X-Frame-Options: Deny
 
+                let form = AuthorizeForm {
+                    redirect_short: redirect_uri_short,
+                    redirect_uri,
+                    method: response_mode.http_method(),
+                    response: code.is_some().then_some(&response[..]),
+                };
                 Ok(render!(oauth2::authorize_html(
+                    locale,
                     &session.user,
                     &app,
-                    redirect_uri_short,
-                    redirect_uri,
-                    code.as_deref(),
-                    authorize.state.as_deref(),
-                    &scopes
+                    &form,
+                    &granted_claims
                 )))
             } else {
                 let uri = format!("{}{}", config.base_url, current_uri);
@@ -147,25 +308,275 @@ pub async fn authorize(
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, rocket::FromFormField)]
-enum GrantType {
+/// Public part of WartID's [SigningKey], used by clients to verify signed responses
+#[utoipa::path(
+    get,
+    path = "/oauth2/jwks",
+    tag = "oauth2",
+    responses((status = 200, description = "JSON Web Key Set (RFC 7517)", content_type = "application/json"))
+)]
+#[get("/oauth2/jwks")]
+pub fn jwks(signing_key: &State<SigningKey>) -> Json<jsonwebtoken::jwk::JwkSet> {
+    Json(signing_key.jwks())
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, rocket::FromFormField, ToSchema)]
+pub enum GrantType {
     #[field(value = "authorization_code")]
+    #[schema(rename = "authorization_code")]
     AuthorizationCode,
 
     #[field(value = "refresh_token")]
+    #[schema(rename = "refresh_token")]
     RefreshToken,
+
+    #[field(value = "urn:ietf:params:oauth:grant-type:token-exchange")]
+    #[schema(rename = "urn:ietf:params:oauth:grant-type:token-exchange")]
+    TokenExchange,
 }
 
-#[derive(FromForm, Debug)]
+const TOKEN_TYPE_ACCESS_TOKEN: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+#[derive(FromForm, Debug, ToSchema)]
 pub struct TokenQuery<'a> {
+    #[schema(inline)]
     grant_type: GrantType,
     code: Option<&'a str>,
     refresh_token: Option<String>,
-    redirect_uri: &'a str,
+    redirect_uri: Option<&'a str>,
     scope: Option<&'a str>,
 
     client_id: Option<&'a str>,
     client_secret: Option<&'a str>,
+    client_assertion_type: Option<&'a str>,
+    client_assertion: Option<&'a str>,
+
+    // Token exchange
+    subject_token: Option<&'a str>,
+    subject_token_type: Option<&'a str>,
+    #[schema(value_type = Option<String>, format = Uuid)]
+    audience: Option<UserAppId>,
+    requested_token_type: Option<&'a str>,
+}
+
+const CLIENT_ASSERTION_TYPE_JWT_BEARER: &str =
+    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Claims of a `client_assertion` JWT, as defined by RFC 7523
+#[derive(serde::Deserialize)]
+struct ClientAssertion {
+    iss: String,
+    sub: String,
+
+    /// Required, as in OpenID Connect, so assertions can't be replayed
+    jti: Option<String>,
+    exp: i64,
+}
+
+/// Client assertions are refused if they are valid for longer than this, so their `jti` doesn't
+/// have to be remembered for too long
+const CLIENT_ASSERTION_MAX_LIFETIME: i64 = 60 * 60;
+
+/// No leeway is allowed on `exp`: the `jti` of an assertion is forgotten as soon as it expires, so
+/// accepting it a bit later would let it be replayed
+fn client_assertion_validation(
+    alg: jsonwebtoken::Algorithm,
+    base_url: &str,
+) -> jsonwebtoken::Validation {
+    let mut validation = jsonwebtoken::Validation::new(alg);
+    validation.set_audience(&[format!("{base_url}oauth2/token"), base_url.to_owned()]);
+    validation.set_required_spec_claims(&["exp", "aud", "iss", "sub"]);
+    validation.leeway = 0;
+    validation
+}
+
+/// `jti`s of the client assertions already used, by client, until they expire
+#[derive(Default)]
+struct UsedAssertions(std::sync::Mutex<HashMap<(UserAppId, String), i64>>);
+
+impl UsedAssertions {
+    /// `false` if the assertion was already used
+    fn insert(&self, client: UserAppId, jti: String, exp: i64, now: i64) -> bool {
+        let mut used = self.0.lock().unwrap();
+        used.retain(|_, exp| *exp > now);
+
+        match used.entry((client, jti)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(exp);
+                true
+            }
+        }
+    }
+}
+
+/// How the client authenticated itself to the token endpoint, before being checked against the
+/// database
+enum ClientCredentials<'a> {
+    Secret {
+        method: ClientAuthMethod,
+        client_id: Cow<'a, str>,
+        secret: Cow<'a, str>,
+    },
+    Assertion(&'a str),
+}
+
+impl<'a> ClientCredentials<'a> {
+    fn from_request(
+        auth: Option<BasicAuthorization>,
+        data: &TokenQuery<'a>,
+    ) -> Result<Self, String> {
+        match (
+            auth,
+            data.client_id,
+            data.client_secret,
+            data.client_assertion_type,
+            data.client_assertion,
+        ) {
+            (Some(auth), None, None, None, None) => Ok(Self::Secret {
+                method: ClientAuthMethod::ClientSecretBasic,
+                client_id: Cow::Owned(auth.username),
+                secret: Cow::Owned(auth.password),
+            }),
+            (None, Some(client_id), Some(secret), None, None) => Ok(Self::Secret {
+                method: ClientAuthMethod::ClientSecretPost,
+                client_id: Cow::Borrowed(client_id),
+                secret: Cow::Borrowed(secret),
+            }),
+            (None, _, None, Some(assertion_type), Some(assertion)) => {
+                if assertion_type != CLIENT_ASSERTION_TYPE_JWT_BEARER {
+                    return Err(String::from("unsupported client_assertion_type"));
+                }
+
+                Ok(Self::Assertion(assertion))
+            }
+            (None, _, None, None, None) => Err(String::from("no client authentication provided")),
+            _ => Err(String::from("multiple auth methods used simultaneously")),
+        }
+    }
+
+    /// Finds the app these credentials belong to, and checks them
+    async fn authenticate(
+        self,
+        db: &DbConn,
+        config: &Config,
+        secrets_key: Option<&SecretsKey>,
+        client_id_param: Option<&str>,
+    ) -> Result<UserApp, String> {
+        let (method, client_id, assertion) = match &self {
+            Self::Secret {
+                method, client_id, ..
+            } => (*method, client_id.to_string(), None),
+            Self::Assertion(assertion) => {
+                // The signature can only be checked once we know who the client claims to be
+                let mut validation = jsonwebtoken::Validation::default();
+                validation.insecure_disable_signature_validation();
+                validation.validate_exp = false;
+                let header = jsonwebtoken::decode_header(assertion)
+                    .map_err(|e| format!("invalid client assertion: {e}"))?;
+                let claims = jsonwebtoken::decode::<ClientAssertion>(
+                    assertion,
+                    &jsonwebtoken::DecodingKey::from_secret(&[]),
+                    &validation,
+                )
+                .map_err(|e| format!("invalid client assertion: {e}"))?
+                .claims;
+
+                if claims.iss != claims.sub {
+                    return Err(String::from("client assertion 'iss' and 'sub' differ"));
+                }
+
+                let method = match header.alg {
+                    jsonwebtoken::Algorithm::HS256
+                    | jsonwebtoken::Algorithm::HS384
+                    | jsonwebtoken::Algorithm::HS512 => ClientAuthMethod::ClientSecretJwt,
+                    _ => ClientAuthMethod::PrivateKeyJwt,
+                };
+
+                (method, claims.sub, Some((*assertion, header)))
+            }
+        };
+
+        if client_id_param.is_some_and(|param| param != client_id) {
+            return Err(String::from(
+                "client_id doesn't match the client credentials",
+            ));
+        }
+
+        let client_id: UserAppId = client_id
+            .parse()
+            .map_err(|_| String::from("cannot parse client uuid"))?;
+
+        let app = match db_await!(UserApp::find_by_id(db, client_id)) {
+            Ok(Some(app)) => app,
+            Ok(None) => return Err(String::from("unknown client id")),
+            Err(e) => return Err(format!("{e}")),
+        };
+
+        if !app.is_oauth2_enabled() {
+            return Err(String::from("OAuth2 is disabled for this client"));
+        }
+
+        if !app.is_oauth2_auth_method_allowed(method) {
+            return Err(format!("{method} is not allowed for this client"));
+        }
+
+        match (self, assertion) {
+            (Self::Secret { secret, .. }, None) => {
+                if !app.verify_oauth2_secret(&secret) {
+                    return Err(String::from("invalid client secret"));
+                }
+            }
+            (Self::Assertion(_), Some((assertion, header))) => {
+                let key = if method == ClientAuthMethod::ClientSecretJwt {
+                    let secret = app
+                        .oauth2_secret_encrypted()
+                        .zip(secrets_key)
+                        .and_then(|(encrypted, key)| key.open(encrypted, &app.id.to_string()))
+                        .ok_or_else(|| {
+                            String::from(
+                                "the client secret must be regenerated for client_secret_jwt",
+                            )
+                        })?;
+
+                    jsonwebtoken::DecodingKey::from_secret(secret.as_bytes())
+                } else {
+                    let jwks: jsonwebtoken::jwk::JwkSet =
+                        serde_json::from_str(app.oauth_jwks.as_deref().unwrap_or_default())
+                            .map_err(|_| String::from("the client's JWKS is invalid"))?;
+
+                    let jwk = match header.kid.as_deref() {
+                        Some(kid) => jwks.find(kid),
+                        None if jwks.keys.len() == 1 => jwks.keys.first(),
+                        None => None,
+                    }
+                    .ok_or_else(|| String::from("no matching key in the client's JWKS"))?;
+
+                    jsonwebtoken::DecodingKey::from_jwk(jwk)
+                        .map_err(|e| format!("unusable key in the client's JWKS: {e}"))?
+                };
+
+                let validation = client_assertion_validation(header.alg, &config.base_url);
+                let claims = jsonwebtoken::decode::<ClientAssertion>(assertion, &key, &validation)
+                    .map_err(|e| format!("invalid client assertion: {e}"))?
+                    .claims;
+
+                let now = chrono::Utc::now().timestamp();
+                if claims.exp > now + CLIENT_ASSERTION_MAX_LIFETIME {
+                    return Err(String::from("client assertion valid for too long"));
+                }
+                let jti = claims
+                    .jti
+                    .ok_or_else(|| String::from("client assertion without 'jti'"))?;
+                if !USED_ASSERTIONS.insert(app.id, jti, claims.exp, now) {
+                    return Err(String::from("client assertion already used"));
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(app)
+    }
 }
 
 /// Other auth methods: https://darutk.medium.com/oauth-2-0-client-authentication-4b5f929305d4
@@ -206,6 +617,7 @@ impl<'r> FromRequest<'r> for BasicAuthorization {
     }
 }
 
+#[derive(ToSchema)]
 enum TokenType {
     Bearer,
 }
@@ -224,19 +636,48 @@ impl serde::Serialize for TokenType {
     }
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, ToSchema)]
 pub struct TokenResponse {
     access_token: String,
     expires_in: u64,
+    #[schema(inline)]
     token_type: TokenType,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     refresh_token: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issued_token_type: Option<&'static str>,
+
+    /// Names of the user's groups, if the `groups` claim was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<String>>,
+}
+
+/// Names of the user's groups, if the `groups` claim is among the granted ones
+async fn granted_groups(
+    db: &DbConn,
+    user: UserId,
+    granted: &Claims,
+) -> WartIDResult<Option<Vec<String>>> {
+    if !granted.contains(Claim::Groups) {
+        return Ok(None);
+    }
+
+    let groups = db_await!(Group::find_by_user(db, user))?;
+    Ok(Some(groups.into_iter().map(|group| group.name).collect()))
 }
 
 pub struct BearerSession {
-    user: User,
-    scopes: OAuth2Scopes,
+    pub user: User,
+
+    /// App the access token was issued to, `None` for personal access tokens
+    pub client: Option<UserAppId>,
+
+    pub scopes: OAuth2Scopes,
+
+    /// Claims requested individually when authorizing the app, none for personal access tokens
+    pub claims: Claims,
 }
 
 #[rocket::async_trait]
@@ -253,52 +694,86 @@ impl<'r> FromRequest<'r> for BearerSession {
 
         let db: crate::DbConn = request.guard().await.unwrap();
 
-        let token_access = try_outcome!(JWT_ACCESS
-            .decode(bearer)
-            .map_err(|_| "cannot validate access token")
-            .into_outcome(Status::Unauthorized));
-        let token_access_user = token_access.user;
-        let user = try_outcome!(db_await!(User::find_by_id(db, token_access_user))
+        let (user_id, client, scopes, claims) = if bearer.starts_with(PersonalAccessToken::PREFIX) {
+            let bearer = bearer.to_owned();
+            let pat = try_outcome!(db_await!(PersonalAccessToken::authenticate(db, &bearer))
+                .map_err(|_| "database error")
+                .into_outcome(Status::InternalServerError));
+            let pat = try_outcome!(pat
+                .ok_or("invalid or expired personal access token")
+                .into_outcome(Status::Unauthorized));
+
+            (
+                pat.users_id,
+                None,
+                pat.scopes.parse().unwrap_or_default(),
+                Claims::default(),
+            )
+        } else {
+            let token_access = try_outcome!(JWT_ACCESS
+                .decode(bearer)
+                .map_err(|_| "cannot validate access token")
+                .into_outcome(Status::Unauthorized));
+
+            (
+                token_access.user,
+                Some(token_access.client),
+                token_access.scopes,
+                token_access.claims,
+            )
+        };
+
+        let user = try_outcome!(db_await!(User::find_by_id(db, user_id))
             .map_err(|_| "database error")
             .into_outcome(Status::InternalServerError));
         let user = try_outcome!(user
             .ok_or("authentication successful but user not in database")
             .into_outcome(Status::InternalServerError));
+        if user.is_suspended() {
+            return Err("suspended user").into_outcome(Status::Unauthorized);
+        }
 
         Outcome::Success(BearerSession {
             user,
-            scopes: token_access.scopes,
+            client,
+            scopes,
+            claims,
         })
     }
 }
 
+/// Exchanges an authorization code, a refresh token or another app's access token (RFC 8693) for
+/// an access token. Clients authenticate with HTTP Basic, `client_secret` or `client_assertion`.
+#[utoipa::path(
+    post,
+    path = "/oauth2/token",
+    tag = "oauth2",
+    request_body(content = TokenQuery, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, body = TokenResponse),
+        (status = 500, description = "Invalid request or client credentials", content_type = "text/plain"),
+    ),
+    security((), ("client" = []))
+)]
 #[post("/oauth2/token", data = "<data>")]
 pub async fn token(
+    config: &State<Config>,
     db: DbConn,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
+    secrets_key: &State<Option<SecretsKey>>,
     auth: Option<BasicAuthorization>,
     data: Form<TokenQuery<'_>>,
 ) -> Result<Json<TokenResponse>, String> {
-    let (client_id, client_password) = match (auth, data.client_id, data.client_secret) {
-        (Some(auth), None, None) => (auth.username.parse(), Cow::Owned(auth.password)),
-        (None, Some(client_id), Some(client_password)) => {
-            (client_id.parse(), Cow::Borrowed(client_password))
-        }
-        _ => return Err(String::from("multiple auth methods used simultaneously")),
-    };
-
-    let client_id = client_id.map_err(|_| String::from("cannot parse client uuid"))?;
-
-    let app = match db_await!(UserApp::find_by_id(db, client_id)) {
-        Ok(Some(app)) => app,
-        Ok(None) => return Err(String::from("unknown client id")),
-        Err(e) => return Err(format!("{e}")),
-    };
+    let app = ClientCredentials::from_request(auth, &data)?
+        .authenticate(&db, config, secrets_key.as_ref(), data.client_id)
+        .await?;
+    let client_id = app.id;
 
-    if Some(client_password.as_ref()) != app.oauth2().map(|(secret, _)| secret) {
-        return Err(String::from("invalid client secret"));
+    if data.grant_type == GrantType::TokenExchange {
+        return token_exchange(db, app, data.into_inner()).await;
     }
 
-    let (user, scopes) = match {
+    let (user, scopes, claims, is_consent) = match {
         let TokenQuery { grant_type, code, refresh_token, .. } = data.into_inner();
         (grant_type, code, refresh_token)
     } {
@@ -313,7 +788,7 @@ pub async fn token(
 
             // TODO check redirect URI
 
-            (authorize.user, authorize.initial_scopes)
+            (authorize.user, authorize.initial_scopes, authorize.claims, true)
         }
         (GrantType::RefreshToken, None, Some(refresh_token)) => {
             let session = db_await!(OAuth2Session::find_by_token(db, &refresh_token)).map_err(|e| format!("{e}"))?;
@@ -324,7 +799,12 @@ pub async fn token(
                         return Err(String::from("Forbidden app"));
                     }
 
-                    (session.users_id, session.initial_scopes.parse().unwrap_or_default())
+                    (
+                        session.users_id,
+                        session.initial_scopes.parse().unwrap_or_default(),
+                        session.claims.parse().unwrap_or_default(),
+                        false,
+                    )
                 }
                 None => return Err(String::from("No session found for this refresh token")), // TODO proper JSON errors
             }
@@ -332,20 +812,43 @@ pub async fn token(
         _ => return Err(String::from("authorization_code grant type can only be used with ?code, and refresh_token grant type can only be used with ?refresh_token")),
     };
 
-    let scopes2 = scopes.clone();
-    let refresh_token =
-        match db_await!(OAuth2Session::insert_or_refresh(db, user, app.id, &scopes2)) {
-            Ok(refresh_token) => Some(refresh_token),
-            Err(e) => {
-                log::error!("couldn't insert refresh token: {:?}", e);
-                None
+    // Group memberships may have changed since the app was authorized
+    if !db_await!(UserApp::is_user_allowed(db, client_id, user)).map_err(|e| format!("{e}"))? {
+        return Err(String::from(
+            "the user isn't in any of the groups allowed to use this app",
+        ));
+    }
+
+    let groups = granted_groups(&db, user, &claims.granted(&scopes))
+        .await
+        .map_err(|e| format!("{e}"))?;
+
+    let is_new_consent = is_consent
+        && !db_await!(OAuth2Session::exists(db, user, client_id)).map_err(|e| format!("{e}"))?;
+
+    let (scopes2, claims2) = (scopes.clone(), claims.clone());
+    let refresh_token = match db_await!(OAuth2Session::insert_or_refresh(
+        db, user, app.id, &scopes2, &claims2
+    )) {
+        Ok(refresh_token) => {
+            if is_new_consent {
+                let app = app.name.clone();
+                crate::discord::notify(discord_agent, user, Notification::AppConsent { app });
             }
-        };
+            Some(refresh_token)
+        }
+        Err(e) => {
+            log::error!("couldn't insert refresh token: {:?}", e);
+            None
+        }
+    };
 
     let access_token = JWT_ACCESS.encode(AccessState {
         user,
         client: client_id,
         scopes,
+        claims,
+        act: None,
     });
 
     Ok(Json(TokenResponse {
@@ -353,27 +856,460 @@ pub async fn token(
         expires_in: ACCESS_TOKEN_EXPIRATION.num_seconds() as _,
         token_type: TokenType::Bearer,
         refresh_token,
+        issued_token_type: None,
+        groups,
     }))
 }
 
-#[derive(serde::Serialize)]
+/// ### Token exchange (RFC 8693)
+///
+/// Lets an app holding one of a user's access tokens (issued to itself) obtain a token for another
+/// app, to call it on the user's behalf. The new token can only have a subset of the original's
+/// scopes, doesn't outlive the original, and records the calling app in its `act` claim. Both apps
+/// must share at least one manager, which is how trust between WartApps is established.
+async fn token_exchange(
+    db: DbConn,
+    app: UserApp,
+    data: TokenQuery<'_>,
+) -> Result<Json<TokenResponse>, String> {
+    let (Some(subject_token), Some(TOKEN_TYPE_ACCESS_TOKEN)) =
+        (data.subject_token, data.subject_token_type)
+    else {
+        return Err(format!(
+            "token exchange requires a subject_token of type {TOKEN_TYPE_ACCESS_TOKEN}"
+        ));
+    };
+
+    if data
+        .requested_token_type
+        .is_some_and(|requested| requested != TOKEN_TYPE_ACCESS_TOKEN)
+    {
+        return Err(String::from("only access tokens can be requested"));
+    }
+
+    let (subject, subject_expiration) = JWT_ACCESS
+        .decode_with_expiration(subject_token)
+        .map_err(|e| format!("invalid subject token: {e}"))?;
+
+    if subject.client != app.id {
+        return Err(String::from(
+            "the subject token wasn't issued to this client",
+        ));
+    }
+
+    // The client already holds a token for itself
+    let audience = match data.audience {
+        Some(audience) if audience != app.id => audience,
+        _ => {
+            return Err(String::from(
+                "token exchange requires another app as audience",
+            ))
+        }
+    };
+
+    let app_id = app.id;
+    let target = db_await!(UserApp::find_by_id(db, audience))
+        .map_err(|e| format!("{e}"))?
+        .filter(UserApp::is_oauth2_enabled)
+        .ok_or_else(|| String::from("unknown audience"))?;
+
+    if !db_await!(UserApp::share_manager(db, app_id, target.id)).map_err(|e| format!("{e}"))? {
+        return Err(String::from(
+            "the client isn't allowed to exchange tokens for this audience",
+        ));
+    }
+
+    let scopes = match data.scope {
+        Some(scope) => {
+            let scopes: OAuth2Scopes = scope.parse().map_err(|()| String::from("invalid scope"))?;
+
+            if !scopes.is_subset(&subject.scopes) {
+                return Err(String::from(
+                    "requested scopes exceed the ones of the subject token",
+                ));
+            }
+
+            scopes
+        }
+        None => subject.scopes,
+    };
+
+    let user = subject.user;
+    if !db_await!(UserApp::is_user_allowed(db, audience, user)).map_err(|e| format!("{e}"))? {
+        return Err(String::from(
+            "the user isn't in any of the groups allowed to use the audience",
+        ));
+    }
+
+    let groups = granted_groups(&db, user, &subject.claims.granted(&scopes))
+        .await
+        .map_err(|e| format!("{e}"))?;
+
+    // Exchanging can't extend the user's session
+    let access_token = JWT_ACCESS.encode_capped(
+        AccessState {
+            client: audience,
+            user: subject.user,
+            scopes,
+            claims: subject.claims,
+            act: Some(Actor {
+                sub: app.id,
+                act: subject.act.map(Box::new),
+            }),
+        },
+        subject_expiration,
+    );
+    let expires_in = (subject_expiration - chrono::Utc::now().timestamp())
+        .clamp(0, ACCESS_TOKEN_EXPIRATION.num_seconds());
+
+    Ok(Json(TokenResponse {
+        access_token,
+        expires_in: expires_in as _,
+        token_type: TokenType::Bearer,
+        refresh_token: None,
+        issued_token_type: Some(TOKEN_TYPE_ACCESS_TOKEN),
+        groups,
+    }))
+}
+
+/// Claims about the user, `sub` and `name` always being present and the others depending on the
+/// granted scopes and claims
+#[derive(serde::Serialize, ToSchema)]
 pub struct UserInfo {
+    #[schema(value_type = String, format = Uuid)]
     sub: UserId,
     name: String,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    picture: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locale: Option<String>,
+
+    /// UNIX time of the last profile change
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<i64>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     email: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+
+    /// Names of the groups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<String>>,
+
+    /// Sent as a string, since Discord IDs don't fit in a JavaScript number
+    #[serde(skip_serializing_if = "Option::is_none")]
+    discord_id: Option<String>,
 }
 
-#[get("/oauth2/userinfo")]
-pub fn userinfo(session: BearerSession) -> Json<UserInfo> {
-    let BearerSession { user, scopes } = session;
+/// Claims of a signed userinfo response
+#[derive(serde::Serialize)]
+struct SignedUserInfo<'a> {
+    iss: &'a str,
+    aud: UserAppId,
+    iat: i64,
 
-    log::info!("scopes: {scopes:?}");
+    #[serde(flatten)]
+    info: &'a UserInfo,
+}
 
-    Json(UserInfo {
+/// Returns the claims as JSON, or as a JWT signed with WartID's key if the app registered a
+/// `userinfo_signed_response_alg`
+#[utoipa::path(
+    get,
+    path = "/oauth2/userinfo",
+    tag = "oauth2",
+    params(
+        ("Authorization" = String, Header, description = "Access token or personal access token, as `Bearer <token>`"),
+    ),
+    responses(
+        (status = 200, body = UserInfo, content_type = "application/json"),
+        (status = 200, description = "Signed JWT containing the claims, with `iss`, `aud` and `iat`", content_type = "application/jwt"),
+        (status = 401, description = "Invalid or expired access token"),
+    ),
+    security(("bearer" = []))
+)]
+#[get("/oauth2/userinfo")]
+pub async fn userinfo(
+    config: &State<Config>,
+    signing_key: &State<SigningKey>,
+    db: DbConn,
+    session: BearerSession,
+) -> WartIDResult<Either<Json<UserInfo>, (ContentType, String)>> {
+    let BearerSession {
+        user,
+        client,
+        scopes,
+        claims,
+    } = session;
+    let claims = claims.granted(&scopes);
+
+    let groups = granted_groups(&db, user.id, &claims).await?;
+
+    let claim = |claim| claims.contains(claim);
+    let discord_id = if claim(Claim::DiscordId) {
+        let user_id = user.id;
+        db_await!(FederatedIdentity::find_subject(
+            db,
+            user_id,
+            FederatedIdentity::DISCORD
+        ))?
+    } else {
+        None
+    };
+    let email_verified =
+        Some(user.email_verified).filter(|_| claim(Claim::EmailVerified) && user.email.is_some());
+
+    let info = UserInfo {
         sub: user.id,
+        preferred_username: Some(user.username.clone()).filter(|_| claim(Claim::PreferredUsername)),
+        picture: user.picture.filter(|_| claim(Claim::Picture)),
+        locale: user.locale.filter(|_| claim(Claim::Locale)),
+        updated_at: Some(user.updated.timestamp()).filter(|_| claim(Claim::UpdatedAt)),
+        email: user.email.filter(|_| claim(Claim::Email)),
+        email_verified,
+        groups,
+        discord_id,
         name: user.username,
-        email: user.email.filter(|_| scopes.contains(OAuth2Scope::Email)),
+    };
+
+    let signed_for = match client {
+        Some(client) => db_await!(UserApp::find_by_id(db, client))?
+            .filter(|app| app.oauth2_userinfo_alg().is_some()),
+        None => None,
+    };
+
+    Ok(match signed_for {
+        Some(app) => Either::Right((
+            ContentType::new("application", "jwt"),
+            signing_key.sign(&SignedUserInfo {
+                iss: &config.base_url,
+                aud: app.id,
+                iat: chrono::Utc::now().timestamp(),
+                info: &info,
+            }),
+        )),
+        None => Either::Left(Json(info)),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::tests::{cleanup, client, create_app, create_user};
+
+    /// Token of `user` for `client`, expiring in `lifetime` seconds
+    fn access_token(client: UserAppId, user: UserId, scopes: &str, lifetime: i64) -> String {
+        JWT_ACCESS.encode_capped(
+            AccessState {
+                client,
+                user,
+                scopes: scopes.parse().unwrap(),
+                claims: Claims::default(),
+                act: None,
+            },
+            chrono::Utc::now().timestamp() + lifetime,
+        )
+    }
+
+    /// Exchanges `subject` as the `app` client, returning the response or the error message
+    async fn exchange(
+        client: &Client,
+        (app, secret): (UserAppId, &str),
+        subject: &str,
+        audience: UserAppId,
+        scope: Option<&str>,
+    ) -> Result<serde_json::Value, String> {
+        let mut body = format!(
+            "grant_type=urn:ietf:params:oauth:grant-type:token-exchange&client_id={app}\
+             &client_secret={secret}&subject_token={subject}\
+             &subject_token_type={TOKEN_TYPE_ACCESS_TOKEN}&audience={audience}"
+        );
+        if let Some(scope) = scope {
+            body += &format!("&scope={scope}");
+        }
+
+        let response = client
+            .post("/oauth2/token")
+            .header(ContentType::Form)
+            .body(body)
+            .dispatch()
+            .await;
+        let json = response.content_type() == Some(ContentType::JSON);
+        let body = response.into_string().await.unwrap();
+
+        if json {
+            Ok(serde_json::from_str(&body).unwrap())
+        } else {
+            Err(body)
+        }
+    }
+
+    #[test]
+    fn used_assertions() {
+        let used = UsedAssertions::default();
+        let client = UserAppId::from_uuid(Uuid::from_u128(1));
+        let other = UserAppId::from_uuid(Uuid::from_u128(2));
+
+        assert!(used.insert(client, String::from("a"), 100, 0));
+        assert!(!used.insert(client, String::from("a"), 100, 50));
+
+        // jtis are only unique per client
+        assert!(used.insert(other, String::from("a"), 100, 50));
+
+        // Forgotten once expired, when the assertion is refused anyway
+        assert!(used.insert(client, String::from("a"), 200, 100));
+    }
+
+    #[test]
+    fn expired_assertion_replay() {
+        let base_url = "https://id.example.com/";
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let client = UserAppId::from_uuid(Uuid::from_u128(1));
+        let now = chrono::Utc::now().timestamp();
+
+        let assertion = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256),
+            &serde_json::json!({
+                "iss": client.to_string(),
+                "sub": client.to_string(),
+                "aud": format!("{base_url}oauth2/token"),
+                "jti": "a",
+                "exp": now - 1,
+            }),
+            &jsonwebtoken::EncodingKey::from_secret(secret),
+        )
+        .unwrap();
+
+        // Used right before expiring, then forgotten
+        let used = UsedAssertions::default();
+        assert!(used.insert(client, String::from("a"), now - 1, now - 2));
+        assert!(used.insert(client, String::from("a"), now - 1, now));
+
+        // But refused by the validation, without any leeway
+        let validation = client_assertion_validation(jsonwebtoken::Algorithm::HS256, base_url);
+        let key = jsonwebtoken::DecodingKey::from_secret(secret);
+        assert!(jsonwebtoken::decode::<ClientAssertion>(&assertion, &key, &validation).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn token_exchange() {
+        let client = client().await;
+        let alice = create_user(&client, None).await;
+        let bob = create_user(&client, None).await;
+
+        let (a, a_secret) = create_app(&client, alice.id).await;
+        let (b, b_secret) = create_app(&client, alice.id).await;
+        let (c, _) = create_app(&client, bob.id).await;
+
+        let subject = access_token(a, alice.id, "basic email", 60);
+
+        // Only for apps sharing a manager, other than the client itself
+        for audience in [c, a] {
+            let error = exchange(&client, (a, &a_secret), &subject, audience, None).await;
+            assert!(error.is_err(), "{audience}");
+        }
+
+        // Never beyond the scopes of the subject token
+        let error = exchange(&client, (a, &a_secret), &subject, b, Some("profile")).await;
+        assert_eq!(
+            error.unwrap_err(),
+            "requested scopes exceed the ones of the subject token"
+        );
+
+        let response = exchange(&client, (a, &a_secret), &subject, b, Some("email"))
+            .await
+            .unwrap();
+        assert!(response["expires_in"].as_i64().unwrap() <= 60);
+
+        let token = response["access_token"].as_str().unwrap();
+        let (exchanged, expiration) = JWT_ACCESS.decode_with_expiration(token).unwrap();
+        assert_eq!(exchanged.client, b);
+        assert_eq!(exchanged.scopes.to_string(), "email");
+        assert!(expiration <= chrono::Utc::now().timestamp() + 60);
+        assert_eq!(exchanged.act.as_ref().map(|act| act.sub), Some(a));
+
+        // Exchanged again, the previous actor is nested
+        let response = exchange(&client, (b, &b_secret), token, a, None)
+            .await
+            .unwrap();
+        let token = response["access_token"].as_str().unwrap();
+        let act = JWT_ACCESS.decode(token).unwrap().act.unwrap();
+        assert_eq!(act.sub, b);
+        assert_eq!(act.act.map(|act| act.sub), Some(a));
+
+        cleanup(&client, vec![alice.id, bob.id]).await;
+    }
+
+    /// Status of a management API request authenticated with `token`
+    async fn api_status(client: &Client, uri: &str, token: &str) -> Status {
+        client
+            .get(uri)
+            .header(Header::new("Authorization", format!("Bearer {token}")))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn personal_access_tokens() {
+        let client = client().await;
+        let user = create_user(&client, None).await;
+        let db = crate::DbConn::get_one(client.rocket()).await.unwrap();
+        let user_id = user.id;
+
+        let pat = |validity| {
+            let scopes = "admin:users".parse().unwrap();
+            db.run(move |db| PersonalAccessToken::insert(db, user_id, "test", &scopes, validity))
+        };
+
+        let (_, valid) = pat(chrono::Duration::days(1)).await.unwrap();
+        assert_eq!(
+            api_status(&client, "/api/v1/users/me", &valid).await,
+            Status::Ok
+        );
+
+        // Admin scopes are only the granted ones
+        assert_eq!(
+            api_status(&client, "/api/v1/apps", &valid).await,
+            Status::Forbidden
+        );
+
+        let (_, expired) = pat(chrono::Duration::seconds(-1)).await.unwrap();
+        assert_eq!(
+            api_status(&client, "/api/v1/users/me", &expired).await,
+            Status::Unauthorized
+        );
+
+        let (revoked, revoked_token) = pat(chrono::Duration::days(1)).await.unwrap();
+        assert!(db_await!(PersonalAccessToken::revoke(db, user_id, revoked.id)).unwrap());
+        assert_eq!(
+            api_status(&client, "/api/v1/users/me", &revoked_token).await,
+            Status::Unauthorized
+        );
+
+        assert!(db_await!(User::set_suspended(
+            db,
+            user_id,
+            true,
+            SuspensionSource::Manual
+        ))
+        .unwrap());
+        assert_eq!(
+            api_status(&client, "/api/v1/users/me", &valid).await,
+            Status::Unauthorized
+        );
+
+        cleanup(&client, vec![user_id]).await;
+    }
+}
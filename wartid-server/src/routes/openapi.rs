@@ -0,0 +1,138 @@
+//! ### OpenAPI document
+//!
+//! Describes the OAuth2 endpoints and the JSON management API, served at `/openapi.json`. The
+//! document is derived from the request and response types, the paths being listed in [ApiDoc].
+
+use rocket::serde::json::Json;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::{self, ComponentsBuilder};
+use utoipa::{Modify, OpenApi};
+
+use super::{api, oauth2};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "WartID"),
+    paths(
+        oauth2::authorize,
+        oauth2::jwks,
+        oauth2::token,
+        oauth2::userinfo,
+        api::apps_list,
+        api::apps_create,
+        api::apps_get,
+        api::apps_update,
+        api::apps_oauth_secret,
+        api::apps_oauth_revoke_previous,
+        api::apps_oauth_disable,
+        api::users_me,
+        api::users_get,
+        api::users_update,
+    ),
+    components(schemas(
+        oauth2::TokenQuery,
+        oauth2::TokenResponse,
+        oauth2::UserInfo,
+        api::ApiErrorBody,
+        api::AppCreate,
+        api::AppOAuth,
+        api::AppResponse,
+        api::AppUpdate,
+        api::OAuthSecretResponse,
+        api::UserResponse,
+        api::UserUpdate,
+    )),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "oauth2", description = "OAuth2 authorization server"),
+        (name = "apps", description = "Management of the WartApps"),
+        (name = "users", description = "User profiles"),
+    )
+)]
+pub struct ApiDoc;
+
+/// The ways to authenticate referenced by the paths
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        let components = openapi
+            .components
+            .get_or_insert_with(|| ComponentsBuilder::new().build());
+
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+        components.add_security_scheme(
+            "client",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(openapi::security::ApiKey::Cookie(
+                openapi::security::ApiKeyValue::new("login_session"),
+            )),
+        );
+    }
+}
+
+#[get("/openapi.json")]
+pub fn document() -> Json<openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// Routes meant to be called by other programs, which must all be documented
+    fn is_public(path: &str) -> bool {
+        path.starts_with("/oauth2/") || path.starts_with("/api/")
+    }
+
+    #[test]
+    fn no_drift() {
+        let mounted = crate::routes()
+            .into_iter()
+            .filter(|route| is_public(route.uri.path()))
+            .map(|route| {
+                // `<app_id>` is written `{app_id}` in OpenAPI
+                let path = route
+                    .uri
+                    .path()
+                    .to_string()
+                    .replace('<', "{")
+                    .replace('>', "}");
+                (route.method.as_str().to_lowercase(), path)
+            })
+            .collect::<BTreeSet<_>>();
+
+        let document = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let documented = document["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| {
+                item.as_object()
+                    .unwrap()
+                    .keys()
+                    .filter(|key| ["get", "post", "put", "patch", "delete"].contains(&key.as_str()))
+                    .map(move |method| (method.clone(), path.clone()))
+            })
+            .collect::<BTreeSet<_>>();
+
+        assert_eq!(
+            mounted.difference(&documented).collect::<Vec<_>>(),
+            Vec::<&(String, String)>::new(),
+            "mounted but not documented"
+        );
+        assert_eq!(
+            documented.difference(&mounted).collect::<Vec<_>>(),
+            Vec::<&(String, String)>::new(),
+            "documented but not mounted"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! ### Password reset from Discord
+//!
+//! Users who forgot their password give their username on [request], and if their account is
+//! linked to Discord, the bot DMs them a single-use link to [reset]. The page says the same
+//! whether a link was sent or not, so it doesn't tell which accounts exist or are linked.
+//!
+//! Setting the new password logs out every browser, in case someone else knew the old one.
+
+use std::sync::Arc;
+
+use diesel::Connection;
+use rocket::http::Status;
+use rocket::State;
+
+use super::prelude::*;
+use crate::discord::{DiscordAgent, Notification};
+use crate::i18n::Locale;
+
+type ResetError = Result<(Status, Cow<'static, str>), WartIDError>;
+
+#[derive(FromForm)]
+pub struct ResetRequest {
+    username: String,
+}
+
+#[derive(FromForm)]
+pub struct NewPassword {
+    password: String,
+    confirmation: String,
+}
+
+#[get("/login/reset")]
+pub fn request(discord_agent: &State<Option<Arc<DiscordAgent>>>, locale: Locale) -> Option<Ructe> {
+    discord_agent.inner().as_ref()?;
+
+    Some(render!(login::reset_html(locale, None)))
+}
+
+#[post("/login/reset", data = "<form>")]
+pub async fn request_post(
+    db: DbConn,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
+    locale: Locale,
+    form: Form<ResetRequest>,
+) -> WartIDResult<Option<Ructe>> {
+    let Some(agent) = discord_agent.inner() else {
+        return Ok(None);
+    };
+
+    let username = form.into_inner().username;
+    let recipient = db
+        .run(move |db| -> WartIDResult<_> {
+            let Some(user) = User::find_by_username(db, &username)? else {
+                return Ok(None);
+            };
+            if user.is_suspended() {
+                return Ok(None);
+            }
+
+            let discord_id = FederatedIdentity::find_discord_id(db, user.id)?;
+            Ok(discord_id.map(|discord_id| (user, discord_id)))
+        })
+        .await?;
+
+    if let Some((user, discord_id)) = recipient {
+        let locale = user
+            .locale
+            .as_deref()
+            .and_then(Locale::parse)
+            .unwrap_or(locale);
+        agent.request_password_reset(user.id, discord_id, locale);
+    }
+
+    Ok(Some(render!(login::reset_html(
+        locale,
+        Some(locale.t("reset.sent"))
+    ))))
+}
+
+fn expired(locale: Locale) -> ResetError {
+    Ok((Status::Forbidden, Cow::Borrowed(locale.t("reset.expired"))))
+}
+
+#[get("/login/reset/<id>")]
+pub fn reset(
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
+    locale: Locale,
+    id: &str,
+) -> Result<Ructe, ResetError> {
+    match discord_agent.inner() {
+        Some(agent) if agent.check_password_reset(id).is_some() => {
+            Ok(render!(login::reset_password_html(locale, None)))
+        }
+        _ => Err(expired(locale)),
+    }
+}
+
+#[post("/login/reset/<id>", data = "<form>")]
+pub async fn reset_post(
+    db: DbConn,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
+    locale: Locale,
+    id: &str,
+    form: Form<NewPassword>,
+) -> Result<Ructe, ResetError> {
+    let Some(agent) = discord_agent.inner() else {
+        return Err(expired(locale));
+    };
+
+    // The link stays usable until a valid password is given
+    let error = if form.password.len() < User::MIN_PASSWORD_LEN {
+        Some("users.password-too-short")
+    } else if form.password != form.confirmation {
+        Some("reset.mismatch")
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        if agent.check_password_reset(id).is_none() {
+            return Err(expired(locale));
+        }
+        return Ok(render!(login::reset_password_html(
+            locale,
+            Some(locale.t(error))
+        )));
+    }
+
+    let Some(user_id) = agent.take_password_reset(id) else {
+        return Err(expired(locale));
+    };
+
+    let password = form.into_inner().password;
+    db.run(move |db| {
+        db.transaction(|db| {
+            User::update_password(db, user_id, &password)?;
+            Session::delete_all_by_user(db, user_id)
+        })
+    })
+    .await
+    .map_err(Err)?;
+    agent.notify(user_id, Notification::PasswordChange);
+
+    Ok(render!(login::reset_html(
+        locale,
+        Some(locale.t("reset.done"))
+    )))
+}
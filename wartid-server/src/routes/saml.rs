@@ -0,0 +1,145 @@
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, RawStr};
+use rocket::State;
+
+use crate::config::Config;
+use crate::i18n::Locale;
+use crate::utils::saml::{AuthnRequest, SamlIdp, ServiceProvider};
+
+use super::prelude::*;
+
+/// IdP metadata, to be imported by service providers. Not found if SAML isn't configured.
+#[get("/saml/metadata")]
+pub fn metadata(idp: &State<Option<SamlIdp>>) -> Option<(ContentType, String)> {
+    idp.as_ref().map(|idp| (ContentType::XML, idp.metadata()))
+}
+
+#[derive(FromForm)]
+pub struct SsoQuery {
+    #[field(name = "SAMLRequest")]
+    saml_request: String,
+
+    #[field(name = "RelayState")]
+    relay_state: Option<String>,
+}
+
+/// SP-initiated SSO, HTTP-Redirect binding
+#[get("/saml/sso?<query..>")]
+pub async fn sso_redirect(
+    config: &State<Config>,
+    idp: &State<Option<SamlIdp>>,
+    current_uri: &Origin<'_>,
+    session: Option<&LoginSession>,
+    locale: Locale,
+    db: DbConn,
+    query: SsoQuery,
+) -> WartIDResult<Result<Ructe, Redirect>> {
+    let Some(idp) = idp.inner() else {
+        return Err(WartIDError::SamlError("SAML is not enabled"));
+    };
+
+    let Some(session) = session else {
+        let uri = format!("{}{}", config.base_url, current_uri);
+        return Ok(Err(Redirect::to(uri!(crate::login(Some(uri))))));
+    };
+
+    let request = AuthnRequest::from_redirect(&query.saml_request)
+        .map_err(|err| WartIDError::InvalidForm(format!("SAMLRequest: {err}")))?;
+
+    sso(idp, session, locale, db, request, query.relay_state)
+        .await
+        .map(Ok)
+}
+
+/// SP-initiated SSO, HTTP-POST binding
+#[post("/saml/sso", data = "<query>")]
+pub async fn sso_post(
+    config: &State<Config>,
+    idp: &State<Option<SamlIdp>>,
+    session: Option<&LoginSession>,
+    locale: Locale,
+    db: DbConn,
+    query: Form<SsoQuery>,
+) -> WartIDResult<Result<Ructe, Redirect>> {
+    let Some(idp) = idp.inner() else {
+        return Err(WartIDError::SamlError("SAML is not enabled"));
+    };
+
+    let Some(session) = session else {
+        // The request can't survive the trip through the login page as a POST body, so it's
+        // carried in the query string of the HTTP-Redirect binding instead
+        let saml_request = AuthnRequest::post_to_redirect(&query.saml_request)
+            .map_err(|err| WartIDError::InvalidForm(format!("SAMLRequest: {err}")))?;
+
+        let mut uri = format!(
+            "{}saml/sso?SAMLRequest={}",
+            config.base_url,
+            RawStr::new(&saml_request).percent_encode()
+        );
+        if let Some(relay_state) = &query.relay_state {
+            uri += "&RelayState=";
+            uri += RawStr::new(relay_state).percent_encode().as_str();
+        }
+
+        return Ok(Err(Redirect::to(uri!(crate::login(Some(uri))))));
+    };
+
+    let query = query.into_inner();
+    let request = AuthnRequest::from_post(&query.saml_request)
+        .map_err(|err| WartIDError::InvalidForm(format!("SAMLRequest: {err}")))?;
+
+    sso(idp, session, locale, db, request, query.relay_state)
+        .await
+        .map(Ok)
+}
+
+async fn sso(
+    idp: &SamlIdp,
+    session: &LoginSession,
+    locale: Locale,
+    db: DbConn,
+    request: AuthnRequest,
+    relay_state: Option<String>,
+) -> WartIDResult<Ructe> {
+    let issuer = request.issuer.clone();
+    let app = db_await!(UserApp::find_by_saml_entity_id(db, &issuer))?
+        .filter(UserApp::is_saml_enabled)
+        .ok_or(WartIDError::SamlError("unknown service provider"))?;
+
+    let (Some(entity_id), Some(acs_url)) = (&app.saml_entity_id, &app.saml_acs_url) else {
+        unreachable!()
+    };
+
+    if request
+        .acs_url
+        .as_ref()
+        .is_some_and(|requested| requested != acs_url)
+    {
+        return Err(WartIDError::SamlError(
+            "assertion consumer service URL is not configured",
+        ));
+    }
+
+    let (app_id, user_id) = (app.id, session.user.id);
+    if !db_await!(UserApp::is_user_allowed(db, app_id, user_id))? {
+        return Err(WartIDError::SamlError(
+            "you aren't in any of the groups allowed to use this service provider",
+        ));
+    }
+
+    let response = idp.response(
+        &request,
+        &ServiceProvider { entity_id, acs_url },
+        &session.user,
+    );
+
+    // TODO X-Frame-Options: Deny
+
+    Ok(render!(saml::post_html(
+        locale,
+        &app,
+        acs_url,
+        &response,
+        relay_state.as_deref()
+    )))
+}
@@ -0,0 +1,400 @@
+//! ### SCIM 2.0 provisioning
+//!
+//! `/scim/v2/Users` and `/scim/v2/Groups`, authenticated with the SCIM token of a WartApp. The apps
+//! listed in `scim_readers` can read, the ones listed in `scim_provisioners` can also write. Tokens
+//! of the other apps are refused.
+
+use std::sync::Arc;
+
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, State};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::discord::{DiscordAgent, Notification};
+use crate::scim::{
+    self, Filter, GroupRequest, Operation, Resources, ScimError, ScimResponse, UserRequest,
+};
+
+use super::prelude::*;
+
+type ScimResult = Result<ScimResponse, ScimError>;
+
+/// WartApp authenticated with its SCIM token
+pub struct ScimClient {
+    can_write: bool,
+}
+
+impl ScimClient {
+    fn check_write(&self) -> Result<(), ScimError> {
+        if self.can_write {
+            Ok(())
+        } else {
+            Err(ScimError::new(
+                Status::Forbidden,
+                "this WartApp is not allowed to provision users",
+            ))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ScimClient {
+    type Error = ScimError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let unauthorized = |detail| {
+            Outcome::Failure((
+                Status::Unauthorized,
+                ScimError::new(Status::Unauthorized, detail),
+            ))
+        };
+
+        let Some(token) = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .filter(|token| token.starts_with(UserApp::SCIM_TOKEN_PREFIX))
+        else {
+            return unauthorized("missing SCIM token");
+        };
+
+        let db: DbConn = request.guard().await.unwrap();
+        let config: &State<Config> = request.guard().await.unwrap();
+
+        let token = token.to_owned();
+        let app = match db_await!(UserApp::find_by_scim_token(db, &token)) {
+            Ok(Some(app)) => app,
+            Ok(None) => return unauthorized("invalid SCIM token"),
+            Err(err) => {
+                let err = ScimError::from(err);
+                return Outcome::Failure((Status::InternalServerError, err));
+            }
+        };
+
+        let can_write = config.scim_provisioners.contains(&app.id);
+        if !can_write && !config.scim_readers.contains(&app.id) {
+            let err = ScimError::new(Status::Forbidden, "this WartApp is not allowed to use SCIM");
+            return Outcome::Failure((Status::Forbidden, err));
+        }
+
+        Outcome::Success(ScimClient { can_write })
+    }
+}
+
+#[derive(FromForm)]
+pub struct ListQuery {
+    filter: Option<String>,
+    #[field(name = "startIndex")]
+    start_index: Option<usize>,
+    count: Option<usize>,
+}
+
+async fn read_json(data: Data<'_>) -> Result<Value, ScimError> {
+    let body = data
+        .open(1.mebibytes())
+        .into_string()
+        .await
+        .map_err(|_| ScimError::bad_request("invalidSyntax", "cannot read body"))?;
+
+    if !body.is_complete() {
+        return Err(ScimError::new(Status::PayloadTooLarge, "body too large"));
+    }
+
+    serde_json::from_str(&body).map_err(|_| ScimError::bad_request("invalidSyntax", "invalid JSON"))
+}
+
+async fn load(config: &Config, db: &DbConn) -> Result<Resources, ScimError> {
+    let base_url = config.base_url.clone();
+    Ok(db_await!(Resources::load(db, base_url))?)
+}
+
+fn list(resources: &[Value], query: ListQuery) -> ScimResult {
+    let filter = query.filter.as_deref().map(Filter::parse).transpose()?;
+
+    Ok(ScimResponse::ok(scim::list_response(
+        resources,
+        filter.as_ref(),
+        query.start_index.unwrap_or(1),
+        query.count.unwrap_or(scim::MAX_RESULTS),
+    )))
+}
+
+/// Applies a PATCH request to the current state of a resource
+fn patch(resource: Option<Value>, body: &Value) -> Result<Value, ScimError> {
+    let mut resource = resource.ok_or_else(ScimError::not_found)?;
+
+    for operation in Operation::parse_all(body)? {
+        operation.apply(&mut resource)?;
+    }
+
+    Ok(resource)
+}
+
+#[get("/scim/v2/ServiceProviderConfig")]
+pub fn service_provider_config(config: &State<Config>) -> ScimResponse {
+    ScimResponse::ok(scim::service_provider_config(&config.base_url))
+}
+
+#[get("/scim/v2/Users?<query..>")]
+pub async fn users_list(
+    config: &State<Config>,
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    query: ListQuery,
+) -> ScimResult {
+    client?;
+
+    list(&load(config, &db).await?.users, query)
+}
+
+#[get("/scim/v2/Users/<id>")]
+pub async fn users_get(
+    config: &State<Config>,
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+) -> ScimResult {
+    client?;
+
+    let resources = load(config, &db).await?;
+    let user = Resources::find(&resources.users, id).ok_or_else(ScimError::not_found)?;
+
+    Ok(ScimResponse::ok(user))
+}
+
+#[post("/scim/v2/Users", data = "<data>")]
+pub async fn users_create(
+    config: &State<Config>,
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    data: Data<'_>,
+) -> ScimResult {
+    client?.check_write()?;
+
+    let request = UserRequest::parse(&read_json(data).await?)?;
+
+    let user = db
+        .run(move |db| {
+            User::insert_local(
+                db,
+                request.username,
+                request.email,
+                request.password.as_deref(),
+            )
+        })
+        .await?;
+
+    let resources = load(config, &db).await?;
+    let user =
+        Resources::find(&resources.users, &user.id.to_string()).ok_or_else(ScimError::not_found)?;
+
+    Ok(ScimResponse::created(user))
+}
+
+async fn users_save(
+    config: &Config,
+    db: DbConn,
+    discord_agent: &Option<Arc<DiscordAgent>>,
+    user_id: UserId,
+    request: UserRequest,
+) -> ScimResult {
+    let password_changed = db
+        .run(move |db| {
+            User::replace(db, user_id, &request.username, request.email.as_deref())?;
+            if let Some(password) = &request.password {
+                User::update_password(db, user_id, password)?;
+            }
+            Ok::<_, WartIDError>(request.password.is_some())
+        })
+        .await?;
+    if password_changed {
+        crate::discord::notify(discord_agent, user_id, Notification::PasswordChange);
+    }
+
+    let resources = load(config, &db).await?;
+    let user =
+        Resources::find(&resources.users, &user_id.to_string()).ok_or_else(ScimError::not_found)?;
+
+    Ok(ScimResponse::ok(user))
+}
+
+#[put("/scim/v2/Users/<id>", data = "<data>")]
+pub async fn users_replace(
+    config: &State<Config>,
+    db: DbConn,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    data: Data<'_>,
+) -> ScimResult {
+    client?.check_write()?;
+
+    let user_id = id.parse().map_err(|_| ScimError::not_found())?;
+    let request = UserRequest::parse(&read_json(data).await?)?;
+
+    users_save(config, db, discord_agent, user_id, request).await
+}
+
+#[patch("/scim/v2/Users/<id>", data = "<data>")]
+pub async fn users_patch(
+    config: &State<Config>,
+    db: DbConn,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    data: Data<'_>,
+) -> ScimResult {
+    client?.check_write()?;
+
+    let user_id = id.parse().map_err(|_| ScimError::not_found())?;
+    let body = read_json(data).await?;
+
+    let resources = load(config, &db).await?;
+    let user = patch(Resources::find(&resources.users, id), &body)?;
+
+    users_save(
+        config,
+        db,
+        discord_agent,
+        user_id,
+        UserRequest::parse(&user)?,
+    )
+    .await
+}
+
+#[delete("/scim/v2/Users/<id>")]
+pub async fn users_delete(
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+) -> ScimResult {
+    client?.check_write()?;
+
+    let user_id = id.parse().map_err(|_| ScimError::not_found())?;
+
+    if db_await!(User::delete(db, user_id))? {
+        Ok(ScimResponse::no_content())
+    } else {
+        Err(ScimError::not_found())
+    }
+}
+
+#[get("/scim/v2/Groups?<query..>")]
+pub async fn groups_list(
+    config: &State<Config>,
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    query: ListQuery,
+) -> ScimResult {
+    client?;
+
+    list(&load(config, &db).await?.groups, query)
+}
+
+#[get("/scim/v2/Groups/<id>")]
+pub async fn groups_get(
+    config: &State<Config>,
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+) -> ScimResult {
+    client?;
+
+    let resources = load(config, &db).await?;
+    let group = Resources::find(&resources.groups, id).ok_or_else(ScimError::not_found)?;
+
+    Ok(ScimResponse::ok(group))
+}
+
+#[post("/scim/v2/Groups", data = "<data>")]
+pub async fn groups_create(
+    config: &State<Config>,
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    data: Data<'_>,
+) -> ScimResult {
+    client?.check_write()?;
+
+    let request = GroupRequest::parse(&read_json(data).await?)?;
+
+    let group = db
+        .run(move |db| Group::insert(db, &request.name, &request.members))
+        .await?;
+
+    let resources = load(config, &db).await?;
+    let group = Resources::find(&resources.groups, &group.id.to_string())
+        .ok_or_else(ScimError::not_found)?;
+
+    Ok(ScimResponse::created(group))
+}
+
+async fn groups_save(
+    config: &Config,
+    db: DbConn,
+    group_id: GroupId,
+    request: GroupRequest,
+) -> ScimResult {
+    db.run(move |db| Group::replace(db, group_id, &request.name, &request.members))
+        .await?;
+
+    let resources = load(config, &db).await?;
+    let group = Resources::find(&resources.groups, &group_id.to_string())
+        .ok_or_else(ScimError::not_found)?;
+
+    Ok(ScimResponse::ok(group))
+}
+
+#[put("/scim/v2/Groups/<id>", data = "<data>")]
+pub async fn groups_replace(
+    config: &State<Config>,
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    data: Data<'_>,
+) -> ScimResult {
+    client?.check_write()?;
+
+    let group_id = id.parse().map_err(|_| ScimError::not_found())?;
+    let request = GroupRequest::parse(&read_json(data).await?)?;
+
+    groups_save(config, db, group_id, request).await
+}
+
+#[patch("/scim/v2/Groups/<id>", data = "<data>")]
+pub async fn groups_patch(
+    config: &State<Config>,
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    data: Data<'_>,
+) -> ScimResult {
+    client?.check_write()?;
+
+    let group_id = id.parse().map_err(|_| ScimError::not_found())?;
+    let body = read_json(data).await?;
+
+    let resources = load(config, &db).await?;
+    let group = patch(Resources::find(&resources.groups, id), &body)?;
+
+    groups_save(config, db, group_id, GroupRequest::parse(&group)?).await
+}
+
+#[delete("/scim/v2/Groups/<id>")]
+pub async fn groups_delete(
+    db: DbConn,
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+) -> ScimResult {
+    client?.check_write()?;
+
+    let group_id = id.parse().map_err(|_| ScimError::not_found())?;
+
+    if db_await!(Group::delete(db, group_id))? {
+        Ok(ScimResponse::no_content())
+    } else {
+        Err(ScimError::not_found())
+    }
+}
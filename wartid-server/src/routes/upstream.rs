@@ -0,0 +1,273 @@
+//! ### Upstream OpenID Connect login
+//!
+//! Same flow as [Discord's](super::discord), for the providers configured in
+//! [Config::upstream_oidc]. Accounts that aren't linked yet only create a new user if the provider
+//! [allows it](crate::config::UpstreamOidcConfig::create_users), otherwise users must log in
+//! another way and [link] them from their profile.
+
+use std::sync::Arc;
+
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::State;
+
+use super::prelude::*;
+use crate::config::Config;
+use crate::discord::{DiscordAgent, LoginMethod, Notification};
+use crate::i18n::Locale;
+use crate::utils::jwt::JWT;
+use crate::utils::upstream::{UpstreamProvider, UpstreamProviders};
+
+/// Binds the `state` to the browser that started the login, see [super::discord]
+const NONCE_COOKIE: &str = "upstream_login_nonce";
+
+lazy_static::lazy_static! {
+    static ref JWT_STATE: JWT<LoginState, LoginState> = JWT::new("wartid-upstream-login", chrono::Duration::minutes(10));
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct LoginState {
+    nonce: String,
+
+    /// ID of the provider, so a `state` can't be replayed on another one
+    provider: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    redirect_to: Option<String>,
+
+    /// User to link the account to, instead of logging in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link: Option<UserId>,
+}
+
+type LoginError = Result<(Status, Cow<'static, str>), WartIDError>;
+
+fn not_found(locale: Locale) -> LoginError {
+    Ok((
+        Status::NotFound,
+        Cow::Borrowed(locale.t("login-error.unknown-provider")),
+    ))
+}
+
+fn unavailable(locale: Locale, provider: &UpstreamProvider) -> LoginError {
+    Ok((
+        Status::BadGateway,
+        Cow::Owned(locale.format(
+            "login-error.unreachable",
+            &[("provider", &provider.config.name)],
+        )),
+    ))
+}
+
+/// Name and URL of the login buttons
+pub fn login_buttons(providers: &UpstreamProviders, then: Option<&str>) -> Vec<(String, String)> {
+    providers
+        .iter()
+        .map(|provider| {
+            let id = &provider.config.id;
+            let url = uri!(login(provider = id, redirect_to = then)).to_string();
+            (provider.config.name.clone(), url)
+        })
+        .collect()
+}
+
+/// Name and URL of the buttons linking an account of the providers the user has none of
+pub fn link_buttons(
+    providers: &UpstreamProviders,
+    identities: &[FederatedIdentity],
+) -> Vec<(String, String)> {
+    providers
+        .iter()
+        .filter(|provider| {
+            !identities
+                .iter()
+                .any(|identity| identity.provider == provider.config.id)
+        })
+        .map(|provider| {
+            let url = uri!(link(provider = &provider.config.id)).to_string();
+            (provider.config.name.clone(), url)
+        })
+        .collect()
+}
+
+#[get("/login/oidc/<provider>?<redirect_to>")]
+pub async fn login(
+    config: &State<Config>,
+    providers: &State<UpstreamProviders>,
+    cookies: &CookieJar<'_>,
+    locale: Locale,
+    provider: &str,
+    redirect_to: Option<String>,
+) -> Result<Redirect, LoginError> {
+    let provider = providers.get(provider).ok_or_else(|| not_found(locale))?;
+
+    authorize_redirect(config, locale, provider, cookies, redirect_to, None).await
+}
+
+#[get("/login/oidc/<provider>/link")]
+pub async fn link(
+    config: &State<Config>,
+    providers: &State<UpstreamProviders>,
+    session: &LoginSession,
+    cookies: &CookieJar<'_>,
+    locale: Locale,
+    provider: &str,
+) -> Result<Redirect, LoginError> {
+    let provider = providers.get(provider).ok_or_else(|| not_found(locale))?;
+
+    authorize_redirect(
+        config,
+        locale,
+        provider,
+        cookies,
+        None,
+        Some(session.user.id),
+    )
+    .await
+}
+
+async fn authorize_redirect(
+    config: &Config,
+    locale: Locale,
+    provider: &UpstreamProvider,
+    cookies: &CookieJar<'_>,
+    redirect_to: Option<String>,
+    link: Option<UserId>,
+) -> Result<Redirect, LoginError> {
+    let nonce = crate::utils::gen_alphanumeric(32);
+    let state = JWT_STATE.encode(LoginState {
+        nonce: nonce.clone(),
+        provider: provider.config.id.clone(),
+        redirect_to,
+        link,
+    });
+
+    let url = provider
+        .authorize_url(&provider.redirect_uri(&config.base_url), &state)
+        .await
+        .map_err(|err| {
+            log::error!("cannot start logging in with {}: {err}", provider.config.id);
+            unavailable(locale, provider)
+        })?;
+
+    let mut cookie = Cookie::new(NONCE_COOKIE, nonce);
+    // The provider redirects back with a cross-site navigation
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_max_age(time::Duration::minutes(10));
+    cookies.add(cookie);
+
+    Ok(Redirect::to(url))
+}
+
+#[derive(FromForm)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: String,
+    error: Option<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/login/oidc/<provider>/callback?<query..>")]
+pub async fn callback(
+    config: &State<Config>,
+    providers: &State<UpstreamProviders>,
+    db: DbConn,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
+    session: Option<&LoginSession>,
+    cookies: &CookieJar<'_>,
+    locale: Locale,
+    provider: &str,
+    query: CallbackQuery,
+) -> Result<Redirect, LoginError> {
+    let CallbackQuery { code, state, error } = query;
+    let forbidden = |msg: String| Err(Ok((Status::Forbidden, Cow::Owned(msg))));
+
+    let provider = providers.get(provider).ok_or_else(|| not_found(locale))?;
+    let name = &provider.config.name;
+
+    let nonce = cookies
+        .get(NONCE_COOKIE)
+        .map(|cookie| cookie.value().to_owned());
+    cookies.remove(Cookie::named(NONCE_COOKIE));
+
+    let state = match JWT_STATE.decode(&state) {
+        Ok(state)
+            if Some(&state.nonce) == nonce.as_ref() && state.provider == provider.config.id =>
+        {
+            state
+        }
+        _ => return forbidden(locale.t("login-error.state-expired").to_owned()),
+    };
+
+    let code = match (code, error) {
+        (Some(code), None) => code,
+        (_, Some(error)) if error == "access_denied" => {
+            return forbidden(locale.format("login-error.cancelled", &[("provider", name)]))
+        }
+        _ => {
+            return Err(Ok((
+                Status::BadGateway,
+                Cow::Owned(locale.format("login-error.refused", &[("provider", name)])),
+            )))
+        }
+    };
+
+    let redirect_uri = provider.redirect_uri(&config.base_url);
+    let account = match provider.fetch_account(&redirect_uri, &code).await {
+        Ok(account) => account,
+        Err(err) => {
+            log::error!("cannot fetch the {} account: {err}", provider.config.id);
+            return Err(unavailable(locale, provider));
+        }
+    };
+
+    let provider_id = provider.config.id.clone();
+
+    if let Some(user_id) = state.link {
+        // Only the user who started linking can finish it
+        if session.map(|session| session.user.id) != Some(user_id) {
+            return forbidden(locale.t("login-error.link-other-account").to_owned());
+        }
+
+        let subject = account.subject;
+        if !db_await!(FederatedIdentity::link(db, user_id, &provider_id, &subject)).map_err(Err)? {
+            return forbidden(locale.format("login-error.already-linked", &[("provider", name)]));
+        }
+
+        return Ok(Redirect::to("/@me"));
+    }
+
+    let subject = account.subject;
+    let username = Some(account.username).filter(|_| provider.config.create_users);
+    let user = db_await!(FederatedIdentity::login(
+        db,
+        &provider_id,
+        &subject,
+        username
+    ))
+    .map_err(Err)?;
+    let Some(user) = user else {
+        return Err(Ok((
+            Status::Conflict,
+            Cow::Owned(locale.format("login-error.not-linked", &[("provider", name)])),
+        )));
+    };
+    if user.is_suspended() {
+        return forbidden(locale.t("login-error.suspended").to_owned());
+    }
+
+    let user_id = user.id;
+    let session_id = db_await!(Session::insert(db, NewSession::new(user_id))).map_err(Err)?;
+    crate::discord::notify(
+        discord_agent,
+        user_id,
+        Notification::Login {
+            method: LoginMethod::Provider(provider.config.name.clone()),
+        },
+    );
+
+    let mut cookie = crate::login_session_cookie(config, session_id.to_string());
+    cookie.set_max_age(crate::SESSION_COOKIE_EXPIRATION);
+    cookies.add(cookie);
+
+    Ok(crate::after_login(config, state.redirect_to))
+}
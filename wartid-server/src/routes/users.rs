@@ -1,9 +1,18 @@
+use std::sync::Arc;
+
+use diesel::Connection;
 use rocket::form::error::ErrorKind;
 use rocket::form::{DataField, FromForm, Options, ValueField};
 use rocket::request::FromParam;
+use rocket::State;
 use uuid::Error as UuidError;
 
 use super::prelude::*;
+use crate::config::Config;
+use crate::discord::{DiscordAgent, Notification};
+use crate::i18n::Locale;
+use crate::ructe_types::UserSettings;
+use crate::utils::upstream::UpstreamProviders;
 
 pub struct UuidParamWithAt(UserId);
 
@@ -33,9 +42,73 @@ pub fn view_me(session: &LoginSession) -> Redirect {
     Redirect::to(format!("/@{}", session.user.id))
 }
 
+async fn view_render(
+    ctx: &PageContext,
+    db: &DbConn,
+    config: &Config,
+    providers: &UpstreamProviders,
+    user: &User,
+    is_me: bool,
+    new_token: Option<&str>,
+) -> WartIDResult<Ructe> {
+    let (tokens, identities, notifications) = if is_me {
+        let user_id = user.id;
+        (
+            db_await!(PersonalAccessToken::find_all_by_user(db, user_id))?,
+            db_await!(FederatedIdentity::find_all_by_user(db, user_id))?,
+            db_await!(NotificationKind::find_enabled(db, user_id))?,
+        )
+    } else {
+        (Vec::new(), Vec::new(), Vec::new())
+    };
+
+    let can_discord_2fa = config.discord.is_some()
+        && identities
+            .iter()
+            .any(|identity| identity.provider == FederatedIdentity::DISCORD);
+
+    let mut links = super::upstream::link_buttons(providers, &identities);
+    if let Some(discord_link) = super::discord::link_url(config) {
+        let is_linked = identities
+            .iter()
+            .any(|identity| identity.provider == FederatedIdentity::DISCORD);
+        if !is_linked {
+            links.insert(0, (String::from("Discord"), discord_link));
+        }
+    }
+
+    let identities = identities
+        .into_iter()
+        .map(|identity| {
+            let name = if identity.provider == FederatedIdentity::DISCORD {
+                String::from("Discord")
+            } else {
+                providers
+                    .get(&identity.provider)
+                    .map(|provider| provider.config.name.clone())
+                    // Removed from the configuration, it can still be unlinked
+                    .unwrap_or_else(|| identity.provider.clone())
+            };
+            (identity, name)
+        })
+        .collect::<Vec<_>>();
+
+    let settings = UserSettings {
+        tokens: &tokens,
+        new_token,
+        identities: &identities,
+        links: &links,
+        notifications: &notifications,
+        can_discord_2fa,
+    };
+    Ok(render!(panel::user_view_html(ctx; user, is_me, &settings)))
+}
+
 #[get("/<user_id>")]
 pub async fn view(
     ctx: PageContext,
+    config: &State<Config>,
+    providers: &State<UpstreamProviders>,
     session: &LoginSession,
     db: DbConn,
     user_id: UuidParamWithAt,
@@ -48,11 +121,17 @@ pub async fn view(
         Err(err) => return Err(err),
     };
 
-    Ok(Some(render!(panel::user_view_html(
-        &ctx;
+    view_render(
+        &ctx,
+        &db,
+        config,
+        providers,
         &user,
-        session.user.id == user_id
-    ))))
+        session.user.id == user_id,
+        None,
+    )
+    .await
+    .map(Some)
 }
 
 #[derive(Debug)]
@@ -60,6 +139,19 @@ pub enum FormUpdateIntent {
     UpdateName(String),
     UpdateEmail(String),
     UpdatePassword(String),
+    UpdateProfile {
+        picture: String,
+        locale: String,
+    },
+    CreateToken {
+        name: String,
+        scopes: Vec<String>,
+        days: i64,
+    },
+    RevokeToken(PersonalAccessTokenId),
+    UnlinkIdentity(String),
+    UpdateNotifications(Vec<NotificationKind>),
+    UpdateDiscord2fa(bool),
 }
 
 #[derive(FromForm)]
@@ -67,14 +159,42 @@ pub struct FormUpdateIntentRaw {
     name: Option<String>,
     email: Option<String>,
     password: Option<String>,
+    picture: Option<String>,
+    locale: Option<String>,
+    #[field(name = "token-name")]
+    token_name: Option<String>,
+    #[field(name = "token-scope")]
+    token_scopes: Vec<String>,
+    #[field(name = "token-days")]
+    token_days: Option<i64>,
+    #[field(name = "token-id")]
+    token_id: Option<PersonalAccessTokenId>,
+    #[field(name = "identity-provider")]
+    identity_provider: Option<String>,
+    #[field(name = "notification")]
+    notifications: Vec<String>,
+    #[field(name = "discord-2fa")]
+    discord_2fa: bool,
 
     // Buttons (mutually exclusive)
-    #[field(name = "update-name", default = false)]
+    #[field(name = "update-name")]
     update_name: bool,
-    #[field(name = "update-email", default = false)]
+    #[field(name = "update-email")]
     update_email: bool,
-    #[field(name = "update-password", default = false)]
+    #[field(name = "update-password")]
     oauth_password: bool,
+    #[field(name = "update-profile")]
+    update_profile: bool,
+    #[field(name = "create-token")]
+    create_token: bool,
+    #[field(name = "revoke-token")]
+    revoke_token: bool,
+    #[field(name = "unlink-identity")]
+    unlink_identity: bool,
+    #[field(name = "update-notifications")]
+    update_notifications: bool,
+    #[field(name = "update-2fa")]
+    update_2fa: bool,
 }
 
 #[rocket::async_trait]
@@ -104,34 +224,226 @@ impl<'r> FromForm<'r> for FormUpdateIntent {
                 name: Some(name),
                 email: None,
                 password: None,
+                picture: None,
+                locale: None,
+                token_name: None,
+                token_scopes: _,
+                token_days: None,
+                token_id: None,
+                identity_provider: None,
+                notifications: _,
+                discord_2fa: _,
                 update_name: true,
                 update_email: false,
                 oauth_password: false,
+                update_profile: false,
+                create_token: false,
+                revoke_token: false,
+                unlink_identity: false,
+                update_notifications: false,
+                update_2fa: false,
             } => FormUpdateIntent::UpdateName(name),
             FormUpdateIntentRaw {
                 name: None,
                 email: Some(email),
                 password: None,
+                picture: None,
+                locale: None,
+                token_name: None,
+                token_scopes: _,
+                token_days: None,
+                token_id: None,
+                identity_provider: None,
+                notifications: _,
+                discord_2fa: _,
                 update_name: false,
                 update_email: true,
                 oauth_password: false,
+                update_profile: false,
+                create_token: false,
+                revoke_token: false,
+                unlink_identity: false,
+                update_notifications: false,
+                update_2fa: false,
             } => FormUpdateIntent::UpdateEmail(email),
             FormUpdateIntentRaw {
                 name: None,
                 email: None,
                 password: Some(password),
+                picture: None,
+                locale: None,
+                token_name: None,
+                token_scopes: _,
+                token_days: None,
+                token_id: None,
+                identity_provider: None,
+                notifications: _,
+                discord_2fa: _,
                 update_name: false,
                 update_email: false,
                 oauth_password: true,
+                update_profile: false,
+                create_token: false,
+                revoke_token: false,
+                unlink_identity: false,
+                update_notifications: false,
+                update_2fa: false,
             } => FormUpdateIntent::UpdatePassword(password),
+            FormUpdateIntentRaw {
+                name: None,
+                email: None,
+                password: None,
+                picture: Some(picture),
+                locale: Some(locale),
+                token_name: None,
+                token_scopes: _,
+                token_days: None,
+                token_id: None,
+                identity_provider: None,
+                notifications: _,
+                discord_2fa: _,
+                update_name: false,
+                update_email: false,
+                oauth_password: false,
+                update_profile: true,
+                create_token: false,
+                revoke_token: false,
+                unlink_identity: false,
+                update_notifications: false,
+                update_2fa: false,
+            } => FormUpdateIntent::UpdateProfile { picture, locale },
+            FormUpdateIntentRaw {
+                name: None,
+                email: None,
+                password: None,
+                picture: None,
+                locale: None,
+                token_name: Some(name),
+                token_scopes: scopes,
+                token_days: Some(days),
+                token_id: None,
+                identity_provider: None,
+                notifications: _,
+                discord_2fa: _,
+                update_name: false,
+                update_email: false,
+                oauth_password: false,
+                update_profile: false,
+                create_token: true,
+                revoke_token: false,
+                unlink_identity: false,
+                update_notifications: false,
+                update_2fa: false,
+            } => FormUpdateIntent::CreateToken { name, scopes, days },
+            FormUpdateIntentRaw {
+                name: None,
+                email: None,
+                password: None,
+                picture: None,
+                locale: None,
+                token_name: None,
+                token_scopes: _,
+                token_days: None,
+                token_id: Some(token_id),
+                identity_provider: None,
+                notifications: _,
+                discord_2fa: _,
+                update_name: false,
+                update_email: false,
+                oauth_password: false,
+                update_profile: false,
+                create_token: false,
+                revoke_token: true,
+                unlink_identity: false,
+                update_notifications: false,
+                update_2fa: false,
+            } => FormUpdateIntent::RevokeToken(token_id),
+            FormUpdateIntentRaw {
+                name: None,
+                email: None,
+                password: None,
+                picture: None,
+                locale: None,
+                token_name: None,
+                token_scopes: _,
+                token_days: None,
+                token_id: None,
+                identity_provider: Some(provider),
+                notifications: _,
+                discord_2fa: _,
+                update_name: false,
+                update_email: false,
+                oauth_password: false,
+                update_profile: false,
+                create_token: false,
+                revoke_token: false,
+                unlink_identity: true,
+                update_notifications: false,
+                update_2fa: false,
+            } => FormUpdateIntent::UnlinkIdentity(provider),
+            FormUpdateIntentRaw {
+                name: None,
+                email: None,
+                password: None,
+                picture: None,
+                locale: None,
+                token_name: None,
+                token_scopes: _,
+                token_days: None,
+                token_id: None,
+                identity_provider: None,
+                notifications,
+                discord_2fa: _,
+                update_name: false,
+                update_email: false,
+                oauth_password: false,
+                update_profile: false,
+                create_token: false,
+                revoke_token: false,
+                unlink_identity: false,
+                update_notifications: true,
+                update_2fa: false,
+            } => FormUpdateIntent::UpdateNotifications(
+                notifications
+                    .iter()
+                    .filter_map(|kind| NotificationKind::parse(kind))
+                    .collect(),
+            ),
+            FormUpdateIntentRaw {
+                name: None,
+                email: None,
+                password: None,
+                picture: None,
+                locale: None,
+                token_name: None,
+                token_scopes: _,
+                token_days: None,
+                token_id: None,
+                identity_provider: None,
+                notifications: _,
+                discord_2fa,
+                update_name: false,
+                update_email: false,
+                oauth_password: false,
+                update_profile: false,
+                create_token: false,
+                revoke_token: false,
+                unlink_identity: false,
+                update_notifications: false,
+                update_2fa: true,
+            } => FormUpdateIntent::UpdateDiscord2fa(discord_2fa),
             _ => Err(ErrorKind::Duplicate)?,
         })
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 #[post("/<user_id>", data = "<data>")]
 pub async fn view_update(
     mut ctx: PageContext,
+    config: &State<Config>,
+    providers: &State<UpstreamProviders>,
+    discord_agent: &State<Option<Arc<DiscordAgent>>>,
     session: &LoginSession,
     db: DbConn,
     user_id: UuidParamWithAt,
@@ -145,53 +457,175 @@ pub async fn view_update(
         )));
     }
 
+    let mut new_token = None;
+
     let (user, success_message) = match data.into_inner() {
         FormUpdateIntent::UpdateName(name) => {
             if name.len() < 3 {
-                ctx.add_flash_message(
-                    Cow::Borrowed("Le nom doit faire minimum 3 caractères."),
-                    true,
-                );
-                return Ok(render!(panel::user_view_html(&ctx; &session.user, true)));
+                ctx.add_flash_message("users.name-too-short", true);
+                return view_render(&ctx, &db, config, providers, &session.user, true, None).await;
             };
 
             (
                 db_await!(User::update_username(db, user_id, &name))?,
-                "Nom mis à jour avec succès !",
+                "users.name-updated",
             )
         }
         FormUpdateIntent::UpdateEmail(email) => {
             // TODO real verification
             if email.len() < 6 && !email.contains('@') {
-                ctx.add_flash_message(
-                    Cow::Borrowed("Merci de rentrer une adresse e-mail valide."),
-                    true,
-                );
-                return Ok(render!(panel::user_view_html(&ctx; &session.user, true)));
+                ctx.add_flash_message("users.invalid-email", true);
+                return view_render(&ctx, &db, config, providers, &session.user, true, None).await;
             };
 
             (
                 db_await!(User::update_email(db, user_id, &email))?,
-                "Adresse e-mail mise à jour avec succès !",
+                "users.email-updated",
             )
         }
         FormUpdateIntent::UpdatePassword(password) => {
-            if password.len() < 8 {
-                ctx.add_flash_message(
-                    Cow::Borrowed("Le mot de passe doit faire minimum 8 caractères."),
-                    true,
-                );
-                return Ok(render!(panel::user_view_html(&ctx; &session.user, true)));
+            if password.len() < User::MIN_PASSWORD_LEN {
+                ctx.add_flash_message("users.password-too-short", true);
+                return view_render(&ctx, &db, config, providers, &session.user, true, None).await;
+            };
+
+            let user = db_await!(User::update_password(db, user_id, &password))?;
+            crate::discord::notify(discord_agent, user_id, Notification::PasswordChange);
+
+            (user, "users.password-updated")
+        }
+        FormUpdateIntent::UpdateProfile { picture, locale } => {
+            let picture = Some(picture.trim()).filter(|picture| !picture.is_empty());
+            let locale = Some(locale.trim()).filter(|locale| !locale.is_empty());
+
+            let error = if picture.is_some_and(|picture| !User::is_valid_picture(picture)) {
+                Some("users.invalid-picture")
+            } else if locale.is_some_and(|locale| !User::is_valid_locale(locale)) {
+                Some("users.invalid-locale")
+            } else {
+                None
+            };
+
+            if let Some(error) = error {
+                ctx.add_flash_message(error, true);
+                return view_render(&ctx, &db, config, providers, &session.user, true, None).await;
+            }
+
+            let picture = picture.map(str::to_string);
+            let locale = locale.map(str::to_string);
+            let user = db_await!(User::update_profile(
+                db,
+                user_id,
+                picture.as_deref(),
+                locale.as_deref()
+            ))?;
+
+            // The page is shown in the new language right away
+            if let Some(locale) = user.locale.as_deref().and_then(Locale::parse) {
+                ctx.locale = locale;
+            }
+
+            (user, "users.profile-updated")
+        }
+        FormUpdateIntent::CreateToken { name, scopes, days } => {
+            let scopes = scopes.join(" ").parse::<OAuth2Scopes>();
+
+            let error = match (&scopes, days) {
+                _ if name.trim().is_empty() => Some("users.token-unnamed"),
+                (Err(()), _) => Some("users.unknown-scopes"),
+                (_, days) if !(1..=365).contains(&days) => Some("users.invalid-token-days"),
+                _ => None,
             };
 
+            if let Some(error) = error {
+                ctx.add_flash_message(error, true);
+                return view_render(&ctx, &db, config, providers, &session.user, true, None).await;
+            }
+
+            let scopes = scopes.unwrap();
+            let (_, token) = db_await!(PersonalAccessToken::insert(
+                db,
+                user_id,
+                &name,
+                &scopes,
+                chrono::Duration::days(days)
+            ))?;
+            new_token = Some(token);
+
+            (session.user.clone(), "users.token-created")
+        }
+        FormUpdateIntent::RevokeToken(token_id) => {
+            if !db_await!(PersonalAccessToken::revoke(db, user_id, token_id))? {
+                ctx.add_flash_message("users.unknown-token", true);
+                return view_render(&ctx, &db, config, providers, &session.user, true, None).await;
+            }
+
+            (session.user.clone(), "users.token-revoked")
+        }
+        FormUpdateIntent::UnlinkIdentity(provider) => {
+            // Groups granted by Discord roles couldn't be kept in sync anymore
+            let managed = config
+                .discord
+                .iter()
+                .filter(|_| provider == FederatedIdentity::DISCORD)
+                .flat_map(|discord| &discord.role_groups)
+                .map(|mapping| mapping.group.clone())
+                .collect::<Vec<_>>();
+
+            let unlinked = db
+                .run(move |db| {
+                    db.transaction(|db| {
+                        if !FederatedIdentity::unlink(db, user_id, &provider)? {
+                            return Ok(None);
+                        }
+
+                        let managed = managed.iter().map(String::as_str).collect::<Vec<_>>();
+                        Group::sync_memberships(db, user_id, &managed, &[])?;
+                        User::find_by_id(db, user_id)
+                    })
+                })
+                .await?;
+
+            let Some(user) = unlinked else {
+                ctx.add_flash_message("users.unlink-needs-password", true);
+                return view_render(&ctx, &db, config, providers, &session.user, true, None).await;
+            };
+
+            (user, "users.identity-unlinked")
+        }
+        FormUpdateIntent::UpdateDiscord2fa(enabled) => {
+            let is_linked = db_await!(FederatedIdentity::find_discord_id(db, user_id))?.is_some();
+            if enabled && (!is_linked || discord_agent.is_none()) {
+                ctx.add_flash_message("users.2fa-unlinked", true);
+                return view_render(&ctx, &db, config, providers, &session.user, true, None).await;
+            }
+
             (
-                db_await!(User::update_password(db, user_id, &password))?,
-                "Mot de passe mis à jour avec succès !",
+                db_await!(User::set_discord_2fa(db, user_id, enabled))?,
+                if enabled {
+                    "users.2fa-enabled"
+                } else {
+                    "users.2fa-disabled"
+                },
             )
         }
+        FormUpdateIntent::UpdateNotifications(enabled) => {
+            db_await!(NotificationKind::set_enabled(db, user_id, &enabled))?;
+
+            (session.user.clone(), "users.notifications-updated")
+        }
     };
 
-    ctx.add_flash_message(Cow::Borrowed(success_message), false);
+    ctx.add_flash_message(success_message, false);
 
-    Ok(render!(panel::user_view_html(&ctx; &user, true)))
+    view_render(
+        &ctx,
+        &db,
+        config,
+        providers,
+        &user,
+        true,
+        new_token.as_deref(),
+    )
+    .await
 }
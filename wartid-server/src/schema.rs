@@ -1,3 +1,46 @@
+table! {
+    federated_identities (provider, subject) {
+        provider -> Varchar,
+        subject -> Varchar,
+        users_id -> Uuid,
+        last_login -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    groups (id) {
+        id -> Uuid,
+        name -> Varchar,
+        description -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    groups_members (groups_id, users_id) {
+        groups_id -> Uuid,
+        users_id -> Uuid,
+    }
+}
+
+table! {
+    notification_opt_outs (users_id, kind) {
+        users_id -> Uuid,
+        kind -> Varchar,
+    }
+}
+
+table! {
+    personal_access_tokens (id) {
+        id -> Uuid,
+        users_id -> Uuid,
+        name -> Varchar,
+        token_hash -> Varchar,
+        scopes -> Varchar,
+        expiration -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     sessions (id) {
         id -> Uuid,
@@ -13,6 +56,7 @@ table! {
         user_apps_id -> Uuid,
         initial_scopes -> Varchar,
         expiration -> Timestamp,
+        claims -> Varchar,
     }
 }
 
@@ -20,10 +64,25 @@ table! {
     user_apps (id) {
         id -> Uuid,
         name -> Varchar,
-        oauth_secret -> Nullable<Varchar>,
+        oauth_secret_hash -> Nullable<Varchar>,
         oauth_redirect -> Varchar,
         description -> Nullable<Varchar>,
         hidden -> Bool,
+        oauth_auth_method -> Nullable<Varchar>,
+        oauth_jwks -> Nullable<Varchar>,
+        oauth_secret_previous_hash -> Nullable<Varchar>,
+        saml_entity_id -> Nullable<Varchar>,
+        saml_acs_url -> Nullable<Varchar>,
+        scim_token_hash -> Nullable<Varchar>,
+        oauth_userinfo_alg -> Nullable<Varchar>,
+        oauth_secret_encrypted -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    user_apps_groups (user_apps_id, groups_id) {
+        user_apps_id -> Uuid,
+        groups_id -> Uuid,
     }
 }
 
@@ -40,20 +99,68 @@ table! {
         username -> Varchar,
         password -> Nullable<Varchar>,
         email -> Nullable<Varchar>,
-        discord_id -> Nullable<Int8>,
+        picture -> Nullable<Varchar>,
+        locale -> Nullable<Varchar>,
+        email_verified -> Bool,
+        updated -> Timestamp,
+        suspended -> Nullable<Timestamp>,
+        discord_2fa -> Bool,
+        suspended_by -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Uuid,
+        user_apps_id -> Uuid,
+        url -> Varchar,
+        secret -> Varchar,
+        events -> Varchar,
+    }
+}
+
+table! {
+    webhooks_deliveries (id) {
+        id -> Uuid,
+        webhooks_id -> Uuid,
+        event -> Varchar,
+        payload -> Varchar,
+        created -> Timestamp,
+        attempts -> Int4,
+        next_attempt -> Nullable<Timestamp>,
+        delivered -> Nullable<Timestamp>,
+        last_status -> Nullable<Int4>,
+        last_error -> Nullable<Varchar>,
     }
 }
 
+joinable!(federated_identities -> users (users_id));
+joinable!(groups_members -> groups (groups_id));
+joinable!(groups_members -> users (users_id));
+joinable!(notification_opt_outs -> users (users_id));
+joinable!(personal_access_tokens -> users (users_id));
 joinable!(sessions -> users (users_id));
 joinable!(sessions_oauth2 -> user_apps (user_apps_id));
 joinable!(sessions_oauth2 -> users (users_id));
+joinable!(user_apps_groups -> groups (groups_id));
+joinable!(user_apps_groups -> user_apps (user_apps_id));
 joinable!(user_apps_managers -> user_apps (user_apps_id));
 joinable!(user_apps_managers -> users (users_id));
+joinable!(webhooks -> user_apps (user_apps_id));
+joinable!(webhooks_deliveries -> webhooks (webhooks_id));
 
 allow_tables_to_appear_in_same_query!(
+    federated_identities,
+    groups,
+    groups_members,
+    notification_opt_outs,
+    personal_access_tokens,
     sessions,
     sessions_oauth2,
     user_apps,
+    user_apps_groups,
     user_apps_managers,
     users,
+    webhooks,
+    webhooks_deliveries,
 );
@@ -0,0 +1,407 @@
+use serde_json::Value;
+
+use super::{attribute, ScimError};
+
+/// Attribute path like `userName` or `emails.value`, without its schema URN prefix
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttrPath {
+    pub attribute: String,
+    pub sub_attribute: Option<String>,
+}
+
+impl AttrPath {
+    fn parse(path: &str) -> Option<Self> {
+        // Fully qualified paths look like `urn:ietf:params:scim:schemas:core:2.0:User:userName`
+        let path = match path.get(..4) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("urn:") => path.rsplit_once(':')?.1,
+            _ => path,
+        };
+
+        let (attribute, sub_attribute) = match path.split_once('.') {
+            Some((attribute, sub_attribute)) => (attribute, Some(sub_attribute)),
+            None => (path, None),
+        };
+
+        let valid_sub_attribute = match sub_attribute {
+            Some(sub_attribute) => is_attribute_name(sub_attribute),
+            None => true,
+        };
+        if !is_attribute_name(attribute) || !valid_sub_attribute {
+            return None;
+        }
+
+        Some(Self {
+            attribute: attribute.to_owned(),
+            sub_attribute: sub_attribute.map(str::to_owned),
+        })
+    }
+
+    /// Every non-null value at this path. The `value` sub-attribute of complex multi-valued
+    /// attributes is implied when none is given, so `emails eq "a@b.c"` works.
+    fn values<'a>(&self, resource: &'a Value) -> Vec<&'a Value> {
+        let values = match attribute(resource, &self.attribute) {
+            Some(Value::Array(values)) => values.iter().collect(),
+            Some(value) => vec![value],
+            None => Vec::new(),
+        };
+
+        values
+            .into_iter()
+            .filter_map(|value| match (&self.sub_attribute, value) {
+                (Some(sub_attribute), value) => attribute(value, sub_attribute),
+                (None, Value::Object(_)) => attribute(value, "value"),
+                (None, value) => Some(value),
+            })
+            .filter(|value| !value.is_null())
+            .collect()
+    }
+}
+
+fn is_attribute_name(name: &str) -> bool {
+    name == "$ref"
+        || (name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Co,
+    Sw,
+    Ew,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Operator {
+    fn parse(operator: &str) -> Option<Self> {
+        Some(match operator.to_ascii_lowercase().as_str() {
+            "eq" => Self::Eq,
+            "ne" => Self::Ne,
+            "co" => Self::Co,
+            "sw" => Self::Sw,
+            "ew" => Self::Ew,
+            "gt" => Self::Gt,
+            "ge" => Self::Ge,
+            "lt" => Self::Lt,
+            "le" => Self::Le,
+            _ => return None,
+        })
+    }
+
+    /// Strings are compared case-insensitively, which is right for every attribute exposed
+    fn compare(self, candidate: &Value, value: &Value) -> bool {
+        use std::cmp::Ordering;
+
+        let ordering = match (candidate, value) {
+            (Value::String(candidate), Value::String(value)) => {
+                let (candidate, value) = (candidate.to_lowercase(), value.to_lowercase());
+                match self {
+                    Self::Co => return candidate.contains(&value),
+                    Self::Sw => return candidate.starts_with(&value),
+                    Self::Ew => return candidate.ends_with(&value),
+                    _ => candidate.cmp(&value),
+                }
+            }
+            (Value::Number(candidate), Value::Number(value)) => {
+                match candidate.as_f64().partial_cmp(&value.as_f64()) {
+                    Some(ordering) => ordering,
+                    None => return false,
+                }
+            }
+            (candidate, value) => {
+                return match self {
+                    Self::Eq => candidate == value,
+                    Self::Ne => candidate != value,
+                    _ => false,
+                }
+            }
+        };
+
+        match self {
+            Self::Eq => ordering == Ordering::Equal,
+            Self::Ne => ordering != Ordering::Equal,
+            Self::Gt => ordering == Ordering::Greater,
+            Self::Ge => ordering != Ordering::Less,
+            Self::Lt => ordering == Ordering::Less,
+            Self::Le => ordering != Ordering::Greater,
+            Self::Co | Self::Sw | Self::Ew => false,
+        }
+    }
+}
+
+/// Filter from the `filter` query parameter or a PATCH path (RFC 7644 §3.4.2.2), evaluated on
+/// the JSON representation of resources
+#[derive(Debug, PartialEq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Present(AttrPath),
+    Compare(AttrPath, Operator, Value),
+
+    /// Filter on the values of a multi-valued attribute, like `emails[type eq "work"]`
+    ValuePath(String, Box<Filter>),
+}
+
+impl Filter {
+    pub fn parse(filter: &str) -> Result<Self, ScimError> {
+        let mut parser = Parser::new(filter)?;
+        let filter = parser.or()?;
+
+        match parser.tokens.get(parser.position) {
+            None => Ok(filter),
+            Some(_) => Err(invalid_filter()),
+        }
+    }
+
+    pub fn matches(&self, resource: &Value) -> bool {
+        match self {
+            Self::And(a, b) => a.matches(resource) && b.matches(resource),
+            Self::Or(a, b) => a.matches(resource) || b.matches(resource),
+            Self::Not(filter) => !filter.matches(resource),
+            Self::Present(path) => path.values(resource).into_iter().any(|value| match value {
+                Value::String(value) => !value.is_empty(),
+                Value::Array(values) => !values.is_empty(),
+                _ => true,
+            }),
+            Self::Compare(path, operator, value) => path
+                .values(resource)
+                .into_iter()
+                .any(|candidate| operator.compare(candidate, value)),
+            Self::ValuePath(name, filter) => match attribute(resource, name) {
+                Some(Value::Array(values)) => values.iter().any(|value| filter.matches(value)),
+                Some(value) => filter.matches(value),
+                None => false,
+            },
+        }
+    }
+}
+
+/// Target of a PATCH operation, like `members[value eq "..."].display`
+#[derive(Debug, PartialEq)]
+pub struct PatchPath {
+    pub attribute: String,
+    pub filter: Option<Filter>,
+    pub sub_attribute: Option<String>,
+}
+
+impl PatchPath {
+    pub fn parse(path: &str) -> Result<Self, ScimError> {
+        let invalid_path = || ScimError::bad_request("invalidPath", "invalid PATCH path");
+
+        let mut parser = Parser::new(path).map_err(|_| invalid_path())?;
+
+        let Some(Token::Word(attribute)) = parser.next() else {
+            return Err(invalid_path());
+        };
+        let attribute = AttrPath::parse(&attribute).ok_or_else(invalid_path)?;
+
+        if parser.next_if(&Token::OpenBracket).is_none() {
+            return match parser.next() {
+                None => Ok(Self {
+                    attribute: attribute.attribute,
+                    filter: None,
+                    sub_attribute: attribute.sub_attribute,
+                }),
+                Some(_) => Err(invalid_path()),
+            };
+        }
+
+        if attribute.sub_attribute.is_some() {
+            return Err(invalid_path());
+        }
+
+        let filter = parser.or().map_err(|_| invalid_path())?;
+        parser
+            .next_if(&Token::CloseBracket)
+            .ok_or_else(invalid_path)?;
+
+        let sub_attribute = match parser.next() {
+            None => None,
+            Some(Token::Word(sub_attribute)) => match sub_attribute.strip_prefix('.') {
+                Some(sub_attribute) if is_attribute_name(sub_attribute) => {
+                    Some(sub_attribute.to_owned())
+                }
+                _ => return Err(invalid_path()),
+            },
+            Some(_) => return Err(invalid_path()),
+        };
+
+        match parser.next() {
+            None => Ok(Self {
+                attribute: attribute.attribute,
+                filter: Some(filter),
+                sub_attribute,
+            }),
+            Some(_) => Err(invalid_path()),
+        }
+    }
+}
+
+fn invalid_filter() -> ScimError {
+    ScimError::bad_request("invalidFilter", "invalid filter")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    String(String),
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn new(input: &str) -> Result<Self, ScimError> {
+        let mut tokens = Vec::new();
+        let mut chars = input.char_indices().peekable();
+
+        while let Some((start, c)) = chars.next() {
+            match c {
+                c if c.is_whitespace() => {}
+                '(' => tokens.push(Token::OpenParen),
+                ')' => tokens.push(Token::CloseParen),
+                '[' => tokens.push(Token::OpenBracket),
+                ']' => tokens.push(Token::CloseBracket),
+                '"' => {
+                    let mut escaped = false;
+                    let end = loop {
+                        match chars.next() {
+                            Some((_, '\\')) if !escaped => escaped = true,
+                            Some((end, '"')) if !escaped => break end,
+                            Some(_) => escaped = false,
+                            None => return Err(invalid_filter()),
+                        }
+                    };
+
+                    let string =
+                        serde_json::from_str(&input[start..=end]).map_err(|_| invalid_filter())?;
+                    tokens.push(Token::String(string));
+                }
+                _ => {
+                    let mut end = start + c.len_utf8();
+                    while let Some(&(index, c)) = chars.peek() {
+                        if c.is_whitespace() || "()[]\"".contains(c) {
+                            break;
+                        }
+                        end = index + c.len_utf8();
+                        chars.next();
+                    }
+                    tokens.push(Token::Word(input[start..end].to_owned()));
+                }
+            }
+        }
+
+        Ok(Self {
+            tokens,
+            position: 0,
+        })
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_if(&mut self, expected: &Token) -> Option<()> {
+        if self.tokens.get(self.position) == Some(expected) {
+            self.position += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn next_keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.position) {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Filter, ScimError> {
+        let mut filter = self.and()?;
+        while self.next_keyword("or") {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, ScimError> {
+        let mut filter = self.unary()?;
+        while self.next_keyword("and") {
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter, ScimError> {
+        if self.next_keyword("not") {
+            self.next_if(&Token::OpenParen).ok_or_else(invalid_filter)?;
+            let filter = self.or()?;
+            self.next_if(&Token::CloseParen)
+                .ok_or_else(invalid_filter)?;
+            return Ok(Filter::Not(Box::new(filter)));
+        }
+
+        match self.next() {
+            Some(Token::OpenParen) => {
+                let filter = self.or()?;
+                self.next_if(&Token::CloseParen)
+                    .ok_or_else(invalid_filter)?;
+                Ok(filter)
+            }
+            Some(Token::Word(path)) => {
+                let path = AttrPath::parse(&path).ok_or_else(invalid_filter)?;
+
+                if self.next_if(&Token::OpenBracket).is_some() {
+                    if path.sub_attribute.is_some() {
+                        return Err(invalid_filter());
+                    }
+
+                    let filter = self.or()?;
+                    self.next_if(&Token::CloseBracket)
+                        .ok_or_else(invalid_filter)?;
+                    return Ok(Filter::ValuePath(path.attribute, Box::new(filter)));
+                }
+
+                if self.next_keyword("pr") {
+                    return Ok(Filter::Present(path));
+                }
+
+                let Some(Token::Word(operator)) = self.next() else {
+                    return Err(invalid_filter());
+                };
+                let operator = Operator::parse(&operator).ok_or_else(invalid_filter)?;
+
+                let value = match self.next() {
+                    Some(Token::String(value)) => Value::String(value),
+                    Some(Token::Word(value)) => match serde_json::from_str(&value) {
+                        Ok(value @ (Value::Bool(_) | Value::Null | Value::Number(_))) => value,
+                        _ => return Err(invalid_filter()),
+                    },
+                    _ => return Err(invalid_filter()),
+                };
+
+                Ok(Filter::Compare(path, operator, value))
+            }
+            _ => Err(invalid_filter()),
+        }
+    }
+}
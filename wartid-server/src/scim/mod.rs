@@ -0,0 +1,478 @@
+//! SCIM 2.0 (RFC 7643 / RFC 7644) representation of WartID users and groups
+//!
+//! Lets downstream apps keep their accounts in sync with WartID. Users are exposed with `userName`,
+//! `displayName`, `emails` and `groups`, groups with `displayName` and `members`. Resources are
+//! built in memory from the whole directory and filtered there, WartID is not expected to hold more
+//! than a few thousand users.
+
+use std::borrow::Cow;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::Responder;
+use rocket::{Request, Response};
+use serde_json::{json, Value};
+
+use crate::model::{Group, GroupId, User, UserId, WartIDError, WartIDResult};
+
+pub use filter::Filter;
+pub use patch::Operation;
+
+mod filter;
+mod patch;
+
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+const LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const PATCH_OP_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const SERVICE_PROVIDER_CONFIG_SCHEMA: &str =
+    "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+
+/// Most resources returned by a single list request
+pub const MAX_RESULTS: usize = 200;
+
+/// Attribute names are case-insensitive
+fn attribute<'a>(resource: &'a Value, name: &str) -> Option<&'a Value> {
+    resource
+        .as_object()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+/// Error message (RFC 7644 §3.12)
+#[derive(Debug)]
+pub struct ScimError {
+    status: Status,
+    scim_type: Option<&'static str>,
+    detail: Cow<'static, str>,
+}
+
+impl ScimError {
+    pub fn new(status: Status, detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            status,
+            scim_type: None,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn bad_request(scim_type: &'static str, detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            status: Status::BadRequest,
+            scim_type: Some(scim_type),
+            detail: detail.into(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(Status::NotFound, "resource not found")
+    }
+}
+
+impl From<WartIDError> for ScimError {
+    fn from(err: WartIDError) -> Self {
+        use diesel::result::{DatabaseErrorKind, Error};
+
+        match err {
+            WartIDError::Database(Error::NotFound) => Self::not_found(),
+            WartIDError::Database(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                Self {
+                    status: Status::Conflict,
+                    scim_type: Some("uniqueness"),
+                    detail: Cow::Borrowed("name already taken"),
+                }
+            }
+            WartIDError::Database(Error::DatabaseError(
+                DatabaseErrorKind::ForeignKeyViolation,
+                _,
+            )) => Self::bad_request("invalidValue", "unknown member"),
+            err => {
+                log::error!("SCIM request failed: {err}");
+                Self::new(Status::InternalServerError, "internal error")
+            }
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ScimError {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut body = json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": self.status.code.to_string(),
+            "detail": self.detail,
+        });
+        if let Some(scim_type) = self.scim_type {
+            body["scimType"] = scim_type.into();
+        }
+
+        let mut response = ScimResponse::new(self.status, body).respond_to(request)?;
+        if self.status == Status::Unauthorized {
+            response.set_header(Header::new("WWW-Authenticate", "Bearer"));
+        }
+        Ok(response)
+    }
+}
+
+/// JSON body sent as `application/scim+json`
+pub struct ScimResponse {
+    status: Status,
+    body: Option<Value>,
+    location: Option<String>,
+}
+
+impl ScimResponse {
+    pub fn new(status: Status, body: Value) -> Self {
+        Self {
+            status,
+            body: Some(body),
+            location: None,
+        }
+    }
+
+    pub fn ok(body: Value) -> Self {
+        Self::new(Status::Ok, body)
+    }
+
+    /// 201 pointing to the new resource
+    pub fn created(resource: Value) -> Self {
+        Self {
+            location: attribute(&resource, "meta")
+                .and_then(|meta| attribute(meta, "location"))
+                .and_then(Value::as_str)
+                .map(str::to_owned),
+            ..Self::new(Status::Created, resource)
+        }
+    }
+
+    pub fn no_content() -> Self {
+        Self {
+            status: Status::NoContent,
+            body: None,
+            location: None,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ScimResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut response = Response::build();
+        response.status(self.status);
+
+        if let Some(body) = self.body {
+            response.merge(body.to_string().respond_to(request)?);
+            response.header(ContentType::new("application", "scim+json"));
+        }
+        if let Some(location) = self.location {
+            response.header(Header::new("Location", location));
+        }
+
+        response.ok()
+    }
+}
+
+/// Every user and group, rendered as SCIM resources
+pub struct Resources {
+    pub users: Vec<Value>,
+    pub groups: Vec<Value>,
+}
+
+impl Resources {
+    pub fn load(db: crate::DbConnection, base_url: String) -> WartIDResult<Self> {
+        let mut users = User::find_all(db, true)?;
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        let groups = Group::find_all(db)?;
+        let memberships = Group::find_all_memberships(db)?;
+
+        let user_location = |user: UserId| format!("{base_url}scim/v2/Users/{user}");
+        let group_location = |group: GroupId| format!("{base_url}scim/v2/Groups/{group}");
+
+        let users_json = users
+            .iter()
+            .map(|user| {
+                let user_groups = groups
+                    .iter()
+                    .filter(|group| memberships.contains(&(group.id, user.id)))
+                    .map(|group| {
+                        json!({
+                            "value": group.id,
+                            "display": group.name,
+                            "$ref": group_location(group.id),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                let emails = user
+                    .email
+                    .iter()
+                    .map(|email| json!({ "value": email, "primary": true }))
+                    .collect::<Vec<_>>();
+
+                json!({
+                    "schemas": [USER_SCHEMA],
+                    "id": user.id,
+                    "userName": user.username,
+                    "displayName": user.username,
+                    "active": true,
+                    "emails": emails,
+                    "groups": user_groups,
+                    "meta": {
+                        "resourceType": "User",
+                        "location": user_location(user.id),
+                    },
+                })
+            })
+            .collect();
+
+        let groups_json = groups
+            .iter()
+            .map(|group| {
+                let members = users
+                    .iter()
+                    .filter(|user| memberships.contains(&(group.id, user.id)))
+                    .map(|user| {
+                        json!({
+                            "value": user.id,
+                            "display": user.username,
+                            "type": "User",
+                            "$ref": user_location(user.id),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                json!({
+                    "schemas": [GROUP_SCHEMA],
+                    "id": group.id,
+                    "displayName": group.name,
+                    "members": members,
+                    "meta": {
+                        "resourceType": "Group",
+                        "location": group_location(group.id),
+                    },
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            users: users_json,
+            groups: groups_json,
+        })
+    }
+
+    pub fn find(resources: &[Value], id: &str) -> Option<Value> {
+        resources
+            .iter()
+            .find(|resource| resource["id"] == id)
+            .cloned()
+    }
+}
+
+/// Paginated search result. `start_index` starts at 1.
+pub fn list_response(
+    resources: &[Value],
+    filter: Option<&Filter>,
+    start_index: usize,
+    count: usize,
+) -> Value {
+    let matching = resources
+        .iter()
+        .filter(|resource| match filter {
+            Some(filter) => filter.matches(resource),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+
+    let start_index = start_index.max(1);
+    let page = matching
+        .iter()
+        .skip(start_index - 1)
+        .take(count.min(MAX_RESULTS))
+        .collect::<Vec<_>>();
+
+    json!({
+        "schemas": [LIST_RESPONSE_SCHEMA],
+        "totalResults": matching.len(),
+        "startIndex": start_index,
+        "itemsPerPage": page.len(),
+        "Resources": page,
+    })
+}
+
+pub fn service_provider_config(base_url: &str) -> Value {
+    json!({
+        "schemas": [SERVICE_PROVIDER_CONFIG_SCHEMA],
+        "patch": { "supported": true },
+        "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+        "filter": { "supported": true, "maxResults": MAX_RESULTS },
+        "changePassword": { "supported": true },
+        "sort": { "supported": false },
+        "etag": { "supported": false },
+        "authenticationSchemes": [{
+            "type": "oauthbearertoken",
+            "name": "SCIM token",
+            "description": "Bearer token generated from the page of the WartApp",
+            "primary": true,
+        }],
+        "meta": {
+            "resourceType": "ServiceProviderConfig",
+            "location": format!("{base_url}scim/v2/ServiceProviderConfig"),
+        },
+    })
+}
+
+/// Writable attributes of a user, from a POST or PUT body
+pub struct UserRequest {
+    pub username: String,
+    pub email: Option<String>,
+
+    /// Only changed when given
+    pub password: Option<String>,
+}
+
+impl UserRequest {
+    pub fn parse(resource: &Value) -> Result<Self, ScimError> {
+        let username = match attribute(resource, "userName") {
+            Some(Value::String(username)) if is_valid_name(username) => username.trim().to_owned(),
+            _ => {
+                return Err(ScimError::bad_request(
+                    "invalidValue",
+                    "userName must be between 1 and 64 characters long",
+                ))
+            }
+        };
+
+        // The primary address, or the first one
+        let emails = match attribute(resource, "emails") {
+            Some(Value::Array(emails)) => emails.iter().collect(),
+            None | Some(Value::Null) => Vec::new(),
+            Some(_) => return Err(ScimError::bad_request("invalidValue", "invalid emails")),
+        };
+        let email = emails
+            .iter()
+            .find(|email| attribute(email, "primary") == Some(&Value::Bool(true)))
+            .or(emails.first())
+            .and_then(|email| attribute(email, "value"))
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|email| !email.is_empty())
+            .map(str::to_owned);
+
+        if attribute(resource, "active") == Some(&Value::Bool(false)) {
+            return Err(ScimError::bad_request(
+                "invalidValue",
+                "users cannot be deactivated, delete them instead",
+            ));
+        }
+
+        let password = match attribute(resource, "password") {
+            Some(Value::String(password)) if !password.is_empty() => Some(password.clone()),
+            None | Some(Value::Null) => None,
+            Some(_) => return Err(ScimError::bad_request("invalidValue", "invalid password")),
+        };
+
+        Ok(Self {
+            username,
+            email,
+            password,
+        })
+    }
+}
+
+/// Writable attributes of a group, from a POST or PUT body
+pub struct GroupRequest {
+    pub name: String,
+    pub members: Vec<UserId>,
+}
+
+impl GroupRequest {
+    pub fn parse(resource: &Value) -> Result<Self, ScimError> {
+        let name = match attribute(resource, "displayName") {
+            Some(Value::String(name)) if is_valid_name(name) => name.trim().to_owned(),
+            _ => {
+                return Err(ScimError::bad_request(
+                    "invalidValue",
+                    "displayName must be between 1 and 64 characters long",
+                ))
+            }
+        };
+
+        let members = match attribute(resource, "members") {
+            Some(Value::Array(members)) => members
+                .iter()
+                .map(|member| {
+                    attribute(member, "value")
+                        .and_then(Value::as_str)
+                        .and_then(|id| id.parse().ok())
+                        .ok_or_else(|| ScimError::bad_request("invalidValue", "invalid member"))
+                })
+                .collect::<Result<_, _>>()?,
+            None | Some(Value::Null) => Vec::new(),
+            Some(_) => return Err(ScimError::bad_request("invalidValue", "invalid members")),
+        };
+
+        Ok(Self { name, members })
+    }
+}
+
+/// Usernames and group names are limited to 64 characters in the database
+fn is_valid_name(name: &str) -> bool {
+    let name = name.trim();
+    !name.is_empty() && name.chars().count() <= 64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_and_patch() {
+        let mut user = json!({
+            "userName": "Alice",
+            "emails": [{ "value": "alice@example.com", "type": "work", "primary": true }],
+            "groups": [{ "value": "1", "display": "wiki" }],
+        });
+
+        let filter = |filter| Filter::parse(filter).unwrap().matches(&user);
+        assert!(filter(r#"username eq "alice""#));
+        assert!(filter(
+            r#"urn:ietf:params:scim:schemas:core:2.0:User:userName sw "al""#
+        ));
+        assert!(filter(
+            r#"emails[type eq "work" and value ew "@example.com"]"#
+        ));
+        assert!(filter(
+            r#"emails eq "ALICE@example.com" and not (groups.display eq "ops")"#
+        ));
+        assert!(!filter(r#"userName eq "bob" or title pr"#));
+        assert!(Filter::parse(r#"userName eq"#).is_err());
+        assert!(Filter::parse(r#"userName eq "alice" )"#).is_err());
+
+        let patch = json!({
+            "schemas": [PATCH_OP_SCHEMA],
+            "Operations": [
+                { "op": "Replace", "value": { "userName": "alice2", "name.givenName": "Alice" } },
+                { "op": "add", "path": "emails[type eq \"home\"].value", "value": "a@home.org" },
+                { "op": "remove", "path": "emails[type eq \"work\"]" },
+                { "op": "add", "path": "groups", "value": [{ "value": "2" }, { "value": "1" }] },
+                { "op": "remove", "path": "groups", "value": [{ "value": "1" }] },
+            ],
+        });
+        for operation in Operation::parse_all(&patch).unwrap() {
+            operation.apply(&mut user).unwrap();
+        }
+
+        assert_eq!(
+            user,
+            json!({
+                "userName": "alice2",
+                "name": { "givenName": "Alice" },
+                "emails": [{ "type": "home", "value": "a@home.org" }],
+                "groups": [{ "value": "2" }],
+            })
+        );
+
+        let request = UserRequest::parse(&user).unwrap();
+        assert_eq!(request.username, "alice2");
+        assert_eq!(request.email.as_deref(), Some("a@home.org"));
+    }
+}
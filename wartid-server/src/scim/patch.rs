@@ -0,0 +1,276 @@
+use serde_json::{Map, Value};
+
+use super::filter::{Filter, Operator, PatchPath};
+use super::{attribute, ScimError, PATCH_OP_SCHEMA};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Add,
+    Remove,
+    Replace,
+}
+
+/// One of the operations of a PATCH request (RFC 7644 §3.5.2)
+///
+/// They are applied on the JSON representation of the resource, which is then saved like it would
+/// have been with a PUT. That way read-only attributes are ignored the same way in both cases.
+#[derive(Debug)]
+pub struct Operation {
+    op: Op,
+    path: Option<PatchPath>,
+    value: Option<Value>,
+}
+
+impl Operation {
+    /// Parses the `Operations` of a `PatchOp` message
+    pub fn parse_all(body: &Value) -> Result<Vec<Self>, ScimError> {
+        let is_patch_op = attribute(body, "schemas")
+            .and_then(Value::as_array)
+            .is_some_and(|schemas| schemas.iter().any(|schema| schema == PATCH_OP_SCHEMA));
+        if !is_patch_op {
+            return Err(ScimError::bad_request(
+                "invalidSyntax",
+                "expected a PatchOp message",
+            ));
+        }
+
+        let Some(Value::Array(operations)) = attribute(body, "Operations") else {
+            return Err(ScimError::bad_request(
+                "invalidSyntax",
+                "missing Operations",
+            ));
+        };
+
+        operations
+            .iter()
+            .map(|operation| {
+                // Some clients send capitalized operations
+                let op = match attribute(operation, "op").and_then(Value::as_str) {
+                    Some(op) if op.eq_ignore_ascii_case("add") => Op::Add,
+                    Some(op) if op.eq_ignore_ascii_case("remove") => Op::Remove,
+                    Some(op) if op.eq_ignore_ascii_case("replace") => Op::Replace,
+                    _ => {
+                        return Err(ScimError::bad_request(
+                            "invalidSyntax",
+                            "unknown PATCH operation",
+                        ))
+                    }
+                };
+
+                let path = match attribute(operation, "path") {
+                    Some(Value::String(path)) => Some(PatchPath::parse(path)?),
+                    None | Some(Value::Null) => None,
+                    Some(_) => return Err(ScimError::bad_request("invalidPath", "invalid path")),
+                };
+
+                let value = attribute(operation, "value").cloned();
+                if op != Op::Remove && value.is_none() {
+                    return Err(ScimError::bad_request("invalidValue", "missing value"));
+                }
+
+                Ok(Self { op, path, value })
+            })
+            .collect()
+    }
+
+    pub fn apply(&self, resource: &mut Value) -> Result<(), ScimError> {
+        let Value::Object(resource) = resource else {
+            return Err(ScimError::bad_request("invalidValue", "not an object"));
+        };
+
+        if self.path.is_some() {
+            return self.apply_path(resource);
+        }
+
+        if self.op == Op::Remove {
+            return Err(ScimError::bad_request("noTarget", "remove needs a path"));
+        }
+
+        // Without a path, the value holds the attributes to add or replace, whose names may be
+        // paths themselves
+        let Some(Value::Object(attributes)) = &self.value else {
+            return Err(ScimError::bad_request(
+                "invalidValue",
+                "expected an object of attributes",
+            ));
+        };
+
+        for (path, value) in attributes {
+            Operation {
+                op: self.op,
+                path: Some(PatchPath::parse(path)?),
+                value: Some(value.clone()),
+            }
+            .apply_path(resource)?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_path(&self, resource: &mut Map<String, Value>) -> Result<(), ScimError> {
+        let path = self.path.as_ref().expect("apply_path without a path");
+        let key = key(resource, &path.attribute);
+
+        match (&path.filter, &path.sub_attribute, &self.value) {
+            (None, None, Some(value)) if self.op == Op::Add => add(resource, key, value.clone()),
+            (None, None, Some(value)) if self.op == Op::Replace => {
+                resource.insert(key, value.clone());
+            }
+            // Removing some values from a multi-valued attribute
+            (None, None, Some(Value::Array(removed))) => {
+                if let Some(Value::Array(values)) = resource.get_mut(&key) {
+                    values.retain(|value| !removed.iter().any(|removed| same(value, removed)));
+                }
+            }
+            (None, None, _) => {
+                resource.remove(&key);
+            }
+            (None, Some(sub_attribute), value) => match resource.get_mut(&key) {
+                Some(Value::Array(values)) => {
+                    for value_object in values.iter_mut().filter_map(Value::as_object_mut) {
+                        self.apply_sub_attribute(value_object, sub_attribute, value);
+                    }
+                }
+                Some(Value::Object(value_object)) => {
+                    self.apply_sub_attribute(value_object, sub_attribute, value);
+                }
+                _ if self.op != Op::Remove => {
+                    let mut value_object = Map::new();
+                    self.apply_sub_attribute(&mut value_object, sub_attribute, value);
+                    resource.insert(key, Value::Object(value_object));
+                }
+                _ => {}
+            },
+            (Some(filter), sub_attribute, value) => {
+                let values = match resource
+                    .entry(key)
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                {
+                    Value::Array(values) => values,
+                    _ => {
+                        return Err(ScimError::bad_request(
+                            "invalidFilter",
+                            "filters only apply to multi-valued attributes",
+                        ))
+                    }
+                };
+
+                let matching = values.iter().filter(|value| filter.matches(value)).count();
+
+                if self.op == Op::Remove && sub_attribute.is_none() {
+                    values.retain(|value| !filter.matches(value));
+                } else if matching > 0 {
+                    for value_object in values
+                        .iter_mut()
+                        .filter(|value| filter.matches(value))
+                        .filter_map(Value::as_object_mut)
+                    {
+                        match (sub_attribute, value) {
+                            (Some(sub_attribute), value) => {
+                                self.apply_sub_attribute(value_object, sub_attribute, value)
+                            }
+                            (None, Some(Value::Object(value))) => {
+                                if self.op == Op::Replace {
+                                    value_object.clear();
+                                }
+                                value_object.extend(value.clone());
+                            }
+                            (None, _) => {
+                                return Err(ScimError::bad_request(
+                                    "invalidValue",
+                                    "expected an object",
+                                ))
+                            }
+                        }
+                    }
+                } else if self.op == Op::Add {
+                    // Adding `emails[type eq "work"].value` to a user without e-mail creates it
+                    let mut value_object = filter_template(filter);
+                    match (sub_attribute, value) {
+                        (Some(sub_attribute), Some(value)) => {
+                            value_object.insert(sub_attribute.clone(), value.clone());
+                        }
+                        (None, Some(Value::Object(value))) => value_object.extend(value.clone()),
+                        _ => return Err(ScimError::bad_request("invalidValue", "invalid value")),
+                    }
+                    values.push(Value::Object(value_object));
+                } else if self.op == Op::Replace {
+                    return Err(ScimError::bad_request("noTarget", "no value matches"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_sub_attribute(
+        &self,
+        value_object: &mut Map<String, Value>,
+        sub_attribute: &str,
+        value: &Option<Value>,
+    ) {
+        let key = key(value_object, sub_attribute);
+        match (self.op, value) {
+            (Op::Remove, _) | (_, None) => {
+                value_object.remove(&key);
+            }
+            (_, Some(value)) => {
+                value_object.insert(key, value.clone());
+            }
+        }
+    }
+}
+
+/// Name of the existing attribute matching `name` case-insensitively, or `name` itself
+fn key(resource: &Map<String, Value>, name: &str) -> String {
+    resource
+        .keys()
+        .find(|key| key.eq_ignore_ascii_case(name))
+        .unwrap_or(&name.to_owned())
+        .clone()
+}
+
+/// Adds values to a multi-valued attribute, or sets a single-valued one
+fn add(resource: &mut Map<String, Value>, key: String, value: Value) {
+    match (resource.get_mut(&key), value) {
+        (Some(Value::Array(values)), Value::Array(added)) => {
+            for added in added {
+                if !values.iter().any(|value| same(value, &added)) {
+                    values.push(added);
+                }
+            }
+        }
+        (Some(Value::Array(values)), added) => {
+            if !values.iter().any(|value| same(value, &added)) {
+                values.push(added);
+            }
+        }
+        (_, value) => {
+            resource.insert(key, value);
+        }
+    }
+}
+
+/// Values of multi-valued attributes are identified by their `value` sub-attribute
+fn same(a: &Value, b: &Value) -> bool {
+    match (attribute(a, "value"), attribute(b, "value")) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Sub-attributes set by the equality tests of a filter, so the value created by an `add` with a
+/// filter would match it
+fn filter_template(filter: &Filter) -> Map<String, Value> {
+    match filter {
+        Filter::Compare(path, Operator::Eq, value) if path.sub_attribute.is_none() => {
+            Map::from_iter([(path.attribute.clone(), value.clone())])
+        }
+        Filter::And(a, b) => {
+            let mut template = filter_template(a);
+            template.extend(filter_template(b));
+            template
+        }
+        _ => Map::new(),
+    }
+}
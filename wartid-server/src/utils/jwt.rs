@@ -5,14 +5,17 @@ use jsonwebtoken::*;
 use serde::{de::DeserializeOwned, Serialize};
 
 #[cfg(test)]
-static mut TEST_NOW: DateTime<Utc> = chrono::MIN_DATETIME;
+thread_local! {
+    /// Faked time, only for the tests that set it so the others get valid tokens
+    static TEST_NOW: std::cell::Cell<Option<DateTime<Utc>>> = const { std::cell::Cell::new(None) };
+}
 
 #[inline]
 fn now() -> DateTime<Utc> {
     #[cfg(not(test))]
     return Utc::now();
     #[cfg(test)]
-    return unsafe { TEST_NOW };
+    return TEST_NOW.with(|now| now.get()).unwrap_or_else(Utc::now);
 }
 
 #[derive(Debug, Eq, PartialEq, thiserror::Error)]
@@ -73,6 +76,11 @@ impl<'de, ClaimsIn: Serialize + 'static, ClaimsOut: DeserializeOwned + 'static>
     }
 
     pub fn encode(&self, ext_claims: ClaimsIn) -> String {
+        self.encode_capped(ext_claims, i64::MAX)
+    }
+
+    /// Like [encode](Self::encode), but the token doesn't outlive `expiration` (UNIX time)
+    pub fn encode_capped(&self, ext_claims: ClaimsIn, expiration: i64) -> String {
         let now = now();
 
         jsonwebtoken::encode(
@@ -81,7 +89,7 @@ impl<'de, ClaimsIn: Serialize + 'static, ClaimsOut: DeserializeOwned + 'static>
                 ext_claims,
                 issuer: self.issuer,
                 issued_at: now.timestamp(),
-                expiration: (now + self.duration).timestamp(),
+                expiration: (now + self.duration).timestamp().min(expiration),
             },
             &self.key_enc,
         )
@@ -89,6 +97,15 @@ impl<'de, ClaimsIn: Serialize + 'static, ClaimsOut: DeserializeOwned + 'static>
     }
 
     pub fn decode(&self, token: &str) -> Result<ClaimsOut, JWTValidationError> {
+        self.decode_with_expiration(token)
+            .map(|(ext_claims, _)| ext_claims)
+    }
+
+    /// Also returns the expiration (UNIX time) of the token
+    pub fn decode_with_expiration(
+        &self,
+        token: &str,
+    ) -> Result<(ClaimsOut, i64), JWTValidationError> {
         let validation = &{
             let mut v = Validation::default();
             v.validate_exp = false;
@@ -110,7 +127,7 @@ impl<'de, ClaimsIn: Serialize + 'static, ClaimsOut: DeserializeOwned + 'static>
             return Err(JWTValidationError::Expired);
         }
 
-        Ok(claims.ext_claims)
+        Ok((claims.ext_claims, claims.expiration))
     }
 }
 
@@ -120,7 +137,7 @@ mod tests {
 
     /// Sets the faked time as minutes from an arbitrary reference point
     fn set_time(minutes: i64) {
-        unsafe { TEST_NOW = Utc.timestamp_nanos(0) + Duration::minutes(minutes) }
+        TEST_NOW.with(|now| now.set(Some(Utc.timestamp_nanos(0) + Duration::minutes(minutes))))
     }
 
     #[derive(serde::Deserialize, serde::Serialize)]
@@ -150,4 +167,23 @@ mod tests {
             assert_eq!(decoded_err, JWTValidationError::Expired);
         }
     }
+
+    #[test]
+    fn capped_expiration() {
+        set_time(0);
+
+        let jwt: JWT<Claims, Claims> = JWT::new("test suite", chrono::Duration::minutes(10));
+        let cap = (Utc.timestamp_nanos(0) + Duration::minutes(2)).timestamp();
+
+        let token = jwt.encode_capped(
+            Claims {
+                sub: String::from("Patrice"),
+            },
+            cap,
+        );
+        assert_eq!(jwt.decode_with_expiration(&token).unwrap().1, cap);
+
+        set_time(5);
+        assert_eq!(jwt.decode(&token).err(), Some(JWTValidationError::Expired));
+    }
 }
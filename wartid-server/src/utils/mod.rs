@@ -1,4 +1,8 @@
 pub mod jwt;
+pub mod saml;
+pub mod secrets;
+pub mod signing;
+pub mod upstream;
 
 pub fn gen_alphanumeric(len: usize) -> String {
     use rand::distributions::Alphanumeric;
@@ -12,3 +16,35 @@ pub fn gen_alphanumeric(len: usize) -> String {
         .take(len)
         .collect()
 }
+
+/// Percent-encodes parameters into a query string, without the leading `?`
+pub fn query_string(params: &[(&str, &str)]) -> String {
+    params
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{name}={}",
+                rocket::http::RawStr::new(value).percent_encode()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Hashes a randomly generated secret (see [gen_alphanumeric]) before storing it
+///
+/// Such secrets have enough entropy for a fast hash to be safe, unlike user passwords which must go
+/// through bcrypt.
+pub fn hash_secret(secret: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, secret.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Checks a secret against a hash produced by [hash_secret], in constant time
+pub fn verify_secret(secret: &str, hash: &str) -> bool {
+    ring::constant_time::verify_slices_are_equal(hash_secret(secret).as_bytes(), hash.as_bytes())
+        .is_ok()
+}
@@ -0,0 +1,128 @@
+use std::path::Path;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::Config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SecretsKeyError {
+    #[error("cannot read or write the secrets key file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("the secrets key file doesn't contain a 256-bit key")]
+    InvalidKey,
+}
+
+/// Encrypts the few secrets WartID has to read back instead of only checking them, like the
+/// client secrets of apps authenticating with `client_secret_jwt`, which are the HMAC key
+///
+/// Secrets are bound to what they belong to (the `context`, like an app ID), so they can't be
+/// moved from one to another in the database.
+pub struct SecretsKey(LessSafeKey);
+
+impl SecretsKey {
+    pub fn from_bytes(key: &[u8]) -> Result<Self, SecretsKeyError> {
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| SecretsKeyError::InvalidKey)?;
+        Ok(Self(LessSafeKey::new(key)))
+    }
+
+    /// Loads the key stored at `path`, generating and saving a new one if the file doesn't exist
+    pub fn load_or_generate(path: &Path) -> Result<Self, SecretsKeyError> {
+        match std::fs::read(path) {
+            Ok(key) => Self::from_bytes(&key),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("generating a new secrets key at {}", path.display());
+
+                let mut key = [0; 32];
+                SystemRandom::new()
+                    .fill(&mut key)
+                    .expect("cannot generate secrets key");
+                std::fs::write(path, key)?;
+
+                Self::from_bytes(&key)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn seal(&self, secret: &str, context: &str) -> String {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("cannot generate nonce");
+
+        let mut sealed = secret.as_bytes().to_vec();
+        self.0
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(context.as_bytes()),
+                &mut sealed,
+            )
+            .expect("cannot encrypt secret");
+
+        URL_SAFE_NO_PAD.encode([&nonce[..], &sealed].concat())
+    }
+
+    /// `None` if it wasn't sealed with this key and context
+    pub fn open(&self, sealed: &str, context: &str) -> Option<String> {
+        let sealed = URL_SAFE_NO_PAD.decode(sealed).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+
+        let mut secret = sealed.to_vec();
+        let secret = self
+            .0
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(context.as_bytes()),
+                &mut secret,
+            )
+            .ok()?;
+
+        String::from_utf8(secret.to_vec()).ok()
+    }
+
+    /// Loads the key configured in [Config::secrets_key] and puts it in Rocket's managed state, as
+    /// an `Option<SecretsKey>`
+    pub fn fairing() -> impl rocket::fairing::Fairing {
+        rocket::fairing::AdHoc::try_on_ignite("secrets key", |rocket| async move {
+            let config = rocket.state::<Config>().unwrap();
+
+            let key = match &config.secrets_key {
+                Some(path) => match Self::load_or_generate(path) {
+                    Ok(key) => Some(key),
+                    Err(err) => {
+                        log::error!("cannot load secrets key: {err}");
+                        return Err(rocket);
+                    }
+                },
+                None => None,
+            };
+
+            Ok(rocket.manage(key))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_open() {
+        let key = SecretsKey::from_bytes(&[1; 32]).unwrap();
+        let sealed = key.seal("hunter2", "app-1");
+
+        assert_eq!(key.open(&sealed, "app-1").as_deref(), Some("hunter2"));
+        assert_eq!(key.open(&sealed, "app-2"), None);
+
+        let other = SecretsKey::from_bytes(&[2; 32]).unwrap();
+        assert_eq!(other.open(&sealed, "app-1"), None);
+        assert_eq!(key.open("AAAA", "app-1"), None);
+    }
+}
@@ -0,0 +1,162 @@
+use std::path::Path;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, OctetKeyPairParameters,
+    OctetKeyPairType, PublicKeyUse,
+};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+
+use crate::config::Config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SigningKeyError {
+    #[error("cannot read or write the signing key file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("the signing key file doesn't contain a PKCS#8 Ed25519 key")]
+    InvalidKey,
+}
+
+fn generate_pkcs8() -> ring::pkcs8::Document {
+    Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+        .expect("cannot generate signing key")
+}
+
+/// WartID's own signing key
+///
+/// Unlike [JWT](super::jwt::JWT), whose secrets never leave the process, tokens signed with this key
+/// are meant to be verified by third parties. The public part is published as a JWK set by
+/// [jwks](crate::routes::oauth2::jwks).
+pub struct SigningKey {
+    kid: String,
+    key_enc: EncodingKey,
+    jwk: Jwk,
+}
+
+impl SigningKey {
+    /// JWA name of the algorithm tokens are signed with, as apps register it
+    pub const ALGORITHM: &'static str = "EdDSA";
+
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, SigningKeyError> {
+        let key_pair =
+            Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|_| SigningKeyError::InvalidKey)?;
+        let public = key_pair.public_key().as_ref();
+
+        let kid = URL_SAFE_NO_PAD
+            .encode(&ring::digest::digest(&ring::digest::SHA256, public).as_ref()[..12]);
+
+        Ok(Self {
+            key_enc: EncodingKey::from_ed_der(pkcs8),
+            jwk: Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    algorithm: Some(Algorithm::EdDSA),
+                    key_id: Some(kid.clone()),
+                    ..Default::default()
+                },
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(public),
+                }),
+            },
+            kid,
+        })
+    }
+
+    pub fn generate() -> Self {
+        Self::from_pkcs8(generate_pkcs8().as_ref()).unwrap()
+    }
+
+    /// Loads the key stored at `path`, generating and saving a new one if the file doesn't exist
+    pub fn load_or_generate(path: &Path) -> Result<Self, SigningKeyError> {
+        match std::fs::read(path) {
+            Ok(pkcs8) => Self::from_pkcs8(&pkcs8),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("generating a new signing key at {}", path.display());
+
+                let pkcs8 = generate_pkcs8();
+                std::fs::write(path, pkcs8.as_ref())?;
+
+                Self::from_pkcs8(pkcs8.as_ref())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(self.kid.clone());
+
+        jsonwebtoken::encode(&header, claims, &self.key_enc).unwrap()
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: vec![self.jwk.clone()],
+        }
+    }
+
+    /// Loads the key configured in [Config::signing_key] and puts it in Rocket's managed state
+    pub fn fairing() -> impl rocket::fairing::Fairing {
+        rocket::fairing::AdHoc::try_on_ignite("signing key", |rocket| async move {
+            let config = rocket.state::<Config>().unwrap();
+
+            let key = match &config.signing_key {
+                Some(path) => match Self::load_or_generate(path) {
+                    Ok(key) => key,
+                    Err(err) => {
+                        log::error!("cannot load signing key: {err}");
+                        return Err(rocket);
+                    }
+                },
+                None => {
+                    log::warn!("no signing key configured, tokens signed by this instance won't be verifiable after a restart");
+                    Self::generate()
+                }
+            };
+
+            Ok(rocket.manage(key))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{DecodingKey, Validation};
+
+    use super::*;
+
+    #[derive(serde::Deserialize, serde::Serialize)]
+    struct Claims {
+        sub: String,
+        exp: i64,
+    }
+
+    #[test]
+    fn verifiable_with_jwks() {
+        let key = SigningKey::generate();
+
+        let token = key.sign(&Claims {
+            sub: String::from("Patrice"),
+            exp: i64::MAX,
+        });
+
+        let header = jsonwebtoken::decode_header(&token).unwrap();
+        let jwks = key.jwks();
+        let jwk = jwks.find(header.kid.as_deref().unwrap()).unwrap();
+
+        let decoded = jsonwebtoken::decode::<Claims>(
+            &token,
+            &DecodingKey::from_jwk(jwk).unwrap(),
+            &Validation::new(Algorithm::EdDSA),
+        )
+        .unwrap();
+
+        assert_eq!(decoded.claims.sub, "Patrice");
+    }
+}
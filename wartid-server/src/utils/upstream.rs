@@ -0,0 +1,312 @@
+//! Client side of the authorization code flow, to log in with the upstream providers in
+//! [Config::upstream_oidc]
+//!
+//! Plain OAuth2 providers like GitHub work too, as long as they have a userinfo-like endpoint: the
+//! ID token is never used, the account is always read from the userinfo endpoint.
+
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+
+use crate::config::{Config, UpstreamOidcConfig};
+use crate::model::FederatedIdentity;
+
+lazy_static::lazy_static! {
+    static ref HTTP: reqwest::Client = reqwest::Client::builder()
+        .user_agent(concat!("WartID/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("cannot build the upstream HTTP client");
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    #[error("{0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("no {0} configured or discovered")]
+    MissingEndpoint(&'static str),
+
+    #[error("the userinfo has no {0} claim")]
+    MissingClaim(String),
+}
+
+/// Endpoints from the configuration, completed by the discovery document
+#[derive(Debug, Deserialize)]
+struct Endpoints {
+    authorization_endpoint: Option<String>,
+    token_endpoint: Option<String>,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// Account at the upstream provider, read from its userinfo endpoint
+#[derive(Debug)]
+pub struct UpstreamAccount {
+    pub subject: String,
+    pub username: String,
+}
+
+pub struct UpstreamProvider {
+    pub config: UpstreamOidcConfig,
+
+    /// Only fetched the first time someone logs in, so a provider being down doesn't prevent
+    /// WartID from starting
+    endpoints: OnceCell<Endpoints>,
+}
+
+impl UpstreamProvider {
+    fn new(config: UpstreamOidcConfig) -> Self {
+        Self {
+            config,
+            endpoints: OnceCell::new(),
+        }
+    }
+
+    pub fn redirect_uri(&self, base_url: &str) -> String {
+        format!("{base_url}login/oidc/{}/callback", self.config.id)
+    }
+
+    async fn endpoints(&self) -> Result<&Endpoints, UpstreamError> {
+        self.endpoints
+            .get_or_try_init(|| async {
+                let mut endpoints = Endpoints {
+                    authorization_endpoint: self.config.authorization_endpoint.clone(),
+                    token_endpoint: self.config.token_endpoint.clone(),
+                    userinfo_endpoint: self.config.userinfo_endpoint.clone(),
+                };
+
+                let is_complete = endpoints.authorization_endpoint.is_some()
+                    && endpoints.token_endpoint.is_some()
+                    && endpoints.userinfo_endpoint.is_some();
+                if let (false, Some(issuer)) = (is_complete, &self.config.issuer) {
+                    let discovered: Endpoints = HTTP
+                        .get(format!(
+                            "{}/.well-known/openid-configuration",
+                            issuer.trim_end_matches('/')
+                        ))
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json()
+                        .await?;
+
+                    endpoints.authorization_endpoint = endpoints
+                        .authorization_endpoint
+                        .or(discovered.authorization_endpoint);
+                    endpoints.token_endpoint =
+                        endpoints.token_endpoint.or(discovered.token_endpoint);
+                    endpoints.userinfo_endpoint =
+                        endpoints.userinfo_endpoint.or(discovered.userinfo_endpoint);
+                }
+
+                Ok(endpoints)
+            })
+            .await
+    }
+
+    /// URL of the provider's consent page
+    pub async fn authorize_url(
+        &self,
+        redirect_uri: &str,
+        state: &str,
+    ) -> Result<String, UpstreamError> {
+        let endpoint = self
+            .endpoints()
+            .await?
+            .authorization_endpoint
+            .as_ref()
+            .ok_or(UpstreamError::MissingEndpoint("authorization_endpoint"))?;
+
+        let query = crate::utils::query_string(&[
+            ("response_type", "code"),
+            ("client_id", &self.config.client_id),
+            ("scope", &self.config.scopes),
+            ("redirect_uri", redirect_uri),
+            ("state", state),
+        ]);
+        let separator = if endpoint.contains('?') { '&' } else { '?' };
+
+        Ok(format!("{endpoint}{separator}{query}"))
+    }
+
+    /// Exchanges the code for an access token, used to read the account from the userinfo
+    /// endpoint
+    pub async fn fetch_account(
+        &self,
+        redirect_uri: &str,
+        code: &str,
+    ) -> Result<UpstreamAccount, UpstreamError> {
+        let endpoints = self.endpoints().await?;
+        let token_endpoint = endpoints
+            .token_endpoint
+            .as_ref()
+            .ok_or(UpstreamError::MissingEndpoint("token_endpoint"))?;
+        let userinfo_endpoint = endpoints
+            .userinfo_endpoint
+            .as_ref()
+            .ok_or(UpstreamError::MissingEndpoint("userinfo_endpoint"))?;
+
+        let token: TokenResponse = HTTP
+            .post(token_endpoint)
+            // GitHub answers with a form otherwise
+            .header(reqwest::header::ACCEPT, "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let userinfo: serde_json::Map<String, serde_json::Value> = HTTP
+            .get(userinfo_endpoint)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .bearer_auth(&token.access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let claim = |name: &str| {
+            userinfo
+                .get(name)
+                .and_then(claim_string)
+                .ok_or_else(|| UpstreamError::MissingClaim(name.to_owned()))
+        };
+
+        Ok(UpstreamAccount {
+            subject: claim(&self.config.subject_claim)?,
+            username: claim(&self.config.username_claim)?,
+        })
+    }
+}
+
+/// Claims are usually strings, but some providers use numbers for IDs
+fn claim_string(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(value) if !value.is_empty() => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Providers in [Config::upstream_oidc], in Rocket's managed state
+pub struct UpstreamProviders(Vec<UpstreamProvider>);
+
+impl UpstreamProviders {
+    pub fn get(&self, id: &str) -> Option<&UpstreamProvider> {
+        self.0.iter().find(|provider| provider.config.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &UpstreamProvider> {
+        self.0.iter()
+    }
+
+    fn validate(configs: &[UpstreamOidcConfig]) -> Result<(), String> {
+        for (i, config) in configs.iter().enumerate() {
+            let id = &config.id;
+            if id.is_empty()
+                || id.len() > 32
+                || !id
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            {
+                return Err(format!(
+                    "invalid id {id:?}, only 1 to 32 lowercase letters, digits and dashes are allowed"
+                ));
+            }
+            if id == FederatedIdentity::DISCORD {
+                return Err(format!("the id {id:?} is reserved"));
+            }
+            if configs[..i].iter().any(|other| &other.id == id) {
+                return Err(format!("duplicate id {id:?}"));
+            }
+
+            let has_endpoints = config.authorization_endpoint.is_some()
+                && config.token_endpoint.is_some()
+                && config.userinfo_endpoint.is_some();
+            if !has_endpoints && config.issuer.is_none() {
+                return Err(format!(
+                    "{id:?} needs an issuer, or the authorization, token and userinfo endpoints"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the providers configured in [Config::upstream_oidc] and puts them in Rocket's
+    /// managed state
+    pub fn fairing() -> impl rocket::fairing::Fairing {
+        rocket::fairing::AdHoc::try_on_ignite("upstream providers", |rocket| async move {
+            let config = rocket.state::<Config>().unwrap();
+
+            if let Err(err) = Self::validate(&config.upstream_oidc) {
+                log::error!("invalid upstream_oidc configuration: {err}");
+                return Err(rocket);
+            }
+
+            let providers = config
+                .upstream_oidc
+                .iter()
+                .cloned()
+                .map(UpstreamProvider::new)
+                .collect();
+
+            Ok(rocket.manage(Self(providers)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(id: &str) -> UpstreamOidcConfig {
+        serde_json::from_value(json!({
+            "id": id,
+            "name": "Test",
+            "client_id": "wartid",
+            "client_secret": "secret",
+            "issuer": "https://idp.example.com",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn claims() {
+        assert_eq!(claim_string(&json!("alice")).as_deref(), Some("alice"));
+        assert_eq!(claim_string(&json!(583231)).as_deref(), Some("583231"));
+        assert_eq!(claim_string(&json!("")), None);
+        assert_eq!(claim_string(&json!(null)), None);
+        assert_eq!(claim_string(&json!(["alice"])), None);
+    }
+
+    #[test]
+    fn validation() {
+        assert!(UpstreamProviders::validate(&[config("gitlab"), config("github-2")]).is_ok());
+        assert!(UpstreamProviders::validate(&[config("gitlab"), config("gitlab")]).is_err());
+        assert!(UpstreamProviders::validate(&[config("discord")]).is_err());
+        assert!(UpstreamProviders::validate(&[config("Git Lab")]).is_err());
+        assert!(UpstreamProviders::validate(&[config("")]).is_err());
+
+        let mut no_issuer = config("gitlab");
+        no_issuer.issuer = None;
+        assert!(UpstreamProviders::validate(&[no_issuer.clone()]).is_err());
+        no_issuer.authorization_endpoint = Some("https://idp.example.com/authorize".into());
+        no_issuer.token_endpoint = Some("https://idp.example.com/token".into());
+        no_issuer.userinfo_endpoint = Some("https://idp.example.com/userinfo".into());
+        assert!(UpstreamProviders::validate(&[no_issuer]).is_ok());
+    }
+}
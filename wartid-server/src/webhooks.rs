@@ -0,0 +1,395 @@
+//! Delivery of the webhooks registered by WartApps
+//!
+//! Events are queued in the database by [WebhookDelivery::enqueue], in the same transaction as the
+//! change they describe. A background task then POSTs the due deliveries, each request carrying:
+//!
+//! * `X-WartID-Event`: the event type, like `user.renamed`
+//! * `X-WartID-Delivery`: the delivery ID, which stays the same across retries
+//! * `X-WartID-Timestamp`: UNIX time of the attempt
+//! * `X-WartID-Signature`: `sha256=` followed by the hex HMAC-SHA256 of `<timestamp>.<body>`, keyed
+//!   with the webhook secret
+//!
+//! Only the WartApps listed in `webhook_apps` get their deliveries, and webhooks are never sent to
+//! loopback, private or link-local addresses, so they can't be used to reach the local network.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Orbit, Rocket};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::model::{DeliveryAttempt, UserAppId, WartIDError, WartIDResult, WebhookDelivery};
+use crate::{DbConn, DbPool};
+
+/// How often due deliveries are looked for
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most deliveries attempted at once
+const BATCH_SIZE: i64 = 50;
+
+/// Receivers taking longer than this to answer are considered down
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Days finished deliveries are kept for the app view
+const RETENTION_DAYS: i64 = 30;
+
+/// Whether an address can be reached from the internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space of carrier-grade NATs
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local and link-local addresses
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolves the host of `url`, `None` unless it is an HTTP(S) URL whose host only resolves to
+/// public addresses
+async fn resolve_public(url: &str) -> Option<(String, Vec<SocketAddr>)> {
+    let url = reqwest::Url::parse(url).ok()?;
+    if url.scheme() != "https" && url.scheme() != "http" {
+        return None;
+    }
+
+    // IPv6 addresses are bracketed in URLs
+    let host = url
+        .host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let addrs = tokio::net::lookup_host((host, url.port_or_known_default()?))
+        .await
+        .ok()?
+        .collect::<Vec<_>>();
+
+    (!addrs.is_empty() && addrs.iter().all(|addr| is_public(addr.ip())))
+        .then(|| (host.to_owned(), addrs))
+}
+
+/// Whether `url` is an HTTP(S) URL whose host only resolves to public addresses
+pub async fn is_public_url(url: &str) -> bool {
+    resolve_public(url).await.is_some()
+}
+
+pub fn signature(secret: &str, timestamp: i64, payload: &str) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = ring::hmac::sign(&key, format!("{timestamp}.{payload}").as_bytes());
+
+    let hex = tag
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    format!("sha256={hex}")
+}
+
+/// Signs and POSTs a delivery, only 2xx responses are considered successful
+pub async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    delivery: &WebhookDelivery,
+) -> DeliveryAttempt {
+    let timestamp = Utc::now().timestamp();
+
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-WartID-Event", &delivery.event)
+        .header("X-WartID-Delivery", delivery.id.to_string())
+        .header("X-WartID-Timestamp", timestamp)
+        .header(
+            "X-WartID-Signature",
+            signature(secret, timestamp, &delivery.payload),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => DeliveryAttempt {
+            status: Some(response.status().as_u16()),
+            error: None,
+        },
+        Ok(response) => DeliveryAttempt {
+            status: Some(response.status().as_u16()),
+            error: Some(format!("unexpected status {}", response.status())),
+        },
+        Err(err) => DeliveryAttempt {
+            status: None,
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+/// Client connecting to `url`, only if the app is allowed to receive webhooks and the URL resolves
+/// to public addresses. The host is only resolved once, so that it can't be pointed at the local
+/// network after being checked.
+async fn client_for(
+    allowed_apps: &[UserAppId],
+    app: UserAppId,
+    url: &str,
+) -> Result<reqwest::Client, String> {
+    if !allowed_apps.contains(&app) {
+        return Err(String::from(
+            "this WartApp is not allowed to receive webhooks",
+        ));
+    }
+
+    let (host, addrs) = resolve_public(url)
+        .await
+        .ok_or("the webhook URL doesn't only resolve to public addresses")?;
+
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .user_agent(concat!("WartID/", env!("CARGO_PKG_VERSION")))
+        .resolve_to_addrs(&host, &addrs)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+async fn deliver_due(pool: &DbPool, allowed_apps: &Arc<[UserAppId]>) -> WartIDResult<()> {
+    let db = pool.get().await.ok_or(WartIDError::DatabaseConnection)?;
+    let due = db
+        .run(|db| WebhookDelivery::find_due(db, BATCH_SIZE))
+        .await?;
+
+    // A slow receiver shouldn't hold back the others
+    let attempts = due
+        .into_iter()
+        .map(|(delivery, webhook)| {
+            let (pool, allowed_apps) = (pool.clone(), allowed_apps.clone());
+            tokio::spawn(async move {
+                let client = client_for(&allowed_apps, webhook.user_apps_id, &webhook.url).await;
+                let db = pool.get().await.ok_or(WartIDError::DatabaseConnection)?;
+
+                let client = match client {
+                    Ok(client) => client,
+                    Err(error) => {
+                        log::warn!("webhook delivery {} given up: {error}", delivery.id);
+                        return db
+                            .run(move |db| WebhookDelivery::give_up(db, &delivery, error))
+                            .await;
+                    }
+                };
+
+                let attempt = deliver(&client, &webhook.url, &webhook.secret, &delivery).await;
+                if let Some(error) = &attempt.error {
+                    log::warn!("webhook delivery {} failed: {error}", delivery.id);
+                }
+
+                db.run(move |db| WebhookDelivery::record_attempt(db, &delivery, attempt))
+                    .await
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for attempt in attempts {
+        match attempt.await {
+            Ok(result) => result?,
+            Err(err) => log::error!("webhook delivery task failed: {err}"),
+        }
+    }
+
+    let before = Utc::now().naive_utc() - chrono::Duration::days(RETENTION_DAYS);
+    db.run(move |db| WebhookDelivery::prune(db, before)).await?;
+
+    Ok(())
+}
+
+pub fn fairing() -> impl Fairing {
+    #[derive(Default)]
+    struct WebhooksFairing {
+        worker: Mutex<Option<JoinHandle<()>>>,
+    }
+
+    #[rocket::async_trait]
+    impl Fairing for WebhooksFairing {
+        fn info(&self) -> Info {
+            Info {
+                name: "Webhook deliveries",
+                kind: Kind::Liftoff | Kind::Shutdown,
+            }
+        }
+
+        async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+            let pool = DbConn::pool(rocket).unwrap().clone();
+            let allowed_apps: Arc<[UserAppId]> = rocket
+                .state::<Config>()
+                .unwrap()
+                .webhook_apps
+                .clone()
+                .into();
+
+            *self.worker.lock().await = Some(tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+
+                    if let Err(err) = deliver_due(&pool, &allowed_apps).await {
+                        log::error!("cannot deliver webhooks: {err}");
+                    }
+                }
+            }));
+        }
+
+        async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+            if let Some(worker) = self.worker.lock().await.take() {
+                worker.abort();
+            }
+        }
+    }
+
+    WebhooksFairing::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+
+    use crate::model::{WebhookDeliveryId, WebhookId};
+
+    use super::*;
+
+    /// Answers a single request with `status`, returning the request as received
+    async fn stub(status: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .map_or(0, |length| length.parse().unwrap());
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+
+            let response =
+                format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+            stream.write_all(response.as_bytes()).await.unwrap();
+
+            String::from_utf8(request).unwrap()
+        });
+
+        (url, request)
+    }
+
+    #[rocket::async_test]
+    async fn deliver_signed() {
+        let delivery = WebhookDelivery {
+            id: WebhookDeliveryId::from_uuid(Uuid::from_u128(1)),
+            webhooks_id: WebhookId::from_uuid(Uuid::from_u128(2)),
+            event: String::from("user.renamed"),
+            payload: String::from(r#"{"event":"user.renamed"}"#),
+            created: Utc::now().naive_utc(),
+            attempts: 0,
+            next_attempt: None,
+            delivered: None,
+            last_status: None,
+            last_error: None,
+        };
+        let client = reqwest::Client::new();
+
+        let (url, request) = stub("204 No Content").await;
+        let attempt = deliver(&client, &url, "s3cr3t", &delivery).await;
+        assert_eq!(attempt.status, Some(204));
+        assert!(attempt.error.is_none());
+
+        let request = request.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /hook HTTP/1.1"));
+        assert_eq!(body, delivery.payload);
+
+        let header = |name: &str| {
+            head.lines()
+                .find_map(|line| line.strip_prefix(&format!("{name}: ")))
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(header("x-wartid-event"), "user.renamed");
+        assert_eq!(
+            header("x-wartid-delivery"),
+            "00000000-0000-0000-0000-000000000001"
+        );
+        let timestamp = header("x-wartid-timestamp").parse().unwrap();
+        assert_eq!(
+            header("x-wartid-signature"),
+            signature("s3cr3t", timestamp, &delivery.payload)
+        );
+
+        let (url, _) = stub("500 Internal Server Error").await;
+        let attempt = deliver(&client, &url, "s3cr3t", &delivery).await;
+        assert_eq!(attempt.status, Some(500));
+        assert!(attempt.error.is_some());
+    }
+
+    #[rocket::async_test]
+    async fn public_urls() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+
+        assert!(is_public_url("https://93.184.216.34/hook").await);
+        assert!(!is_public_url("http://127.0.0.1:8000/hook").await);
+        assert!(!is_public_url("http://[::1]/hook").await);
+        assert!(!is_public_url("http://localhost/hook").await);
+        assert!(!is_public_url("ftp://93.184.216.34/hook").await);
+        assert!(!is_public_url("/hook").await);
+    }
+}